@@ -0,0 +1,96 @@
+//! Benchmarks of the protocol overhead of pinentry prompts
+//!
+//! The prompt benchmarks run against a fake pinentry (a shell script answering every command immediately), so they
+//! measure the cost of spawning and talking to pinentry rather than the time taken by a user.
+
+extern crate criterion;
+extern crate pinentry_rs;
+
+use std::io::Cursor;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+use pinentry_rs::assuan;
+
+#[cfg(unix)]
+mod fake {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::process;
+
+    const SCRIPT: &str = r#"#!/bin/sh
+echo "OK Pleased to meet you"
+while IFS= read -r line; do
+  case "$line" in
+    GETPIN) echo "D secret"; echo OK ;;
+    BYE) echo "OK closing connection"; exit 0 ;;
+    *) echo OK ;;
+  esac
+done
+"#;
+
+    /// Write the fake pinentry to a temporary directory and return its path
+    pub fn pinentry() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pinentry-rs-bench-{}", process::id()));
+        fs::create_dir_all(&dir).expect("temporary directory can be created");
+        let exe = dir.join("pinentry");
+        fs::write(&exe, SCRIPT).expect("fake pinentry can be written");
+        fs::set_permissions(&exe, fs::Permissions::from_mode(0o755)).expect("fake pinentry can be made executable");
+        exe
+    }
+}
+
+#[cfg(unix)]
+fn configured(exe: &str) -> pinentry_rs::PinentryBuilder {
+    pinentry_rs::pinentry()
+        .exe(exe)
+        .window_title("Benchmark")
+        .description("Enter the passphrase for the benchmark")
+        .label_ok("Unlock")
+        .label_cancel("Cancel")
+        .timeout(60)
+}
+
+#[cfg(unix)]
+fn bench_cold_prompt(c: &mut Criterion) {
+    let exe = fake::pinentry().display().to_string();
+    c.bench_function("cold prompt", |b| {
+        b.iter(|| configured(&exe).pin("PIN:").expect("PIN is returned"))
+    });
+}
+
+#[cfg(unix)]
+fn bench_session_prompt(c: &mut Criterion) {
+    let exe = fake::pinentry().display().to_string();
+    let mut session = configured(&exe).connect().expect("session is started");
+    c.bench_function("session prompt", |b| {
+        b.iter(|| session.pin("PIN:").expect("PIN is returned"))
+    });
+}
+
+fn bench_parser(c: &mut Criterion) {
+    let mut input = String::new();
+    for _ in 0..100 {
+        input.push_str(
+            "S PASSWORD_FROM_CACHE\n# a comment\nD some%25secret%0Adata\nERR 83886179 Operation cancelled\nOK\n",
+        );
+    }
+
+    let mut group = c.benchmark_group("parser");
+    group.throughput(Throughput::Bytes(input.len() as u64));
+    group.bench_function("read_line", |b| {
+        b.iter_batched(
+            || Cursor::new(input.as_bytes()),
+            |mut reader| while assuan::read_line(&mut reader).expect("valid line").is_some() {},
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+#[cfg(unix)]
+criterion_group!(benches, bench_cold_prompt, bench_session_prompt, bench_parser);
+#[cfg(not(unix))]
+criterion_group!(benches, bench_parser);
+criterion_main!(benches);
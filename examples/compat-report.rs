@@ -0,0 +1,18 @@
+#![deny(warnings)]
+#![warn(unused_must_use)]
+extern crate pinentry_rs;
+
+use pinentry_rs::compat;
+
+fn main() {
+    let matrix = compat::run_matrix();
+    if matrix.is_empty() {
+        println!("No pinentry found in PATH");
+    }
+    for (exe, report) in matrix {
+        match report {
+            Ok(report) => println!("{}\n", report),
+            Err(e) => println!("{}: cannot be checked: {}\n", exe.display(), e),
+        }
+    }
+}
@@ -0,0 +1,47 @@
+#![deny(warnings)]
+#![warn(unused_must_use)]
+//! A `crypttab` keyscript (`keyscript=/path/to/luks-keyscript`) asking for the passphrase through pinentry
+//!
+//! cryptsetup runs the keyscript with the volume in `CRYPTTAB_NAME`, the device in `CRYPTTAB_SOURCE` and the number
+//! of failed attempts in `CRYPTTAB_TRIED`, and reads the passphrase from its standard output.
+extern crate pinentry_rs;
+
+use std::env;
+use std::io::{self, Write};
+use std::process;
+
+use pinentry_rs::luks::{LuksVolume, WRONG_PASSPHRASE};
+use pinentry_rs::pinentry;
+
+fn main() {
+    let name = env::var("CRYPTTAB_NAME").unwrap_or_else(|_| "cryptroot".to_string());
+    let mut volume = LuksVolume::new(name);
+    if let Ok(source) = env::var("CRYPTTAB_SOURCE") {
+        // sources are often given as UUID=...
+        match source.strip_prefix("UUID=") {
+            Some(uuid) => volume = volume.uuid(uuid.to_string()),
+            None => volume = volume.device(source.into()),
+        }
+    }
+
+    let mut builder = pinentry();
+    let tried: u32 = env::var("CRYPTTAB_TRIED")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(0);
+    if tried > 0 {
+        builder = builder.error_text(WRONG_PASSPHRASE.to_string());
+    }
+
+    match builder.volume_passphrase(&volume) {
+        Ok(passphrase) => {
+            let mut stdout = io::stdout();
+            stdout.write_all(passphrase.unsecure()).expect("passphrase is written");
+            stdout.flush().expect("passphrase is written");
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -1,5 +1,5 @@
 #![deny(warnings)]
-#[warn(unused_must_use)]
+#![warn(unused_must_use)]
 extern crate pinentry_rs;
 
 use pinentry_rs::pinentry;
@@ -7,9 +7,9 @@ use std::str;
 
 fn main() {
     let pin = pinentry()
-        .window_title("Unlock disk".to_string())
-        .description("A disk has no key.".to_string())
-        .pin("Enter passphrase to unlock:".to_string())
+        .window_title("Unlock disk")
+        .description("A disk has no key.")
+        .pin("Enter passphrase to unlock:")
         .expect("A password");
 
     println!("PIN: {}", pin);
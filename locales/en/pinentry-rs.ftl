@@ -0,0 +1,83 @@
+# The texts generated by pinentry-rs, as the template for translations
+#
+# Line breaks are added by the library (e.g. between a description and a lockout notice), so messages are single
+# lines. $unit selectors choose between "digits" and "characters".
+
+## Retries
+
+attempts-left = { $message } ({ $left ->
+    [one] 1 attempt left
+   *[other] { $left } attempts left
+})
+empty-passphrase = The passphrase must not be empty
+
+## Choices and pages
+
+choice-prompt = Choice (1-{ $count }):
+choice-out-of-range = Please enter a number between 1 and { $count }
+continue-label = Continue
+page-indicator = (Page { $page } of { $pages })
+
+## Prompts
+
+passphrase-prompt = Passphrase:
+password-prompt = Password:
+username-prompt = Username:
+
+## New passphrases
+
+new-passphrase-title = New passphrase
+new-passphrase-description = Please enter the new passphrase
+new-passphrase-repeat = Repeat:
+new-passphrase-mismatch = The passphrases do not match
+new-passphrase-quality = Quality:
+passphrase-too-short = The passphrase must be at least { $min } characters long
+passphrase-unchanged = The new passphrase must differ from the current one
+
+## Security tokens
+
+token-description = Please enter the PIN of your security token
+token-digits-only = The PIN may only contain digits
+token-length-exact = The PIN must have { $length } { $unit ->
+    [digits] digits
+   *[characters] characters
+}
+token-length-range = The PIN must have { $min } to { $max } { $unit ->
+    [digits] digits
+   *[characters] characters
+}
+token-length-min = The PIN must have at least { $min } { $unit ->
+    [digits] digits
+   *[characters] characters
+}
+token-lockout = { $attempts ->
+    [one] 1 attempt remaining before lockout
+   *[other] { $attempts } attempts remaining before lockout
+}
+
+## SSH keys
+
+ssh-passphrase = Enter passphrase for { $path }
+ssh-passphrase-fingerprint = Enter passphrase for { $path } ({ $fingerprint })
+ssh-confirm = Allow use of key { $key }?
+ssh-fingerprint = Key fingerprint { $fingerprint }.
+
+## Disks
+
+luks-title = Disk unlock
+luks-description = Please enter the passphrase for disk { $name }
+luks-description-device = Please enter the passphrase for disk { $name } ({ $device })
+luks-wrong-passphrase = No key available with this passphrase
+
+## Git
+
+git-auth-failed = Authentication failed, please try again
+git-password = Password for { $user } at { $url }
+git-username = Username for { $url }
+
+## Errors
+
+policy-denied = The prompt was denied by policy: { $reason }
+rate-limited = Too many prompts, not showing another one for { $seconds } seconds
+locked-out = Locked out after { $attempts } wrong attempts
+unlock-failed = Unlocking failed: { $cause }
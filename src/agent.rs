@@ -0,0 +1,299 @@
+//! Client for [gpg-agent](https://www.gnupg.org/documentation/manuals/gnupg/Invoking-GPG_002dAGENT.html)
+//!
+//! gpg-agent speaks the same Assuan protocol as pinentry, over a Unix socket. An application that keeps its keys in
+//! gpg-agent can ask whether a passphrase is cached before deciding to prompt:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use pinentry_rs::agent::GpgAgent;
+//!
+//! let mut agent = GpgAgent::connect()?;
+//! if !agent.is_cached("0123456789ABCDEF0123456789ABCDEF01234567")? {
+//!     // prompt for the passphrase
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A passphrase obtained through pinentry can be handed to the agent with [`GpgAgent::preset`], so that the following
+//! gpg operations do not prompt again (this needs `allow-preset-passphrase` in `gpg-agent.conf`).
+//!
+//! The agent is not started by this module: [`GpgAgent::connect`] fails if it is not running.
+
+use std::env;
+use std::io;
+use std::io::{BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use zeroize::Zeroizing;
+
+use super::assuan::{read_line, redacted, Line, Status, MAX_LINE_LENGTH};
+use super::{invalid, Error, Result, SecretPin};
+
+/// A connection to gpg-agent
+pub struct GpgAgent {
+    stream: BufReader<UnixStream>,
+}
+
+impl GpgAgent {
+    /// Connect to the agent of the current user (the socket reported by `gpgconf --list-dirs agent-socket`)
+    pub fn connect() -> Result<GpgAgent> {
+        GpgAgent::connect_to(socket_path()?)
+    }
+
+    /// Connect to the agent listening on `socket`
+    pub fn connect_to<P: AsRef<Path>>(socket: P) -> Result<GpgAgent> {
+        let mut agent = GpgAgent {
+            stream: BufReader::new(UnixStream::connect(socket)?),
+        };
+        match agent.next_line()? {
+            Line::Ok(_) => {
+                debug!("connected to gpg-agent");
+                Ok(agent)
+            }
+            line => Err(Error::ProtocolError(format!("unexpected greeting: {:?}", line))),
+        }
+    }
+
+    /// Whether the passphrase of the key with the given keygrip is cached, i.e. using the key will not prompt
+    ///
+    /// Fails with [`Error::AgentError`] (`NOT_FOUND`) if the agent does not know the key.
+    pub fn is_cached(&mut self, keygrip: &str) -> Result<bool> {
+        check_keygrip(keygrip)?;
+        let status = self.request(&Line::Command(
+            "KEYINFO".to_string(),
+            Some(format!("--no-ask {}", keygrip)),
+        ))?;
+        // S KEYINFO <keygrip> <type> <serialno> <idstr> <cached> <protection> ...
+        let info = status
+            .iter()
+            .filter(|s| s.keyword == "KEYINFO")
+            .find_map(|s| s.info.as_deref())
+            .ok_or_else(|| Error::ProtocolError("gpg-agent did not return the key info".to_string()))?;
+        match info.split(' ').nth(4) {
+            Some(cached) => Ok(cached == "1"),
+            None => Err(Error::ProtocolError(format!("malformed key info: {}", info))),
+        }
+    }
+
+    /// Put `passphrase` into the agent's cache for the key with the given keygrip, for `ttl` (or until the agent
+    /// is restarted, if `None`)
+    ///
+    /// The agent only accepts this if `allow-preset-passphrase` is configured - otherwise this fails with
+    /// [`Error::AgentError`] (`NOT_SUPPORTED`).
+    pub fn preset(&mut self, keygrip: &str, passphrase: &SecretPin, ttl: Option<Duration>) -> Result<()> {
+        check_keygrip(keygrip)?;
+        let ttl = ttl.map_or(-1, |ttl| ttl.as_secs().min(i32::MAX as u64) as i64);
+        let prefix = format!("PRESET_PASSPHRASE {} {} ", keygrip, ttl);
+        if prefix.len() + 2 * passphrase.unsecure().len() >= MAX_LINE_LENGTH {
+            return Err(invalid("the passphrase is too long to be preset"));
+        }
+
+        trace!("> {}[redacted]", prefix);
+        // allocated once, so that no copy of the passphrase is left behind by growing the buffer, and wiped when dropped
+        let mut buf = Zeroizing::new(Vec::with_capacity(prefix.len() + 2 * passphrase.unsecure().len() + 1));
+        buf.extend_from_slice(prefix.as_bytes());
+        for b in passphrase.unsecure() {
+            buf.push(HEX_DIGITS[(b >> 4) as usize]);
+            buf.push(HEX_DIGITS[(b & 0xF) as usize]);
+        }
+        buf.push(b'\n');
+        self.exchange(&buf).map(|_| ())
+    }
+
+    /// Send `line` and read the reply, returning the status lines
+    fn request(&mut self, line: &Line) -> Result<Vec<Status>> {
+        trace!("> {}", redacted(line));
+        let mut buf = Vec::new();
+        line.encode(&mut buf)?;
+        self.exchange(&buf)
+    }
+
+    /// Send an encoded request and read the reply
+    fn exchange(&mut self, request: &[u8]) -> Result<Vec<Status>> {
+        let stream = self.stream.get_mut();
+        stream.write_all(request)?;
+        stream.flush()?;
+
+        let mut status = Vec::new();
+        loop {
+            match self.next_line()? {
+                Line::Ok(_) => return Ok(status),
+                Line::Err(e) => return Err(Error::AgentError(e)),
+                Line::Status(s) => status.push(s),
+                Line::Comment(_) => (),
+                line => return Err(Error::ProtocolError(format!("unexpected line: {}", redacted(&line)))),
+            }
+        }
+    }
+
+    fn next_line(&mut self) -> Result<Line> {
+        let line = read_line(&mut self.stream)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "gpg-agent closed the connection"))?;
+        trace!("< {}", redacted(&line));
+        Ok(line)
+    }
+}
+
+impl Drop for GpgAgent {
+    fn drop(&mut self) {
+        let _ = self.request(&Line::Command("BYE".to_string(), None));
+    }
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+/// A keygrip is 40 hex digits - anything else would end up verbatim in the command
+fn check_keygrip(keygrip: &str) -> Result<()> {
+    if keygrip.len() == 40 && keygrip.bytes().all(|b| b.is_ascii_hexdigit()) {
+        Ok(())
+    } else {
+        Err(invalid(&format!("{:?} is not a keygrip", keygrip)))
+    }
+}
+
+/// Path of the agent socket, asking `gpgconf` first and falling back to the default location in the GnuPG home
+fn socket_path() -> Result<PathBuf> {
+    let output = Command::new("gpgconf")
+        .args(["--list-dirs", "agent-socket"])
+        .stderr(Stdio::null())
+        .output();
+    if let Ok(output) = output {
+        let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if output.status.success() && !path.is_empty() {
+            return Ok(PathBuf::from(path));
+        }
+    }
+
+    let home = match env::var_os("GNUPGHOME") {
+        Some(home) => PathBuf::from(home),
+        None => env::var_os("HOME")
+            .map(|home| Path::new(&home).join(".gnupg"))
+            .ok_or_else(|| invalid("neither GNUPGHOME nor HOME is set"))?,
+    };
+    Ok(home.join("S.gpg-agent"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    use super::super::assuan::AssuanError;
+
+    /// Serve a single connection on a fresh socket, answering each command with the lines produced by `respond`
+    fn fake_agent(name: &str, respond: fn(&str) -> Vec<String>) -> (PathBuf, thread::JoinHandle<Vec<String>>) {
+        let dir = env::temp_dir().join(format!("pinentry-rs-agent-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("S.gpg-agent");
+        let _ = fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            writeln!(stream, "OK Pleased to meet you").unwrap();
+            let mut commands = Vec::new();
+            for line in BufReader::new(stream.try_clone().unwrap()).lines() {
+                let line = line.unwrap();
+                for reply in respond(&line) {
+                    writeln!(stream, "{}", reply).unwrap();
+                }
+                let bye = line == "BYE";
+                commands.push(line);
+                if bye {
+                    break;
+                }
+            }
+            commands
+        });
+        (socket, handle)
+    }
+
+    #[test]
+    fn test_is_cached() {
+        let (socket, handle) = fake_agent("is-cached", |line| match line.rsplit(' ').next() {
+            Some("0123456789ABCDEF0123456789ABCDEF01234567") => vec![
+                "S KEYINFO 0123456789ABCDEF0123456789ABCDEF01234567 D - - 1 P - - -".to_string(),
+                "OK".to_string(),
+            ],
+            Some("89ABCDEF0123456789ABCDEF0123456789ABCDEF") => vec![
+                "S KEYINFO 89ABCDEF0123456789ABCDEF0123456789ABCDEF D - - - P - - -".to_string(),
+                "OK".to_string(),
+            ],
+            Some("BYE") => vec!["OK closing connection".to_string()],
+            _ => vec!["ERR 67108891 Not found <GPG Agent>".to_string()],
+        });
+
+        let mut agent = GpgAgent::connect_to(&socket).unwrap();
+        assert!(agent.is_cached("0123456789ABCDEF0123456789ABCDEF01234567").unwrap());
+        assert!(!agent.is_cached("89ABCDEF0123456789ABCDEF0123456789ABCDEF").unwrap());
+        match agent.is_cached("FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF") {
+            Err(Error::AgentError(e)) => assert_eq!(AssuanError::NOT_FOUND, e.error_code()),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        match agent.is_cached("0123\nBYE") {
+            Err(Error::InvalidConfiguration(_)) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        drop(agent);
+
+        let commands = handle.join().unwrap();
+        assert_eq!(
+            vec![
+                "KEYINFO --no-ask 0123456789ABCDEF0123456789ABCDEF01234567",
+                "KEYINFO --no-ask 89ABCDEF0123456789ABCDEF0123456789ABCDEF",
+                "KEYINFO --no-ask FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF",
+                "BYE",
+            ],
+            commands
+        );
+    }
+
+    #[test]
+    fn test_preset() {
+        let (socket, handle) = fake_agent("preset", |line| {
+            if line.starts_with("PRESET_PASSPHRASE 0123456789ABCDEF0123456789ABCDEF01234567 ") {
+                vec!["OK".to_string()]
+            } else if line == "BYE" {
+                vec!["OK closing connection".to_string()]
+            } else {
+                vec!["ERR 67108924 Not supported <GPG Agent> - no --allow-preset-passphrase".to_string()]
+            }
+        });
+
+        let mut agent = GpgAgent::connect_to(&socket).unwrap();
+        let grip = "0123456789ABCDEF0123456789ABCDEF01234567";
+        agent.preset(grip, &SecretPin::from("pass word\n%"), None).unwrap();
+        agent
+            .preset(grip, &SecretPin::from("\u{fc}"), Some(Duration::from_secs(600)))
+            .unwrap();
+        match agent.preset("89ABCDEF0123456789ABCDEF0123456789ABCDEF", &SecretPin::from("x"), None) {
+            Err(Error::AgentError(e)) => assert_eq!(AssuanError::NOT_SUPPORTED, e.error_code()),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        match agent.preset(grip, &SecretPin::new(vec![b'x'; 500]), None) {
+            Err(Error::InvalidConfiguration(_)) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        drop(agent);
+
+        let commands = handle.join().unwrap();
+        assert_eq!(
+            vec![
+                "PRESET_PASSPHRASE 0123456789ABCDEF0123456789ABCDEF01234567 -1 7061737320776F72640A25",
+                "PRESET_PASSPHRASE 0123456789ABCDEF0123456789ABCDEF01234567 600 C3BC",
+                "PRESET_PASSPHRASE 89ABCDEF0123456789ABCDEF0123456789ABCDEF -1 78",
+                "BYE",
+            ],
+            commands
+        );
+    }
+}
@@ -0,0 +1,318 @@
+//! Answering systemd password requests (the agent side of the
+//! [password agent protocol](https://systemd.io/PASSWORD_AGENTS/))
+//!
+//! systemd (e.g. `systemd-cryptsetup`, or services using `systemd-ask-password`) asks for passwords by writing
+//! `ask.*` files to `/run/systemd/ask-password/`. A [`PasswordAgent`] shows each pending question through pinentry and
+//! sends the answer to the socket named in the question, so a desktop session can answer them with proper dialogs:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use std::time::Duration;
+//!
+//! use pinentry_rs::ask_password::PasswordAgent;
+//! use pinentry_rs::pinentry;
+//!
+//! let mut agent = PasswordAgent::new(pinentry().window_title("Password request"));
+//! agent.run(Duration::from_millis(500))
+//! # }
+//! ```
+//!
+//! Replying needs write access to the sockets, which usually means running as root. Questions whose asking process
+//! has exited are skipped; the `NotAfter` deadline is not checked (systemd removes expired questions itself).
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use zeroize::Zeroize;
+
+use super::messages::Message;
+use super::{Error, PinentryBuilder, Result, SecretPin};
+
+/// The directory systemd puts its questions in
+pub const ASK_PASSWORD_DIR: &str = "/run/systemd/ask-password";
+
+/// A pending password request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    /// The `ask.*` file
+    pub path: PathBuf,
+    /// The socket to send the answer to
+    pub socket: PathBuf,
+    /// The text to show (e.g. `Please enter passphrase for disk ...`)
+    pub message: Option<String>,
+    /// Identifies the requester (e.g. `cryptsetup:/dev/sda2`)
+    pub id: Option<String>,
+    /// Name of an icon for the dialog
+    pub icon: Option<String>,
+    /// Process asking the question
+    pub pid: Option<u32>,
+    /// Whether the password may be shown while it is typed
+    pub echo: bool,
+}
+
+impl Question {
+    /// Parse the contents of an `ask.*` file
+    pub fn parse(path: &Path, contents: &str) -> Result<Question> {
+        let mut socket = None;
+        let mut question = Question {
+            path: path.to_path_buf(),
+            socket: PathBuf::new(),
+            message: None,
+            id: None,
+            icon: None,
+            pid: None,
+            echo: false,
+        };
+        let mut in_ask = false;
+        for line in contents.lines().map(str::trim) {
+            if line.starts_with('[') {
+                in_ask = line == "[Ask]";
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some(pair) if in_ask => pair,
+                _ => continue,
+            };
+            match key {
+                "Socket" => socket = Some(PathBuf::from(value)),
+                "Message" => question.message = Some(value.to_string()),
+                "Id" => question.id = Some(value.to_string()),
+                "Icon" => question.icon = Some(value.to_string()),
+                "PID" => question.pid = value.parse().ok(),
+                "Echo" => question.echo = value == "1",
+                _ => (),
+            }
+        }
+        question.socket =
+            socket.ok_or_else(|| Error::ProtocolError(format!("{} does not name a socket", path.display())))?;
+        Ok(question)
+    }
+
+    /// Whether the process asking the question is still running (`true` if it is not known)
+    pub fn is_alive(&self) -> bool {
+        self.pid
+            .is_none_or(|pid| Path::new("/proc").join(pid.to_string()).exists())
+    }
+
+    /// Send `password` as the answer, or tell the requester that the question was cancelled (`None`)
+    pub fn reply(&self, password: Option<&SecretPin>) -> io::Result<()> {
+        let mut packet = match password {
+            Some(password) => {
+                let mut packet = Vec::with_capacity(password.unsecure().len() + 1);
+                packet.push(b'+');
+                packet.extend_from_slice(password.unsecure());
+                packet
+            }
+            None => vec![b'-'],
+        };
+        let res = UnixDatagram::unbound().and_then(|socket| socket.send_to(&packet, &self.socket));
+        packet.zeroize();
+        res.map(|_| ())
+    }
+}
+
+/// The questions pending in `dir` (usually [`ASK_PASSWORD_DIR`]), leaving out `ask.*` files that cannot be parsed
+pub fn pending(dir: &Path) -> Result<Vec<Question>> {
+    let mut questions = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_question = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("ask."));
+        if !is_question {
+            continue;
+        }
+        // the question may have been answered (and removed) in the meantime
+        match fs::read_to_string(&path) {
+            Ok(contents) => match Question::parse(&path, &contents) {
+                Ok(question) => questions.push(question),
+                Err(e) => debug!("skipping password request {}: {}", path.display(), e),
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    questions.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(questions)
+}
+
+/// Answers systemd's password requests through pinentry, see the [module documentation](self)
+pub struct PasswordAgent {
+    builder: PinentryBuilder,
+    dir: PathBuf,
+    answered: HashSet<PathBuf>,
+}
+
+impl PasswordAgent {
+    /// An agent prompting with the settings of `builder` (the message of each question becomes the description)
+    pub fn new(builder: PinentryBuilder) -> Self {
+        PasswordAgent {
+            builder,
+            dir: PathBuf::from(ASK_PASSWORD_DIR),
+            answered: HashSet::new(),
+        }
+    }
+
+    /// Watch `dir` instead of [`ASK_PASSWORD_DIR`]
+    pub fn dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.dir = dir.as_ref().to_path_buf();
+        self
+    }
+
+    /// Answer the questions that are pending now (and were not answered before), returning how many were answered
+    ///
+    /// A cancelled (or timed out) prompt is answered as cancelled, so the requester does not wait for its timeout. A
+    /// question that cannot be answered (e.g. because pinentry fails or its socket is gone) is logged and not asked
+    /// again; only failing to read the directory is an error.
+    pub fn answer_pending(&mut self) -> Result<usize> {
+        let questions = pending(&self.dir)?;
+        self.answered.retain(|path| questions.iter().any(|q| q.path == *path));
+
+        let mut count = 0;
+        for question in questions {
+            if self.answered.contains(&question.path) || !question.is_alive() {
+                continue;
+            }
+            match self.answer(&question) {
+                Ok(()) => count += 1,
+                Err(e) => debug!("could not answer password request {}: {}", question.path.display(), e),
+            }
+            self.answered.insert(question.path);
+        }
+        Ok(count)
+    }
+
+    /// Answer questions as they come, checking for new ones every `poll`
+    ///
+    /// Returns only if the directory cannot be read, see [`answer_pending`](PasswordAgent::answer_pending).
+    pub fn run(&mut self, poll: Duration) -> Result<()> {
+        loop {
+            self.answer_pending()?;
+            thread::sleep(poll);
+        }
+    }
+
+    fn answer(&self, question: &Question) -> Result<()> {
+        let mut builder = self.builder.clone();
+        if let Some(ref message) = question.message {
+            builder = builder.description(message.clone());
+        }
+        debug!("answering password request {}", question.path.display());
+        match builder.pin(Message::PasswordPrompt.text()) {
+            Ok(password) => question.reply(Some(&password))?,
+            Err(Error::Cancelled) | Err(Error::Timeout) => question.reply(None)?,
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    use super::super::pinentry;
+    use super::super::test_util::FakePinentry;
+
+    #[test]
+    fn test_parse() {
+        let contents = "[Ask]\nPID=1\nSocket=/run/systemd/ask-password/sck.1\nAcceptCached=1\nEcho=0\n\
+                        Message=Please enter passphrase for disk root\nIcon=drive-harddisk\nId=cryptsetup:/dev/sda2\n";
+        let question = Question::parse(Path::new("ask.1"), contents).unwrap();
+        assert_eq!(Path::new("/run/systemd/ask-password/sck.1"), question.socket);
+        assert_eq!(
+            Some("Please enter passphrase for disk root"),
+            question.message.as_deref()
+        );
+        assert_eq!(Some("cryptsetup:/dev/sda2"), question.id.as_deref());
+        assert_eq!(Some(1), question.pid);
+        assert!(!question.echo);
+        assert!(Question::parse(Path::new("ask.2"), "[Ask]\nMessage=no socket\n").is_err());
+    }
+
+    #[test]
+    fn test_answer_pending() {
+        // answers the first question, cancels the second
+        let fake = FakePinentry::new(&[
+            ("SETDESC*", r#"desc="$line"; echo OK"#),
+            (
+                "GETPIN",
+                r#"if [ "$desc" = "SETDESC Question 1" ]; then echo "D secret"; echo OK; else echo "ERR 83886179 Operation cancelled <Pinentry>"; fi"#,
+            ),
+        ]);
+        let dir = env::temp_dir().join(format!("pinentry-rs-ask-password-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut sockets = Vec::new();
+        for n in 1..=2 {
+            let path = dir.join(format!("sck.{}", n));
+            let _ = fs::remove_file(&path);
+            sockets.push(UnixDatagram::bind(&path).unwrap());
+            let ask = format!("[Ask]\nSocket={}\nMessage=Question {}\n", path.display(), n);
+            fs::write(dir.join(format!("ask.{}", n)), ask).unwrap();
+        }
+
+        let mut agent = PasswordAgent::new(pinentry().exe(fake.exe())).dir(&dir);
+        assert_eq!(2, agent.answer_pending().unwrap());
+        assert_eq!(2, fake.spawn_count());
+        // still pending, but already answered
+        assert_eq!(0, agent.answer_pending().unwrap());
+
+        let mut buf = [0; 64];
+        let n = sockets[0].recv(&mut buf).unwrap();
+        assert_eq!(b"+secret", &buf[..n]);
+        let n = sockets[1].recv(&mut buf).unwrap();
+        assert_eq!(b"-", &buf[..n]);
+        assert!(fake.commands().contains(&"SETDESC Question 1".to_string()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_answer_pending_malformed() {
+        // a malformed question and one whose socket is gone do not keep the others from being answered, a timed out
+        // prompt is answered as cancelled
+        let fake = FakePinentry::new(&[
+            ("SETDESC*", r#"desc="$line"; echo OK"#),
+            (
+                "GETPIN",
+                r#"if [ "$desc" = "SETDESC Slow" ]; then echo "ERR 83886142 Timeout <Pinentry>"; else echo "D secret"; echo OK; fi"#,
+            ),
+        ]);
+        let dir = env::temp_dir().join(format!("pinentry-rs-ask-password-malformed-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("ask.1"), "[Ask]\nMessage=no socket\n").unwrap();
+        let gone = format!("[Ask]\nSocket={}\n", dir.join("sck.gone").display());
+        fs::write(dir.join("ask.2"), gone).unwrap();
+        let mut sockets = Vec::new();
+        for (n, message) in [(3, "Fast"), (4, "Slow")] {
+            let path = dir.join(format!("sck.{}", n));
+            let _ = fs::remove_file(&path);
+            sockets.push(UnixDatagram::bind(&path).unwrap());
+            let ask = format!("[Ask]\nSocket={}\nMessage={}\n", path.display(), message);
+            fs::write(dir.join(format!("ask.{}", n)), ask).unwrap();
+        }
+
+        let mut agent = PasswordAgent::new(pinentry().exe(fake.exe())).dir(&dir);
+        assert_eq!(3, pending(&dir).unwrap().len());
+        assert_eq!(2, agent.answer_pending().unwrap());
+        assert_eq!(3, fake.spawn_count());
+        // the failed question is not asked again
+        assert_eq!(0, agent.answer_pending().unwrap());
+        assert_eq!(3, fake.spawn_count());
+
+        let mut buf = [0; 64];
+        let n = sockets[0].recv(&mut buf).unwrap();
+        assert_eq!(b"+secret", &buf[..n]);
+        let n = sockets[1].recv(&mut buf).unwrap();
+        assert_eq!(b"-", &buf[..n]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,49 +1,53 @@
-use std::io::{BufRead, Write};
+//! Implementation of the [Assuan protocol](https://www.gnupg.org/documentation/manuals/assuan/) spoken by pinentry
+//!
+//! The protocol is line-based: [`Line`] covers parsing, encoding, escaping and length limits of
+//! individual lines, while [`AssuanCommand`] models the requests that pinentry understands.
+//!
+//! [`Connection`] drives pinentry directly, one request at a time, for commands that the builder and sessions do
+//! not model:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # #[cfg(feature = "process")]
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use pinentry_rs::assuan::{AssuanCommand, AssuanResponse, Connection};
+//!
+//! let mut connection = Connection::spawn("pinentry")?;
+//! connection.request_raw("SETKEYINFO n/0123456789ABCDEF")?;
+//! connection.send(&AssuanCommand::Option("allow-external-password-cache".to_string(), None))?;
+//! if let AssuanResponse::PIN(pin) = connection.send(&AssuanCommand::GetPin)? {
+//!     // use the PIN
+//! }
+//! connection.bye()?;
+//! # Ok(())
+//! # }
+//! ```
 
-use secstr::SecStr;
+use std::io;
+use std::io::{BufRead, Read, Write};
 
-use super::Result;
+use zeroize::Zeroize;
 
-/// Button type in the pinentry (usually there are two buttons, OK and CANCEL, but there is an option
-/// to use a third 'not ok' button)
-#[derive(Debug)]
-pub enum Button {
-    OK,
-    CANCEL,
-    NOTOK,
-}
+use super::{Result, SecretPin};
 
-/// Commands in the Assuan protocol (used for communicating with pinentry)
-///
-/// _Note_ this is not a complete formulation of the protocol - notably passphrase quality, output device
-/// and default strings are missing.
-#[derive(Debug)]
-pub enum AssuanCommand {
-    /// Set the timeout before returning an error
-    SetTimeout(u32),
-    /// Set the descriptive text to display
-    SetDescriptiveText(String),
-    /// Set the prompt to show
-    SetPrompt(String),
-    /// Set the window title
-    SetWindowTitle(String),
-    /// Set a button label (text)
-    SetButtonLabel(Button, String),
-    /// Set the error text
-    SetErrorText(String),
-    /// Ask for a PIN
-    GetPin,
-    /// Ask for confirmation
-    Confirm,
-    /// Show a message
-    ShowMessage,
-}
+mod command;
+mod filter;
+pub(crate) mod line;
+
+pub use self::command::{AssuanCommand, Button};
+pub use self::filter::CommandFilter;
+pub(crate) use self::line::plus_escape;
+pub use self::line::{escape, read_line, unescape, AssuanError, Inquiry, Line, Status, MAX_LINE_LENGTH};
+pub use super::transport::Connection;
+
+use self::command::CommandWrite;
 
 /// Responses in the Assuan protocol
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 pub enum AssuanResponse {
-    /// A PIN held in a _secure_ string
-    PIN(SecStr),
+    /// A PIN (or the data returned by another request, such as `GETINFO`) held in a _secure_ string
+    PIN(SecretPin),
     /// OK (can mean successful confirmation or just that the last command was successful)
     OK,
     /// Not OK (can either mean non-confirmation or just that the last command was unsuccessful)
@@ -52,34 +56,6 @@ pub enum AssuanResponse {
     NOTOK(String),
 }
 
-// strictly speaking a trait is not necessary
-trait CommandWrite {
-    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()>;
-}
-
-impl CommandWrite for AssuanCommand {
-    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
-        match self {
-            AssuanCommand::SetTimeout(timeout_secs) => write_line(writer, format!("SETTIMEOUT {}", timeout_secs)),
-            AssuanCommand::SetDescriptiveText(text) => write_line(writer, format!("SETDESC {}", text)),
-            AssuanCommand::SetPrompt(text) => write_line(writer, format!("SETPROMPT {}", text)),
-            AssuanCommand::SetWindowTitle(text) => write_line(writer, format!("SETTITLE {}", text)),
-            AssuanCommand::SetButtonLabel(button, text) => {
-                let command = match button {
-                    Button::OK => "SETOK",
-                    Button::CANCEL => "SETCANCEL",
-                    Button::NOTOK => "SETNOTOK",
-                };
-                write_line(writer, format!("{} {}", command, text))
-            }
-            AssuanCommand::SetErrorText(text) => write_line(writer, format!("SETERROR {}", text)),
-            AssuanCommand::GetPin => write_line(writer, "GETPIN".to_string()),
-            AssuanCommand::Confirm => write_line(writer, "CONFIRM".to_string()),
-            AssuanCommand::ShowMessage => write_line(writer, "MESSAGE".to_string()),
-        }
-    }
-}
-
 /// Main processing function - take in an iterator of commands, and process the commands while interacting with an
 /// Assuan-protocol speaking backend (pinentry) using the read/write pipes until the first terminal command.
 ///
@@ -90,84 +66,334 @@ impl CommandWrite for AssuanCommand {
 ///   * `Message`
 ///
 /// For a `GetPin` command, a `PIN` is expected to be returned. For the other two commands, an `OK` should be returned.
+/// A `GetInfo` command finishes the sequence as well, returning its answer as `PIN` (or `OK` without one).
 /// If something goes wrong (not at the I/O level) then a `NOTOK` will be returned with the error message from pinentry.
+///
+/// The (non-terminal) commands preceding a terminal command are pipelined: they are written in one go and their
+/// responses read afterwards, saving a round-trip per command.
 pub fn process_commands<'a, W: Write, R: BufRead, I: Iterator<Item = &'a AssuanCommand>>(
     cmds: I,
     writer: &mut W,
     reader: &mut R,
 ) -> Result<AssuanResponse> {
-    let mut line = String::with_capacity(256);
+    process_stream(cmds, &mut Duplex { reader, writer })
+}
+
+/// Answers an inquiry made by pinentry while a PIN is entered (e.g. `QUALITY` or `GENPIN`), or cancels it with `None`
+pub(crate) type InquiryHandler<'h> = dyn FnMut(&Inquiry) -> Option<SecretPin> + 'h;
+
+/// Same as [`process_commands`], over a single stream used for both reading and writing
+pub(crate) fn process_stream<'a, S: BufRead + Write, I: Iterator<Item = &'a AssuanCommand>>(
+    cmds: I,
+    stream: &mut S,
+) -> Result<AssuanResponse> {
+    process_stream_with(cmds, stream, &mut |_| None, None, &mut Vec::new())
+}
+
+/// Same as [`process_stream`], answering inquiries made during `GetPin` with `on_inquire`, and restricted by
+/// `filter` (nothing is sent if one of the commands is not allowed) - the status lines received in the replies are
+/// added to `statuses`
+pub(crate) fn process_stream_with<'a, S: BufRead + Write, I: Iterator<Item = &'a AssuanCommand>>(
+    cmds: I,
+    stream: &mut S,
+    on_inquire: &mut InquiryHandler<'_>,
+    filter: Option<&CommandFilter>,
+    statuses: &mut Vec<Status>,
+) -> Result<AssuanResponse> {
+    let cmds: Vec<_> = cmds.collect();
+    if let Some(filter) = filter {
+        for cmd in &cmds {
+            filter.check_command(cmd)?;
+        }
+    }
+    let mut pending = Vec::new();
 
     for cmd in cmds {
-        cmd.write_to(writer)?;
-        match cmd {
-            AssuanCommand::GetPin => {
-                // Expect to read 'D ', so read 2 chars
-                let mut ok_or = [0u8; 2];
-                let read = reader.read(&mut ok_or)?;
-                match &ok_or {
-                    b"D " => (),
-                    _ => {
-                        line.clear();
-                        // 2 chars may have already been read - they need to be added to the response
-                        for i in 0..read {
-                            line.push(ok_or[i] as char);
-                        }
-                        reader.read_line(&mut line)?;
-                        return Ok(AssuanResponse::NOTOK(trim_newl(line)));
-                    }
-                }
-                // Now next chars until end of line are password
-                let mut pw = String::with_capacity(2048); // TODO - maybe instead allocate a SecStr and read to that?
-                reader.read_line(&mut pw)?;
-                let res = AssuanResponse::PIN(SecStr::new(trim_newl(pw).into_bytes()));
-
-                // Next line should be 'OK' - fail if not
-                if read_line_is_ok(reader, &mut line)? {
-                    return Ok(res);
-                } else {
-                    return Ok(AssuanResponse::NOTOK(trim_newl(line)));
-                }
+        // GETINFO finishes a sequence as well, as its answer is the data returned
+        if !cmd.is_terminal() && !matches!(cmd, AssuanCommand::GetInfo(_)) {
+            pending.push(cmd);
+            continue;
+        }
+        if let Some(error) = send_batch(&pending, stream)? {
+            return Ok(AssuanResponse::NOTOK(error));
+        }
+
+        trace!("> {}", redacted(&cmd.to_line()));
+        cmd.write_to(stream)?;
+        stream.flush()?;
+        // inquiries are answered for GETPIN only: they are about the PIN being typed
+        let reply = match cmd {
+            AssuanCommand::GetPin => read_reply(stream, on_inquire, filter, statuses)?,
+            _ => read_reply(stream, &mut |_| None, filter, statuses)?,
+        };
+        return reply.into_response(matches!(cmd, AssuanCommand::GetPin));
+    }
+
+    match send_batch(&pending, stream)? {
+        Some(error) => Ok(AssuanResponse::NOTOK(error)),
+        None => Ok(AssuanResponse::OK),
+    }
+}
+
+/// Send a single request and read its response: the data lines (joined), status lines (added to `statuses`) and the
+/// final `OK` or `ERR` - inquiries are cancelled
+pub(crate) fn request<S: BufRead + Write>(
+    line: &Line,
+    stream: &mut S,
+    statuses: &mut Vec<Status>,
+) -> Result<AssuanResponse> {
+    request_reply(line, stream, None, statuses)?.into_response(false)
+}
+
+/// Same as [`request`], returning the whole reply - status lines and inquiries (which are cancelled) are checked
+/// with `filter`
+pub(crate) fn request_reply<S: BufRead + Write>(
+    line: &Line,
+    stream: &mut S,
+    filter: Option<&CommandFilter>,
+    statuses: &mut Vec<Status>,
+) -> Result<Reply> {
+    trace!("> {}", redacted(line));
+    line.write_to(stream)?;
+    stream.flush()?;
+
+    read_reply(stream, &mut |_| None, filter, statuses)
+}
+
+/// What a line read while waiting for a reply is
+pub(crate) enum ReplyLine {
+    /// `OK` - the reply is complete
+    Done,
+    /// `ERR` (or a line that has no place in a reply) - the reply is complete, and the request failed
+    Failed(String),
+    /// `D` - part of the data of the reply
+    Data(SecretPin),
+    /// `S` - status information, belonging to the reply
+    Status(Status),
+    /// `INQUIRE` - pinentry needs an answer before replying
+    Inquire(Inquiry),
+    /// `#` - nothing to do
+    Skip,
+}
+
+impl From<Line> for ReplyLine {
+    fn from(line: Line) -> ReplyLine {
+        match line {
+            Line::Ok(_) => ReplyLine::Done,
+            Line::Data(data) => ReplyLine::Data(data),
+            Line::Status(status) => ReplyLine::Status(status),
+            Line::Inquire(inquiry) => ReplyLine::Inquire(inquiry),
+            Line::Comment(_) => ReplyLine::Skip,
+            line => ReplyLine::Failed(describe(&line)),
+        }
+    }
+}
+
+/// A complete reply: its data (if any), and how it ended
+#[derive(Default)]
+pub(crate) struct Reply {
+    // the chunks of data, in the order of their `D` lines
+    pub(crate) data: Vec<SecretPin>,
+    pub(crate) error: Option<String>,
+    // the first status or inquiry refused by the filter
+    pub(crate) denied: Option<super::Error>,
+}
+
+impl Reply {
+    /// `PIN` with the data (always for `GETPIN`, where no data is an empty PIN), `OK` or `NOTOK`
+    pub(crate) fn into_response(self, is_pin: bool) -> Result<AssuanResponse> {
+        match (self.error, self.denied) {
+            (Some(error), _) => Ok(AssuanResponse::NOTOK(error)),
+            (None, Some(denied)) => Err(denied),
+            (None, None) if is_pin || !self.data.is_empty() => Ok(AssuanResponse::PIN(join(self.data))),
+            (None, None) => Ok(AssuanResponse::OK),
+        }
+    }
+
+    /// The data of the reply joined into one string, if there was any
+    pub(crate) fn take_data(&mut self) -> Option<SecretPin> {
+        match self.data.is_empty() {
+            true => None,
+            false => Some(join(std::mem::take(&mut self.data))),
+        }
+    }
+}
+
+/// Read lines up to the end of a reply (`OK` or `ERR`): comments are skipped, data is collected, status lines are
+/// added to `statuses` and inquiries answered with `on_inquire` - unless `filter` refuses them
+fn read_reply<S: BufRead + Write>(
+    stream: &mut S,
+    on_inquire: &mut InquiryHandler<'_>,
+    filter: Option<&CommandFilter>,
+    statuses: &mut Vec<Status>,
+) -> Result<Reply> {
+    let mut reply = Reply::default();
+    loop {
+        match ReplyLine::from(next_line(stream)?) {
+            ReplyLine::Done => return Ok(reply),
+            ReplyLine::Failed(error) => {
+                reply.error = Some(error);
+                return Ok(reply);
             }
-            AssuanCommand::Confirm | AssuanCommand::ShowMessage => {
-                // same as the fallthrough case but return immediately with OK
-                if read_line_is_ok(reader, &mut line)? {
-                    return Ok(AssuanResponse::OK);
-                } else {
-                    return Ok(AssuanResponse::NOTOK(trim_newl(line)));
+            ReplyLine::Data(chunk) => reply.data.push(chunk),
+            ReplyLine::Status(status) => match filter.map(|f| f.check_status(&status)) {
+                Some(Err(e)) => {
+                    reply.denied.get_or_insert(e);
                 }
-            }
-            _ => {
-                if !read_line_is_ok(reader, &mut line)? {
-                    return Ok(AssuanResponse::NOTOK(trim_newl(line)));
+                _ => statuses.push(status),
+            },
+            ReplyLine::Inquire(inquiry) => match filter.map(|f| f.check_inquiry(&inquiry)) {
+                Some(Err(e)) => {
+                    reply.denied.get_or_insert(e);
+                    answer_inquiry(inquiry, stream, &mut |_| None)?
                 }
+                _ => answer_inquiry(inquiry, stream, on_inquire)?,
+            },
+            ReplyLine::Skip => (),
+        }
+    }
+}
+
+/// Join data sent in several `D` lines, without leaving copies behind
+fn join(chunks: Vec<SecretPin>) -> SecretPin {
+    if chunks.len() == 1 {
+        return chunks.into_iter().next().expect("one chunk");
+    }
+    let mut data = Vec::with_capacity(chunks.iter().map(|chunk| chunk.unsecure().len()).sum());
+    for chunk in &chunks {
+        data.extend_from_slice(chunk.unsecure());
+    }
+    SecretPin::new(data)
+}
+
+/// Write all commands at once, then read one response per command - returns the first error (if any)
+fn send_batch<S: BufRead + Write>(cmds: &[&AssuanCommand], stream: &mut S) -> Result<Option<String>> {
+    if cmds.is_empty() {
+        return Ok(None);
+    }
+
+    let mut buf = Vec::new();
+    for cmd in cmds {
+        let line = cmd.to_line();
+        trace!("> {}", redacted(&line));
+        line.encode(&mut buf)?;
+    }
+    stream.write_all(&buf)?;
+    stream.flush()?;
+
+    // all responses need to be read to keep the connection in sync, even after an error
+    let mut error = None;
+    for cmd in cmds {
+        let reply = read_reply(stream, &mut |_| None, None, &mut Vec::new())?;
+        match reply.error {
+            Some(ref e) if cmd.is_optional() && is_unknown_option(e) => {
+                debug!("pinentry does not know {}, leaving it out", redacted(&cmd.to_line()));
             }
+            Some(e) if error.is_none() => error = Some(e),
+            _ => (),
+        }
+    }
+    Ok(error)
+}
+
+/// Whether `error` (an `ERR` line) says that the option sent is not known
+fn is_unknown_option(error: &str) -> bool {
+    matches!(Line::parse(error.as_bytes()), Ok(Line::Err(e)) if e.error_code() == AssuanError::UNKNOWN_OPTION)
+}
+
+/// Send the answer to `inquiry` (or cancel it) - its parameters, which may contain the PIN typed so far, are wiped
+/// when it is dropped
+fn answer_inquiry<S: Write>(inquiry: Inquiry, stream: &mut S, on_inquire: &mut InquiryHandler<'_>) -> Result<()> {
+    let answer = on_inquire(&inquiry);
+    drop(inquiry);
+
+    let mut buf = Vec::new();
+    let res = match answer {
+        Some(data) => {
+            trace!("> D [redacted]");
+            trace!("> END");
+            Line::Data(data)
+                .encode(&mut buf)
+                .and_then(|_| Line::End.encode(&mut buf))
+        }
+        None => {
+            trace!("> CAN");
+            Line::Cancel.encode(&mut buf)
         }
+    };
+    let res = res.and_then(|_| {
+        stream.write_all(&buf)?;
+        stream.flush()?;
+        Ok(())
+    });
+    buf.zeroize();
+    res
+}
+
+/// Separate reader and writer combined into a single stream
+struct Duplex<'r, 'w, R, W> {
+    reader: &'r mut R,
+    writer: &'w mut W,
+}
+
+impl<R: BufRead, W> Read for Duplex<'_, '_, R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<R: BufRead, W> BufRead for Duplex<'_, '_, R, W> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt)
+    }
+}
+
+impl<R, W: Write> Write for Duplex<'_, '_, R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
     }
 
-    Ok(AssuanResponse::OK)
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
 }
 
-fn write_line<W: Write>(writer: &mut W, mut s: String) -> Result<()> {
-    s.push('\n');
-    let _ = writer.write(s.as_ref())?;
-    Ok(())
+fn next_line<R: BufRead>(reader: &mut R) -> Result<Line> {
+    let line = read_line(reader)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "pinentry closed the connection"))?;
+    trace!("< {}", redacted(&line));
+    if let Line::Err(ref e) = line {
+        debug!("pinentry returned an error: {}", e);
+    }
+    Ok(line)
 }
 
-fn read_line_is_ok<R: BufRead>(reader: &mut R, l: &mut String) -> Result<bool> {
-    l.clear();
-    let _ = reader.read_line(l)?;
-    match l.as_ref() {
-        "OK" | "OK\n" => Ok(true),
-        _ => Ok(false),
+/// Textual representation of an unexpected line (without revealing any data)
+pub(crate) fn describe(line: &Line) -> String {
+    if let Line::Data(_) = line {
+        return "unexpected data".to_string();
     }
+    redacted(line)
 }
 
-fn trim_newl(mut s: String) -> String {
-    if s.ends_with('\n') {
-        let _ = s.pop();
+/// A line as it is sent, except that the data of `D` lines and the parameters of inquiries are left out
+pub(crate) fn redacted(line: &Line) -> String {
+    match line {
+        Line::Data(_) => return "D [redacted]".to_string(),
+        // e.g. the PIN typed so far for QUALITY
+        Line::Inquire(Inquiry {
+            keyword,
+            params: Some(_),
+        }) => return format!("INQUIRE {} [redacted]", keyword),
+        _ => (),
     }
-    s
+    let mut buf = Vec::new();
+    let _ = line.encode(&mut buf);
+    String::from_utf8_lossy(&buf).trim_end().to_string()
 }
 
 #[cfg(test)]
@@ -177,12 +403,6 @@ mod tests {
     use std::io::Cursor;
     use std::str;
 
-    fn write_to_string<C: CommandWrite>(cmd: &C) -> String {
-        let mut c = Cursor::new(Vec::new());
-        cmd.write_to(&mut c).expect("can write to in-memory buffer");
-        String::from_utf8(c.into_inner()).expect("utf8-encoded command")
-    }
-
     fn process(cmds: &[AssuanCommand], expected: &[&str]) -> Result<(Vec<String>, AssuanResponse)> {
         let mut w = Cursor::new(Vec::new());
         let mut r = Cursor::new(expected.join("\n"));
@@ -196,49 +416,6 @@ mod tests {
         Ok((written, res))
     }
 
-    #[test]
-    fn test_assuan_command_write() {
-        assert_eq!("SETTIMEOUT 30\n", write_to_string(&AssuanCommand::SetTimeout(30)));
-        assert_eq!(
-            "SETDESC Enter PIN for Donald Trump <trump@tower.gov>\n",
-            write_to_string(&AssuanCommand::SetDescriptiveText(
-                "Enter PIN for Donald Trump <trump@tower.gov>".to_string()
-            ))
-        );
-        assert_eq!(
-            "SETPROMPT PIN:\n",
-            write_to_string(&AssuanCommand::SetPrompt("PIN:".to_string()))
-        );
-        assert_eq!(
-            "SETTITLE ATM\n",
-            write_to_string(&AssuanCommand::SetWindowTitle("ATM".to_string()))
-        );
-        assert_eq!(
-            "SETOK Yes\n",
-            write_to_string(&AssuanCommand::SetButtonLabel(Button::OK, "Yes".to_string()))
-        );
-        assert_eq!(
-            "SETCANCEL No\n",
-            write_to_string(&AssuanCommand::SetButtonLabel(Button::CANCEL, "No".to_string()))
-        );
-        assert_eq!(
-            "SETNOTOK Don't push this button\n",
-            write_to_string(&AssuanCommand::SetButtonLabel(
-                Button::NOTOK,
-                "Don't push this button".to_string()
-            ))
-        );
-        assert_eq!(
-            "SETERROR Invalid PIN entered - please try again\n",
-            write_to_string(&AssuanCommand::SetErrorText(
-                "Invalid PIN entered - please try again".to_string()
-            ))
-        );
-        assert_eq!("GETPIN\n", write_to_string(&AssuanCommand::GetPin));
-        assert_eq!("CONFIRM\n", write_to_string(&AssuanCommand::Confirm));
-        assert_eq!("MESSAGE\n", write_to_string(&AssuanCommand::ShowMessage));
-    }
-
     #[test]
     fn test_process_commands_getpin() {
         // command sequence to execute
@@ -289,4 +466,259 @@ mod tests {
             x => panic!("unexpected result {:?}", x),
         }
     }
+
+    #[test]
+    fn test_process_commands_crlf() {
+        let cmds = vec![AssuanCommand::SetPrompt("PIN:".to_string()), AssuanCommand::GetPin];
+
+        // simulated responses from a pinentry using windows line endings, with stray blank lines
+        let responses = vec!["OK\r", "", "D secret\r", "OK \r"];
+
+        let (_, res) = process(&cmds, &responses).expect("commands should be processed successfully");
+        match res {
+            AssuanResponse::PIN(pw) => assert_eq!("secret", str::from_utf8(pw.unsecure()).unwrap()),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_process_commands_escaped() {
+        let cmds = vec![
+            AssuanCommand::SetDescriptiveText("Line 1\nLine 2 (100%)".to_string()),
+            AssuanCommand::GetPin,
+        ];
+
+        // the passphrase contains a literal "%0A", which pinentry sends escaped
+        let responses = vec!["OK", "D a%250Ab%0Ac", "OK"];
+
+        let (written, res) = process(&cmds, &responses).expect("commands should be processed successfully");
+        assert_eq!(vec!["SETDESC Line 1%0ALine 2 (100%25)", "GETPIN", ""], written);
+        match res {
+            AssuanResponse::PIN(pw) => assert_eq!("a%0Ab\nc", str::from_utf8(pw.unsecure()).unwrap()),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_process_commands_inquiry() {
+        let cmds = [AssuanCommand::SetQualityBar(None), AssuanCommand::GetPin];
+        let responses = [
+            "OK",
+            "INQUIRE QUALITY se%25cret",
+            "INQUIRE UNKNOWN",
+            "S PIN_REPEATED",
+            "D secret",
+            "OK",
+        ];
+
+        let mut reader = Cursor::new(responses.join("\n"));
+        let mut writer = Cursor::new(Vec::new());
+        let mut inquiries = Vec::new();
+        let mut statuses = Vec::new();
+        let res = process_stream_with(
+            cmds.iter(),
+            &mut Duplex {
+                reader: &mut reader,
+                writer: &mut writer,
+            },
+            &mut |inquiry| {
+                inquiries.push(inquiry.clone());
+                match inquiry.keyword.as_str() {
+                    "QUALITY" => Some(SecretPin::from("42")),
+                    _ => None,
+                }
+            },
+            None,
+            &mut statuses,
+        )
+        .expect("commands should be processed successfully");
+        assert_eq!(
+            vec!["PIN_REPEATED"],
+            statuses.iter().map(|s| &s.keyword).collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            vec!["QUALITY", "UNKNOWN"],
+            inquiries.iter().map(|i| &i.keyword).collect::<Vec<_>>()
+        );
+        assert_eq!(Some(SecretPin::from("se%25cret")), inquiries[0].params);
+        let written = String::from_utf8(writer.into_inner()).unwrap();
+        assert_eq!("SETQUALITYBAR\nGETPIN\nD 42\nEND\nCAN\n", written);
+        match res {
+            AssuanResponse::PIN(pw) => assert_eq!("secret", str::from_utf8(pw.unsecure()).unwrap()),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_process_commands_interleaved() {
+        let cmds = [
+            AssuanCommand::SetTimeout(60),
+            AssuanCommand::SetPrompt("PIN:".to_string()),
+            AssuanCommand::GetPin,
+        ];
+        // comments and status lines around the replies in the batch and around the PIN
+        let responses = [
+            "# setting the timeout",
+            "OK",
+            "S PROGRESS 1",
+            "OK",
+            "# asking",
+            "S PASSWORD_FROM_CACHE",
+            "D sec",
+            "# more",
+            "D ret",
+            "S PIN_REPEATED",
+            "OK",
+        ];
+
+        let mut reader = Cursor::new(responses.join("\n"));
+        let mut writer = Cursor::new(Vec::new());
+        let mut statuses = Vec::new();
+        let res = process_stream_with(
+            cmds.iter(),
+            &mut Duplex {
+                reader: &mut reader,
+                writer: &mut writer,
+            },
+            &mut |_| None,
+            None,
+            &mut statuses,
+        )
+        .expect("commands should be processed successfully");
+        match res {
+            AssuanResponse::PIN(pw) => assert_eq!("secret", str::from_utf8(pw.unsecure()).unwrap()),
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert_eq!(
+            vec!["PASSWORD_FROM_CACHE", "PIN_REPEATED"],
+            statuses.iter().map(|s| &s.keyword).collect::<Vec<_>>()
+        );
+
+        // a confirmation, asking for something first
+        let (written, res) = process(
+            &[AssuanCommand::Confirm],
+            &["# confirm?", "INQUIRE SOMETHING", "S BUTTON_INFO", "OK"],
+        )
+        .expect("command should be processed successfully");
+        assert_eq!(vec!["CONFIRM", "CAN", ""], written);
+        assert!(matches!(res, AssuanResponse::OK));
+
+        // an empty PIN has no data line
+        let (_, res) = process(&[AssuanCommand::GetPin], &["S PIN_REPEATED", "OK"]).expect("PIN is read");
+        match res {
+            AssuanResponse::PIN(pw) => assert!(pw.unsecure().is_empty()),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_process_commands_long_pin() {
+        // pinentry sends a long passphrase (here, with characters that need escaping) in several data lines
+        let passphrase: Vec<u8> = (0..5000).map(|i| b"ab%\n"[i % 4]).collect();
+        let mut encoded = Vec::new();
+        Line::Data(SecretPin::new(passphrase.clone()))
+            .encode(&mut encoded)
+            .expect("data can be encoded");
+        let encoded = String::from_utf8(encoded).unwrap();
+        let mut responses: Vec<&str> = encoded.lines().collect();
+        assert!(responses.len() > 1);
+        responses.push("OK");
+
+        let (_, res) = process(&[AssuanCommand::GetPin], &responses).expect("PIN is read");
+        match res {
+            AssuanResponse::PIN(pw) => assert_eq!(&passphrase[..], pw.unsecure()),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_process_commands_error_in_batch() {
+        let cmds = vec![
+            AssuanCommand::SetTimeout(60),
+            AssuanCommand::SetKeyInfo("n/ABCD".to_string()),
+            AssuanCommand::SetDescriptiveText("Enter PIN".to_string()),
+            AssuanCommand::GetPin,
+        ];
+
+        // simulated responses of a pinentry not knowing SETKEYINFO
+        let responses = vec!["OK", "ERR 536871187 Unknown IPC command <User defined source 1>", "OK"];
+
+        let (written, res) = process(&cmds, &responses).expect("commands should be processed successfully");
+
+        // the terminal command is not sent after an error
+        let expected_written = vec!["SETTIMEOUT 60", "SETKEYINFO n/ABCD", "SETDESC Enter PIN", ""];
+        assert_eq!(expected_written, written);
+        match res {
+            AssuanResponse::NOTOK(error) => assert!(error.starts_with("ERR 536871187")),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_process_commands_options() {
+        let builder = super::super::pinentry()
+            .touch_file("/run/user/1000/gnupg/S.gpg-agent")
+            .owner_pid(4242)
+            .tty_name("/dev/pts/3");
+        let mut cmds = builder.options;
+        cmds.push(AssuanCommand::GetPin);
+        let responses = vec!["OK", "OK", "OK", "D 1234", "OK"];
+
+        let (written, res) = process(&cmds, &responses).expect("commands should be processed successfully");
+
+        let expected_written = vec![
+            "OPTION touch-file=/run/user/1000/gnupg/S.gpg-agent",
+            "OPTION owner=4242",
+            "OPTION ttyname=/dev/pts/3",
+            "GETPIN",
+            "",
+        ];
+        assert_eq!(expected_written, written);
+        assert!(matches!(res, AssuanResponse::PIN(pin) if pin.unsecure() == b"1234"));
+    }
+
+    #[test]
+    fn test_process_commands_optional_options() {
+        let builder = super::super::pinentry()
+            .formatted_passphrase(true)
+            .formatted_passphrase_hint("Groups of 5")
+            .invisible_char('•')
+            .tty_name("/dev/pts/3");
+        let mut cmds = builder.options;
+        cmds.push(AssuanCommand::GetPin);
+        // an older pinentry that knows none of the display options
+        let unknown = "ERR 83886254 Unknown option <Pinentry>";
+        let responses = vec![unknown, unknown, unknown, "OK", "D 1234", "OK"];
+
+        let (written, res) = process(&cmds, &responses).expect("commands should be processed successfully");
+
+        let expected_written = vec![
+            "OPTION formatted-passphrase",
+            "OPTION formatted-passphrase-hint=Groups+of+5",
+            "OPTION invisible-char=•",
+            "OPTION ttyname=/dev/pts/3",
+            "GETPIN",
+            "",
+        ];
+        assert_eq!(expected_written, written);
+        assert!(matches!(res, AssuanResponse::PIN(pin) if pin.unsecure() == b"1234"));
+
+        // other options are still required
+        let responses = vec!["OK", "OK", "OK", unknown];
+        match process(&cmds, &responses) {
+            Ok((_, AssuanResponse::NOTOK(error))) => assert_eq!(unknown, error),
+            x => panic!("unexpected result {:?}", x.map(|(_, res)| res)),
+        }
+    }
+
+    #[test]
+    fn test_redacted() {
+        let quality = Line::Inquire(Inquiry {
+            keyword: "QUALITY".to_string(),
+            params: Some(SecretPin::from("hunter2")),
+        });
+        assert_eq!("INQUIRE QUALITY [redacted]", redacted(&quality));
+        assert_eq!("D [redacted]", redacted(&Line::Data(SecretPin::from("hunter2"))));
+    }
 }
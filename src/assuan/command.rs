@@ -0,0 +1,306 @@
+use std::io::Write;
+
+use zeroize::Zeroize;
+
+use super::super::Result;
+use super::line::{escape_text, Line};
+
+/// Button type in the pinentry (usually there are two buttons, OK and CANCEL, but there is an option
+/// to use a third 'not ok' button)
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    OK,
+    CANCEL,
+    NOTOK,
+}
+
+/// Commands understood by pinentry
+///
+/// Covers the commands of pinentry 1.2 - any command can also be sent as a raw [`Line::Command`]. The texts shown to
+/// the user (descriptions, prompts, labels, ...) are percent-escaped when the command is converted into a line, so
+/// they may contain line breaks and `%`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssuanCommand {
+    /// Set the timeout before returning an error
+    SetTimeout(u32),
+    /// Set the descriptive text to display
+    SetDescriptiveText(String),
+    /// Set the prompt to show
+    SetPrompt(String),
+    /// Set the window title
+    SetWindowTitle(String),
+    /// Set a button label (text)
+    SetButtonLabel(Button, String),
+    /// Set the error text
+    SetErrorText(String),
+    /// Ask for the PIN a second time (with an optional prompt) and compare both entries
+    SetRepeat(Option<String>),
+    /// Set the error text shown when the repeated PIN does not match
+    SetRepeatError(String),
+    /// Show a passphrase quality bar (with an optional label)
+    SetQualityBar(Option<String>),
+    /// Set the tooltip of the quality bar
+    SetQualityBarTooltip(String),
+    /// Offer to generate a PIN, using the given label for the button
+    SetGenPin(String),
+    /// Set the tooltip of the PIN generation button
+    SetGenPinTooltip(String),
+    /// Set the key (cache) identifier for the external password cache
+    SetKeyInfo(String),
+    /// Set an option, optionally with a value (`OPTION name[=value]`)
+    Option(String, Option<String>),
+    /// Query information about pinentry (e.g. `version`, `flavor`, `pid`, `ttyinfo`)
+    GetInfo(String),
+    /// Remove a passphrase from the external password cache
+    ClearPassphrase(String),
+    /// Reset the dialog settings to their defaults
+    Reset,
+    /// Do nothing (useful to check the connection is alive)
+    Nop,
+    /// Close the connection
+    Bye,
+    /// Ask for a PIN
+    GetPin,
+    /// Ask for confirmation
+    Confirm,
+    /// Ask for confirmation with only a single button
+    ConfirmOneButton,
+    /// Show a message
+    ShowMessage,
+}
+
+impl AssuanCommand {
+    /// Name of the command as used on the wire
+    pub fn name(&self) -> &'static str {
+        match self {
+            AssuanCommand::SetTimeout(_) => "SETTIMEOUT",
+            AssuanCommand::SetDescriptiveText(_) => "SETDESC",
+            AssuanCommand::SetPrompt(_) => "SETPROMPT",
+            AssuanCommand::SetWindowTitle(_) => "SETTITLE",
+            AssuanCommand::SetButtonLabel(Button::OK, _) => "SETOK",
+            AssuanCommand::SetButtonLabel(Button::CANCEL, _) => "SETCANCEL",
+            AssuanCommand::SetButtonLabel(Button::NOTOK, _) => "SETNOTOK",
+            AssuanCommand::SetErrorText(_) => "SETERROR",
+            AssuanCommand::SetRepeat(_) => "SETREPEAT",
+            AssuanCommand::SetRepeatError(_) => "SETREPEATERROR",
+            AssuanCommand::SetQualityBar(_) => "SETQUALITYBAR",
+            AssuanCommand::SetQualityBarTooltip(_) => "SETQUALITYBAR_TT",
+            AssuanCommand::SetGenPin(_) => "SETGENPIN",
+            AssuanCommand::SetGenPinTooltip(_) => "SETGENPIN_TT",
+            AssuanCommand::SetKeyInfo(_) => "SETKEYINFO",
+            AssuanCommand::Option(_, _) => "OPTION",
+            AssuanCommand::GetInfo(_) => "GETINFO",
+            AssuanCommand::ClearPassphrase(_) => "CLEARPASSPHRASE",
+            AssuanCommand::Reset => "RESET",
+            AssuanCommand::Nop => "NOP",
+            AssuanCommand::Bye => "BYE",
+            AssuanCommand::GetPin => "GETPIN",
+            AssuanCommand::Confirm | AssuanCommand::ConfirmOneButton => "CONFIRM",
+            AssuanCommand::ShowMessage => "MESSAGE",
+        }
+    }
+
+    /// Whether the command shows a dialog to the user (and thus finishes a sequence of commands)
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            AssuanCommand::GetPin
+                | AssuanCommand::Confirm
+                | AssuanCommand::ConfirmOneButton
+                | AssuanCommand::ShowMessage
+        )
+    }
+
+    /// Whether the command only changes how the dialog looks, so that it can be left out if pinentry does not know
+    /// it (older versions and some flavors answer `ERR` with the code `UNKNOWN_OPTION` then)
+    pub(crate) fn is_optional(&self) -> bool {
+        matches!(
+            self,
+            AssuanCommand::Option(ref name, _)
+                if matches!(name.as_str(), "formatted-passphrase" | "formatted-passphrase-hint" | "invisible-char")
+        )
+    }
+
+    /// Convert the command into the protocol line sent to pinentry
+    pub fn to_line(&self) -> Line {
+        let params = match self {
+            AssuanCommand::SetTimeout(timeout_secs) => Some(timeout_secs.to_string()),
+            AssuanCommand::SetDescriptiveText(text)
+            | AssuanCommand::SetPrompt(text)
+            | AssuanCommand::SetWindowTitle(text)
+            | AssuanCommand::SetButtonLabel(_, text)
+            | AssuanCommand::SetErrorText(text)
+            | AssuanCommand::SetRepeatError(text)
+            | AssuanCommand::SetQualityBarTooltip(text)
+            | AssuanCommand::SetGenPin(text)
+            | AssuanCommand::SetGenPinTooltip(text) => Some(self.display_text(text)),
+            AssuanCommand::SetRepeat(text) | AssuanCommand::SetQualityBar(text) => {
+                text.as_ref().map(|text| self.display_text(text))
+            }
+            AssuanCommand::SetKeyInfo(text) | AssuanCommand::GetInfo(text) | AssuanCommand::ClearPassphrase(text) => {
+                Some(text.clone())
+            }
+            AssuanCommand::Option(name, Some(value)) => Some(format!("{}={}", name, value)),
+            AssuanCommand::Option(name, None) => Some(name.clone()),
+            AssuanCommand::ConfirmOneButton => Some("--one-button".to_string()),
+            AssuanCommand::Reset
+            | AssuanCommand::Nop
+            | AssuanCommand::Bye
+            | AssuanCommand::GetPin
+            | AssuanCommand::Confirm
+            | AssuanCommand::ShowMessage => None,
+        };
+        Line::Command(self.name().to_string(), params)
+    }
+
+    /// A text shown to the user, percent-escaped and shortened to fit the line (and the title width) if the
+    /// `unicode-width` feature is enabled
+    #[cfg(feature = "unicode-width")]
+    fn display_text(&self, text: &str) -> String {
+        escape_text(&super::super::text::fit_command(self.name(), text))
+    }
+
+    #[cfg(not(feature = "unicode-width"))]
+    fn display_text(&self, text: &str) -> String {
+        escape_text(text)
+    }
+}
+
+// strictly speaking a trait is not necessary
+pub(super) trait CommandWrite {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()>;
+}
+
+impl CommandWrite for AssuanCommand {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        self.to_line().write_to(writer)
+    }
+}
+
+impl CommandWrite for Line {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut buf = Vec::new();
+        let res = self.encode(&mut buf).and_then(|_| Ok(writer.write_all(&buf)?));
+        buf.zeroize();
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    fn write_to_string<C: CommandWrite>(cmd: &C) -> String {
+        let mut c = Cursor::new(Vec::new());
+        cmd.write_to(&mut c).expect("can write to in-memory buffer");
+        String::from_utf8(c.into_inner()).expect("utf8-encoded command")
+    }
+
+    #[test]
+    fn test_assuan_command_write() {
+        assert_eq!("SETTIMEOUT 30\n", write_to_string(&AssuanCommand::SetTimeout(30)));
+        assert_eq!(
+            "SETDESC Enter PIN for Donald Trump <trump@tower.gov>\n",
+            write_to_string(&AssuanCommand::SetDescriptiveText(
+                "Enter PIN for Donald Trump <trump@tower.gov>".to_string()
+            ))
+        );
+        assert_eq!(
+            "SETPROMPT PIN:\n",
+            write_to_string(&AssuanCommand::SetPrompt("PIN:".to_string()))
+        );
+        assert_eq!(
+            "SETTITLE ATM\n",
+            write_to_string(&AssuanCommand::SetWindowTitle("ATM".to_string()))
+        );
+        assert_eq!(
+            "SETOK Yes\n",
+            write_to_string(&AssuanCommand::SetButtonLabel(Button::OK, "Yes".to_string()))
+        );
+        assert_eq!(
+            "SETCANCEL No\n",
+            write_to_string(&AssuanCommand::SetButtonLabel(Button::CANCEL, "No".to_string()))
+        );
+        assert_eq!(
+            "SETNOTOK Don't push this button\n",
+            write_to_string(&AssuanCommand::SetButtonLabel(
+                Button::NOTOK,
+                "Don't push this button".to_string()
+            ))
+        );
+        assert_eq!(
+            "SETERROR Invalid PIN entered - please try again\n",
+            write_to_string(&AssuanCommand::SetErrorText(
+                "Invalid PIN entered - please try again".to_string()
+            ))
+        );
+        assert_eq!("GETPIN\n", write_to_string(&AssuanCommand::GetPin));
+        assert_eq!("CONFIRM\n", write_to_string(&AssuanCommand::Confirm));
+        assert_eq!("MESSAGE\n", write_to_string(&AssuanCommand::ShowMessage));
+    }
+
+    #[test]
+    fn test_assuan_command_write_extended() {
+        assert_eq!("SETREPEAT\n", write_to_string(&AssuanCommand::SetRepeat(None)));
+        assert_eq!(
+            "SETREPEAT Repeat:\n",
+            write_to_string(&AssuanCommand::SetRepeat(Some("Repeat:".to_string())))
+        );
+        assert_eq!(
+            "SETQUALITYBAR_TT Strength\n",
+            write_to_string(&AssuanCommand::SetQualityBarTooltip("Strength".to_string()))
+        );
+        assert_eq!(
+            "OPTION ttyname=/dev/pts/1\n",
+            write_to_string(&AssuanCommand::Option(
+                "ttyname".to_string(),
+                Some("/dev/pts/1".to_string())
+            ))
+        );
+        assert_eq!(
+            "OPTION allow-external-password-cache\n",
+            write_to_string(&AssuanCommand::Option(
+                "allow-external-password-cache".to_string(),
+                None
+            ))
+        );
+        assert_eq!(
+            "GETINFO flavor\n",
+            write_to_string(&AssuanCommand::GetInfo("flavor".to_string()))
+        );
+        assert_eq!(
+            "CONFIRM --one-button\n",
+            write_to_string(&AssuanCommand::ConfirmOneButton)
+        );
+        assert_eq!("BYE\n", write_to_string(&AssuanCommand::Bye));
+    }
+
+    #[test]
+    fn test_assuan_command_write_escapes_texts() {
+        assert_eq!(
+            "SETDESC one%0AGETPIN\n",
+            write_to_string(&AssuanCommand::SetDescriptiveText("one\nGETPIN".to_string()))
+        );
+        assert_eq!(
+            "SETDESC Line 1%0D%0ALine 2%0A%0A100%25 sure\n",
+            write_to_string(&AssuanCommand::SetDescriptiveText(
+                "Line 1\r\nLine 2\n\n100% sure".to_string()
+            ))
+        );
+        assert_eq!(
+            "SETREPEAT 50%25\n",
+            write_to_string(&AssuanCommand::SetRepeat(Some("50%".to_string())))
+        );
+    }
+
+    #[test]
+    fn test_assuan_command_write_rejects_newlines() {
+        let mut c = Cursor::new(Vec::new());
+        let res = AssuanCommand::Option("ttyname".to_string(), Some("/dev/tty1\nGETPIN".to_string())).write_to(&mut c);
+        assert!(res.is_err());
+        assert!(c.into_inner().is_empty());
+    }
+}
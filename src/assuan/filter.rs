@@ -0,0 +1,202 @@
+use std::collections::HashSet;
+
+use super::super::{Error, Result};
+use super::command::AssuanCommand;
+use super::line::{Inquiry, Status};
+
+/// Restricts which commands and options are sent to pinentry, and which status lines and inquiries are accepted from
+/// it
+///
+/// Hardened deployments can use this to guarantee that, say, `OPTION putenv` or the external password cache are never
+/// enabled, whatever the configuration says:
+///
+/// ```
+/// # extern crate pinentry_rs;
+/// use pinentry_rs::assuan::{AssuanCommand, CommandFilter};
+///
+/// let filter = CommandFilter::new()
+///     .allow_options(["ttyname", "ttytype", "lc-ctype"])
+///     // PINs must be typed, not taken from a password cache
+///     .allow_statuses(Vec::<String>::new());
+/// assert!(filter
+///     .check_command(&AssuanCommand::Option("allow-external-password-cache".to_string(), None))
+///     .is_err());
+/// assert!(filter.check_command(&AssuanCommand::SetPrompt("PIN:".to_string())).is_ok());
+/// ```
+///
+/// Everything is allowed until restricted: each `allow_*()` method limits its kind to the given names (and adds to
+/// them when called again). `RESET`, `NOP` and `BYE` are always allowed. Anything else fails with
+/// [`Error::PolicyDenied`] - commands before anything is sent, status lines and inquiries once the response has been
+/// read (unwanted inquiries are cancelled, and a PIN returned along with an unwanted status line is dropped).
+#[derive(Debug, Clone, Default)]
+pub struct CommandFilter {
+    commands: Option<HashSet<String>>,
+    options: Option<HashSet<String>>,
+    statuses: Option<HashSet<String>>,
+    inquiries: Option<HashSet<String>>,
+}
+
+impl CommandFilter {
+    /// A filter that allows everything
+    pub fn new() -> Self {
+        CommandFilter::default()
+    }
+
+    /// Only send the commands named `names` (as on the wire, e.g. `SETDESC` or `OPTION` - like in the protocol, case
+    /// does not matter)
+    pub fn allow_commands<I: IntoIterator<Item = S>, S: Into<String>>(mut self, names: I) -> Self {
+        let names = names.into_iter().map(|name| name.into().to_ascii_uppercase());
+        extend(&mut self.commands, names);
+        self
+    }
+
+    /// Only send the options named `names` (e.g. `ttyname`, without the value)
+    pub fn allow_options<I: IntoIterator<Item = S>, S: Into<String>>(mut self, names: I) -> Self {
+        extend(&mut self.options, names);
+        self
+    }
+
+    /// Only accept the status lines with the keywords `keywords` (e.g. `PASSWORD_FROM_CACHE`)
+    pub fn allow_statuses<I: IntoIterator<Item = S>, S: Into<String>>(mut self, keywords: I) -> Self {
+        extend(&mut self.statuses, keywords);
+        self
+    }
+
+    /// Only accept the inquiries with the keywords `keywords` (e.g. `QUALITY`)
+    pub fn allow_inquiries<I: IntoIterator<Item = S>, S: Into<String>>(mut self, keywords: I) -> Self {
+        extend(&mut self.inquiries, keywords);
+        self
+    }
+
+    /// Check whether `cmd` may be sent
+    pub fn check_command(&self, cmd: &AssuanCommand) -> Result<()> {
+        if matches!(cmd, AssuanCommand::Reset | AssuanCommand::Nop | AssuanCommand::Bye) {
+            return Ok(());
+        }
+        match cmd {
+            AssuanCommand::Option(name, _) => self.check_name(cmd.name(), Some(name)),
+            _ => self.check_name(cmd.name(), None),
+        }
+    }
+
+    /// Check whether a command line not modelled by [`AssuanCommand`] (e.g. `OPTION no-grab`) may be sent, the same
+    /// way as the command it stands for
+    pub(crate) fn check_request(&self, name: &str, params: Option<&str>) -> Result<()> {
+        // command names are not case-sensitive
+        let name = name.to_ascii_uppercase();
+        if matches!(name.as_str(), "RESET" | "NOP" | "BYE") {
+            return Ok(());
+        }
+        let option = match name.as_str() {
+            "OPTION" => params.map(|params| params.trim_start().split(['=', ' ']).next().unwrap_or_default()),
+            _ => None,
+        };
+        self.check_name(&name, option)
+    }
+
+    fn check_name(&self, name: &str, option: Option<&str>) -> Result<()> {
+        if !allows(&self.commands, name) {
+            return Err(denied(format!("the command {} is not allowed", name)));
+        }
+        match option {
+            Some(option) if !allows(&self.options, option) => {
+                Err(denied(format!("the option {} is not allowed", option)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check whether `status` is accepted
+    pub fn check_status(&self, status: &Status) -> Result<()> {
+        match allows(&self.statuses, &status.keyword) {
+            true => Ok(()),
+            false => Err(denied(format!("the status {} is not accepted", status.keyword))),
+        }
+    }
+
+    /// Check whether `inquiry` is accepted
+    pub fn check_inquiry(&self, inquiry: &Inquiry) -> Result<()> {
+        match allows(&self.inquiries, &inquiry.keyword) {
+            true => Ok(()),
+            false => Err(denied(format!("the inquiry {} is not accepted", inquiry.keyword))),
+        }
+    }
+}
+
+fn extend<I: IntoIterator<Item = S>, S: Into<String>>(allowed: &mut Option<HashSet<String>>, names: I) {
+    allowed
+        .get_or_insert_with(HashSet::new)
+        .extend(names.into_iter().map(Into::into));
+}
+
+fn allows(allowed: &Option<HashSet<String>>, name: &str) -> bool {
+    allowed.as_ref().is_none_or(|allowed| allowed.contains(name))
+}
+
+fn denied(reason: String) -> Error {
+    debug!("filtered: {}", reason);
+    Error::PolicyDenied(reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_filter() {
+        let filter = CommandFilter::new()
+            .allow_commands(["SETDESC", "OPTION"])
+            .allow_commands(["GETPIN"])
+            .allow_options(["ttyname"])
+            .allow_inquiries(["QUALITY"]);
+        assert!(filter.check_command(&AssuanCommand::GetPin).is_ok());
+        assert!(filter.check_command(&AssuanCommand::Reset).is_ok());
+        assert!(filter.check_command(&AssuanCommand::Confirm).is_err());
+        assert!(filter
+            .check_command(&AssuanCommand::Option(
+                "ttyname".to_string(),
+                Some("/dev/pts/1".to_string())
+            ))
+            .is_ok());
+        assert!(matches!(
+            filter.check_command(&AssuanCommand::Option("putenv".to_string(), Some("X=1".to_string()))),
+            Err(Error::PolicyDenied(_))
+        ));
+        // statuses are not restricted
+        let status = Status {
+            keyword: "PASSWORD_FROM_CACHE".to_string(),
+            info: None,
+        };
+        assert!(filter.check_status(&status).is_ok());
+        let inquiry = Inquiry {
+            keyword: "GENPIN".to_string(),
+            params: None,
+        };
+        assert!(filter.check_inquiry(&inquiry).is_err());
+    }
+
+    #[test]
+    fn test_command_filter_requests() {
+        let filter = CommandFilter::new()
+            .allow_commands(["GETPIN", "OPTION"])
+            .allow_options(["ttyname"]);
+        assert!(filter.check_request("getpin", None).is_ok());
+        assert!(filter.check_request("bye", None).is_ok());
+        assert!(filter.check_request("OPTION", Some("ttyname=/dev/pts/1")).is_ok());
+        assert!(filter.check_request("option", Some(" ttyname /dev/pts/1")).is_ok());
+        assert!(matches!(
+            filter.check_request("OPTION", Some("no-grab")),
+            Err(Error::PolicyDenied(_))
+        ));
+        assert!(filter.check_request("SETTIMEOUT", Some("10")).is_err());
+
+        // allowed command names are not case-sensitive either
+        let filter = CommandFilter::new().allow_commands(["getpin", "SetDesc"]);
+        assert!(filter.check_command(&AssuanCommand::GetPin).is_ok());
+        assert!(filter
+            .check_command(&AssuanCommand::SetDescriptiveText("PIN".to_string()))
+            .is_ok());
+        assert!(filter.check_request("GetPin", None).is_ok());
+        assert!(filter.check_command(&AssuanCommand::Confirm).is_err());
+    }
+}
@@ -0,0 +1,594 @@
+use std::fmt::{Display, Formatter};
+use std::io::{self, BufRead};
+use std::str;
+
+use zeroize::Zeroize;
+
+use super::super::{Error, Result, SecretPin};
+
+/// Maximum length of a single protocol line, including the terminating newline
+pub const MAX_LINE_LENGTH: usize = 1000;
+
+/// An `ERR` reply, carrying a [`libgpg-error`](https://www.gnupg.org/related_software/libgpg-error/) code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssuanError {
+    /// The full error value (error source in the upper 8 bits, error code in the lower 16 bits)
+    pub code: u32,
+    /// Human-readable description of the error (usually followed by the error source in angle brackets)
+    pub description: Option<String>,
+}
+
+impl AssuanError {
+    /// Bad passphrase
+    pub const BAD_PASSPHRASE: u32 = 11;
+    /// Not found (e.g. gpg-agent does not know the key)
+    pub const NOT_FOUND: u32 = 27;
+    /// End of data / no data available
+    pub const NO_DATA: u32 = 58;
+    /// Not supported (e.g. presetting a passphrase without `allow-preset-passphrase`)
+    pub const NOT_SUPPORTED: u32 = 60;
+    /// Timeout
+    pub const TIMEOUT: u32 = 62;
+    /// The requested functionality is not implemented
+    pub const NOT_IMPLEMENTED: u32 = 69;
+    /// Operation cancelled
+    pub const CANCELED: u32 = 99;
+    /// The user did not confirm
+    pub const NOT_CONFIRMED: u32 = 114;
+    /// Unknown option
+    pub const UNKNOWN_OPTION: u32 = 174;
+    /// Operation fully cancelled
+    pub const FULLY_CANCELED: u32 = 198;
+    /// Line too long (Assuan)
+    pub const ASS_LINE_TOO_LONG: u32 = 263;
+    /// Unknown command (Assuan)
+    pub const ASS_UNKNOWN_CMD: u32 = 275;
+    /// Syntax error (Assuan)
+    pub const ASS_SYNTAX: u32 = 276;
+    /// Operation cancelled (Assuan)
+    pub const ASS_CANCELED: u32 = 277;
+    /// Invalid parameter (Assuan)
+    pub const ASS_PARAMETER: u32 = 280;
+    /// Unknown inquiry (Assuan)
+    pub const ASS_UNKNOWN_INQUIRE: u32 = 281;
+
+    /// Error source `pinentry`
+    pub const SOURCE_PINENTRY: u32 = 5;
+
+    /// Create an error from an error source and code
+    pub fn new(source: u32, code: u32, description: Option<String>) -> Self {
+        AssuanError {
+            code: (source << 24) | (code & 0xFFFF),
+            description,
+        }
+    }
+
+    /// The error code without the error source (compare with the associated constants)
+    pub fn error_code(&self) -> u32 {
+        self.code & 0xFFFF
+    }
+
+    /// The component that generated the error (e.g. `SOURCE_PINENTRY`)
+    pub fn error_source(&self) -> u32 {
+        self.code >> 24
+    }
+}
+
+impl Display for AssuanError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.description {
+            Some(ref desc) => write!(f, "{} ({})", desc, self.code),
+            None => write!(f, "error code {}", self.code),
+        }
+    }
+}
+
+/// An `S` status line, carrying additional information about the current request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    /// Status keyword (e.g. `PIN_REPEATED`)
+    pub keyword: String,
+    /// Keyword-specific information
+    pub info: Option<String>,
+}
+
+/// An `INQUIRE` line, with which the server asks the client for more data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inquiry {
+    /// Inquiry keyword (e.g. `QUALITY`)
+    pub keyword: String,
+    /// Keyword-specific parameters as sent (percent-escaped), held in a _secure_ string as they may contain the PIN
+    /// typed so far (e.g. for `QUALITY`)
+    pub params: Option<SecretPin>,
+}
+
+impl Inquiry {
+    /// The parameters unescaped, in a secure string (empty without parameters)
+    pub(crate) fn secret_params(&self) -> Result<SecretPin> {
+        unescape_secret(self.params.as_ref().map_or(&[][..], |params| params.unsecure()))
+    }
+}
+
+/// A single line of the Assuan protocol, as sent by either the client or the server
+///
+/// Text fields are carried verbatim (they may not contain a CR or LF), whereas `Data` is percent-unescaped when
+/// parsed and escaped again when encoded.
+#[derive(Debug)]
+pub enum Line {
+    /// `OK [<info>]` - the request was successful
+    Ok(Option<String>),
+    /// `ERR <code> [<description>]` - the request failed
+    Err(AssuanError),
+    /// `S <keyword> [<info>]` - status information
+    Status(Status),
+    /// `# <comment>` - a comment (to be ignored by the receiver)
+    Comment(String),
+    /// `D <data>` - a chunk of raw data, held in a _secure_ string
+    Data(SecretPin),
+    /// `INQUIRE <keyword> [<parameters>]` - the server asks the client for more data
+    Inquire(Inquiry),
+    /// `END` - end of the data sent in response to an inquiry
+    End,
+    /// `CAN` - the client cancels an inquiry
+    Cancel,
+    /// `<command> [<parameters>]` - a client request
+    Command(String, Option<String>),
+}
+
+impl Line {
+    /// Parse a single line (without the terminating newline)
+    ///
+    /// A trailing CR (from `\r\n` line endings) is ignored, as is trailing whitespace on all lines except `D` lines
+    /// (where it may be part of the data).
+    pub fn parse(line: &[u8]) -> Result<Line> {
+        let line = normalize(line);
+        if line.len() >= MAX_LINE_LENGTH {
+            return Err(Error::ProtocolError(format!("line exceeds {} bytes", MAX_LINE_LENGTH)));
+        }
+        if let Some(data) = line.strip_prefix(b"D ") {
+            return Ok(Line::Data(unescape_secret(data)?));
+        }
+        if line == b"D" {
+            return Ok(Line::Data(SecretPin::new(Vec::new())));
+        }
+
+        let line = str::from_utf8(line).map_err(|_| Error::ProtocolError("line is not valid UTF-8".to_string()))?;
+        if let Some(comment) = line.strip_prefix('#') {
+            return Ok(Line::Comment(comment.trim_start_matches(' ').to_string()));
+        }
+
+        // the fields are borrowed from the (secret) line until they are known not to be secret
+        let (verb, rest) = split_word(line);
+        let res = match verb {
+            "OK" => Line::Ok(rest.map(String::from)),
+            "ERR" => {
+                let rest = rest.ok_or_else(|| Error::ProtocolError("ERR line without error code".to_string()))?;
+                let (code, description) = split_word(rest);
+                let code = code
+                    .parse()
+                    .map_err(|_| Error::ProtocolError(format!("invalid error code: {}", code)))?;
+                Line::Err(AssuanError {
+                    code,
+                    description: description.map(String::from),
+                })
+            }
+            "S" => {
+                let rest = rest.ok_or_else(|| Error::ProtocolError("status line without keyword".to_string()))?;
+                let (keyword, info) = split_word(rest);
+                Line::Status(Status {
+                    keyword: keyword.to_string(),
+                    info: info.map(String::from),
+                })
+            }
+            "INQUIRE" => {
+                let rest = rest.ok_or_else(|| Error::ProtocolError("inquiry without keyword".to_string()))?;
+                let (keyword, params) = split_word(rest);
+                Line::Inquire(Inquiry {
+                    keyword: keyword.to_string(),
+                    // copied into a buffer of the right size, so it is not reallocated
+                    params: params.map(|params| SecretPin::new(params.as_bytes().to_vec())),
+                })
+            }
+            "END" if rest.is_none() => Line::End,
+            "CAN" if rest.is_none() => Line::Cancel,
+            "" => return Err(Error::ProtocolError("empty line".to_string())),
+            _ => Line::Command(verb.to_string(), rest.map(String::from)),
+        };
+        Ok(res)
+    }
+
+    /// Encode the line into `buf`, including the terminating newline
+    ///
+    /// `Data` longer than fits on a single line is split across several `D` lines.
+    pub fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        match self {
+            Line::Ok(info) => encode_text(buf, "OK", &[info.as_deref()]),
+            Line::Err(err) => encode_text(buf, "ERR", &[Some(&err.code.to_string()), err.description.as_deref()]),
+            Line::Status(status) => encode_text(buf, "S", &[Some(&status.keyword), status.info.as_deref()]),
+            Line::Comment(comment) => encode_text(buf, "#", &[Some(comment)]),
+            Line::Data(data) => {
+                encode_data(buf, data.unsecure());
+                Ok(())
+            }
+            Line::Inquire(inquiry) => {
+                let params = match inquiry.params {
+                    Some(ref params) => Some(
+                        str::from_utf8(params.unsecure())
+                            .map_err(|_| Error::ProtocolError("inquiry parameters are not valid UTF-8".to_string()))?,
+                    ),
+                    None => None,
+                };
+                encode_text(buf, "INQUIRE", &[Some(&inquiry.keyword), params])
+            }
+            Line::End => encode_text(buf, "END", &[]),
+            Line::Cancel => encode_text(buf, "CAN", &[]),
+            Line::Command(command, params) => encode_text(buf, command, &[params.as_deref()]),
+        }
+    }
+}
+
+/// Read a single line from `reader`, enforcing the maximum line length
+///
+/// Blank lines are skipped, and an unterminated line at the end of the stream is accepted. Returns `None` if the end
+/// of the stream has been reached before any data was read.
+pub fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Line>> {
+    // a buffer of the maximum length, wiped when dropped (and locked in memory with the `secstr` feature), as the line
+    // may hold a PIN
+    let mut buf = SecretPin::new(vec![0; MAX_LINE_LENGTH]);
+    loop {
+        match read_raw_line(reader, buf.unsecure_mut())? {
+            Some(len) if is_blank(&buf.unsecure()[..len]) => continue,
+            Some(len) => return Line::parse(&buf.unsecure()[..len]).map(Some),
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Read a line into `buf` (which bounds its length), returning its length without the newline - `None` at the end of
+/// the stream
+fn read_raw_line<R: BufRead>(reader: &mut R, buf: &mut [u8]) -> Result<Option<usize>> {
+    let mut len = 0;
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if available.is_empty() {
+            return Ok(Some(len).filter(|&len| len > 0));
+        }
+        let (used, line_end) = match available.iter().position(|&b| b == b'\n') {
+            Some(newline) => (newline + 1, Some(newline)),
+            None => (available.len(), None),
+        };
+        let content = line_end.unwrap_or(used);
+        if len + used > buf.len() || (line_end.is_none() && len + used == buf.len()) {
+            return Err(Error::ProtocolError(format!("line exceeds {} bytes", MAX_LINE_LENGTH)));
+        }
+        buf[len..len + content].copy_from_slice(&available[..content]);
+        len += content;
+        reader.consume(used);
+        if line_end.is_some() {
+            return Ok(Some(len));
+        }
+    }
+}
+
+/// Whether a received line is blank (and should be skipped)
+pub(crate) fn is_blank(line: &[u8]) -> bool {
+    line.trim_ascii().is_empty()
+}
+
+fn normalize(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    if line.starts_with(b"D ") {
+        line
+    } else {
+        line.trim_ascii_end()
+    }
+}
+
+fn split_word(s: &str) -> (&str, Option<&str>) {
+    match s.split_once(' ') {
+        Some((word, rest)) => (word, Some(rest)),
+        None => (s, None),
+    }
+}
+
+fn encode_text(buf: &mut Vec<u8>, verb: &str, fields: &[Option<&str>]) -> Result<()> {
+    let start = buf.len();
+    buf.extend_from_slice(verb.as_bytes());
+    for field in fields.iter().flatten() {
+        if field.contains(['\r', '\n']) {
+            buf.truncate(start);
+            return Err(Error::ProtocolError(format!("{} line may not contain CR or LF", verb)));
+        }
+        buf.push(b' ');
+        buf.extend_from_slice(field.as_bytes());
+    }
+    if buf.len() - start >= MAX_LINE_LENGTH {
+        buf.truncate(start);
+        return Err(Error::ProtocolError(format!(
+            "{} line exceeds {} bytes",
+            verb, MAX_LINE_LENGTH
+        )));
+    }
+    buf.push(b'\n');
+    Ok(())
+}
+
+fn encode_data(buf: &mut Vec<u8>, data: &[u8]) {
+    // every input byte takes at most 3 bytes once escaped, and "D " + "\n" take 3 more
+    let chunk_len = (MAX_LINE_LENGTH - 3) / 3;
+    if data.is_empty() {
+        buf.extend_from_slice(b"D \n");
+    }
+    for chunk in data.chunks(chunk_len) {
+        buf.extend_from_slice(b"D ");
+        escape_into(buf, chunk);
+        buf.push(b'\n');
+    }
+}
+
+/// Percent-escape `%`, CR and LF as required for data sent over the Assuan protocol
+pub fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    escape_into(&mut escaped, data);
+    escaped
+}
+
+fn escape_into(buf: &mut Vec<u8>, data: &[u8]) {
+    for &b in data {
+        match b {
+            b'%' | b'\r' | b'\n' => buf.extend_from_slice(format!("%{:02X}", b).as_bytes()),
+            _ => buf.push(b),
+        }
+    }
+}
+
+/// Percent-escape a text parameter of a command (see [`escape`])
+pub(crate) fn escape_text(text: &str) -> String {
+    // only ASCII bytes are replaced, so the result is still valid UTF-8
+    String::from_utf8(escape(text.as_bytes())).expect("escaping keeps UTF-8 valid")
+}
+
+/// Percent-plus-escape a text sent as the value of an option (e.g. `constraints-hint-short`), like gpg-agent does:
+/// spaces become `+`, and `%`, `+`, CR and LF are percent-escaped
+pub(crate) fn plus_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            ' ' => escaped.push('+'),
+            '%' | '+' | '\r' | '\n' => escaped.push_str(&format!("%{:02X}", c as u8)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reverse the percent-escaping applied to data sent over the Assuan protocol
+pub fn unescape(data: &[u8]) -> Result<Vec<u8>> {
+    let mut unescaped = vec![0; data.len()];
+    match unescape_into(data, &mut unescaped) {
+        Ok(len) => {
+            unescaped.truncate(len);
+            Ok(unescaped)
+        }
+        Err(e) => {
+            unescaped.zeroize();
+            Err(e)
+        }
+    }
+}
+
+/// Same as [`unescape`], for secret data: the result is unescaped in place in a secure string
+pub(crate) fn unescape_secret(data: &[u8]) -> Result<SecretPin> {
+    let mut unescaped = SecretPin::new(vec![0; data.len()]);
+    let len = unescape_into(data, unescaped.unsecure_mut())?;
+    // truncating keeps the allocation, so nothing is copied
+    unescaped.resize(len, 0);
+    Ok(unescaped)
+}
+
+/// Unescape `data` into `buf` (at least as long), returning the length of the result
+fn unescape_into(data: &[u8], buf: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] == b'%' {
+            buf[len] = data
+                .get(i + 1..i + 3)
+                .and_then(|hex| str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| Error::ProtocolError("invalid percent-escape in data".to_string()))?;
+            i += 3;
+        } else {
+            buf[len] = data[i];
+            i += 1;
+        }
+        len += 1;
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    fn encode_to_string(line: &Line) -> String {
+        let mut buf = Vec::new();
+        line.encode(&mut buf).expect("line can be encoded");
+        String::from_utf8(buf).expect("utf8-encoded line")
+    }
+
+    #[test]
+    fn test_line_parse() {
+        assert!(matches!(Line::parse(b"OK").unwrap(), Line::Ok(None)));
+        assert!(
+            matches!(Line::parse(b"OK Pleased to meet you").unwrap(), Line::Ok(Some(ref s)) if s == "Pleased to meet you")
+        );
+        match Line::parse(b"ERR 83886179 Operation cancelled <Pinentry>").unwrap() {
+            Line::Err(err) => {
+                assert_eq!(83886179, err.code);
+                assert_eq!(AssuanError::CANCELED, err.error_code());
+                assert_eq!(AssuanError::SOURCE_PINENTRY, err.error_source());
+                assert_eq!(Some("Operation cancelled <Pinentry>".to_string()), err.description);
+            }
+            x => panic!("unexpected line {:?}", x),
+        }
+        assert!(matches!(Line::parse(b"S PIN_REPEATED").unwrap(), Line::Status(ref s) if s.keyword == "PIN_REPEATED"));
+        assert!(matches!(Line::parse(b"# hello").unwrap(), Line::Comment(ref c) if c == "hello"));
+        match Line::parse(b"INQUIRE QUALITY abc").unwrap() {
+            Line::Inquire(inquiry) => {
+                assert_eq!("QUALITY", inquiry.keyword);
+                assert_eq!(Some(SecretPin::from("abc")), inquiry.params);
+            }
+            x => panic!("unexpected line {:?}", x),
+        }
+        assert!(matches!(Line::parse(b"END").unwrap(), Line::End));
+        assert!(matches!(Line::parse(b"CAN").unwrap(), Line::Cancel));
+        assert!(
+            matches!(Line::parse(b"SETDESC Hi there").unwrap(), Line::Command(ref c, Some(ref a)) if c == "SETDESC" && a == "Hi there")
+        );
+        match Line::parse(b"D 100%25 sure%0Aok").unwrap() {
+            Line::Data(data) => assert_eq!(b"100% sure\nok", data.unsecure()),
+            x => panic!("unexpected line {:?}", x),
+        }
+
+        assert!(Line::parse(b"").is_err());
+        assert!(Line::parse(b"ERR notanumber").is_err());
+        assert!(Line::parse(b"D %4").is_err());
+        assert!(Line::parse(&[b'#'; MAX_LINE_LENGTH]).is_err());
+    }
+
+    #[test]
+    fn test_line_encode() {
+        assert_eq!("OK\n", encode_to_string(&Line::Ok(None)));
+        assert_eq!(
+            "ERR 83886179 Operation cancelled\n",
+            encode_to_string(&Line::Err(AssuanError::new(
+                AssuanError::SOURCE_PINENTRY,
+                AssuanError::CANCELED,
+                Some("Operation cancelled".to_string())
+            )))
+        );
+        assert_eq!(
+            "S PASSWORD_FROM_CACHE\n",
+            encode_to_string(&Line::Status(Status {
+                keyword: "PASSWORD_FROM_CACHE".to_string(),
+                info: None
+            }))
+        );
+        assert_eq!(
+            "D 50%25%0D%0A\n",
+            encode_to_string(&Line::Data(SecretPin::from("50%\r\n")))
+        );
+        assert_eq!("END\n", encode_to_string(&Line::End));
+        assert_eq!(
+            "GETINFO version\n",
+            encode_to_string(&Line::Command("GETINFO".to_string(), Some("version".to_string())))
+        );
+
+        let mut buf = Vec::new();
+        assert!(Line::Comment("two\nlines".to_string()).encode(&mut buf).is_err());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_line_encode_long_data() {
+        let data = vec![b'%'; 2000];
+        let encoded = encode_to_string(&Line::Data(SecretPin::new(data.clone())));
+
+        let mut decoded = Vec::new();
+        for line in encoded.lines() {
+            assert!(line.len() < MAX_LINE_LENGTH);
+            match Line::parse(line.as_bytes()).unwrap() {
+                Line::Data(chunk) => decoded.extend_from_slice(chunk.unsecure()),
+                x => panic!("unexpected line {:?}", x),
+            }
+        }
+        assert_eq!(data, decoded);
+    }
+
+    #[test]
+    fn test_read_line() {
+        let mut r = Cursor::new("OK\nS PIN_REPEATED\nOK trailing");
+        assert!(matches!(read_line(&mut r).unwrap(), Some(Line::Ok(None))));
+        assert!(matches!(read_line(&mut r).unwrap(), Some(Line::Status(_))));
+        assert!(matches!(read_line(&mut r).unwrap(), Some(Line::Ok(Some(ref s))) if s == "trailing"));
+        assert!(read_line(&mut r).unwrap().is_none());
+
+        let mut long = vec![b'#'; 2 * MAX_LINE_LENGTH];
+        long.push(b'\n');
+        assert!(read_line(&mut Cursor::new(long)).is_err());
+    }
+
+    #[test]
+    fn test_read_line_crlf() {
+        let mut r = Cursor::new("OK Pleased to meet you\r\nD secret\r\nERR 83886179 Operation cancelled\r\n");
+        assert!(matches!(read_line(&mut r).unwrap(), Some(Line::Ok(Some(ref s))) if s == "Pleased to meet you"));
+        match read_line(&mut r).unwrap() {
+            Some(Line::Data(data)) => assert_eq!(b"secret", data.unsecure()),
+            x => panic!("unexpected line {:?}", x),
+        }
+        match read_line(&mut r).unwrap() {
+            Some(Line::Err(err)) => assert_eq!(Some("Operation cancelled".to_string()), err.description),
+            x => panic!("unexpected line {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_read_line_trailing_whitespace() {
+        let mut r = Cursor::new("OK  \nS PIN_REPEATED\t\nEND \r\nD secret with space \n");
+        assert!(matches!(read_line(&mut r).unwrap(), Some(Line::Ok(None))));
+        assert!(
+            matches!(read_line(&mut r).unwrap(), Some(Line::Status(ref s)) if s.keyword == "PIN_REPEATED" && s.info.is_none())
+        );
+        assert!(matches!(read_line(&mut r).unwrap(), Some(Line::End)));
+        // whitespace is part of the data in D lines
+        match read_line(&mut r).unwrap() {
+            Some(Line::Data(data)) => assert_eq!(b"secret with space ", data.unsecure()),
+            x => panic!("unexpected line {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_read_line_blank_lines() {
+        let mut r = Cursor::new("\n\r\n  \nOK\n\n");
+        assert!(matches!(read_line(&mut r).unwrap(), Some(Line::Ok(None))));
+        assert!(read_line(&mut r).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_line_chunks() {
+        // lines spanning several reads of the underlying stream
+        let data = format!("D {}\nOK\n", "x".repeat(MAX_LINE_LENGTH - 3));
+        let mut r = std::io::BufReader::with_capacity(7, Cursor::new(data));
+        match read_line(&mut r).unwrap() {
+            Some(Line::Data(data)) => assert_eq!(MAX_LINE_LENGTH - 3, data.unsecure().len()),
+            x => panic!("unexpected line {:?}", x),
+        }
+        assert!(matches!(read_line(&mut r).unwrap(), Some(Line::Ok(None))));
+
+        let unterminated = format!("# {}", "x".repeat(MAX_LINE_LENGTH - 2));
+        assert!(read_line(&mut Cursor::new(unterminated)).is_err());
+    }
+
+    #[test]
+    fn test_plus_escape() {
+        assert_eq!("At+least+8+characters", plus_escape("At least 8 characters"));
+        assert_eq!("1%2B1%25%0Anext", plus_escape("1+1%\nnext"));
+        assert_eq!("Kennwort-Länge", plus_escape("Kennwort-Länge"));
+    }
+
+    #[test]
+    fn test_unescape_secret() {
+        let pin = unescape_secret(b"50%25 off%0A").unwrap();
+        assert_eq!(b"50% off\n", pin.unsecure());
+        assert!(unescape_secret(b"secret%2").is_err());
+        assert!(unescape(b"secret%zz").is_err());
+        assert_eq!(b"a%b".to_vec(), unescape(b"a%25b").unwrap());
+    }
+}
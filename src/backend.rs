@@ -0,0 +1,403 @@
+//! Dialogs shown in-process instead of by a pinentry executable
+//!
+//! A [`Backend`] shows the dialogs itself, e.g. with the GUI toolkit the application already uses. [`InProcess`]
+//! answers the commands sent to pinentry by calling it, so it can be used as the [`Transport`] of a session and
+//! everything else - the settings, the flows, policies and rate limits - works as with an external pinentry:
+//!
+//! ```
+//! # extern crate pinentry_rs;
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use std::io;
+//!
+//! use pinentry_rs::backend::{Answer, Backend, Dialog, InProcess};
+//! use pinentry_rs::{pinentry, SecretPin};
+//!
+//! /// Answers every prompt from the environment, for unattended runs
+//! struct FromEnv;
+//!
+//! impl Backend for FromEnv {
+//!     fn get_pin(&mut self, _: &Dialog) -> io::Result<Option<SecretPin>> {
+//!         Ok(std::env::var("PIN").ok().map(SecretPin::from))
+//!     }
+//!
+//!     fn confirm(&mut self, _: &Dialog, _: bool) -> io::Result<Answer> {
+//!         Ok(Answer::Ok)
+//!     }
+//! }
+//!
+//! let mut session = pinentry().connect_transport(InProcess::new(FromEnv))?;
+//! let pin = session.pin("PIN:")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! With the `gtk` feature, `gtk::Gtk` shows native GTK4 dialogs; on Windows, with the `credui` feature,
+//! `credui::CredUi` shows the system's credential dialog. With the `tty-fallback` feature, `tty::Tty` prompts on the
+//! terminal (Unix). [`mock::MockPinentry`] answers from a script, for tests.
+//!
+//! [`PinentryBuilder::backend`](super::PinentryBuilder::backend) makes the builder use a backend for its one-shot
+//! prompts and sessions instead of starting pinentry.
+
+use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::process;
+use std::time::Duration;
+
+use zeroize::Zeroize;
+
+use super::assuan::{unescape, AssuanError, Line, Status};
+use super::transport::Transport;
+use super::SecretPin;
+
+#[cfg(all(feature = "credui", windows))]
+pub mod credui;
+#[cfg(feature = "gtk")]
+pub mod gtk;
+pub mod mock;
+#[cfg(all(feature = "tty-fallback", unix))]
+pub mod tty;
+
+/// The flavor reported by [`InProcess`] unless the backend names one
+pub const DEFAULT_FLAVOR: &str = "in-process";
+
+/// The settings of the dialog to show, as sent to pinentry (texts are unescaped)
+///
+/// Texts not set should be left out or replaced by the backend's defaults. Button labels may contain a mnemonic
+/// (the character after an underscore).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Dialog {
+    /// The descriptive text (may span several lines)
+    pub description: Option<String>,
+    /// The prompt next to the PIN entry
+    pub prompt: Option<String>,
+    /// The window title
+    pub title: Option<String>,
+    /// An error to show along with the dialog (e.g. about a previously rejected PIN)
+    pub error: Option<String>,
+    /// Label of the 'OK' button
+    pub ok: Option<String>,
+    /// Label of the 'Cancel' button
+    pub cancel: Option<String>,
+    /// Label of the 'Not OK' button - the button is only shown if set
+    pub not_ok: Option<String>,
+    /// Whether the PIN has to be entered twice
+    pub repeat: bool,
+    /// The prompt next to the second PIN entry
+    pub repeat_prompt: Option<String>,
+    /// The error shown when both entries differ
+    pub repeat_error: Option<String>,
+    /// The cache key of the passphrase
+    pub key_info: Option<String>,
+    /// How long to wait for the user before giving up
+    pub timeout: Option<Duration>,
+}
+
+/// The answer to a confirmation dialog
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Answer {
+    /// The 'OK' button was pressed
+    Ok,
+    /// The 'Not OK' button was pressed
+    NotOk,
+    /// The dialog was cancelled (or closed)
+    Cancelled,
+}
+
+/// Shows dialogs for an [`InProcess`] transport
+///
+/// A backend that gives up waiting for the user after [`Dialog::timeout`] returns an error of kind
+/// [`io::ErrorKind::TimedOut`]; other errors end the connection.
+pub trait Backend: Send {
+    /// Ask for a PIN, `None` if the dialog was cancelled
+    ///
+    /// If [`Dialog::repeat`] is set, the PIN has to be entered twice and is only returned once both entries match.
+    fn get_pin(&mut self, dialog: &Dialog) -> io::Result<Option<SecretPin>>;
+
+    /// Ask for confirmation, with only the 'OK' button if `one_button` is set
+    fn confirm(&mut self, dialog: &Dialog, one_button: bool) -> io::Result<Answer>;
+
+    /// Show a message
+    ///
+    /// The default implementation shows a confirmation with only the 'OK' button.
+    fn message(&mut self, dialog: &Dialog) -> io::Result<()> {
+        self.confirm(dialog, true).map(|_| ())
+    }
+
+    /// The flavor reported to `GETINFO flavor` (e.g. `gtk4`)
+    fn flavor(&self) -> &str {
+        DEFAULT_FLAVOR
+    }
+}
+
+/// A [`Transport`] answering the commands sent to pinentry with a [`Backend`], see the
+/// [module documentation](self)
+///
+/// The dialog is shown while the command asking for it is written, so the session blocks until it is closed.
+pub struct InProcess<B: Backend> {
+    backend: B,
+    dialog: Dialog,
+    // a partial command line written so far
+    input: Vec<u8>,
+    // responses not read yet, starting at `read`
+    output: Vec<u8>,
+    read: usize,
+    closed: bool,
+}
+
+impl<B: Backend> InProcess<B> {
+    /// Answer with dialogs shown by `backend`
+    pub fn new(backend: B) -> Self {
+        InProcess {
+            backend,
+            dialog: Dialog::default(),
+            input: Vec::new(),
+            output: b"OK Pleased to meet you\n".to_vec(),
+            read: 0,
+            closed: false,
+        }
+    }
+
+    /// The backend showing the dialogs
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    fn handle(&mut self, line: &[u8]) -> io::Result<()> {
+        let (command, params) = match Line::parse(line) {
+            Ok(Line::Command(command, params)) => (command, params),
+            Ok(_) => return self.respond_err(AssuanError::ASS_UNKNOWN_CMD, "Unknown command"),
+            Err(_) => return self.respond_err(AssuanError::ASS_SYNTAX, "Syntax error"),
+        };
+        let text = params.as_deref().map(unescape_text);
+        match (command.as_str(), text) {
+            ("SETDESC", text) => self.dialog.description = text,
+            ("SETPROMPT", text) => self.dialog.prompt = text,
+            ("SETTITLE", text) => self.dialog.title = text,
+            ("SETERROR", text) => self.dialog.error = text,
+            ("SETOK", text) => self.dialog.ok = text,
+            ("SETCANCEL", text) => self.dialog.cancel = text,
+            ("SETNOTOK", text) => self.dialog.not_ok = text,
+            ("SETREPEAT", text) => {
+                self.dialog.repeat = true;
+                self.dialog.repeat_prompt = text;
+            }
+            ("SETREPEATERROR", text) => self.dialog.repeat_error = text,
+            ("SETKEYINFO", text) => self.dialog.key_info = text,
+            ("SETTIMEOUT", text) => match text.as_deref().unwrap_or("0").parse() {
+                Ok(0) => self.dialog.timeout = None,
+                Ok(secs) => self.dialog.timeout = Some(Duration::from_secs(secs)),
+                Err(_) => return self.respond_err(AssuanError::ASS_PARAMETER, "Invalid timeout"),
+            },
+            // settings for features the backends do not offer
+            ("OPTION", _) | ("SETQUALITYBAR", _) | ("SETQUALITYBAR_TT", _) | ("SETGENPIN", _) | ("SETGENPIN_TT", _) => {
+            }
+            ("RESET", _) => self.dialog = Dialog::default(),
+            ("NOP", _) | ("CLEARPASSPHRASE", _) => (),
+            ("BYE", _) => {
+                self.closed = true;
+                return self.respond(&Line::Ok(Some("closing connection".to_string())));
+            }
+            ("GETINFO", Some(key)) => return self.get_info(&key),
+            ("GETPIN", _) => return self.get_pin(),
+            ("CONFIRM", text) => {
+                let one_button = text.as_deref() == Some("--one-button");
+                return match self.show(|backend, dialog| backend.confirm(dialog, one_button))? {
+                    Some(Answer::Ok) => self.respond(&Line::Ok(None)),
+                    Some(Answer::NotOk) => self.respond_err(AssuanError::NOT_CONFIRMED, "Not confirmed"),
+                    Some(Answer::Cancelled) => self.respond_err(AssuanError::CANCELED, "Operation cancelled"),
+                    None => self.respond_err(AssuanError::TIMEOUT, "Timeout"),
+                };
+            }
+            ("MESSAGE", _) => {
+                return match self.show(|backend, dialog| backend.message(dialog))? {
+                    Some(()) => self.respond(&Line::Ok(None)),
+                    None => self.respond_err(AssuanError::TIMEOUT, "Timeout"),
+                };
+            }
+            _ => return self.respond_err(AssuanError::ASS_UNKNOWN_CMD, "Unknown command"),
+        }
+        self.respond(&Line::Ok(None))
+    }
+
+    fn get_info(&mut self, key: &str) -> io::Result<()> {
+        let info = match key {
+            "flavor" => self.backend.flavor().to_string(),
+            "version" => env!("CARGO_PKG_VERSION").to_string(),
+            "pid" => process::id().to_string(),
+            _ => return self.respond_err(AssuanError::ASS_PARAMETER, "Invalid parameter"),
+        };
+        self.respond(&Line::Data(SecretPin::from(info)))?;
+        self.respond(&Line::Ok(None))
+    }
+
+    fn get_pin(&mut self) -> io::Result<()> {
+        let repeat = self.dialog.repeat;
+        match self.show(|backend, dialog| backend.get_pin(dialog))? {
+            Some(Some(pin)) => {
+                if repeat {
+                    self.respond(&Line::Status(Status {
+                        keyword: "PIN_REPEATED".to_string(),
+                        info: None,
+                    }))?;
+                }
+                self.respond(&Line::Data(pin))?;
+                self.respond(&Line::Ok(None))
+            }
+            Some(None) => self.respond_err(AssuanError::CANCELED, "Operation cancelled"),
+            None => self.respond_err(AssuanError::TIMEOUT, "Timeout"),
+        }
+    }
+
+    /// Show a dialog with `show`, `None` if it timed out
+    fn show<T, F>(&mut self, show: F) -> io::Result<Option<T>>
+    where
+        F: FnOnce(&mut B, &Dialog) -> io::Result<T>,
+    {
+        let res = show(&mut self.backend, &self.dialog);
+        // like pinentry, only show an error once
+        self.dialog.error = None;
+        match res {
+            Ok(res) => Ok(Some(res)),
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn respond_err(&mut self, code: u32, description: &str) -> io::Result<()> {
+        let description = format!("{} <Pinentry>", description);
+        self.respond(&Line::Err(AssuanError::new(
+            AssuanError::SOURCE_PINENTRY,
+            code,
+            Some(description),
+        )))
+    }
+
+    fn respond(&mut self, line: &Line) -> io::Result<()> {
+        line.encode(&mut self.output)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+}
+
+/// Unescape a text parameter, keeping it as it is if it is not validly escaped
+fn unescape_text(text: &str) -> String {
+    match unescape(text.as_bytes()) {
+        Ok(unescaped) => String::from_utf8_lossy(&unescaped).into_owned(),
+        Err(_) => text.to_string(),
+    }
+}
+
+impl<B: Backend> Read for InProcess<B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let pending = &self.output[self.read..];
+        let n = pending.len().min(buf.len());
+        buf[..n].copy_from_slice(&pending[..n]);
+        self.read += n;
+        if self.read == self.output.len() {
+            // the responses may contain a PIN
+            self.output.zeroize();
+            self.read = 0;
+        }
+        Ok(n)
+    }
+}
+
+impl<B: Backend> Write for InProcess<B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "the connection is closed"));
+        }
+        self.input.extend_from_slice(buf);
+        while let Some(end) = self.input.iter().position(|&b| b == b'\n') {
+            let rest = self.input.split_off(end + 1);
+            let line = mem::replace(&mut self.input, rest);
+            self.handle(&line[..end])?;
+            if self.closed {
+                break;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<B: Backend> Transport for InProcess<B> {
+    fn close(&mut self) -> io::Result<()> {
+        self.closed = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{pinentry, Error};
+    use super::*;
+
+    /// Enters the PINs given, then cancels; declines confirmations
+    #[derive(Default)]
+    struct Canned {
+        pins: Vec<&'static str>,
+        dialogs: Vec<Dialog>,
+    }
+
+    impl Backend for Canned {
+        fn get_pin(&mut self, dialog: &Dialog) -> io::Result<Option<SecretPin>> {
+            self.dialogs.push(dialog.clone());
+            match dialog.prompt.as_deref() {
+                Some("Slow:") => Err(io::Error::new(io::ErrorKind::TimedOut, "no answer")),
+                _ if self.pins.is_empty() => Ok(None),
+                _ => Ok(Some(SecretPin::from(self.pins.remove(0)))),
+            }
+        }
+
+        fn confirm(&mut self, dialog: &Dialog, _: bool) -> io::Result<Answer> {
+            self.dialogs.push(dialog.clone());
+            Ok(Answer::NotOk)
+        }
+    }
+
+    #[test]
+    fn test_in_process() {
+        let backend = Canned {
+            pins: vec!["1234"],
+            ..Canned::default()
+        };
+        let mut session = pinentry()
+            .window_title("Unlock")
+            .description("Line 1\nLine 2")
+            .connect_transport(InProcess::new(backend))
+            .unwrap();
+
+        assert_eq!(b"1234", session.pin("PIN:").unwrap().unsecure());
+        assert!(session.pin("PIN:").is_err());
+        assert!(!session.confirm_yes_no().unwrap());
+        match session.pin("Slow:") {
+            Err(Error::Timeout) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_in_process_dialog() {
+        let mut transport = InProcess::new(Canned::default());
+        let mut response = String::new();
+        transport
+            .write_all(b"SETDESC Line 1%0ALine 2\nSETREPEAT Again:\nSETERROR Wrong\nGETPIN\nGETINFO flavor\nBYE\n")
+            .unwrap();
+        transport.read_to_string(&mut response).unwrap();
+        assert_eq!(
+            "OK Pleased to meet you\nOK\nOK\nOK\nERR 83886179 Operation cancelled <Pinentry>\nD in-process\nOK\n\
+             OK closing connection\n",
+            response
+        );
+        let dialog = &transport.backend().dialogs[0];
+        assert_eq!(Some("Line 1\nLine 2"), dialog.description.as_deref());
+        assert!(dialog.repeat);
+        assert_eq!(Some("Again:"), dialog.repeat_prompt.as_deref());
+        assert_eq!(Some("Wrong"), dialog.error.as_deref());
+        assert!(transport.write_all(b"NOP\n").is_err());
+    }
+}
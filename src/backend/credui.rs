@@ -0,0 +1,423 @@
+//! Windows credential dialogs (Windows, with the `credui` feature)
+
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::io;
+use std::mem;
+use std::ptr;
+
+use windows_sys::core::{BOOL, HRESULT};
+use windows_sys::Win32::Foundation::{ERROR_CANCELLED, ERROR_INSUFFICIENT_BUFFER, HWND, LPARAM, S_OK, WPARAM};
+use windows_sys::Win32::Security::Credentials::{
+    CredPackAuthenticationBufferW, CredUIPromptForWindowsCredentialsW, CredUnPackAuthenticationBufferW,
+    CREDUIWIN_GENERIC, CREDUIWIN_SECURE_PROMPT, CREDUI_INFOW, CRED_PACK_GENERIC_CREDENTIALS,
+};
+use windows_sys::Win32::System::Com::CoTaskMemFree;
+use windows_sys::Win32::System::LibraryLoader::{GetProcAddress, LoadLibraryW};
+use windows_sys::Win32::UI::Controls::{
+    TASKDIALOGCONFIG, TASKDIALOG_BUTTON, TDF_ALLOW_DIALOG_CANCELLATION, TDF_CALLBACK_TIMER, TDN_TIMER,
+};
+use windows_sys::Win32::UI::WindowsAndMessaging::{
+    MessageBoxW, SendMessageW, IDCANCEL, IDNO, IDOK, IDYES, MB_ICONQUESTION, MB_OK, MB_OKCANCEL, MB_SETFOREGROUND,
+    MB_YESNOCANCEL, WM_CLOSE,
+};
+use zeroize::Zeroize;
+
+use super::super::SecretPin;
+use super::{Answer, Backend, Dialog};
+
+/// The user name shown with the PIN unless set with [`CredUi::user_name`]
+pub const DEFAULT_USER_NAME: &str = "PIN";
+
+/// Button id of the 'Not OK' button in task dialogs
+const ID_NOT_OK: i32 = 100;
+
+/// Asks for PINs with the Windows credential dialog (`CredUIPromptForWindowsCredentialsW`), and for confirmations
+/// with task dialogs
+///
+/// ```no_run
+/// # extern crate pinentry_rs;
+/// # fn run() -> pinentry_rs::Result<()> {
+/// use pinentry_rs::backend::credui::CredUi;
+/// use pinentry_rs::backend::InProcess;
+/// use pinentry_rs::pinentry;
+///
+/// let backend = CredUi::new().secure_desktop(true);
+/// let pin = pinentry()
+///     .description("Unlock the signing key")
+///     .connect_transport(InProcess::new(backend))?
+///     .pin("PIN:")?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The credential dialog has a user name field next to the password: it is filled in with the
+/// [user name](CredUi::user_name) and ignored in the answer. The dialog shows the description (or else the prompt)
+/// and the error of the prompt, but no custom labels; a repeated PIN is asked for in a second dialog. Neither kind of
+/// dialog has a timeout, except task dialogs (confirmations and messages).
+///
+/// Task dialogs need version 6 of the common controls (declared in the application manifest); without it,
+/// confirmations fall back to message boxes with the standard labels.
+#[derive(Debug, Clone)]
+pub struct CredUi {
+    secure_desktop: bool,
+    user_name: String,
+}
+
+impl CredUi {
+    /// Show the dialogs on the user's desktop, with [`DEFAULT_USER_NAME`] as the user name
+    pub fn new() -> Self {
+        CredUi {
+            secure_desktop: false,
+            user_name: DEFAULT_USER_NAME.to_string(),
+        }
+    }
+
+    /// Show the credential dialog on the secure desktop (like the UAC prompt), where other applications cannot read
+    /// or fake it - the user has to confirm switching to it first (off by default)
+    pub fn secure_desktop(mut self, secure_desktop: bool) -> Self {
+        self.secure_desktop = secure_desktop;
+        self
+    }
+
+    /// Fill in the user name field of the credential dialog with `user_name`
+    pub fn user_name<S: Into<String>>(mut self, user_name: S) -> Self {
+        self.user_name = user_name.into();
+        self
+    }
+
+    /// Show the credential dialog once, `None` if it was cancelled
+    fn prompt(&self, caption: Option<&str>, message: &str) -> io::Result<Option<SecretPin>> {
+        // the generic package cannot be used on the secure desktop, so the credentials are packed for Negotiate
+        let (pack_flags, prompt_flags) = match self.secure_desktop {
+            true => (0, CREDUIWIN_SECURE_PROMPT),
+            false => (CRED_PACK_GENERIC_CREDENTIALS, CREDUIWIN_GENERIC),
+        };
+        let in_buffer = pack(pack_flags, &self.user_name)?;
+        let message = wide(message);
+        let caption = caption.map(wide);
+        let info = CREDUI_INFOW {
+            cbSize: mem::size_of::<CREDUI_INFOW>() as u32,
+            hwndParent: ptr::null_mut(),
+            pszMessageText: message.as_ptr(),
+            pszCaptionText: caption.as_ref().map_or(ptr::null(), |c| c.as_ptr()),
+            hbmBanner: ptr::null_mut(),
+        };
+        let mut package = 0;
+        let mut out_buffer = ptr::null_mut();
+        let mut out_size = 0;
+        let mut save: BOOL = 0;
+        // SAFETY: all pointers are valid for the call, the out buffer is freed below
+        let res = unsafe {
+            CredUIPromptForWindowsCredentialsW(
+                &info,
+                0,
+                &mut package,
+                in_buffer.as_ptr() as *const c_void,
+                in_buffer.len() as u32,
+                &mut out_buffer,
+                &mut out_size,
+                &mut save,
+                prompt_flags,
+            )
+        };
+        match res {
+            0 => (),
+            ERROR_CANCELLED => return Ok(None),
+            e => return Err(io::Error::from_raw_os_error(e as i32)),
+        }
+        let pin = unpack(out_buffer, out_size);
+        // SAFETY: the buffer was allocated by CredUI with `out_size` bytes
+        unsafe {
+            ptr::write_bytes(out_buffer as *mut u8, 0, out_size as usize);
+            CoTaskMemFree(out_buffer);
+        }
+        pin.map(Some)
+    }
+}
+
+impl Default for CredUi {
+    fn default() -> Self {
+        CredUi::new()
+    }
+}
+
+impl Backend for CredUi {
+    fn get_pin(&mut self, dialog: &Dialog) -> io::Result<Option<SecretPin>> {
+        let text = dialog
+            .description
+            .as_deref()
+            .or(dialog.prompt.as_deref())
+            .unwrap_or_default();
+        let mut error = dialog.error.clone();
+        loop {
+            let pin = match self.prompt(dialog.title.as_deref(), &with_error(error.as_deref(), text))? {
+                Some(pin) => pin,
+                None => return Ok(None),
+            };
+            if !dialog.repeat {
+                return Ok(Some(pin));
+            }
+            let repeat_text = dialog.repeat_prompt.as_deref().unwrap_or("Repeat the PIN:");
+            match self.prompt(dialog.title.as_deref(), repeat_text)? {
+                Some(repeated) if repeated == pin => return Ok(Some(pin)),
+                Some(_) => {
+                    error = Some(
+                        dialog
+                            .repeat_error
+                            .clone()
+                            .unwrap_or_else(|| "The PINs do not match".to_string()),
+                    )
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn confirm(&mut self, dialog: &Dialog, one_button: bool) -> io::Result<Answer> {
+        match task_dialog_indirect() {
+            Some(task_dialog) => show_task_dialog(task_dialog, dialog, one_button),
+            None => show_message_box(dialog, one_button),
+        }
+    }
+
+    fn flavor(&self) -> &str {
+        "credui"
+    }
+}
+
+/// Pack `user_name` with an empty password, to fill in the credential dialog
+fn pack(flags: u32, user_name: &str) -> io::Result<Vec<u8>> {
+    let user_name = wide(user_name);
+    let password = wide("");
+    let mut size = 0;
+    // SAFETY: a null buffer only queries the size
+    let ok = unsafe {
+        CredPackAuthenticationBufferW(flags, user_name.as_ptr(), password.as_ptr(), ptr::null_mut(), &mut size)
+    };
+    if ok == 0 && io::Error::last_os_error().raw_os_error() != Some(ERROR_INSUFFICIENT_BUFFER as i32) {
+        return Err(io::Error::last_os_error());
+    }
+    let mut buffer = vec![0u8; size as usize];
+    // SAFETY: the buffer has the size queried above
+    let ok = unsafe {
+        CredPackAuthenticationBufferW(
+            flags,
+            user_name.as_ptr(),
+            password.as_ptr(),
+            buffer.as_mut_ptr(),
+            &mut size,
+        )
+    };
+    match ok {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(buffer),
+    }
+}
+
+/// The password from the credentials returned by the credential dialog
+fn unpack(buffer: *const c_void, size: u32) -> io::Result<SecretPin> {
+    let (mut user_len, mut domain_len, mut password_len) = (0, 0, 0);
+    // SAFETY: null buffers only query the sizes
+    unsafe {
+        CredUnPackAuthenticationBufferW(
+            0,
+            buffer,
+            size,
+            ptr::null_mut(),
+            &mut user_len,
+            ptr::null_mut(),
+            &mut domain_len,
+            ptr::null_mut(),
+            &mut password_len,
+        );
+    }
+    let mut user = vec![0u16; user_len as usize];
+    let mut domain = vec![0u16; domain_len as usize];
+    let mut password = vec![0u16; password_len as usize];
+    // SAFETY: the buffers have the sizes queried above
+    let ok = unsafe {
+        CredUnPackAuthenticationBufferW(
+            0,
+            buffer,
+            size,
+            user.as_mut_ptr(),
+            &mut user_len,
+            domain.as_mut_ptr(),
+            &mut domain_len,
+            password.as_mut_ptr(),
+            &mut password_len,
+        )
+    };
+    let res = match ok {
+        0 => Err(io::Error::last_os_error()),
+        _ => {
+            // the length includes the terminating NUL
+            let chars = &password[..(password_len as usize).saturating_sub(1)];
+            String::from_utf16(chars)
+                .map(|pin| SecretPin::new(pin.into_bytes()))
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "the PIN is not valid UTF-16"))
+        }
+    };
+    password.zeroize();
+    res
+}
+
+type TaskDialogIndirect = unsafe extern "system" fn(*const TASKDIALOGCONFIG, *mut i32, *mut i32, *mut BOOL) -> HRESULT;
+
+/// `TaskDialogIndirect`, if the common controls in use have it (version 6)
+fn task_dialog_indirect() -> Option<TaskDialogIndirect> {
+    let comctl32 = wide("comctl32.dll");
+    // SAFETY: the module stays loaded, and the function has the signature documented for it
+    unsafe {
+        let module = LoadLibraryW(comctl32.as_ptr());
+        if module.is_null() {
+            return None;
+        }
+        GetProcAddress(module, c"TaskDialogIndirect".as_ptr() as *const u8)
+            .map(|f| mem::transmute::<unsafe extern "system" fn() -> isize, TaskDialogIndirect>(f))
+    }
+}
+
+/// State shared with the task dialog callback
+struct Timer {
+    timeout_ms: usize,
+    timed_out: Cell<bool>,
+}
+
+unsafe extern "system" fn on_notification(hwnd: HWND, msg: u32, wparam: WPARAM, _: LPARAM, data: isize) -> HRESULT {
+    if msg == TDN_TIMER as u32 {
+        // SAFETY: `data` points to the timer for as long as the dialog is shown
+        let timer = unsafe { &*(data as *const Timer) };
+        // `wparam` is the time since the dialog was opened, in milliseconds
+        if wparam >= timer.timeout_ms && !timer.timed_out.replace(true) {
+            unsafe { SendMessageW(hwnd, WM_CLOSE, 0, 0) };
+        }
+    }
+    S_OK
+}
+
+fn show_task_dialog(task_dialog: TaskDialogIndirect, dialog: &Dialog, one_button: bool) -> io::Result<Answer> {
+    let title = dialog.title.as_deref().map(wide);
+    let content = wide(&with_error(
+        dialog.error.as_deref(),
+        dialog.description.as_deref().unwrap_or_default(),
+    ));
+    let ok = wide(&mnemonic(dialog.ok.as_deref().unwrap_or("_OK")));
+    let not_ok = dialog.not_ok.as_deref().map(|label| wide(&mnemonic(label)));
+    let cancel = wide(&mnemonic(dialog.cancel.as_deref().unwrap_or("_Cancel")));
+    let button = |id: i32, label: &[u16]| TASKDIALOG_BUTTON {
+        nButtonID: id,
+        pszButtonText: label.as_ptr(),
+    };
+    let mut buttons = vec![button(IDOK, &ok)];
+    if !one_button {
+        buttons.extend(not_ok.as_deref().map(|label| button(ID_NOT_OK, label)));
+        buttons.push(button(IDCANCEL, &cancel));
+    }
+
+    let timer = Timer {
+        timeout_ms: dialog
+            .timeout
+            .map_or(usize::MAX, |t| t.as_millis().min(usize::MAX as u128) as usize),
+        timed_out: Cell::new(false),
+    };
+    let mut config = TASKDIALOGCONFIG {
+        cbSize: mem::size_of::<TASKDIALOGCONFIG>() as u32,
+        dwFlags: TDF_ALLOW_DIALOG_CANCELLATION,
+        pszWindowTitle: title.as_ref().map_or(ptr::null(), |t| t.as_ptr()),
+        pszContent: content.as_ptr(),
+        cButtons: buttons.len() as u32,
+        pButtons: buttons.as_ptr(),
+        nDefaultButton: IDOK,
+        ..TASKDIALOGCONFIG::default()
+    };
+    if dialog.timeout.is_some() {
+        config.dwFlags |= TDF_CALLBACK_TIMER;
+        config.pfCallback = Some(on_notification);
+        config.lpCallbackData = &timer as *const Timer as isize;
+    }
+
+    let mut pressed = 0;
+    // SAFETY: all strings, the buttons and the timer outlive the (modal) dialog
+    let res = unsafe { task_dialog(&config, &mut pressed, ptr::null_mut(), ptr::null_mut()) };
+    if res != S_OK {
+        return Err(io::Error::from_raw_os_error(res));
+    }
+    match pressed {
+        _ if timer.timed_out.get() => Err(io::Error::new(io::ErrorKind::TimedOut, "the dialog timed out")),
+        IDOK => Ok(Answer::Ok),
+        ID_NOT_OK => Ok(Answer::NotOk),
+        _ => Ok(Answer::Cancelled),
+    }
+}
+
+fn show_message_box(dialog: &Dialog, one_button: bool) -> io::Result<Answer> {
+    let title = dialog.title.as_deref().map(wide);
+    let text = wide(&with_error(
+        dialog.error.as_deref(),
+        dialog.description.as_deref().unwrap_or_default(),
+    ));
+    // without custom labels, 'Yes' stands for OK and 'No' for 'Not OK'
+    let style = match (one_button, dialog.not_ok.is_some()) {
+        (true, _) => MB_OK,
+        (false, true) => MB_YESNOCANCEL | MB_ICONQUESTION,
+        (false, false) => MB_OKCANCEL | MB_ICONQUESTION,
+    };
+    let title = title.as_ref().map_or(ptr::null(), |t| t.as_ptr());
+    // SAFETY: the strings outlive the (modal) message box
+    match unsafe { MessageBoxW(ptr::null_mut(), text.as_ptr(), title, style | MB_SETFOREGROUND) } {
+        0 => Err(io::Error::last_os_error()),
+        IDOK | IDYES => Ok(Answer::Ok),
+        IDNO => Ok(Answer::NotOk),
+        _ => Ok(Answer::Cancelled),
+    }
+}
+
+/// `text` preceded by `error` if there is one
+fn with_error(error: Option<&str>, text: &str) -> String {
+    match error {
+        Some(error) if text.is_empty() => error.to_string(),
+        Some(error) => format!("{}\n\n{}", error, text),
+        None => text.to_string(),
+    }
+}
+
+/// A button label with pinentry's mnemonic syntax (`_OK`) converted to the Windows one (`&OK`)
+fn mnemonic(label: &str) -> String {
+    let mut converted = String::with_capacity(label.len() + 1);
+    let mut chars = label.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => converted.push_str("&&"),
+            '_' if chars.peek() == Some(&'_') => {
+                chars.next();
+                converted.push('_');
+            }
+            '_' => converted.push('&'),
+            c => converted.push(c),
+        }
+    }
+    converted
+}
+
+/// `s` as a NUL-terminated UTF-16 string
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(Some(0)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_labels() {
+        assert_eq!("&OK", mnemonic("_OK"));
+        assert_eq!("Save && &quit", mnemonic("Save & _quit"));
+        assert_eq!("snake_case", mnemonic("snake__case"));
+        assert_eq!(
+            "Wrong PIN\n\nEnter the PIN",
+            with_error(Some("Wrong PIN"), "Enter the PIN")
+        );
+    }
+}
@@ -0,0 +1,268 @@
+//! Native GTK4 dialogs (with the `gtk` feature)
+
+use std::cell::Cell;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+
+use gtk4::prelude::*;
+use gtk4::{gdk, glib};
+
+use super::super::SecretPin;
+use super::{Answer, Backend, Dialog};
+
+/// Shows the dialogs with GTK4, in the calling process
+///
+/// ```no_run
+/// # extern crate pinentry_rs;
+/// # fn run() -> pinentry_rs::Result<()> {
+/// use pinentry_rs::backend::gtk::Gtk;
+/// use pinentry_rs::backend::InProcess;
+/// use pinentry_rs::pinentry;
+///
+/// let pin = pinentry()
+///     .connect_transport(InProcess::new(Gtk::new()))?
+///     .pin("PIN:")?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// GTK has to be used from a single thread: the session must stay on the thread GTK was initialized on (it is
+/// initialized for the calling thread with the first dialog if the application has not done so yet). Each dialog
+/// runs its own main loop until it is closed, so it can be shown from within the application's main loop.
+#[derive(Debug, Clone, Default)]
+pub struct Gtk {
+    _private: (),
+}
+
+impl Gtk {
+    /// Show the dialogs with GTK4
+    pub fn new() -> Self {
+        Gtk::default()
+    }
+}
+
+impl Backend for Gtk {
+    fn get_pin(&mut self, dialog: &Dialog) -> io::Result<Option<SecretPin>> {
+        init()?;
+        let window = Window::new(dialog, false, true);
+        let answer = window.run();
+        let pin = window.entries.as_ref().map(Entries::take_pin);
+        match answer? {
+            Answer::Ok => Ok(pin),
+            _ => Ok(None),
+        }
+    }
+
+    fn confirm(&mut self, dialog: &Dialog, one_button: bool) -> io::Result<Answer> {
+        init()?;
+        Window::new(dialog, one_button, false).run()
+    }
+
+    fn flavor(&self) -> &str {
+        "gtk4"
+    }
+}
+
+fn init() -> io::Result<()> {
+    if gtk4::is_initialized() && !gtk4::is_initialized_main_thread() {
+        return Err(io::Error::other("GTK is used from another thread"));
+    }
+    gtk4::init().map_err(|e| io::Error::other(e.to_string()))
+}
+
+/// The window of a dialog
+struct Window {
+    window: gtk4::Window,
+    entries: Option<Entries>,
+    answer: Rc<Cell<Option<Answer>>>,
+    main_loop: glib::MainLoop,
+    timeout: Option<Duration>,
+}
+
+/// The PIN entries of a dialog
+#[derive(Clone)]
+struct Entries {
+    pin: gtk4::PasswordEntry,
+    repeat: Option<(gtk4::PasswordEntry, gtk4::Label)>,
+}
+
+impl Window {
+    /// Build the window: the texts, the PIN entries if `pin` is set, and the buttons
+    fn new(dialog: &Dialog, one_button: bool, pin: bool) -> Window {
+        let window = gtk4::Window::builder()
+            .title(dialog.title.as_deref().unwrap_or_default())
+            .modal(true)
+            .resizable(false)
+            .build();
+        let content = gtk4::Box::new(gtk4::Orientation::Vertical, 12);
+        content.set_margin_top(18);
+        content.set_margin_bottom(18);
+        content.set_margin_start(18);
+        content.set_margin_end(18);
+        window.set_child(Some(&content));
+
+        if let Some(ref description) = dialog.description {
+            content.append(&text_label(description));
+        }
+        if let Some(ref error) = dialog.error {
+            content.append(&error_label(error));
+        }
+        let entries = match pin {
+            true => Some(Entries::new(dialog, &content)),
+            false => None,
+        };
+
+        let answer = Rc::new(Cell::new(None));
+        let buttons = gtk4::Box::new(gtk4::Orientation::Horizontal, 6);
+        buttons.set_halign(gtk4::Align::End);
+        let add_button = |label: &str, value: Answer| {
+            let button = gtk4::Button::with_mnemonic(label);
+            let (answer, window, entries) = (answer.clone(), window.clone(), entries.clone());
+            button.connect_clicked(move |_| {
+                // only accept repeated PINs once they match
+                if let (Answer::Ok, Some(ref entries)) = (value, &entries) {
+                    if !entries.check_repeat() {
+                        return;
+                    }
+                }
+                answer.set(Some(value));
+                window.close();
+            });
+            buttons.append(&button);
+            button
+        };
+        if !one_button {
+            add_button(dialog.cancel.as_deref().unwrap_or("_Cancel"), Answer::Cancelled);
+            if let Some(ref not_ok) = dialog.not_ok {
+                add_button(not_ok, Answer::NotOk);
+            }
+        }
+        let ok = add_button(dialog.ok.as_deref().unwrap_or("_OK"), Answer::Ok);
+        ok.add_css_class("suggested-action");
+        window.set_default_widget(Some(&ok));
+        content.append(&buttons);
+
+        // Escape cancels, like in pinentry's own dialogs
+        let keys = gtk4::EventControllerKey::new();
+        let escape_window = window.clone();
+        keys.connect_key_pressed(move |_, key, _, _| match key {
+            gdk::Key::Escape => {
+                escape_window.close();
+                glib::Propagation::Stop
+            }
+            _ => glib::Propagation::Proceed,
+        });
+        window.add_controller(keys);
+
+        let main_loop = glib::MainLoop::new(None, false);
+        let close_loop = main_loop.clone();
+        window.connect_close_request(move |_| {
+            close_loop.quit();
+            glib::Propagation::Proceed
+        });
+
+        Window {
+            window,
+            entries,
+            answer,
+            main_loop,
+            timeout: dialog.timeout,
+        }
+    }
+
+    /// Show the window until it is closed, failing with [`io::ErrorKind::TimedOut`] after the timeout of the dialog
+    fn run(&self) -> io::Result<Answer> {
+        let timed_out = Rc::new(Cell::new(false));
+        let timer = self.timeout.map(|timeout| {
+            let (timed_out, window) = (timed_out.clone(), self.window.clone());
+            glib::timeout_add_local_once(timeout, move || {
+                timed_out.set(true);
+                window.close();
+            })
+        });
+        if let Some(ref entries) = self.entries {
+            entries.pin.grab_focus();
+        }
+        self.window.present();
+        self.main_loop.run();
+        self.window.destroy();
+        match (timer, timed_out.get()) {
+            (_, true) => Err(io::Error::new(io::ErrorKind::TimedOut, "the dialog timed out")),
+            (Some(timer), false) => {
+                timer.remove();
+                Ok(self.answer.get().unwrap_or(Answer::Cancelled))
+            }
+            (None, false) => Ok(self.answer.get().unwrap_or(Answer::Cancelled)),
+        }
+    }
+}
+
+impl Entries {
+    /// Add the PIN entry (and the one for the repeated PIN) to `content`
+    fn new(dialog: &Dialog, content: &gtk4::Box) -> Entries {
+        let grid = gtk4::Grid::new();
+        grid.set_row_spacing(6);
+        grid.set_column_spacing(12);
+        let add_entry = |row: i32, prompt: &str| {
+            let label = gtk4::Label::with_mnemonic(prompt);
+            label.set_xalign(1.0);
+            let entry = gtk4::PasswordEntry::new();
+            entry.set_hexpand(true);
+            entry.set_activates_default(true);
+            label.set_mnemonic_widget(Some(&entry));
+            grid.attach(&label, 0, row, 1, 1);
+            grid.attach(&entry, 1, row, 1, 1);
+            entry
+        };
+        let pin = add_entry(0, dialog.prompt.as_deref().unwrap_or("PIN:"));
+        let repeat = dialog.repeat.then(|| {
+            let entry = add_entry(1, dialog.repeat_prompt.as_deref().unwrap_or("Repeat:"));
+            let mismatch = error_label(dialog.repeat_error.as_deref().unwrap_or("Passphrases do not match"));
+            mismatch.set_visible(false);
+            grid.attach(&mismatch, 1, 2, 1, 1);
+            (entry, mismatch)
+        });
+        content.append(&grid);
+        Entries { pin, repeat }
+    }
+
+    /// Whether the repeated PIN matches, pointing out the mismatch if not
+    fn check_repeat(&self) -> bool {
+        match self.repeat {
+            Some((ref entry, ref mismatch)) if entry.text() != self.pin.text() => {
+                mismatch.set_visible(true);
+                entry.set_text("");
+                entry.grab_focus();
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// The PIN entered, clearing the entries
+    fn take_pin(&self) -> SecretPin {
+        let pin = SecretPin::from(self.pin.text().as_str());
+        self.pin.set_text("");
+        if let Some((ref entry, _)) = self.repeat {
+            entry.set_text("");
+        }
+        pin
+    }
+}
+
+/// A label for a (possibly long) text
+fn text_label(text: &str) -> gtk4::Label {
+    let label = gtk4::Label::new(Some(text));
+    label.set_wrap(true);
+    label.set_max_width_chars(60);
+    label.set_xalign(0.0);
+    label
+}
+
+/// A label for an error
+fn error_label(text: &str) -> gtk4::Label {
+    let label = text_label(text);
+    label.add_css_class("error");
+    label
+}
@@ -0,0 +1,237 @@
+//! A scripted backend for testing code that prompts for PINs
+//!
+//! [`MockPinentry`] answers the prompts from a list of canned answers and records the dialogs it was asked to show,
+//! so password flows can be tested without a display server or a pinentry executable. Hand it to the builder the
+//! code under test uses, with [`PinentryBuilder::backend`](super::super::PinentryBuilder::backend) (or to
+//! [`InProcess`](super::InProcess) as a transport):
+//!
+//! ```
+//! # extern crate pinentry_rs;
+//! # #[cfg(feature = "process")]
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use pinentry_rs::backend::mock::{MockPinentry, PromptKind};
+//! use pinentry_rs::backend::Answer;
+//! use pinentry_rs::pinentry;
+//!
+//! let mock = MockPinentry::new().enter_pin("1234").answer(Answer::NotOk);
+//! let builder = pinentry().backend(mock.clone()).description("Unlock the vault");
+//!
+//! assert_eq!(b"1234", builder.clone().pin("PIN:")?.unsecure());
+//! assert!(!builder.confirm_yes_no()?);
+//!
+//! let prompts = mock.prompts();
+//! assert_eq!(PromptKind::Pin, prompts[0].kind);
+//! assert_eq!(Some("Unlock the vault"), prompts[0].dialog.description.as_deref());
+//! # Ok(())
+//! # }
+//! # #[cfg(feature = "process")]
+//! # run().unwrap();
+//! ```
+//!
+//! Prompts beyond the canned answers are cancelled. A prompt of another kind than the next answer (e.g. a
+//! confirmation when a PIN is expected) ends the connection with an error, which fails the prompt, and the answer is
+//! kept for the next one.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::super::SecretPin;
+use super::{Answer, Backend, Dialog};
+
+/// A backend answering prompts from a script, see the [module documentation](self)
+///
+/// Clones share the script and the recorded prompts.
+#[derive(Debug, Clone, Default)]
+pub struct MockPinentry {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    answers: VecDeque<Scripted>,
+    prompts: Vec<Prompt>,
+}
+
+#[derive(Debug)]
+enum Scripted {
+    Pin(SecretPin),
+    Confirm(Answer),
+    Cancel,
+    Timeout,
+}
+
+/// A prompt shown by [`MockPinentry`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prompt {
+    /// What was asked for
+    pub kind: PromptKind,
+    /// The settings of the dialog
+    pub dialog: Dialog,
+}
+
+/// Kinds of prompts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptKind {
+    /// `GETPIN`
+    Pin,
+    /// `CONFIRM`, with only the 'OK' button if `one_button` is set
+    Confirm {
+        /// Whether the `--one-button` variant was used
+        one_button: bool,
+    },
+    /// `MESSAGE`
+    Message,
+}
+
+impl MockPinentry {
+    /// A mock without any answers (all prompts are cancelled)
+    pub fn new() -> Self {
+        MockPinentry::default()
+    }
+
+    /// Answer the next PIN prompt with `pin`
+    pub fn enter_pin(self, pin: &str) -> Self {
+        self.push(Scripted::Pin(SecretPin::from(pin)))
+    }
+
+    /// Answer the next confirmation with `answer`
+    pub fn answer(self, answer: Answer) -> Self {
+        self.push(Scripted::Confirm(answer))
+    }
+
+    /// Cancel the next prompt (of any kind)
+    pub fn cancel(self) -> Self {
+        self.push(Scripted::Cancel)
+    }
+
+    /// Let the next prompt (of any kind) time out
+    pub fn time_out(self) -> Self {
+        self.push(Scripted::Timeout)
+    }
+
+    /// The prompts shown so far, in order
+    pub fn prompts(&self) -> Vec<Prompt> {
+        self.state().prompts.clone()
+    }
+
+    /// The number of answers not used yet
+    pub fn remaining(&self) -> usize {
+        self.state().answers.len()
+    }
+
+    fn push(self, answer: Scripted) -> Self {
+        self.state().answers.push_back(answer);
+        self
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Record the prompt and take the answer for it
+    fn next(&mut self, kind: PromptKind, dialog: &Dialog) -> Option<Scripted> {
+        let mut state = self.state();
+        state.prompts.push(Prompt {
+            kind,
+            dialog: dialog.clone(),
+        });
+        state.answers.pop_front()
+    }
+
+    /// Keep `answer` for the next prompt, failing this one (of another kind)
+    fn unexpected(&mut self, kind: &str, answer: Scripted) -> io::Error {
+        let error = io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unexpected {}, the next scripted answer is {:?}", kind, answer),
+        );
+        self.state().answers.push_front(answer);
+        error
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "the scripted prompt timed out")
+}
+
+impl Backend for MockPinentry {
+    fn get_pin(&mut self, dialog: &Dialog) -> io::Result<Option<SecretPin>> {
+        match self.next(PromptKind::Pin, dialog) {
+            Some(Scripted::Pin(pin)) => Ok(Some(pin)),
+            Some(Scripted::Cancel) | None => Ok(None),
+            Some(Scripted::Timeout) => Err(timed_out()),
+            Some(answer) => Err(self.unexpected("PIN prompt", answer)),
+        }
+    }
+
+    fn confirm(&mut self, dialog: &Dialog, one_button: bool) -> io::Result<Answer> {
+        match self.next(PromptKind::Confirm { one_button }, dialog) {
+            Some(Scripted::Confirm(answer)) => Ok(answer),
+            Some(Scripted::Cancel) | None => Ok(Answer::Cancelled),
+            Some(Scripted::Timeout) => Err(timed_out()),
+            Some(answer) => Err(self.unexpected("confirmation", answer)),
+        }
+    }
+
+    fn message(&mut self, dialog: &Dialog) -> io::Result<()> {
+        match self.next(PromptKind::Message, dialog) {
+            Some(Scripted::Confirm(_)) | Some(Scripted::Cancel) | None => Ok(()),
+            Some(Scripted::Timeout) => Err(timed_out()),
+            Some(answer) => Err(self.unexpected("message", answer)),
+        }
+    }
+
+    fn flavor(&self) -> &str {
+        "mock"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::{pinentry, Confirmation, Error};
+    use super::super::InProcess;
+    use super::*;
+
+    #[test]
+    fn test_mock_session() {
+        let mock = MockPinentry::new()
+            .enter_pin("secret")
+            .answer(Answer::NotOk)
+            .time_out()
+            .enter_pin("other");
+        let mut session = pinentry()
+            .window_title("Vault")
+            .connect_transport(InProcess::new(mock.clone()))
+            .unwrap();
+
+        assert_eq!(b"secret", session.pin("Passphrase:").unwrap().unsecure());
+        assert_eq!(Confirmation::NotOk, session.confirm().unwrap());
+        assert!(matches!(session.show_message(), Err(Error::Timeout)));
+        assert!(matches!(session.confirm_yes_no(), Err(Error::IoError(_))));
+        assert_eq!(1, mock.remaining());
+
+        let prompts = mock.prompts();
+        assert_eq!(
+            vec![
+                PromptKind::Pin,
+                PromptKind::Confirm { one_button: false },
+                PromptKind::Message,
+                PromptKind::Confirm { one_button: false },
+            ],
+            prompts.iter().map(|prompt| prompt.kind).collect::<Vec<_>>()
+        );
+        assert_eq!(Some("Vault"), prompts[0].dialog.title.as_deref());
+        assert_eq!(Some("Passphrase:"), prompts[0].dialog.prompt.as_deref());
+    }
+
+    #[cfg(feature = "process")]
+    #[test]
+    fn test_mock_builder() {
+        let mock = MockPinentry::new().enter_pin("1234");
+        let builder = pinentry().backend(mock.clone()).respawn(true);
+
+        assert_eq!(b"1234", builder.clone().pin("PIN:").unwrap().unsecure());
+        assert!(matches!(builder.pin("PIN:"), Err(Error::Cancelled)));
+        assert_eq!(2, mock.prompts().len());
+    }
+}
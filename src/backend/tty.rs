@@ -0,0 +1,431 @@
+//! Prompts on the controlling terminal, for machines without pinentry (Unix, with the `tty-fallback` feature)
+//!
+//! [`Tty`] asks for PINs on `/dev/tty` with echo turned off, and for confirmations by the first letter (or the
+//! mnemonic) of the button labels. It is what [`PinentryBuilder::allow_tty_fallback`] falls back to when pinentry is
+//! not installed, and can be used as a backend of its own:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use pinentry_rs::backend::tty::Tty;
+//! use pinentry_rs::pinentry;
+//!
+//! let pin = pinentry().backend(Tty::new()).pin("PIN:")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Echo stays off if the process is killed by a signal (e.g. by pressing Ctrl-C) while a PIN is read.
+//!
+//! [`PinentryBuilder::allow_tty_fallback`]: super::super::PinentryBuilder::allow_tty_fallback
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::time::Instant;
+
+use super::super::SecretPin;
+use super::{Answer, Backend, Dialog};
+
+/// The longest PIN read, in bytes (as with pinentry)
+const MAX_PIN_LENGTH: usize = 2048;
+
+/// The flavor reported by [`Tty`]
+pub const FLAVOR: &str = "tty-fallback";
+
+/// A backend prompting on the controlling terminal, see the [module documentation](self)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tty;
+
+impl Tty {
+    /// Prompt on `/dev/tty` (opened for each prompt)
+    pub fn new() -> Self {
+        Tty
+    }
+}
+
+impl Backend for Tty {
+    fn get_pin(&mut self, dialog: &Dialog) -> io::Result<Option<SecretPin>> {
+        ask_pin(&mut Terminal::open(dialog)?, dialog)
+    }
+
+    fn confirm(&mut self, dialog: &Dialog, one_button: bool) -> io::Result<Answer> {
+        ask_confirmation(&mut Terminal::open(dialog)?, dialog, one_button)
+    }
+
+    fn message(&mut self, dialog: &Dialog) -> io::Result<()> {
+        ask_confirmation(&mut Terminal::open(dialog)?, dialog, true).map(|_| ())
+    }
+
+    fn flavor(&self) -> &str {
+        FLAVOR
+    }
+}
+
+/// Where the prompts are shown and answered
+trait Console: Read + Write {
+    /// Turn echo of the input on or off
+    fn set_echo(&mut self, echo: bool) -> io::Result<()>;
+}
+
+/// `/dev/tty`, giving up on reads after the timeout of the dialog
+struct Terminal {
+    tty: File,
+    deadline: Option<Instant>,
+    // the settings before echo was turned off
+    saved: Option<libc::termios>,
+}
+
+impl Terminal {
+    fn open(dialog: &Dialog) -> io::Result<Terminal> {
+        Ok(Terminal {
+            tty: OpenOptions::new().read(true).write(true).open("/dev/tty")?,
+            deadline: dialog.timeout.map(|timeout| Instant::now() + timeout),
+            saved: None,
+        })
+    }
+
+    /// Wait until there is input, failing with [`io::ErrorKind::TimedOut`] after the deadline
+    fn wait(&self) -> io::Result<()> {
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return Ok(()),
+        };
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let mut fd = libc::pollfd {
+                fd: self.tty.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let millis = left.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+            match unsafe { libc::poll(&mut fd, 1, millis) } {
+                0 => return Err(io::Error::new(io::ErrorKind::TimedOut, "no answer on the terminal")),
+                n if n > 0 => return Ok(()),
+                _ => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Read for Terminal {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.wait()?;
+        self.tty.read(buf)
+    }
+}
+
+impl Write for Terminal {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tty.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.tty.flush()
+    }
+}
+
+impl Console for Terminal {
+    fn set_echo(&mut self, echo: bool) -> io::Result<()> {
+        let fd = self.tty.as_raw_fd();
+        match (echo, self.saved) {
+            (true, Some(saved)) => {
+                self.saved = None;
+                check(unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &saved) })
+            }
+            (false, None) => {
+                let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+                check(unsafe { libc::tcgetattr(fd, &mut termios) })?;
+                let saved = termios;
+                // the newline ending the PIN is still shown
+                termios.c_lflag &= !libc::ECHO;
+                termios.c_lflag |= libc::ECHONL;
+                check(unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &termios) })?;
+                self.saved = Some(saved);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = self.set_echo(true);
+    }
+}
+
+fn check(res: libc::c_int) -> io::Result<()> {
+    match res {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Show the texts of `dialog` that go before the question
+fn show_texts<C: Console>(console: &mut C, dialog: &Dialog) -> io::Result<()> {
+    for text in [&dialog.title, &dialog.description].into_iter().flatten() {
+        writeln!(console, "{}", text)?;
+    }
+    if let Some(ref error) = dialog.error {
+        writeln!(console, "Error: {}", error)?;
+    }
+    Ok(())
+}
+
+fn ask_pin<C: Console>(console: &mut C, dialog: &Dialog) -> io::Result<Option<SecretPin>> {
+    show_texts(console, dialog)?;
+    let prompt = dialog.prompt.as_deref().unwrap_or("PIN:");
+    loop {
+        let pin = match read_pin(console, prompt)? {
+            Some(pin) => pin,
+            None => return Ok(None),
+        };
+        if !dialog.repeat {
+            return Ok(Some(pin));
+        }
+        match read_pin(console, dialog.repeat_prompt.as_deref().unwrap_or("Repeat:"))? {
+            Some(repeated) if repeated == pin => return Ok(Some(pin)),
+            Some(_) => {
+                let error = dialog.repeat_error.as_deref().unwrap_or("The PINs do not match");
+                writeln!(console, "Error: {}", error)?;
+            }
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Read a PIN with echo turned off, `None` at the end of the input
+fn read_pin<C: Console>(console: &mut C, prompt: &str) -> io::Result<Option<SecretPin>> {
+    write!(console, "{} ", prompt)?;
+    console.flush()?;
+    console.set_echo(false)?;
+    let res = read_line(console, MAX_PIN_LENGTH);
+    console.set_echo(true)?;
+    res
+}
+
+/// Read a line without its line ending, `None` at the end of the input
+///
+/// The line is read byte by byte into a buffer that is never reallocated, so that no copies of it are left behind.
+fn read_line<R: Read>(input: &mut R, max_length: usize) -> io::Result<Option<SecretPin>> {
+    let mut line = SecretPin::new(vec![0; max_length]);
+    let mut len = 0;
+    loop {
+        let buf = &mut line.unsecure_mut()[len..];
+        if buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the input is too long"));
+        }
+        match input.read(&mut buf[..1]) {
+            Ok(0) if len == 0 => return Ok(None),
+            Ok(0) => break,
+            Ok(_) if buf[0] == b'\n' => break,
+            Ok(_) => len += 1,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    if len > 0 && line.unsecure()[len - 1] == b'\r' {
+        len -= 1;
+    }
+    // truncating keeps the buffer, which is wiped (as a whole) when the PIN is dropped
+    line.resize(len, 0);
+    Ok(Some(line))
+}
+
+fn ask_confirmation<C: Console>(console: &mut C, dialog: &Dialog, one_button: bool) -> io::Result<Answer> {
+    show_texts(console, dialog)?;
+    let ok = label(dialog.ok.as_deref().unwrap_or("OK"));
+    if one_button {
+        write!(console, "Press Enter to continue ({}) ", ok)?;
+        console.flush()?;
+        return match read_line(console, MAX_PIN_LENGTH)? {
+            Some(_) => Ok(Answer::Ok),
+            None => Ok(Answer::Cancelled),
+        };
+    }
+
+    let mut choices = vec![(dialog.ok.as_deref().unwrap_or("_OK"), Answer::Ok)];
+    if let Some(ref not_ok) = dialog.not_ok {
+        choices.push((not_ok, Answer::NotOk));
+    }
+    choices.push((dialog.cancel.as_deref().unwrap_or("_Cancel"), Answer::Cancelled));
+    let choices: Vec<(String, char, Answer)> = choices
+        .into_iter()
+        .map(|(text, answer)| (label(text), key(text), answer))
+        .collect();
+    let question: Vec<String> = choices
+        .iter()
+        .map(|(label, key, _)| format!("{} ({})", label, key))
+        .collect();
+    loop {
+        write!(console, "{}? ", question.join(", "))?;
+        console.flush()?;
+        let line = match read_line(console, MAX_PIN_LENGTH)? {
+            Some(line) => String::from_utf8_lossy(line.unsecure()).trim().to_lowercase(),
+            None => return Ok(Answer::Cancelled),
+        };
+        let chosen = line
+            .chars()
+            .next()
+            .and_then(|c| choices.iter().find(|(_, key, _)| *key == c));
+        if let Some((_, _, answer)) = chosen {
+            return Ok(*answer);
+        }
+    }
+}
+
+/// A button label without the underscore marking its mnemonic (`__` is a literal underscore)
+fn label(text: &str) -> String {
+    text.replace("__", "\0").replace('_', "").replace('\0', "_")
+}
+
+/// The key choosing a button: its mnemonic, or the first letter of its label
+fn key(text: &str) -> char {
+    let mnemonic = text
+        .replace("__", "")
+        .split('_')
+        .nth(1)
+        .and_then(|rest| rest.chars().next());
+    mnemonic
+        .or_else(|| label(text).chars().find(|c| c.is_alphanumeric()))
+        .unwrap_or('?')
+        .to_lowercase()
+        .next()
+        .unwrap_or('?')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::{pinentry, Error};
+    use super::*;
+
+    /// Answers from `input`, recording what was shown and when echo was turned off
+    struct Scripted {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+        echo: bool,
+        hidden: Vec<String>,
+    }
+
+    impl Scripted {
+        fn new(input: &str) -> Self {
+            Scripted {
+                input: io::Cursor::new(input.as_bytes().to_vec()),
+                output: Vec::new(),
+                echo: true,
+                hidden: Vec::new(),
+            }
+        }
+
+        fn output(&self) -> String {
+            String::from_utf8(self.output.clone()).unwrap()
+        }
+    }
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.input.read(buf)?;
+            if !self.echo {
+                self.hidden.push(String::from_utf8_lossy(&buf[..n]).into_owned());
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for Scripted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Console for Scripted {
+        fn set_echo(&mut self, echo: bool) -> io::Result<()> {
+            self.echo = echo;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_ask_pin() {
+        let dialog = Dialog {
+            title: Some("Vault".to_string()),
+            description: Some("Unlock the vault".to_string()),
+            prompt: Some("Passphrase:".to_string()),
+            repeat: true,
+            ..Dialog::default()
+        };
+        let mut console = Scripted::new("secret\nsecert\nsecret\r\nsecret\n");
+        let pin = ask_pin(&mut console, &dialog).unwrap().unwrap();
+        assert_eq!(b"secret", pin.unsecure());
+        assert!(console.echo);
+        assert_eq!("secret\nsecert\nsecret\r\nsecret\n", console.hidden.concat());
+        assert_eq!(
+            "Vault\nUnlock the vault\nPassphrase: Repeat: Error: The PINs do not match\nPassphrase: Repeat: ",
+            console.output()
+        );
+
+        let mut console = Scripted::new("");
+        assert!(ask_pin(&mut console, &Dialog::default()).unwrap().is_none());
+        let mut console = Scripted::new(&"x".repeat(MAX_PIN_LENGTH + 1));
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            ask_pin(&mut console, &Dialog::default()).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn test_ask_confirmation() {
+        let dialog = Dialog {
+            description: Some("Delete the key?".to_string()),
+            ok: Some("_Yes".to_string()),
+            not_ok: Some("_No".to_string()),
+            ..Dialog::default()
+        };
+        let mut console = Scripted::new("maybe\nNO\n");
+        assert_eq!(Answer::NotOk, ask_confirmation(&mut console, &dialog, false).unwrap());
+        assert_eq!(
+            "Delete the key?\nYes (y), No (n), Cancel (c)? Yes (y), No (n), Cancel (c)? ",
+            console.output()
+        );
+        assert!(console.hidden.is_empty());
+
+        let mut console = Scripted::new("y\n");
+        assert_eq!(Answer::Ok, ask_confirmation(&mut console, &dialog, false).unwrap());
+        let mut console = Scripted::new("");
+        assert_eq!(
+            Answer::Cancelled,
+            ask_confirmation(&mut console, &dialog, false).unwrap()
+        );
+        let mut console = Scripted::new("\n");
+        assert_eq!(
+            Answer::Ok,
+            ask_confirmation(&mut console, &Dialog::default(), true).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        assert_eq!("Save as", label("Save _as"));
+        assert_eq!('a', key("Save _as"));
+        assert_eq!("my_file", label("my__file"));
+        assert_eq!('m', key("my__file"));
+        assert_eq!('o', key("OK"));
+    }
+
+    #[test]
+    fn test_tty_fallback() {
+        let builder = pinentry().exe("/nonexistent/pinentry");
+        assert!(matches!(builder.clone().connect(), Err(Error::IoError(_))));
+        assert!(builder.allow_tty_fallback(true).connect().is_ok());
+    }
+}
@@ -0,0 +1,76 @@
+#![deny(warnings)]
+#![warn(unused_must_use)]
+extern crate pinentry_rs;
+
+use std::env;
+use std::fs::File;
+use std::io;
+use std::process;
+
+use pinentry_rs::daemon::Daemon;
+use pinentry_rs::pinentry;
+
+const USAGE: &str = "usage: pinentry-rs daemon --secret-fd <fd> [--exe <pinentry>]
+
+Commands:
+  daemon  serve JSON-RPC prompt requests on stdin/stdout, writing PINs to the secret file descriptor";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let res = match args.first().map(|s| s.as_str()) {
+        Some("daemon") => daemon(&args[1..]),
+        _ => Err(USAGE.to_string()),
+    };
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        process::exit(2);
+    }
+}
+
+fn daemon(args: &[String]) -> Result<(), String> {
+    let mut builder = pinentry();
+    let mut secret_fd = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("{} needs a value\n\n{}", arg, USAGE))
+        };
+        match arg.as_str() {
+            "--exe" => builder = builder.exe(value()?),
+            "--secret-fd" => secret_fd = Some(value()?.parse().map_err(|_| "invalid --secret-fd".to_string())?),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let secrets = open_fd(secret_fd.ok_or_else(|| USAGE.to_string())?).map_err(|e| e.to_string())?;
+
+    let session = builder.connect().map_err(|e| e.to_string())?;
+    let stdin = io::stdin();
+    Daemon::new(session, secrets)
+        .run(stdin.lock(), io::stdout())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(unix)]
+fn open_fd(fd: i32) -> io::Result<File> {
+    use std::os::unix::io::FromRawFd;
+
+    if fd <= 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the secret fd must not be stdin/stdout/stderr",
+        ));
+    }
+    // SAFETY: the fd is handed to us by the parent process for our exclusive use
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(not(unix))]
+fn open_fd(_fd: i32) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--secret-fd is only supported on unix",
+    ))
+}
@@ -0,0 +1,285 @@
+//! Aborting prompts from another thread
+//!
+//! A prompt blocks until the user answers it. To dismiss the dialog earlier - because the parent window was closed,
+//! or the hardware token the PIN was for has been removed - give the builder a [`CancelToken`] and cancel it from
+//! another thread. The pinentry process is killed, and the pending prompt returns
+//! [`Error::Aborted`](super::Error::Aborted):
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use pinentry_rs::pinentry;
+//!
+//! let (handle, token) = pinentry().description("Please enter the PIN of your token")
+//!     .pin_cancellable("PIN:");
+//! // later, when the token is removed:
+//! token.cancel();
+//! assert!(handle.wait().is_err());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A token stays cancelled: prompts made with it afterwards (on the same session, too) are aborted right away.
+//!
+//! The same mechanism enforces [hard timeouts](super::PinentryBuilder::hard_timeout), for pinentry flavors that
+//! ignore `SETTIMEOUT`.
+
+use std::process::Child;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, Weak};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::{Result, SecretPin};
+
+/// Cancels the prompts of the builder (or session) it was given to, see the [module documentation](self)
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    // the pinentry processes started with the token, by all sessions sharing it
+    children: Mutex<Vec<Weak<Mutex<Child>>>>,
+}
+
+impl CancelToken {
+    /// A token that has not been cancelled yet
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// Abort the pending prompts (if any) by killing pinentry, and all later ones
+    ///
+    /// This applies to every session the token was given to, e.g. through clones of a builder.
+    pub fn cancel(&self) {
+        debug!("prompt was cancelled");
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.kill_all();
+    }
+
+    /// Whether [`cancel`](CancelToken::cancel) has been called
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Kill `child` when the token is cancelled (right away if it already is)
+    pub(crate) fn watch(&self, child: &Arc<Mutex<Child>>) {
+        let mut children = self.inner.children.lock().unwrap_or_else(|e| e.into_inner());
+        // processes whose transport is gone have been reaped already
+        children.retain(|child| child.strong_count() > 0);
+        children.push(Arc::downgrade(child));
+        drop(children);
+        // checked after registering, so that a concurrent cancel() cannot be missed
+        if self.is_cancelled() {
+            kill(child);
+        }
+    }
+
+    fn kill_all(&self) {
+        let children: Vec<_> = (self.inner.children.lock().unwrap_or_else(|e| e.into_inner()).iter())
+            .filter_map(Weak::upgrade)
+            .collect();
+        for child in children {
+            kill(&child);
+        }
+    }
+}
+
+/// The pinentry process currently running for one session, which its [hard
+/// timeout](super::PinentryBuilder::hard_timeout) kills
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SessionChild(Arc<Mutex<Weak<Mutex<Child>>>>);
+
+impl SessionChild {
+    /// Make `child` the process of the session (after it has been respawned, say)
+    pub(crate) fn set(&self, child: &Arc<Mutex<Child>>) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = Arc::downgrade(child);
+    }
+
+    /// Kill the process of the session unless the returned watchdog is stopped within `timeout`
+    pub(crate) fn kill_after(&self, timeout: Duration) -> Watchdog {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let current = self.clone();
+        let thread = thread::spawn(move || match stopped.recv_timeout(timeout) {
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                debug!("prompt exceeded the hard timeout of {:?}, killing pinentry", timeout);
+                let child = current.0.lock().unwrap_or_else(|e| e.into_inner()).upgrade();
+                if let Some(child) = child {
+                    kill(&child);
+                }
+                true
+            }
+            _ => false,
+        });
+        Watchdog { stop, thread }
+    }
+}
+
+/// Kills pinentry when a prompt takes too long, see [`SessionChild::kill_after`]
+pub(crate) struct Watchdog {
+    stop: mpsc::Sender<()>,
+    thread: JoinHandle<bool>,
+}
+
+impl Watchdog {
+    /// Stop watching, returning whether pinentry has been killed
+    pub(crate) fn stop(self) -> bool {
+        let _ = self.stop.send(());
+        self.thread.join().unwrap_or(false)
+    }
+}
+
+fn kill(child: &Mutex<Child>) {
+    // the process may already have exited, in which case kill fails harmlessly
+    let _ = child.lock().unwrap_or_else(|e| e.into_inner()).kill();
+}
+
+/// A PIN prompt running on another thread, see
+/// [`PinentryBuilder::pin_cancellable`](super::PinentryBuilder::pin_cancellable)
+#[derive(Debug)]
+pub struct PinHandle(pub(crate) JoinHandle<Result<SecretPin>>);
+
+impl PinHandle {
+    /// Wait for the prompt to finish
+    pub fn wait(self) -> Result<SecretPin> {
+        self.0.join().unwrap_or_else(|e| std::panic::resume_unwind(e))
+    }
+
+    /// Whether the prompt has finished (so that [`wait`](PinHandle::wait) does not block)
+    pub fn is_finished(&self) -> bool {
+        self.0.is_finished()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::super::test_util::FakePinentry;
+    use super::super::{pinentry, Error};
+    use super::*;
+
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_pin_cancellable() {
+        let fake = FakePinentry::new(&[("GETPIN", "exec sleep 30")]);
+        let (handle, token) = pinentry().exe(fake.exe()).pin_cancellable("PIN:");
+        while !fake.commands().iter().any(|cmd| cmd == "GETPIN") {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let start = Instant::now();
+        token.cancel();
+        assert!(matches!(handle.wait(), Err(Error::Aborted)));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn test_cancel_shared_token() {
+        let fake = FakePinentry::new(&[("GETPIN", "exec sleep 30")]);
+        let token = CancelToken::new();
+        let builder = pinentry().exe(fake.exe()).cancel_token(token.clone());
+        let prompts: Vec<_> = (0..2)
+            .map(|_| {
+                let builder = builder.clone();
+                thread::spawn(move || builder.pin("PIN:"))
+            })
+            .collect();
+        while fake.commands().iter().filter(|cmd| *cmd == "GETPIN").count() < 2 {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // both sessions are aborted, not only the one started last
+        let start = Instant::now();
+        token.cancel();
+        for prompt in prompts {
+            assert!(matches!(prompt.join().unwrap(), Err(Error::Aborted)));
+        }
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(2, fake.spawn_count());
+    }
+
+    #[test]
+    fn test_cancelled_session() {
+        let fake = FakePinentry::new(&[("GETPIN", "echo 'D secret'; echo OK")]);
+        let token = CancelToken::new();
+        let mut session = pinentry()
+            .exe(fake.exe())
+            .respawn(true)
+            .cancel_token(token.clone())
+            .connect()
+            .expect("session is started");
+        assert_eq!(b"secret", session.pin("PIN:").unwrap().unsecure());
+
+        // later prompts are aborted without respawning pinentry
+        token.cancel();
+        assert!(matches!(session.pin("PIN:"), Err(Error::Aborted)));
+        assert!(matches!(session.confirm_yes_no(), Err(Error::Aborted)));
+        assert_eq!(1, fake.spawn_count());
+
+        // as are new ones
+        let res = pinentry().exe(fake.exe()).cancel_token(token).pin("PIN:");
+        assert!(matches!(res, Err(Error::Aborted)));
+    }
+
+    #[test]
+    fn test_hard_timeout() {
+        // the first GETPIN hangs (as if SETTIMEOUT was ignored), the second one is answered
+        let fake = FakePinentry::new(&[(
+            "GETPIN",
+            r#"if [ -e "$DIR/hung" ]; then echo "D secret"; echo OK; else touch "$DIR/hung"; exec sleep 30; fi"#,
+        )]);
+        let mut session = pinentry()
+            .exe(fake.exe())
+            .respawn(true)
+            .hard_timeout(Duration::from_millis(300))
+            .connect()
+            .expect("session is started");
+
+        let start = Instant::now();
+        assert!(matches!(session.pin("PIN:"), Err(Error::Timeout)));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(b"secret", session.pin("PIN:").unwrap().unsecure());
+        assert_eq!(2, fake.spawn_count());
+
+        let res = pinentry()
+            .hard_timeout(Duration::from_secs(1))
+            .connect_with(|| Err::<super::super::transport::ProcessTransport, _>(std::io::ErrorKind::NotFound.into()));
+        assert!(matches!(res, Err(Error::InvalidConfiguration(_))));
+    }
+
+    #[test]
+    fn test_hard_timeout_shared_token() {
+        // the first GETPIN hangs, the second one is answered
+        let fake = FakePinentry::new(&[(
+            "GETPIN",
+            r#"if [ -e "$DIR/hung" ]; then echo "D secret"; echo OK; else touch "$DIR/hung"; exec sleep 30; fi"#,
+        )]);
+        let builder = pinentry()
+            .exe(fake.exe())
+            .cancel_token(CancelToken::new())
+            .hard_timeout(Duration::from_millis(300));
+        let mut hung = builder.clone().connect().expect("session is started");
+        let mut other = builder.connect().expect("session is started");
+
+        // only the pinentry of the prompt that hangs is killed, not the one started last
+        let start = Instant::now();
+        assert!(matches!(hung.pin("PIN:"), Err(Error::Timeout)));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(b"secret", other.pin("PIN:").unwrap().unsecure());
+        assert_eq!(2, fake.spawn_count());
+    }
+
+    #[test]
+    fn test_cancel_token_needs_connect() {
+        let res = pinentry()
+            .cancel_token(CancelToken::new())
+            .connect_with(|| Err::<super::super::transport::ProcessTransport, _>(std::io::ErrorKind::NotFound.into()));
+        assert!(matches!(res, Err(Error::InvalidConfiguration(_))));
+    }
+}
@@ -0,0 +1,182 @@
+//! [`tokio-util`](https://docs.rs/tokio-util) codec for the Assuan protocol
+//!
+//! The codec frames a byte stream into Assuan [`Line`]s and back, so it can be used with `Framed` to build
+//! asynchronous transports for either side of the protocol.
+
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+use zeroize::Zeroize;
+
+use super::{Error, Result};
+
+use super::assuan::line::is_blank;
+use super::assuan::{Line, MAX_LINE_LENGTH};
+
+/// Encoder/decoder of Assuan protocol lines
+#[derive(Debug, Default)]
+pub struct AssuanCodec {
+    // index up to which the buffer has already been searched for a newline
+    next_index: usize,
+}
+
+impl AssuanCodec {
+    /// Create a new codec
+    pub fn new() -> Self {
+        AssuanCodec::default()
+    }
+}
+
+impl Decoder for AssuanCodec {
+    type Item = Line;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Line>> {
+        loop {
+            let newline = src[self.next_index..].iter().position(|&b| b == b'\n');
+            match newline {
+                Some(offset) => {
+                    let end = self.next_index + offset;
+                    self.next_index = 0;
+
+                    let mut line = src.split_to(end + 1);
+                    if is_blank(&line) {
+                        continue;
+                    }
+                    let res = Line::parse(&line[..end]);
+                    // the line may have contained a secret, so wipe it before it is released
+                    line[..].zeroize();
+                    return res.map(Some);
+                }
+                None if src.len() >= MAX_LINE_LENGTH => {
+                    return Err(Error::ProtocolError(format!("line exceeds {} bytes", MAX_LINE_LENGTH)));
+                }
+                None => {
+                    self.next_index = src.len();
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Line>> {
+        match self.decode(src)? {
+            Some(line) => Ok(Some(line)),
+            None => {
+                // accept an unterminated last line
+                self.next_index = 0;
+                let mut line = src.split();
+                if is_blank(&line) {
+                    return Ok(None);
+                }
+                let res = Line::parse(&line);
+                line[..].zeroize();
+                res.map(Some)
+            }
+        }
+    }
+}
+
+impl Encoder<Line> for AssuanCodec {
+    type Error = Error;
+
+    fn encode(&mut self, line: Line, dst: &mut BytesMut) -> Result<()> {
+        Encoder::<&Line>::encode(self, &line, dst)
+    }
+}
+
+impl Encoder<&Line> for AssuanCodec {
+    type Error = Error;
+
+    fn encode(&mut self, line: &Line, dst: &mut BytesMut) -> Result<()> {
+        let mut buf = Vec::new();
+        let res = line.encode(&mut buf);
+        if res.is_ok() {
+            dst.put_slice(&buf);
+        }
+        // the encoded line may contain a secret
+        buf.zeroize();
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::SecretPin;
+
+    fn decode_all(codec: &mut AssuanCodec, buf: &mut BytesMut) -> Vec<Line> {
+        let mut lines = Vec::new();
+        while let Some(line) = codec.decode(buf).expect("valid line") {
+            lines.push(line);
+        }
+        lines
+    }
+
+    #[test]
+    fn test_decode_partial_lines() {
+        let mut codec = AssuanCodec::new();
+        let mut buf = BytesMut::from(&b"OK Pleased to meet you\nD secr"[..]);
+
+        let lines = decode_all(&mut codec, &mut buf);
+        assert_eq!(1, lines.len());
+        assert!(matches!(lines[0], Line::Ok(Some(_))));
+
+        buf.extend_from_slice(b"et%25\nOK\n");
+        let lines = decode_all(&mut codec, &mut buf);
+        assert_eq!(2, lines.len());
+        match lines[0] {
+            Line::Data(ref data) => assert_eq!(b"secret%", data.unsecure()),
+            ref x => panic!("unexpected line {:?}", x),
+        }
+        assert!(matches!(lines[1], Line::Ok(None)));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_too_long() {
+        let mut codec = AssuanCodec::new();
+        let mut buf = BytesMut::from(&vec![b'x'; MAX_LINE_LENGTH][..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_decode_eof() {
+        let mut codec = AssuanCodec::new();
+        let mut buf = BytesMut::from(&b"OK"[..]);
+        assert!(matches!(codec.decode_eof(&mut buf).unwrap(), Some(Line::Ok(None))));
+        assert!(codec.decode_eof(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_decode_crlf_and_blank_lines() {
+        let mut codec = AssuanCodec::new();
+        let mut buf = BytesMut::from(&b"\r\nOK \r\n\nS PIN_REPEATED\r\nD pass \r\n"[..]);
+
+        let lines = decode_all(&mut codec, &mut buf);
+        assert_eq!(3, lines.len());
+        assert!(matches!(lines[0], Line::Ok(None)));
+        assert!(matches!(lines[1], Line::Status(ref s) if s.keyword == "PIN_REPEATED"));
+        match lines[2] {
+            Line::Data(ref data) => assert_eq!(b"pass ", data.unsecure()),
+            ref x => panic!("unexpected line {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut codec = AssuanCodec::new();
+        let mut buf = BytesMut::new();
+        codec
+            .encode(
+                Line::Command("SETPROMPT".to_string(), Some("PIN:".to_string())),
+                &mut buf,
+            )
+            .unwrap();
+        codec.encode(&Line::Data(SecretPin::from("a\nb")), &mut buf).unwrap();
+        codec.encode(Line::End, &mut buf).unwrap();
+        assert_eq!(&b"SETPROMPT PIN:\nD a%0Ab\nEND\n"[..], &buf[..]);
+
+        assert!(codec.encode(Line::Comment("a\nb".to_string()), &mut buf).is_err());
+    }
+}
@@ -0,0 +1,247 @@
+//! Compatibility matrix of the pinentry flavors installed on this machine
+//!
+//! The pinentry flavors (`pinentry-curses`, `pinentry-gnome3`, `pinentry-qt`, ...) differ in which commands and
+//! options they accept and in how they fail when nobody answers. [`run_matrix`] finds the installed flavors and
+//! [`check`]s each of them, so the differences can be looked at with real data:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! use pinentry_rs::compat;
+//!
+//! for (exe, report) in compat::run_matrix() {
+//!     match report {
+//!         Ok(report) => println!("{}", report),
+//!         Err(e) => println!("{}: {}", exe.display(), e),
+//!     }
+//! }
+//! ```
+//!
+//! The checks do not need any user input, but graphical flavors may briefly show a dialog (for one second) while the
+//! behaviour of an unanswered prompt is checked. Run `cargo run --example compat-report --features compat` for a
+//! report, or `cargo test --features compat -- --ignored --nocapture` to check the installed flavors (the test is
+//! not run by default, as its outcome depends on the machine - e.g. `pinentry-curses` cannot start without a
+//! terminal).
+//!
+//! What a flavor reports when the user cancels is not checked: pinentry only answers with a cancel code when the
+//! dialog is dismissed by the user, and the protocol has no way for the client to cancel a prompt in its place.
+//!
+//! [`rpassword`] offers the functions of the `rpassword` crate, for projects migrating from it.
+
+use std::collections::HashSet;
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::assuan::{AssuanCommand, AssuanError, Button};
+use super::diagnostics::{find_pinentries, Probe};
+use super::Result;
+
+pub mod rpassword;
+
+/// Outcome of a single check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckOutcome {
+    /// All commands of the check were accepted
+    Supported,
+    /// A command was rejected with the given error
+    Rejected(AssuanError),
+}
+
+/// What a pinentry executable supports
+#[derive(Debug, Clone)]
+pub struct CapabilityReport {
+    /// The executable that was checked
+    pub exe: PathBuf,
+    /// Flavor reported by `GETINFO flavor` (e.g. `curses`)
+    pub flavor: Option<String>,
+    /// Version reported by `GETINFO version`
+    pub version: Option<String>,
+    /// Outcome of each check, by name
+    pub checks: Vec<(&'static str, CheckOutcome)>,
+    /// Error returned by a PIN prompt that nobody answered (within a 1 second timeout), e.g. a timeout or a
+    /// "no tty" error - `None` if a PIN was returned
+    pub unattended_error: Option<AssuanError>,
+}
+
+impl CapabilityReport {
+    /// Whether the check with the given name passed
+    pub fn supports(&self, check: &str) -> bool {
+        self.checks
+            .iter()
+            .any(|(name, outcome)| *name == check && *outcome == CheckOutcome::Supported)
+    }
+}
+
+impl Display for CapabilityReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} (flavor {}, version {})",
+            self.exe.display(),
+            self.flavor.as_deref().unwrap_or("unknown"),
+            self.version.as_deref().unwrap_or("unknown")
+        )?;
+        for (name, outcome) in &self.checks {
+            match outcome {
+                CheckOutcome::Supported => writeln!(f, "  {:<40} yes", name)?,
+                CheckOutcome::Rejected(e) => writeln!(f, "  {:<40} no: {}", name, e)?,
+            }
+        }
+        match self.unattended_error {
+            Some(ref e) => write!(f, "  {:<40} {}", "unattended prompt", e),
+            None => write!(f, "  {:<40} returned a PIN", "unattended prompt"),
+        }
+    }
+}
+
+/// The checks run against every flavor: a name and the (non-interactive) commands that have to be accepted
+fn checks() -> Vec<(&'static str, Vec<AssuanCommand>)> {
+    let option = |name: &str, value: Option<&str>| AssuanCommand::Option(name.to_string(), value.map(String::from));
+    vec![
+        (
+            "escaped description",
+            vec![AssuanCommand::SetDescriptiveText(
+                "100% sure\nyes, \"quoted\"".to_string(),
+            )],
+        ),
+        (
+            "window title",
+            vec![AssuanCommand::SetWindowTitle("Compatibility check".to_string())],
+        ),
+        (
+            "button labels",
+            vec![
+                AssuanCommand::SetButtonLabel(Button::OK, "_Unlock".to_string()),
+                AssuanCommand::SetButtonLabel(Button::CANCEL, "_Abort".to_string()),
+                AssuanCommand::SetButtonLabel(Button::NOTOK, "_Other".to_string()),
+            ],
+        ),
+        ("timeout", vec![AssuanCommand::SetTimeout(1)]),
+        ("repeat", vec![AssuanCommand::SetRepeat(Some("Repeat:".to_string()))]),
+        (
+            "quality bar",
+            vec![AssuanCommand::SetQualityBar(Some("Quality:".to_string()))],
+        ),
+        (
+            "PIN generation",
+            vec![AssuanCommand::SetGenPin("_Generate".to_string())],
+        ),
+        (
+            "key info",
+            vec![AssuanCommand::SetKeyInfo("n/compat-check".to_string())],
+        ),
+        ("option ttytype", vec![option("ttytype", Some("dumb"))]),
+        ("option lc-ctype", vec![option("lc-ctype", Some("C.UTF-8"))]),
+        ("option default-ok", vec![option("default-ok", Some("_Unlock"))]),
+        (
+            "option allow-external-password-cache",
+            vec![option("allow-external-password-cache", None)],
+        ),
+        ("option constraints-enforce", vec![option("constraints-enforce", None)]),
+    ]
+}
+
+/// Check what the pinentry executable `exe` supports
+pub fn check<P: AsRef<Path>>(exe: P) -> Result<CapabilityReport> {
+    let exe = exe.as_ref();
+    let mut probe = Probe::start(exe)?;
+    let flavor = probe.info("flavor")?;
+    let version = probe.info("version")?;
+
+    let mut results = Vec::new();
+    for (name, cmds) in checks() {
+        let mut outcome = CheckOutcome::Supported;
+        for cmd in &cmds {
+            if let Err(e) = probe.request(cmd)?.result {
+                outcome = CheckOutcome::Rejected(e);
+                break;
+            }
+        }
+        // start from the defaults again, so that the checks do not influence each other
+        probe.request(&AssuanCommand::Reset)?;
+        results.push((name, outcome));
+    }
+
+    probe.request(&AssuanCommand::SetTimeout(1))?;
+    let unattended_error = probe.request(&AssuanCommand::GetPin)?.result.err();
+
+    Ok(CapabilityReport {
+        exe: exe.to_path_buf(),
+        flavor,
+        version,
+        checks: results,
+        unattended_error,
+    })
+}
+
+/// Find the pinentry executables (`pinentry` and `pinentry-*`) in `PATH`
+///
+/// Executables are listed once, even if they are reachable under several names (e.g. `pinentry` is usually a link
+/// to one of the flavors).
+pub fn installed_flavors() -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    find_pinentries(&env::var_os("PATH").unwrap_or_default())
+        .into_iter()
+        .filter(|exe| seen.insert(fs::canonicalize(exe).unwrap_or_else(|_| exe.clone())))
+        .collect()
+}
+
+/// Check all installed flavors
+pub fn run_matrix() -> Vec<(PathBuf, Result<CapabilityReport>)> {
+    installed_flavors()
+        .into_iter()
+        .map(|exe| {
+            let report = check(&exe);
+            (exe, report)
+        })
+        .collect()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use super::super::test_util::FakePinentry;
+
+    #[test]
+    fn test_check_fake_flavor() {
+        let fake = FakePinentry::new(&[
+            ("'GETINFO flavor'", r#"echo "D fake"; echo OK"#),
+            ("'GETINFO version'", r#"echo "D 1.2.3"; echo OK"#),
+            (
+                "SETQUALITYBAR*",
+                r#"echo "ERR 536871187 Unknown command <User defined source 1>""#,
+            ),
+            ("GETPIN", r#"echo "ERR 83886142 Timeout <Pinentry>""#),
+        ]);
+        let report = check(fake.exe()).expect("fake pinentry is checked");
+        assert_eq!(Some("fake".to_string()), report.flavor);
+        assert_eq!(Some("1.2.3".to_string()), report.version);
+        assert!(report.supports("escaped description"));
+        assert!(report.supports("option allow-external-password-cache"));
+        assert!(!report.supports("quality bar"));
+        assert_eq!(
+            Some(AssuanError::TIMEOUT),
+            report.unattended_error.as_ref().map(|e| e.error_code())
+        );
+        assert!(fake
+            .commands()
+            .contains(&"SETDESC 100%25 sure%0Ayes, \"quoted\"".to_string()));
+    }
+
+    /// Checks the flavors installed on this machine: `cargo test --features compat -- --ignored --nocapture` (prints
+    /// the report)
+    #[test]
+    #[ignore = "starts the pinentry flavors installed on this machine"]
+    fn test_installed_flavors() {
+        for (exe, report) in run_matrix() {
+            let report = report.unwrap_or_else(|e| panic!("{} cannot be checked: {}", exe.display(), e));
+            println!("{}", report);
+            // pinentry has supported these for a long time, in all flavors
+            for check in &["escaped description", "window title", "button labels", "timeout"] {
+                assert!(report.supports(check), "{} does not support {}", exe.display(), check);
+            }
+        }
+    }
+}
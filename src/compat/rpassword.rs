@@ -0,0 +1,104 @@
+//! Drop-in replacement for the [`rpassword`](https://crates.io/crates/rpassword) functions
+//!
+//! Projects using `rpassword` can switch to pinentry dialogs by changing only the import:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # fn run() -> std::io::Result<()> {
+//! use pinentry_rs::compat::rpassword::prompt_password;
+//!
+//! let password = prompt_password("Password: ")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! If pinentry cannot be started or cannot show a dialog (no display and no terminal it can use), the password is
+//! read from `/dev/tty` with echo turned off, as `rpassword` does. Cancelling the dialog is an error of kind
+//! [`io::ErrorKind::Other`]. Like with `rpassword`, the password is returned in a plain `String` - use the rest of
+//! this crate to keep it in a secure string instead.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Command, Stdio};
+
+use super::super::{pinentry, Error, PinentryBuilder, SecretPin};
+
+/// Read a password, without a prompt
+pub fn read_password() -> io::Result<String> {
+    read_with(pinentry(), None)
+}
+
+/// Show `prompt` and read a password
+pub fn prompt_password<T: ToString>(prompt: T) -> io::Result<String> {
+    read_with(pinentry(), Some(prompt.to_string()))
+}
+
+fn read_with(builder: PinentryBuilder, prompt: Option<String>) -> io::Result<String> {
+    let label = prompt
+        .as_deref()
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .unwrap_or("Password:")
+        .to_string();
+    let password = match builder.pin(label) {
+        Ok(password) => password,
+        Err(Error::Cancelled) => return Err(io::Error::other("the password prompt was cancelled")),
+        Err(e) => {
+            debug!("pinentry failed ({}), reading the password from the terminal", e);
+            read_from_tty(prompt.as_deref().unwrap_or(""))?
+        }
+    };
+    String::from_utf8(password.unsecure().to_vec())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "the password is not valid UTF-8"))
+}
+
+/// Read a line from the terminal with echo turned off
+fn read_from_tty(prompt: &str) -> io::Result<SecretPin> {
+    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    tty.write_all(prompt.as_bytes())?;
+    tty.flush()?;
+
+    stty(&tty, "-echo")?;
+    let mut line = Vec::new();
+    let res = BufReader::new(&tty).read_until(b'\n', &mut line);
+    let restored = stty(&tty, "echo");
+    let _ = tty.write_all(b"\n");
+    res?;
+    restored?;
+
+    while line.last().is_some_and(|b| *b == b'\n' || *b == b'\r') {
+        line.pop();
+    }
+    Ok(SecretPin::new(line))
+}
+
+fn stty(tty: &File, setting: &str) -> io::Result<()> {
+    let status = Command::new("stty")
+        .arg(setting)
+        .stdin(Stdio::from(tty.try_clone()?))
+        .status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("stty {} failed", setting)))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use super::super::super::test_util::FakePinentry;
+
+    #[test]
+    fn test_prompt_password() {
+        let fake = FakePinentry::new(&[("GETPIN", r#"echo "D hunter2"; echo OK"#)]);
+        let password = read_with(pinentry().exe(fake.exe()), Some("Password for db: ".to_string())).unwrap();
+        assert_eq!("hunter2", password);
+        assert!(fake.commands().contains(&"SETPROMPT Password for db:".to_string()));
+
+        let fake = FakePinentry::new(&[("GETPIN", r#"echo "ERR 83886179 Operation cancelled <Pinentry>""#)]);
+        let err = read_with(pinentry().exe(fake.exe()), None).unwrap_err();
+        assert_eq!(io::ErrorKind::Other, err.kind());
+    }
+}
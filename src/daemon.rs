@@ -0,0 +1,262 @@
+//! A JSON-RPC daemon, for using pinentry from other languages as a sidecar process
+//!
+//! The daemon reads [JSON-RPC 2.0](https://www.jsonrpc.org/specification) requests from its input (one per line)
+//! and writes the responses to its output. PINs never appear in the JSON: they are written to a separate secrets
+//! stream (e.g. an inherited file descriptor), and the response only says how many bytes to read from it.
+//!
+//! Methods (all parameters are optional unless noted otherwise):
+//!
+//!   * `getpin` - `{"prompt": "PIN:", ...}`, returns `{"secret_length": 4}` after writing the PIN to the secrets stream
+//!   * `confirm` - returns `{"confirmed": true}`
+//!   * `message` - returns `null` once the message has been dismissed
+//!   * `shutdown` - returns `null` and stops the daemon
+//!
+//! The prompt methods accept `description`, `error_text`, `label_cancel`, `label_notok`, `label_ok`, `timeout` and
+//! `window_title` parameters, which apply to that prompt only. Failed prompts are reported as errors with code
+//! [`PINENTRY_ERROR`].
+//!
+//! The `pinentry-rs daemon` command runs the daemon over standard input and output.
+
+use std::io::{BufRead, Write};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::session::{PinentrySession, SessionPrompt};
+use super::Result;
+
+/// JSON-RPC error code of a failed prompt (cancelled, pinentry error, ...)
+pub const PINENTRY_ERROR: i64 = -32000;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// Serves JSON-RPC prompt requests using a pinentry session
+pub struct Daemon<S: Write> {
+    secrets: S,
+    session: PinentrySession,
+}
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Option<Value>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PromptParams {
+    prompt: Option<String>,
+    description: Option<String>,
+    error_text: Option<String>,
+    label_cancel: Option<String>,
+    label_notok: Option<String>,
+    label_ok: Option<String>,
+    timeout: Option<u32>,
+    window_title: Option<String>,
+}
+
+/// Outcome of a single request
+enum Reply {
+    Result(Value),
+    Error(i64, String),
+}
+
+impl<S: Write> Daemon<S> {
+    /// Serve requests using `session`, writing PINs to `secrets`
+    pub fn new(session: PinentrySession, secrets: S) -> Self {
+        Daemon { secrets, session }
+    }
+
+    /// Handle requests from `input` until it is closed or a `shutdown` request is received
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> Result<()> {
+        for line in input.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let (id, reply, shutdown) = match serde_json::from_str::<Request>(&line) {
+                Ok(request) => {
+                    let shutdown = request.method == "shutdown";
+                    let reply = self.handle(&request)?;
+                    // requests without an id are notifications and get no response
+                    match request.id {
+                        Some(id) => (id, reply, shutdown),
+                        None if shutdown => return Ok(()),
+                        None => continue,
+                    }
+                }
+                Err(e) => (Value::Null, Reply::Error(PARSE_ERROR, e.to_string()), false),
+            };
+
+            let response = match reply {
+                Reply::Result(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                Reply::Error(code, message) => {
+                    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+                }
+            };
+            writeln!(output, "{}", response)?;
+            output.flush()?;
+            if shutdown {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a request - only I/O errors on the secrets stream are returned as errors
+    fn handle(&mut self, request: &Request) -> Result<Reply> {
+        if request.jsonrpc != "2.0" {
+            return Ok(Reply::Error(
+                INVALID_REQUEST,
+                "only JSON-RPC 2.0 is supported".to_string(),
+            ));
+        }
+        let params: PromptParams = match request.params.clone().map(serde_json::from_value).transpose() {
+            Ok(params) => params.unwrap_or_default(),
+            Err(e) => return Ok(Reply::Error(INVALID_PARAMS, e.to_string())),
+        };
+
+        let res = match request.method.as_str() {
+            "getpin" => {
+                let prompt = params.prompt.clone().unwrap_or_else(|| "PIN:".to_string());
+                match params.apply(self.session.prompt()).pin(prompt) {
+                    Ok(pin) => {
+                        self.secrets.write_all(pin.unsecure())?;
+                        self.secrets.flush()?;
+                        Ok(json!({"secret_length": pin.unsecure().len()}))
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            "confirm" => params
+                .apply(self.session.prompt())
+                .confirm_yes_no()
+                .map(|confirmed| json!({"confirmed": confirmed})),
+            "message" => params.apply(self.session.prompt()).show_message().map(|_| Value::Null),
+            "shutdown" => Ok(Value::Null),
+            method => return Ok(Reply::Error(METHOD_NOT_FOUND, format!("unknown method: {}", method))),
+        };
+        Ok(match res {
+            Ok(result) => Reply::Result(result),
+            Err(e) => Reply::Error(PINENTRY_ERROR, e.to_string()),
+        })
+    }
+}
+
+impl PromptParams {
+    fn apply(self, mut prompt: SessionPrompt<'_>) -> SessionPrompt<'_> {
+        if let Some(desc) = self.description {
+            prompt = prompt.description(desc);
+        }
+        if let Some(text) = self.error_text {
+            prompt = prompt.error_text(text);
+        }
+        if let Some(label) = self.label_cancel {
+            prompt = prompt.label_cancel(label);
+        }
+        if let Some(label) = self.label_notok {
+            prompt = prompt.label_notok(label);
+        }
+        if let Some(label) = self.label_ok {
+            prompt = prompt.label_ok(label);
+        }
+        if let Some(secs) = self.timeout {
+            prompt = prompt.timeout(secs);
+        }
+        if let Some(title) = self.window_title {
+            prompt = prompt.window_title(title);
+        }
+        prompt
+    }
+}
+
+#[cfg(all(test, unix, feature = "process"))]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use super::super::pinentry;
+    use super::super::test_util::FakePinentry;
+
+    fn run_daemon(fake: &FakePinentry, requests: &[&str]) -> (Vec<Value>, Vec<u8>) {
+        let session = pinentry().exe(fake.exe()).connect().expect("session is started");
+        let mut secrets = Vec::new();
+        let mut output = Vec::new();
+        Daemon::new(session, &mut secrets)
+            .run(Cursor::new(requests.join("\n")), &mut output)
+            .expect("daemon runs");
+
+        let responses = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).expect("response is valid JSON"))
+            .collect();
+        (responses, secrets)
+    }
+
+    #[test]
+    fn test_daemon_requests() {
+        let fake = FakePinentry::new(&[("GETPIN", "echo 'D 1234'; echo OK"), ("CONFIRM", "echo OK")]);
+        let (responses, secrets) = run_daemon(
+            &fake,
+            &[
+                r#"{"jsonrpc": "2.0", "id": 1, "method": "getpin", "params": {"prompt": "PIN:", "window_title": "Card"}}"#,
+                r#"{"jsonrpc": "2.0", "id": "c", "method": "confirm"}"#,
+                r#"{"jsonrpc": "2.0", "id": 3, "method": "shutdown"}"#,
+                r#"{"jsonrpc": "2.0", "id": 4, "method": "confirm"}"#,
+            ],
+        );
+
+        assert_eq!(
+            vec![
+                json!({"jsonrpc": "2.0", "id": 1, "result": {"secret_length": 4}}),
+                json!({"jsonrpc": "2.0", "id": "c", "result": {"confirmed": true}}),
+                json!({"jsonrpc": "2.0", "id": 3, "result": null}),
+            ],
+            responses
+        );
+        assert_eq!(b"1234", &secrets[..]);
+        assert_eq!(
+            vec!["SETTITLE Card", "SETPROMPT PIN:", "GETPIN", "RESET", "CONFIRM", "BYE"],
+            fake.commands()
+        );
+    }
+
+    #[test]
+    fn test_daemon_errors() {
+        let fake = FakePinentry::new(&[("GETPIN", "echo 'ERR 83886179 Operation cancelled'")]);
+        let (responses, secrets) = run_daemon(
+            &fake,
+            &[
+                "not json",
+                r#"{"jsonrpc": "2.0", "id": 1, "method": "getpin"}"#,
+                r#"{"jsonrpc": "2.0", "id": 2, "method": "getpin", "params": {"colour": "red"}}"#,
+                r#"{"jsonrpc": "2.0", "id": 3, "method": "reboot"}"#,
+                r#"{"jsonrpc": "1.0", "id": 4, "method": "confirm"}"#,
+            ],
+        );
+
+        let codes: Vec<_> = responses.iter().map(|r| r["error"]["code"].as_i64().unwrap()).collect();
+        assert_eq!(
+            vec![
+                PARSE_ERROR,
+                PINENTRY_ERROR,
+                INVALID_PARAMS,
+                METHOD_NOT_FOUND,
+                INVALID_REQUEST
+            ],
+            codes
+        );
+        assert_eq!(Value::Null, responses[0]["id"]);
+        assert!(secrets.is_empty());
+    }
+}
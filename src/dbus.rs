@@ -0,0 +1,462 @@
+//! Sharing one pinentry between processes over [D-Bus](https://www.freedesktop.org/wiki/Software/dbus/)
+//!
+//! [`PromptService`] owns a [`PinentrySession`] and offers it on the session bus, so the processes of an application
+//! suite queue their prompts on one pinentry (and can share cached PINs) instead of each spawning their own.
+//! [`DbusClient`] is the matching client:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # #[cfg(feature = "process")]
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use std::time::Duration;
+//!
+//! use pinentry_rs::dbus::{DbusClient, PromptService, DEFAULT_BUS_NAME};
+//! use pinentry_rs::pinentry;
+//!
+//! // in the process that owns the prompt
+//! let session = pinentry().window_title("Mail suite").connect()?;
+//! let _service = PromptService::new(session)
+//!     .cache_ttl(Duration::from_secs(600))
+//!     .serve(DEFAULT_BUS_NAME)?;
+//!
+//! // in any other process of the suite
+//! let client = DbusClient::connect(DEFAULT_BUS_NAME)?;
+//! let pin = client.prompt().cache_key("imap").pin("IMAP password:")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! _Note_ that PINs are sent to the client in plain D-Bus messages: the session bus is only reachable by the user's
+//! own processes, but the message buffers are not wiped.
+//!
+//! The service implements the `org.pinentry_rs.Prompt1` interface at [`OBJECT_PATH`]:
+//!
+//!   * `GetPin(s prompt, a{ss} options) -> ay`
+//!   * `Confirm(a{ss} options) -> b`
+//!   * `ShowMessage(a{ss} options)`
+//!   * `Forget(s cache_key)`
+//!
+//! Recognized options are `description`, `error-text`, `label-cancel`, `label-notok`, `label-ok`, `timeout`,
+//! `window-title` and, for `GetPin` only, `cache-key`.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use zbus::blocking::connection::Builder;
+use zbus::blocking::{Connection, Proxy};
+use zbus::{fdo, interface};
+
+use super::assuan::{describe, Line};
+use super::secret::ExpiringSecret;
+use super::session::{PinentrySession, SessionPrompt};
+use super::{Error, Result, SecretPin};
+
+/// Well-known bus name used if the application suite does not pick its own
+pub const DEFAULT_BUS_NAME: &str = "org.pinentry_rs.Prompt";
+
+/// Object path the service is served at
+pub const OBJECT_PATH: &str = "/org/pinentry_rs/Prompt";
+
+const INTERFACE: &str = "org.pinentry_rs.Prompt1";
+
+/// A pinentry session offered to other processes over D-Bus
+///
+/// Prompts are handled one at a time, in the order they arrive.
+pub struct PromptService {
+    cache: Mutex<HashMap<String, ExpiringSecret>>,
+    cache_ttl: Option<Duration>,
+    session: Mutex<PinentrySession>,
+}
+
+impl PromptService {
+    /// Offer `session` for prompting (its settings become the defaults of all prompts)
+    pub fn new(session: PinentrySession) -> Self {
+        PromptService {
+            cache: Mutex::new(HashMap::new()),
+            cache_ttl: None,
+            session: Mutex::new(session),
+        }
+    }
+
+    /// Remember PINs requested with a `cache-key` for `ttl`, answering later requests for the same key without
+    /// prompting (off by default)
+    pub fn cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = Some(ttl);
+        self
+    }
+
+    /// Serve on the session bus under the well-known `name`
+    pub fn serve(self, name: &str) -> Result<DbusService> {
+        self.serve_on(Builder::session().map_err(dbus_error)?, name)
+    }
+
+    /// Serve on a connection to a custom bus under the well-known `name`
+    pub fn serve_on(self, builder: Builder<'_>, name: &str) -> Result<DbusService> {
+        let connection = builder
+            .name(name)
+            .and_then(|b| b.serve_at(OBJECT_PATH, self))
+            .and_then(|b| b.build())
+            .map_err(dbus_error)?;
+        Ok(DbusService {
+            _connection: connection,
+        })
+    }
+
+    fn cached(&self, key: &str) -> Option<SecretPin> {
+        let mut cache = self.cache.lock().expect("cache lock is not poisoned");
+        cache.retain(|_, pin| !pin.is_expired());
+        cache
+            .get(key)
+            .and_then(|pin| pin.with_secret(|pin| SecretPin::from(pin)))
+    }
+}
+
+#[interface(name = "org.pinentry_rs.Prompt1")]
+impl PromptService {
+    fn get_pin(&self, prompt: String, options: HashMap<String, String>) -> fdo::Result<Vec<u8>> {
+        let cache_key = options.get("cache-key").cloned();
+        if let Some(pin) = cache_key.as_deref().and_then(|key| self.cached(key)) {
+            return Ok(pin.unsecure().to_vec());
+        }
+
+        let pin = {
+            let mut session = self.session.lock().expect("session lock is not poisoned");
+            apply_options(session.prompt(), &options, &["cache-key"])?
+                .pin(prompt)
+                .map_err(service_error)?
+        };
+        if let (Some(key), Some(ttl)) = (cache_key, self.cache_ttl) {
+            let mut cache = self.cache.lock().expect("cache lock is not poisoned");
+            cache.insert(key, ExpiringSecret::new(pin.clone(), ttl));
+        }
+        Ok(pin.unsecure().to_vec())
+    }
+
+    fn confirm(&self, options: HashMap<String, String>) -> fdo::Result<bool> {
+        let mut session = self.session.lock().expect("session lock is not poisoned");
+        apply_options(session.prompt(), &options, &[])?
+            .confirm_yes_no()
+            .map_err(service_error)
+    }
+
+    fn show_message(&self, options: HashMap<String, String>) -> fdo::Result<()> {
+        let mut session = self.session.lock().expect("session lock is not poisoned");
+        apply_options(session.prompt(), &options, &[])?
+            .show_message()
+            .map_err(service_error)
+    }
+
+    fn forget(&self, cache_key: String) {
+        let mut cache = self.cache.lock().expect("cache lock is not poisoned");
+        if let Some(pin) = cache.remove(&cache_key) {
+            pin.expire();
+        }
+    }
+}
+
+/// A running [`PromptService`] - it stops serving when dropped
+pub struct DbusService {
+    _connection: Connection,
+}
+
+/// Client of a [`PromptService`] running in another process
+pub struct DbusClient {
+    proxy: Proxy<'static>,
+}
+
+impl DbusClient {
+    /// Connect to the service registered under `name` on the session bus
+    pub fn connect(name: &str) -> Result<DbusClient> {
+        DbusClient::connect_on(&Connection::session().map_err(dbus_error)?, name)
+    }
+
+    /// Connect to the service registered under `name` on an existing bus connection
+    pub fn connect_on(connection: &Connection, name: &str) -> Result<DbusClient> {
+        let proxy = Proxy::new(connection, name.to_string(), OBJECT_PATH, INTERFACE).map_err(dbus_error)?;
+        Ok(DbusClient { proxy })
+    }
+
+    /// Start a prompt
+    pub fn prompt(&self) -> DbusPrompt<'_> {
+        DbusPrompt {
+            client: self,
+            options: HashMap::new(),
+        }
+    }
+
+    /// Prompt for confirmation
+    ///
+    /// The text for the confirmation should be set using `.description()` on a [`prompt()`](DbusClient::prompt)
+    pub fn confirm_yes_no(&self) -> Result<bool> {
+        self.prompt().confirm_yes_no()
+    }
+
+    /// Prompt for a PIN
+    pub fn pin<S: Into<String>>(&self, prompt: S) -> Result<SecretPin> {
+        self.prompt().pin(prompt)
+    }
+
+    /// Show a message
+    ///
+    /// The text for the message should be set using `.description()` on a [`prompt()`](DbusClient::prompt)
+    pub fn show_message(&self) -> Result<()> {
+        self.prompt().show_message()
+    }
+
+    /// Drop the PIN cached by the service under `cache_key` (e.g. after it turned out to be wrong)
+    pub fn forget<S: Into<String>>(&self, cache_key: S) -> Result<()> {
+        self.proxy.call("Forget", &(cache_key.into(),)).map_err(client_error)
+    }
+}
+
+/// A single prompt made through a [`DbusClient`]
+///
+/// Settings made here override the defaults of the service for this prompt only.
+pub struct DbusPrompt<'a> {
+    client: &'a DbusClient,
+    options: HashMap<String, String>,
+}
+
+impl DbusPrompt<'_> {
+    /// Answer from (and store into) the service's cache under `key`, if caching is enabled there
+    pub fn cache_key<S: Into<String>>(self, key: S) -> Self {
+        self.option("cache-key", key.into())
+    }
+
+    /// Set the descriptive text of the prompt
+    pub fn description<S: Into<String>>(self, desc: S) -> Self {
+        self.option("description", desc.into())
+    }
+
+    /// Set the text that gets the displayed in case of error
+    pub fn error_text<S: Into<String>>(self, error_text: S) -> Self {
+        self.option("error-text", error_text.into())
+    }
+
+    /// Set the label of the 'Cancel' button
+    pub fn label_cancel<S: Into<String>>(self, label: S) -> Self {
+        self.option("label-cancel", label.into())
+    }
+
+    /// Set the label of the 'Not OK' button
+    pub fn label_notok<S: Into<String>>(self, label: S) -> Self {
+        self.option("label-notok", label.into())
+    }
+
+    /// Set the label of the 'OK' button
+    pub fn label_ok<S: Into<String>>(self, label: S) -> Self {
+        self.option("label-ok", label.into())
+    }
+
+    /// Set timeout for prompt (in seconds)
+    pub fn timeout(self, secs: u32) -> Self {
+        self.option("timeout", secs.to_string())
+    }
+
+    /// Set the window title of the prompt
+    pub fn window_title<S: Into<String>>(self, title: S) -> Self {
+        self.option("window-title", title.into())
+    }
+
+    /// Prompt for confirmation
+    ///
+    /// The text for the confirmation should be set using `.description()`
+    pub fn confirm_yes_no(self) -> Result<bool> {
+        self.client
+            .proxy
+            .call("Confirm", &(self.options,))
+            .map_err(client_error)
+    }
+
+    /// Prompt for a PIN
+    pub fn pin<S: Into<String>>(self, prompt: S) -> Result<SecretPin> {
+        let pin: Vec<u8> = self
+            .client
+            .proxy
+            .call("GetPin", &(prompt.into(), self.options))
+            .map_err(client_error)?;
+        Ok(SecretPin::new(pin))
+    }
+
+    /// Show a message
+    ///
+    /// The text for the message should be set using `.description()`
+    pub fn show_message(self) -> Result<()> {
+        self.client
+            .proxy
+            .call("ShowMessage", &(self.options,))
+            .map_err(client_error)
+    }
+
+    fn option(mut self, key: &str, value: String) -> Self {
+        self.options.insert(key.to_string(), value);
+        self
+    }
+}
+
+/// Apply prompt options received over D-Bus, ignoring the keys in `handled`
+fn apply_options<'a>(
+    mut prompt: SessionPrompt<'a>,
+    options: &HashMap<String, String>,
+    handled: &[&str],
+) -> fdo::Result<SessionPrompt<'a>> {
+    for (key, value) in options {
+        let value = value.clone();
+        prompt = match key.as_str() {
+            "description" => prompt.description(value),
+            "error-text" => prompt.error_text(value),
+            "label-cancel" => prompt.label_cancel(value),
+            "label-notok" => prompt.label_notok(value),
+            "label-ok" => prompt.label_ok(value),
+            "timeout" => match value.parse() {
+                Ok(secs) => prompt.timeout(secs),
+                Err(_) => return Err(fdo::Error::InvalidArgs(format!("invalid timeout: {}", value))),
+            },
+            "window-title" => prompt.window_title(value),
+            key if handled.contains(&key) => prompt,
+            key => return Err(fdo::Error::InvalidArgs(format!("unknown option: {}", key))),
+        };
+    }
+    Ok(prompt)
+}
+
+/// Errors returned by pinentry are passed on as their `ERR` line, so that clients can tell them apart
+fn service_error(e: Error) -> fdo::Error {
+    match e.assuan_error() {
+        Some(error) => fdo::Error::Failed(describe(&Line::Err(error))),
+        None => fdo::Error::Failed(e.to_string()),
+    }
+}
+
+fn dbus_error(e: zbus::Error) -> Error {
+    Error::IoError(io::Error::other(e))
+}
+
+/// Errors reported by the service are passed on as the errors of pinentry (or protocol errors), everything else is
+/// an I/O error
+fn client_error(e: zbus::Error) -> Error {
+    match e {
+        zbus::Error::MethodError(_, Some(description), _) => Error::from_response(description),
+        e => dbus_error(e),
+    }
+}
+
+#[cfg(all(test, unix, feature = "process"))]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::str;
+
+    use super::super::pinentry;
+    use super::super::test_util::FakePinentry;
+
+    /// A private bus, so the tests do not depend on (or disturb) a session bus
+    struct TestBus {
+        address: String,
+        daemon: Child,
+    }
+
+    impl TestBus {
+        /// Start `dbus-daemon`, if it is installed
+        fn start() -> Option<TestBus> {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address=1"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().expect("stdout is piped"))
+                .read_line(&mut address)
+                .ok()?;
+            Some(TestBus {
+                address: address.trim().to_string(),
+                daemon,
+            })
+        }
+
+        fn builder(&self) -> Builder<'static> {
+            Builder::address(self.address.as_str()).expect("bus address is valid")
+        }
+
+        fn client(&self) -> DbusClient {
+            let connection = self.builder().build().expect("client connects to the bus");
+            DbusClient::connect_on(&connection, DEFAULT_BUS_NAME).expect("client is created")
+        }
+    }
+
+    impl Drop for TestBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    #[test]
+    fn test_dbus_prompts_and_cache() {
+        let bus = match TestBus::start() {
+            Some(bus) => bus,
+            None => return eprintln!("dbus-daemon is not available, skipping"),
+        };
+        let fake = FakePinentry::new(&[("GETPIN", "echo 'D secret'; echo OK")]);
+        let session = pinentry().exe(fake.exe()).connect().expect("session is started");
+        let _service = PromptService::new(session)
+            .cache_ttl(Duration::from_secs(60))
+            .serve_on(bus.builder(), DEFAULT_BUS_NAME)
+            .expect("service is started");
+
+        let client = bus.client();
+        let first = client
+            .prompt()
+            .cache_key("imap")
+            .window_title("Mail")
+            .pin("Password:")
+            .expect("PIN is returned");
+        let second = bus
+            .client()
+            .prompt()
+            .cache_key("imap")
+            .pin("Password:")
+            .expect("PIN is returned from the cache");
+        assert_eq!("secret", str::from_utf8(first.unsecure()).unwrap());
+        assert_eq!(first, second);
+        assert!(client.confirm_yes_no().expect("confirmation is returned"));
+
+        assert_eq!(
+            vec!["SETTITLE Mail", "SETPROMPT Password:", "GETPIN", "RESET", "CONFIRM"],
+            fake.commands()
+        );
+    }
+
+    #[test]
+    fn test_dbus_errors() {
+        let bus = match TestBus::start() {
+            Some(bus) => bus,
+            None => return eprintln!("dbus-daemon is not available, skipping"),
+        };
+        let fake = FakePinentry::new(&[("GETPIN", "echo 'ERR 83886179 Operation cancelled'")]);
+        let session = pinentry().exe(fake.exe()).connect().expect("session is started");
+        let _service = PromptService::new(session)
+            .serve_on(bus.builder(), DEFAULT_BUS_NAME)
+            .expect("service is started");
+
+        let client = bus.client();
+        match client.pin("PIN:") {
+            Err(Error::Cancelled) => (),
+            x => panic!("unexpected result {:?}", x.map(|_| ())),
+        }
+        match client
+            .prompt()
+            .timeout(30)
+            .option("colour", "red".to_string())
+            .show_message()
+        {
+            Err(Error::ProtocolError(e)) => assert!(e.contains("unknown option: colour"), "{}", e),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,345 @@
+//! Finding out why prompting does not work
+//!
+//! [`diagnose()`] reports which pinentry executables are installed, whether they start and speak the protocol, and
+//! whether a display or terminal is available to show the prompt on - for a "troubleshoot prompting" screen in an
+//! application:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # #[cfg(feature = "process")]
+//! # fn run() {
+//! let report = pinentry_rs::diagnose();
+//! if report.selected().is_none() {
+//!     eprintln!("No working pinentry found:\n{}", report);
+//! }
+//! # }
+//! ```
+
+use std::env;
+use std::ffi::OsStr;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::result;
+
+use super::assuan::{read_line, AssuanCommand, AssuanError, Line};
+use super::transport::ProcessTransport;
+use super::{Error, Result};
+
+/// Name of the executable spawned by default (see [`PinentryBuilder::exe`](super::PinentryBuilder::exe))
+const DEFAULT_EXE: &str = "pinentry";
+
+/// Environment variables that tell pinentry where to show the prompt
+const DISPLAY_VARS: &[&str] = &["DISPLAY", "WAYLAND_DISPLAY", "GPG_TTY", "TERM"];
+
+/// What [`diagnose()`] found out
+#[derive(Debug, Clone)]
+pub struct Diagnostics {
+    /// The pinentry executables found in `PATH`, in the order they are found
+    pub binaries: Vec<BinaryReport>,
+    /// Values of the variables describing the display and terminal (`DISPLAY`, `WAYLAND_DISPLAY`, `GPG_TTY`,
+    /// `TERM`), `None` if unset
+    pub environment: Vec<(&'static str, Option<String>)>,
+    /// Whether the controlling terminal (`/dev/tty`) can be opened, e.g. for `pinentry-curses`
+    pub tty_available: bool,
+}
+
+impl Diagnostics {
+    /// The executable a default [`pinentry()`](super::pinentry) would run, if it works
+    pub fn selected(&self) -> Option<&BinaryReport> {
+        self.default_binary().filter(|binary| binary.is_working())
+    }
+
+    /// Whether a graphical prompt can be shown (a X11 or Wayland display is set)
+    pub fn display_available(&self) -> bool {
+        self.environment
+            .iter()
+            .any(|(name, value)| matches!(*name, "DISPLAY" | "WAYLAND_DISPLAY") && value.is_some())
+    }
+
+    fn default_binary(&self) -> Option<&BinaryReport> {
+        self.binaries
+            .iter()
+            .find(|binary| binary.path.file_name() == Some(OsStr::new(DEFAULT_EXE)))
+    }
+}
+
+impl Display for Diagnostics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Pinentry executables:")?;
+        if self.binaries.is_empty() {
+            writeln!(f, "  none found in PATH")?;
+        }
+        for binary in &self.binaries {
+            writeln!(f, "  {}", binary)?;
+        }
+        writeln!(f, "Environment:")?;
+        for (name, value) in &self.environment {
+            writeln!(f, "  {}={}", name, value.as_deref().unwrap_or("(unset)"))?;
+        }
+        writeln!(
+            f,
+            "  terminal: {}",
+            if self.tty_available {
+                "available"
+            } else {
+                "not available"
+            }
+        )?;
+        match (self.default_binary(), self.selected()) {
+            (_, Some(selected)) => write!(f, "Selected: {}", selected.path.display()),
+            (Some(default), None) => write!(f, "Selected: none ({} does not work)", default.path.display()),
+            (None, None) => write!(f, "Selected: none ({} not found in PATH)", DEFAULT_EXE),
+        }
+    }
+}
+
+/// A pinentry executable and how it responded
+#[derive(Debug, Clone)]
+pub struct BinaryReport {
+    /// Where the executable was found
+    pub path: PathBuf,
+    /// The file the path resolves to, if it is a link (e.g. `pinentry` to `pinentry-gnome3`)
+    pub target: Option<PathBuf>,
+    /// Whether it could be started
+    pub status: BinaryStatus,
+}
+
+impl BinaryReport {
+    /// Whether the executable started and greeted
+    pub fn is_working(&self) -> bool {
+        matches!(self.status, BinaryStatus::Ready { .. })
+    }
+}
+
+impl Display for BinaryReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some(ref target) = self.target {
+            write!(f, " -> {}", target.display())?;
+        }
+        match self.status {
+            BinaryStatus::Ready {
+                ref flavor,
+                ref version,
+            } => write!(
+                f,
+                ": ok (flavor {}, version {})",
+                flavor.as_deref().unwrap_or("unknown"),
+                version.as_deref().unwrap_or("unknown")
+            ),
+            BinaryStatus::SpawnFailed(ref e) => write!(f, ": cannot be started: {}", e),
+            BinaryStatus::NoGreeting(ref e) => write!(f, ": does not speak the pinentry protocol: {}", e),
+        }
+    }
+}
+
+/// How a pinentry executable responded to being started
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BinaryStatus {
+    /// It started and greeted, and reported its flavor and version (if it knows them)
+    Ready {
+        /// Flavor reported by `GETINFO flavor`
+        flavor: Option<String>,
+        /// Version reported by `GETINFO version`
+        version: Option<String>,
+    },
+    /// It could not be started
+    SpawnFailed(String),
+    /// It started, but did not greet (or answer) as pinentry would
+    NoGreeting(String),
+}
+
+/// Find out which pinentry executables are installed and whether they work
+///
+/// Every executable is started (without showing a prompt) and asked for its flavor and version.
+pub fn diagnose() -> Diagnostics {
+    let environment = DISPLAY_VARS.iter().map(|name| (*name, env::var(name).ok())).collect();
+    Diagnostics {
+        binaries: check_binaries(&env::var_os("PATH").unwrap_or_default()),
+        environment,
+        tty_available: tty_available(),
+    }
+}
+
+fn check_binaries(path: &OsStr) -> Vec<BinaryReport> {
+    find_pinentries(path)
+        .into_iter()
+        .map(|path| {
+            let target = fs::canonicalize(&path).ok().filter(|target| *target != path);
+            let status = check_binary(&path);
+            BinaryReport { path, target, status }
+        })
+        .collect()
+}
+
+fn check_binary(exe: &Path) -> BinaryStatus {
+    let mut probe = match Probe::start(exe) {
+        Ok(probe) => probe,
+        Err(Error::IoError(e)) => return BinaryStatus::SpawnFailed(e.to_string()),
+        Err(e) => return BinaryStatus::NoGreeting(e.to_string()),
+    };
+    match (probe.info("flavor"), probe.info("version")) {
+        (Ok(flavor), Ok(version)) => BinaryStatus::Ready { flavor, version },
+        (Err(e), _) | (_, Err(e)) => BinaryStatus::NoGreeting(e.to_string()),
+    }
+}
+
+#[cfg(unix)]
+fn tty_available() -> bool {
+    fs::OpenOptions::new().read(true).write(true).open("/dev/tty").is_ok()
+}
+
+#[cfg(not(unix))]
+fn tty_available() -> bool {
+    false
+}
+
+/// The pinentry executables (`pinentry` and `pinentry-*`) in the directories of `path` (formatted like `PATH`)
+pub(crate) fn find_pinentries(path: &OsStr) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    for dir in env::split_paths(path) {
+        let mut entries: Vec<_> = match fs::read_dir(&dir) {
+            Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+            Err(_) => continue,
+        };
+        entries.sort();
+        found.extend(entries.into_iter().filter(|exe| is_pinentry(exe) && is_executable(exe)));
+    }
+    found
+}
+
+fn is_pinentry(exe: &Path) -> bool {
+    match exe.file_name().and_then(|name| name.to_str()) {
+        // do not mistake our own daemon for a flavor
+        Some("pinentry-rs") => false,
+        Some(name) => name == "pinentry" || name.starts_with("pinentry-"),
+        None => false,
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn is_executable(exe: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::metadata(exe)
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_executable(exe: &Path) -> bool {
+    exe.is_file()
+}
+
+/// A reply to a single request: the data lines and the final `OK` or `ERR`
+pub(crate) struct Reply {
+    pub(crate) data: Vec<u8>,
+    pub(crate) result: result::Result<(), AssuanError>,
+}
+
+/// A raw conversation with pinentry, one request at a time
+///
+/// Unlike a session, replies may contain data (e.g. for `GETINFO`), which is why the probe is used for inspecting
+/// pinentry rather than for prompting.
+pub(crate) struct Probe {
+    stream: BufReader<ProcessTransport>,
+}
+
+impl Probe {
+    /// Spawn `exe` and wait for its greeting
+    pub(crate) fn start(exe: &Path) -> Result<Probe> {
+        let mut probe = Probe {
+            stream: BufReader::new(ProcessTransport::spawn(exe)?),
+        };
+        match probe.next_line()? {
+            Line::Ok(_) => Ok(probe),
+            line => Err(Error::ProtocolError(format!("unexpected greeting: {:?}", line))),
+        }
+    }
+
+    /// Send `cmd` and read the reply
+    pub(crate) fn request(&mut self, cmd: &AssuanCommand) -> Result<Reply> {
+        let mut buf = Vec::new();
+        cmd.to_line().encode(&mut buf)?;
+        let transport = self.stream.get_mut();
+        transport.write_all(&buf)?;
+        transport.flush()?;
+
+        let mut data = Vec::new();
+        loop {
+            match self.next_line()? {
+                Line::Ok(_) => return Ok(Reply { data, result: Ok(()) }),
+                Line::Err(e) => return Ok(Reply { data, result: Err(e) }),
+                Line::Data(chunk) => data.extend_from_slice(chunk.unsecure()),
+                // status lines and comments carry nothing needed here
+                Line::Status(_) | Line::Comment(_) => (),
+                line => return Err(Error::ProtocolError(format!("unexpected line: {:?}", line))),
+            }
+        }
+    }
+
+    /// Value of `GETINFO <what>`, or `None` if pinentry does not know it
+    pub(crate) fn info(&mut self, what: &str) -> Result<Option<String>> {
+        let reply = self.request(&AssuanCommand::GetInfo(what.to_string()))?;
+        Ok(match reply.result {
+            Ok(()) => Some(String::from_utf8_lossy(&reply.data).into_owned()),
+            Err(_) => None,
+        })
+    }
+
+    fn next_line(&mut self) -> Result<Line> {
+        read_line(&mut self.stream)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "pinentry closed the connection").into())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use std::ffi::OsString;
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::super::test_util::FakePinentry;
+
+    #[test]
+    fn test_check_binaries() {
+        let fake = FakePinentry::new(&[("'GETINFO flavor'", r#"echo "D fake"; echo OK"#)]);
+        let dir = Path::new(&fake.exe()).parent().unwrap().to_path_buf();
+        let broken = dir.join("pinentry-broken");
+        fs::write(&broken, "#!/bin/sh\necho 'ERR 1 no display'\n").unwrap();
+        fs::set_permissions(&broken, fs::Permissions::from_mode(0o755)).unwrap();
+        // not executable, so not a candidate
+        fs::write(dir.join("pinentry-notes.txt"), "").unwrap();
+
+        let mut path = OsString::from(&dir);
+        path.push(":/nonexistent");
+        let binaries = check_binaries(&path);
+        assert_eq!(
+            vec![dir.join("pinentry"), broken.clone()],
+            binaries.iter().map(|b| b.path.clone()).collect::<Vec<_>>()
+        );
+        assert!(matches!(binaries[1].status, BinaryStatus::NoGreeting(_)));
+        assert_eq!(
+            BinaryStatus::Ready {
+                flavor: Some("fake".to_string()),
+                version: Some("".to_string()),
+            },
+            binaries[0].status
+        );
+
+        let diagnostics = Diagnostics {
+            binaries,
+            environment: vec![("DISPLAY", None), ("WAYLAND_DISPLAY", Some("wayland-0".to_string()))],
+            tty_available: false,
+        };
+        assert!(diagnostics.display_available());
+        assert_eq!(Some(&dir.join("pinentry")), diagnostics.selected().map(|b| &b.path));
+        assert!(diagnostics
+            .to_string()
+            .ends_with(&format!("Selected: {}", dir.join("pinentry").display())));
+    }
+}
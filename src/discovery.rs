@@ -0,0 +1,240 @@
+//! Finding a pinentry that works
+//!
+//! The default executable, `pinentry`, does not exist everywhere: Homebrew only installs `pinentry-mac`, and
+//! minimal Linux installations often only have one of the flavors. [`discover()`] looks for a usable executable the
+//! way users configure them:
+//!
+//! 1. the `PINENTRY_BINARY` and `PINENTRY_PROGRAM` environment variables (a path, or a name looked up in `PATH`)
+//! 2. the `pinentry-program` line of gpg-agent's configuration (`$GNUPGHOME/gpg-agent.conf`, by default
+//!    `~/.gnupg/gpg-agent.conf`, or `%APPDATA%\gnupg\gpg-agent.conf` on Windows)
+//! 3. the flavors in [`FLAVORS`], then `pinentry`, in `PATH` (and the Homebrew directories, or the `bin`
+//!    directories of Gpg4win and GnuPG under `Program Files` on Windows)
+//!
+//! The first candidate that starts and greets is used:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use pinentry_rs::pinentry;
+//!
+//! let pin = pinentry().discover_exe()?.pin("PIN:")?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::diagnostics::{is_executable, Probe};
+use super::Result;
+
+/// The flavors tried if no pinentry is configured, in order of preference
+pub const FLAVORS: &[&str] = &[
+    "pinentry-mac",
+    "pinentry-gnome3",
+    "pinentry-qt",
+    "pinentry-curses",
+    "pinentry-tty",
+    "pinentry-w32",
+    "pinentry-basic",
+];
+
+/// Environment variables naming the pinentry to use
+const ENV_VARS: &[&str] = &["PINENTRY_BINARY", "PINENTRY_PROGRAM"];
+
+/// Directories searched in addition to `PATH`, where package managers install executables that may not be in the
+/// `PATH` of GUI applications
+const EXTRA_DIRS: &[&str] = &["/opt/homebrew/bin", "/usr/local/bin"];
+
+/// Where the Windows installers put pinentry, below `Program Files`
+const WINDOWS_DIRS: &[&str] = &["Gpg4win\\bin", "GnuPG\\bin"];
+
+/// Environment variables naming the `Program Files` directories on Windows
+const PROGRAM_FILES_VARS: &[&str] = &["ProgramFiles(x86)", "ProgramFiles"];
+
+/// Find a pinentry that starts and greets, see the [module documentation](self)
+///
+/// Fails with an [`io::ErrorKind::NotFound`] error listing the candidates tried if none works.
+pub fn discover() -> Result<PathBuf> {
+    let home = env::var_os("HOME").map(PathBuf::from);
+    let path = env::var_os("PATH").unwrap_or_default();
+    let candidates = candidates(|var| env::var_os(var), home.as_deref(), &path);
+    first_working(candidates)
+}
+
+/// The candidates, most preferred first (without duplicates)
+fn candidates<F: Fn(&str) -> Option<OsString>>(var: F, home: Option<&Path>, path: &OsStr) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = env::split_paths(path).collect();
+    dirs.extend(EXTRA_DIRS.iter().map(PathBuf::from));
+    for program_files in PROGRAM_FILES_VARS.iter().filter_map(|name| var(name)) {
+        dirs.extend(WINDOWS_DIRS.iter().map(|dir| Path::new(&program_files).join(dir)));
+    }
+
+    let mut candidates = Vec::new();
+    for name in ENV_VARS
+        .iter()
+        .filter_map(|name| var(name))
+        .filter(|name| !name.is_empty())
+    {
+        candidates.extend(resolve(Path::new(&name), &dirs));
+    }
+    let gnupg_home = var("GNUPGHOME")
+        .map(PathBuf::from)
+        .or_else(|| home.map(|home| home.join(".gnupg")))
+        .or_else(|| var("APPDATA").map(|dir| Path::new(&dir).join("gnupg")));
+    if let Some(program) = gnupg_home.and_then(|dir| agent_pinentry(&dir.join("gpg-agent.conf"))) {
+        candidates.extend(resolve(&program, &dirs));
+    }
+    for name in FLAVORS.iter().chain(&["pinentry"]) {
+        candidates.extend(resolve(Path::new(name), &dirs));
+    }
+
+    let mut unique = Vec::new();
+    for candidate in candidates {
+        if !unique.contains(&candidate) {
+            unique.push(candidate);
+        }
+    }
+    unique
+}
+
+/// The first of `candidates` that greets
+fn first_working(candidates: Vec<PathBuf>) -> Result<PathBuf> {
+    for candidate in &candidates {
+        match Probe::start(candidate) {
+            Ok(_) => return Ok(candidate.clone()),
+            Err(e) => debug!("{} does not work: {}", candidate.display(), e),
+        }
+    }
+    let tried: Vec<String> = candidates.iter().map(|c| c.display().to_string()).collect();
+    let tried = match tried.is_empty() {
+        true => "none found".to_string(),
+        false => format!("tried {}", tried.join(", ")),
+    };
+    Err(io::Error::new(io::ErrorKind::NotFound, format!("no working pinentry ({})", tried)).into())
+}
+
+/// `exe` if it is a path, otherwise the first executable named `exe` in `dirs`
+fn resolve(exe: &Path, dirs: &[PathBuf]) -> Option<PathBuf> {
+    if exe.components().count() > 1 {
+        return Some(exe.to_path_buf()).filter(|exe| is_executable(exe));
+    }
+    let mut name = exe.as_os_str().to_os_string();
+    name.push(env::consts::EXE_SUFFIX);
+    dirs.iter().map(|dir| dir.join(&name)).find(|exe| is_executable(exe))
+}
+
+/// The `pinentry-program` configured in the gpg-agent configuration file `conf`
+fn agent_pinentry(conf: &Path) -> Option<PathBuf> {
+    let conf = fs::read_to_string(conf).ok()?;
+    // the last line wins, as in gpg-agent
+    conf.lines()
+        .rev()
+        .map(str::trim)
+        .filter_map(|line| line.strip_prefix("pinentry-program"))
+        .filter(|rest| rest.starts_with([' ', '\t']))
+        .map(|rest| PathBuf::from(rest.trim()))
+        .next()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::super::test_util::FakePinentry;
+
+    fn executable(path: &Path, script: &str) {
+        fs::write(path, script).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn test_candidates() {
+        let fake = FakePinentry::new(&[]);
+        let dir = Path::new(&fake.exe()).parent().unwrap().to_path_buf();
+        let bin = dir.join("bin");
+        let gnupg = dir.join("home").join(".gnupg");
+        fs::create_dir_all(&bin).unwrap();
+        fs::create_dir_all(&gnupg).unwrap();
+        for name in &["pinentry-tty", "pinentry-qt", "pinentry-custom", "pinentry"] {
+            executable(&bin.join(name), "#!/bin/sh\n");
+        }
+        fs::write(
+            gnupg.join("gpg-agent.conf"),
+            format!(
+                "# pinentry-program /nonexistent\npinentry-programs are configured here\n  pinentry-program {}\n",
+                bin.join("pinentry-custom").display()
+            ),
+        )
+        .unwrap();
+
+        let env = |var: &str| match var {
+            "PINENTRY_PROGRAM" => Some(OsString::from("pinentry-tty")),
+            "PINENTRY_BINARY" => Some(OsString::from("/nonexistent/pinentry")),
+            _ => None,
+        };
+        assert_eq!(
+            vec![
+                bin.join("pinentry-tty"),
+                bin.join("pinentry-custom"),
+                bin.join("pinentry-qt"),
+                bin.join("pinentry"),
+            ],
+            candidates(env, Some(&dir.join("home")), bin.as_os_str())
+        );
+        assert_eq!(None, agent_pinentry(&dir.join("nonexistent.conf")));
+
+        // the installation directories and the configuration directory on Windows
+        let program_files = dir.join("Program Files");
+        let gpg4win = program_files.join(WINDOWS_DIRS[0]);
+        let appdata = dir.join("AppData");
+        fs::create_dir_all(&gpg4win).unwrap();
+        fs::create_dir_all(appdata.join("gnupg")).unwrap();
+        executable(&gpg4win.join("pinentry-w32"), "#!/bin/sh\n");
+        fs::write(
+            appdata.join("gnupg").join("gpg-agent.conf"),
+            format!("pinentry-program {}\n", bin.join("pinentry-custom").display()),
+        )
+        .unwrap();
+        let env = |var: &str| match var {
+            "ProgramFiles" => Some(program_files.clone().into_os_string()),
+            "APPDATA" => Some(appdata.clone().into_os_string()),
+            _ => None,
+        };
+        assert_eq!(
+            vec![
+                bin.join("pinentry-custom"),
+                bin.join("pinentry-qt"),
+                bin.join("pinentry-tty"),
+                gpg4win.join("pinentry-w32"),
+                bin.join("pinentry"),
+            ],
+            candidates(env, None, bin.as_os_str())
+        );
+    }
+
+    #[test]
+    fn test_first_working() {
+        let fake = FakePinentry::new(&[]);
+        let dir = Path::new(&fake.exe()).parent().unwrap().to_path_buf();
+        let broken = dir.join("pinentry-broken");
+        executable(&broken, "#!/bin/sh\necho 'ERR 1 no display'\n");
+
+        assert_eq!(
+            PathBuf::from(fake.exe()),
+            first_working(vec![broken.clone(), PathBuf::from(fake.exe())]).unwrap()
+        );
+        match first_working(vec![broken]) {
+            Err(super::super::Error::IoError(e)) => {
+                assert_eq!(io::ErrorKind::NotFound, e.kind());
+                assert!(e.to_string().contains("pinentry-broken"));
+            }
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+}
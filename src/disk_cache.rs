@@ -0,0 +1,405 @@
+//! An encrypted on-disk cache of passphrases, for "remember for 8 hours" across process restarts
+//!
+//! Every entry is encrypted (XChaCha20-Poly1305) under a key that never touches the disk: it comes from a
+//! [`KeySource`], e.g. derived from a master passphrase ([`MasterPassphrase`]) or fetched from the OS keyring by
+//! an implementation of the trait. Entries expire after their own TTL, and the expiry time is authenticated, so it
+//! cannot be extended by editing the file.
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # #[cfg(feature = "process")]
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use std::time::Duration;
+//!
+//! use pinentry_rs::disk_cache::{DiskCache, MasterPassphrase};
+//! use pinentry_rs::pinentry;
+//!
+//! let mut session = pinentry().connect()?;
+//! let master = MasterPassphrase::new(session.pin("Master passphrase:")?);
+//! let mut cache = DiskCache::open("/home/me/.cache/app/passphrases", &master)?;
+//!
+//! let pin = session.pin_cached(&mut cache, "imap", "IMAP password:", Duration::from_secs(8 * 3600))?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+
+use super::kdf::{KdfParams, SecretKey};
+use super::session::PinentrySession;
+use super::{Error, Result, SecretPin};
+
+const VERSION: u32 = 1;
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+// encrypted in place of an entry to detect a wrong key when the cache is opened
+const CHECK_NAME: &str = "\0check";
+
+/// Provides the key the cache is encrypted with
+pub trait KeySource {
+    /// Return the 32-byte key for a cache with the given `salt` (which is random and stored in the cache file)
+    fn key(&self, salt: &[u8]) -> Result<SecretKey>;
+}
+
+/// Derives the cache key from a master passphrase (using Argon2id)
+pub struct MasterPassphrase {
+    passphrase: SecretPin,
+    memory_kib: u32,
+    iterations: u32,
+}
+
+impl MasterPassphrase {
+    /// Use `passphrase` with the parameters of [`KdfParams::argon2id`]
+    pub fn new(passphrase: SecretPin) -> Self {
+        MasterPassphrase {
+            passphrase,
+            memory_kib: 19 * 1024,
+            iterations: 2,
+        }
+    }
+
+    /// Override the Argon2id cost parameters (they have to stay the same for the lifetime of the cache)
+    pub fn cost(mut self, memory_kib: u32, iterations: u32) -> Self {
+        self.memory_kib = memory_kib;
+        self.iterations = iterations;
+        self
+    }
+}
+
+impl KeySource for MasterPassphrase {
+    fn key(&self, salt: &[u8]) -> Result<SecretKey> {
+        let kdf = KdfParams::Argon2id {
+            salt: salt.to_vec(),
+            memory_kib: self.memory_kib,
+            iterations: self.iterations,
+            parallelism: 1,
+            key_len: KEY_LEN,
+        };
+        kdf.derive(self.passphrase.unsecure())
+    }
+}
+
+impl KeySource for SecretKey {
+    fn key(&self, _salt: &[u8]) -> Result<SecretKey> {
+        Ok(self.clone())
+    }
+}
+
+/// Passphrases cached in an encrypted file
+pub struct DiskCache {
+    cipher: XChaCha20Poly1305,
+    file: CacheFile,
+    path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    version: u32,
+    salt: String,
+    check: Entry,
+    entries: HashMap<String, Entry>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Entry {
+    // seconds since the epoch
+    expires: u64,
+    nonce: String,
+    ciphertext: String,
+}
+
+impl DiskCache {
+    /// Open the cache at `path` (creating it if it does not exist), encrypted with the key from `keys`
+    ///
+    /// Fails with [`Error::CacheError`] if the key is not the one the cache was created with.
+    pub fn open<P: AsRef<Path>, K: KeySource + ?Sized>(path: P, keys: &K) -> Result<DiskCache> {
+        let path = path.as_ref().to_path_buf();
+        let existing = match fs::read(&path) {
+            Ok(contents) => Some(serde_json::from_slice::<CacheFile>(&contents).map_err(cache_error)?),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        match existing {
+            Some(file) => {
+                if file.version != VERSION {
+                    return Err(Error::CacheError(format!("unsupported cache version {}", file.version)));
+                }
+                let cipher = cipher(keys, &from_hex(&file.salt)?)?;
+                let cache = DiskCache { cipher, file, path };
+                let check = cache.file.check.clone();
+                cache
+                    .decrypt(CHECK_NAME, &check)
+                    .map_err(|_| Error::CacheError("wrong key for the passphrase cache".to_string()))?;
+                Ok(cache)
+            }
+            None => {
+                let mut salt = [0; SALT_LEN];
+                OsRng.fill_bytes(&mut salt);
+                let cipher = cipher(keys, &salt)?;
+                let mut cache = DiskCache {
+                    cipher,
+                    file: CacheFile {
+                        version: VERSION,
+                        salt: to_hex(&salt),
+                        check: Entry {
+                            expires: 0,
+                            nonce: String::new(),
+                            ciphertext: String::new(),
+                        },
+                        entries: HashMap::new(),
+                    },
+                    path,
+                };
+                cache.file.check = cache.encrypt(CHECK_NAME, b"", 0)?;
+                cache.save()?;
+                Ok(cache)
+            }
+        }
+    }
+
+    /// Look up the passphrase cached under `name`, unless it has expired
+    pub fn get(&mut self, name: &str) -> Result<Option<SecretPin>> {
+        if self.purge_expired() {
+            self.save()?;
+        }
+        match self.file.entries.get(name) {
+            Some(entry) => self.decrypt(name, entry).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Cache `secret` under `name` for `ttl`
+    pub fn put(&mut self, name: &str, secret: &[u8], ttl: Duration) -> Result<()> {
+        let expires = now().saturating_add(ttl.as_secs());
+        let entry = self.encrypt(name, secret, expires)?;
+        self.file.entries.insert(name.to_string(), entry);
+        self.purge_expired();
+        self.save()
+    }
+
+    /// Forget the passphrase cached under `name`
+    pub fn remove(&mut self, name: &str) -> Result<()> {
+        if self.file.entries.remove(name).is_some() {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Forget all cached passphrases
+    pub fn clear(&mut self) -> Result<()> {
+        self.file.entries.clear();
+        self.save()
+    }
+
+    fn purge_expired(&mut self) -> bool {
+        let now = now();
+        let before = self.file.entries.len();
+        self.file.entries.retain(|_, entry| entry.expires > now);
+        self.file.entries.len() != before
+    }
+
+    fn encrypt(&self, name: &str, secret: &[u8], expires: u64) -> Result<Entry> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = associated_data(name, expires);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, Payload { msg: secret, aad: &aad })
+            .map_err(|_| Error::CacheError("encryption failed".to_string()))?;
+        Ok(Entry {
+            expires,
+            nonce: to_hex(&nonce),
+            ciphertext: to_hex(&ciphertext),
+        })
+    }
+
+    fn decrypt(&self, name: &str, entry: &Entry) -> Result<SecretPin> {
+        let nonce = from_hex(&entry.nonce)?;
+        if nonce.len() != 24 {
+            return Err(Error::CacheError("invalid nonce".to_string()));
+        }
+        let ciphertext = from_hex(&entry.ciphertext)?;
+        let aad = associated_data(name, entry.expires);
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map(SecretPin::new)
+            .map_err(|_| Error::CacheError(format!("entry {} cannot be decrypted", name)))
+    }
+
+    /// Write the cache atomically, readable by the owner only
+    fn save(&self) -> Result<()> {
+        let contents = serde_json::to_vec(&self.file).map_err(cache_error)?;
+        let tmp = self.path.with_extension("tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&tmp)?;
+        file.write_all(&contents)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+impl PinentrySession {
+    /// Return the passphrase cached under `name`, or prompt for it and cache it for `ttl`
+    pub fn pin_cached<S: Into<String>>(
+        &mut self,
+        cache: &mut DiskCache,
+        name: &str,
+        prompt: S,
+        ttl: Duration,
+    ) -> Result<SecretPin> {
+        if let Some(pin) = cache.get(name)? {
+            return Ok(pin);
+        }
+        let pin = self.pin(prompt)?;
+        cache.put(name, pin.unsecure(), ttl)?;
+        Ok(pin)
+    }
+}
+
+fn cipher<K: KeySource + ?Sized>(keys: &K, salt: &[u8]) -> Result<XChaCha20Poly1305> {
+    let key = keys.key(salt)?;
+    if key.unsecure().len() != KEY_LEN {
+        return Err(Error::CacheError(format!(
+            "the cache key must be {} bytes long",
+            KEY_LEN
+        )));
+    }
+    Ok(XChaCha20Poly1305::new(Key::from_slice(key.unsecure())))
+}
+
+/// Binds an entry to its name and expiry time
+fn associated_data(name: &str, expires: u64) -> Vec<u8> {
+    let mut aad = expires.to_be_bytes().to_vec();
+    aad.extend_from_slice(name.as_bytes());
+    aad
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    let invalid = || Error::CacheError("invalid hex string".to_string());
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [hi, lo] => Ok(hex_digit(*hi).ok_or_else(invalid)? << 4 | hex_digit(*lo).ok_or_else(invalid)?),
+            _ => Err(invalid()),
+        })
+        .collect()
+}
+
+fn hex_digit(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|d| d as u8)
+}
+
+fn cache_error(e: serde_json::Error) -> Error {
+    Error::CacheError(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    /// A cache file in the temporary directory, removed when dropped
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new() -> Self {
+            TempPath(std::env::temp_dir().join(format!(
+                "pinentry-rs-cache-{}-{}",
+                process::id(),
+                COUNTER.fetch_add(1, Ordering::SeqCst)
+            )))
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn master(passphrase: &str) -> MasterPassphrase {
+        // cheap parameters to keep the tests fast
+        MasterPassphrase::new(SecretPin::from(passphrase)).cost(64, 1)
+    }
+
+    #[test]
+    fn test_disk_cache_roundtrip() {
+        let path = TempPath::new();
+        let mut cache = DiskCache::open(&path.0, &master("master")).expect("cache is created");
+        cache.put("imap", b"secret", Duration::from_secs(3600)).unwrap();
+        cache.put("smtp", b"other", Duration::from_secs(3600)).unwrap();
+        drop(cache);
+
+        let contents = fs::read_to_string(&path.0).unwrap();
+        assert!(!contents.contains(&to_hex(b"secret")));
+
+        let mut cache = DiskCache::open(&path.0, &master("master")).expect("cache is opened");
+        assert_eq!(Some(SecretPin::from("secret")), cache.get("imap").unwrap());
+        cache.remove("imap").unwrap();
+        assert_eq!(None, cache.get("imap").unwrap());
+        assert_eq!(Some(SecretPin::from("other")), cache.get("smtp").unwrap());
+    }
+
+    #[test]
+    fn test_disk_cache_wrong_key() {
+        let path = TempPath::new();
+        DiskCache::open(&path.0, &master("master")).expect("cache is created");
+        match DiskCache::open(&path.0, &master("guess")) {
+            Err(Error::CacheError(e)) => assert_eq!("wrong key for the passphrase cache", e),
+            x => panic!("unexpected result {:?}", x.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_disk_cache_expiry_is_authenticated() {
+        let path = TempPath::new();
+        let key = SecretKey::new(SecretPin::new(vec![7; KEY_LEN]));
+        let mut cache = DiskCache::open(&path.0, &key).expect("cache is created");
+        cache.put("expired", b"secret", Duration::from_secs(0)).unwrap();
+        assert_eq!(None, cache.get("expired").unwrap());
+
+        // extending the expiry time of an entry by editing the file breaks it
+        cache.put("imap", b"secret", Duration::from_secs(60)).unwrap();
+        let entry = cache.file.entries.get_mut("imap").unwrap();
+        entry.expires += 3600;
+        assert!(matches!(cache.get("imap"), Err(Error::CacheError(_))));
+    }
+}
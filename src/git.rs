@@ -0,0 +1,275 @@
+//! Credentials for [`git2`](https://crates.io/crates/git2) remotes
+//!
+//! [`callbacks()`] returns `RemoteCallbacks` that ask for usernames, passwords and SSH key passphrases through
+//! pinentry:
+//!
+//! ```no_run
+//! # extern crate git2;
+//! # extern crate pinentry_rs;
+//! # fn run(repo: &git2::Repository) -> Result<(), git2::Error> {
+//! let mut options = git2::FetchOptions::new();
+//! options.remote_callbacks(pinentry_rs::git::callbacks(pinentry_rs::pinentry()));
+//! repo.find_remote("origin")?.fetch(&["main"], Some(&mut options), None)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Credentials are remembered per URL for as long as the [`GitCredentials`] they were asked for (and its clones)
+//! live, so a tool running several operations keeps one of them and asks it for the callbacks of each operation.
+//! If a remote rejects remembered credentials, they are forgotten and asked for again - up to three times per
+//! operation.
+
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::{Arc, Mutex};
+
+use git2::{Cred, CredentialType, RemoteCallbacks};
+
+use super::messages::Message;
+use super::ssh::SshKey;
+use super::{Error, PinentryBuilder, Result, SecretPin};
+
+/// Number of times credentials are asked for per URL and operation
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Callbacks for a single operation, asking with the settings of `builder`
+pub fn callbacks(builder: PinentryBuilder) -> RemoteCallbacks<'static> {
+    GitCredentials::new(builder).callbacks()
+}
+
+/// Credentials remembered for a URL
+#[derive(Clone)]
+struct Remembered {
+    username: String,
+    secret: SecretPin,
+}
+
+/// Asks for the credentials of git remotes and remembers them per URL, see the [module documentation](self)
+#[derive(Clone)]
+pub struct GitCredentials {
+    builder: PinentryBuilder,
+    ssh_key: Option<PathBuf>,
+    remembered: Arc<Mutex<HashMap<String, Remembered>>>,
+}
+
+impl GitCredentials {
+    /// Ask with the settings of `builder` (the description is set for each prompt)
+    pub fn new(builder: PinentryBuilder) -> Self {
+        GitCredentials {
+            builder,
+            ssh_key: None,
+            remembered: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Use the SSH key at `path` (by default `~/.ssh/id_ed25519`, or `~/.ssh/id_rsa` if that does not exist)
+    pub fn ssh_key<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.ssh_key = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Callbacks for a single operation
+    pub fn callbacks(&self) -> RemoteCallbacks<'static> {
+        let credentials = self.clone();
+        let mut attempts = HashMap::new();
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.credentials(move |url, username, allowed| credentials.attempt(&mut attempts, url, username, allowed));
+        callbacks
+    }
+
+    /// Forget the credentials remembered for `url`
+    pub fn forget(&self, url: &str) {
+        self.remembered.lock().expect("lock is not poisoned").remove(url);
+    }
+
+    /// The credentials for the next attempt at `url`, counting the attempts of the operation in `attempts`
+    fn attempt(
+        &self,
+        attempts: &mut HashMap<String, u32>,
+        url: &str,
+        username: Option<&str>,
+        allowed: CredentialType,
+    ) -> std::result::Result<Cred, git2::Error> {
+        // a username is not what the remote rejects, so asking for it does not use up an attempt
+        if allowed.contains(CredentialType::USERNAME) {
+            return self.credentials(url, username, allowed, false);
+        }
+        let attempt = attempts.entry(url.to_string()).or_insert(0);
+        *attempt += 1;
+        if *attempt > MAX_ATTEMPTS {
+            return Err(git2::Error::from_str("authentication failed"));
+        }
+        self.credentials(url, username, allowed, *attempt > 1)
+    }
+
+    /// The credentials for `url`, asking for them unless they are remembered (and have not just been rejected)
+    fn credentials(
+        &self,
+        url: &str,
+        username: Option<&str>,
+        allowed: CredentialType,
+        rejected: bool,
+    ) -> std::result::Result<Cred, git2::Error> {
+        if rejected {
+            self.forget(url);
+        }
+        let error_text = if rejected {
+            Some(Message::GitAuthFailed.text())
+        } else {
+            None
+        };
+        let error_text = error_text.as_deref();
+
+        if allowed.contains(CredentialType::USERNAME) {
+            let username = match username {
+                Some(username) => username.to_string(),
+                None => self.ask_username(url, None).map_err(to_git_error)?,
+            };
+            return Cred::username(&username);
+        }
+        if allowed.contains(CredentialType::SSH_KEY) {
+            let key = self.ssh_key.clone().unwrap_or_else(default_ssh_key);
+            let remembered = self.remember(url, |this| {
+                let username = match username {
+                    Some(username) => username.to_string(),
+                    None => this.ask_username(url, None)?,
+                };
+                let prompt = this.prompt(error_text).description(SshKey::new(&key).passphrase_text());
+                Ok(Remembered {
+                    username,
+                    secret: prompt.pin(Message::PassphrasePrompt.text())?,
+                })
+            })?;
+            return Cred::ssh_key(&remembered.username, None, &key, Some(utf8(&remembered.secret)?));
+        }
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            let remembered = self.remember(url, |this| {
+                let username = match username {
+                    Some(username) => username.to_string(),
+                    None => this.ask_username(url, error_text)?,
+                };
+                let prompt = this
+                    .prompt(error_text)
+                    .description(Message::GitPassword { user: &username, url }.text());
+                Ok(Remembered {
+                    username,
+                    secret: prompt.pin(Message::PasswordPrompt.text())?,
+                })
+            })?;
+            return Cred::userpass_plaintext(&remembered.username, utf8(&remembered.secret)?);
+        }
+        Err(git2::Error::from_str("no supported credential type"))
+    }
+
+    /// The credentials remembered for `url`, or the ones asked for by `ask` (which are remembered then)
+    fn remember<F>(&self, url: &str, ask: F) -> std::result::Result<Remembered, git2::Error>
+    where
+        F: FnOnce(&Self) -> Result<Remembered>,
+    {
+        if let Some(remembered) = self.remembered.lock().expect("lock is not poisoned").get(url) {
+            return Ok(remembered.clone());
+        }
+        let remembered = ask(self).map_err(to_git_error)?;
+        self.remembered
+            .lock()
+            .expect("lock is not poisoned")
+            .insert(url.to_string(), remembered.clone());
+        Ok(remembered)
+    }
+
+    fn ask_username(&self, url: &str, error_text: Option<&str>) -> Result<String> {
+        let username = self
+            .prompt(error_text)
+            .description(Message::GitUsername { url }.text())
+            .pin(Message::UsernamePrompt.text())?;
+        Ok(String::from_utf8_lossy(username.unsecure()).into_owned())
+    }
+
+    fn prompt(&self, error_text: Option<&str>) -> PinentryBuilder {
+        match error_text {
+            Some(text) => self.builder.clone().error_text(text.to_string()),
+            None => self.builder.clone(),
+        }
+    }
+}
+
+fn default_ssh_key() -> PathBuf {
+    let dir = env::var_os("HOME").map(PathBuf::from).unwrap_or_default().join(".ssh");
+    let ed25519 = dir.join("id_ed25519");
+    if ed25519.exists() {
+        ed25519
+    } else {
+        dir.join("id_rsa")
+    }
+}
+
+fn to_git_error(e: Error) -> git2::Error {
+    git2::Error::from_str(&e.to_string())
+}
+
+fn utf8(secret: &SecretPin) -> std::result::Result<&str, git2::Error> {
+    str::from_utf8(secret.unsecure()).map_err(|_| git2::Error::from_str("the credentials are not valid UTF-8"))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use super::super::pinentry;
+    use super::super::test_util::FakePinentry;
+
+    #[test]
+    fn test_credentials() {
+        let fake = FakePinentry::new(&[("GETPIN", r#"echo "D secret"; echo OK"#)]);
+        let credentials = GitCredentials::new(pinentry().exe(fake.exe()));
+        let url = "https://example.com/repo.git";
+        let userpass = CredentialType::USER_PASS_PLAINTEXT;
+
+        let cred = credentials.credentials(url, Some("user"), userpass, false).unwrap();
+        assert_eq!(userpass.bits(), cred.credtype());
+        // remembered for the next operation
+        credentials.credentials(url, Some("user"), userpass, false).unwrap();
+        assert_eq!(1, fake.commands().iter().filter(|c| *c == "GETPIN").count());
+        // but asked for again once rejected
+        credentials.credentials(url, Some("user"), userpass, true).unwrap();
+        assert_eq!(2, fake.commands().iter().filter(|c| *c == "GETPIN").count());
+
+        let commands = fake.commands();
+        assert!(commands.contains(&format!("SETDESC Password for user at {}", url)));
+        assert!(commands.contains(&"SETERROR Authentication failed, please try again".to_string()));
+    }
+
+    #[test]
+    fn test_attempts_after_username() {
+        let fake = FakePinentry::new(&[("GETPIN", r#"echo "D secret"; echo OK"#)]);
+        let credentials = GitCredentials::new(pinentry().exe(fake.exe())).ssh_key("/nonexistent/id_ed25519");
+        let url = "ssh://git@example.com/repo.git";
+        let mut attempts = HashMap::new();
+        let getpins = || fake.commands().iter().filter(|c| *c == "GETPIN").count();
+
+        // libgit2 asks for the username before the key of an SSH remote
+        credentials
+            .attempt(&mut attempts, url, Some("git"), CredentialType::USERNAME)
+            .unwrap();
+        credentials
+            .attempt(&mut attempts, url, Some("git"), CredentialType::SSH_KEY)
+            .unwrap();
+        assert_eq!(1, getpins());
+        assert!(!fake.commands().iter().any(|c| c.starts_with("SETERROR")));
+        assert!(credentials.remembered.lock().unwrap().contains_key(url));
+
+        // asking for the key again means it was rejected, up to MAX_ATTEMPTS keys per operation
+        for _ in 1..MAX_ATTEMPTS {
+            credentials
+                .attempt(&mut attempts, url, Some("git"), CredentialType::SSH_KEY)
+                .unwrap();
+        }
+        assert_eq!(MAX_ATTEMPTS as usize, getpins());
+        assert!(fake.commands().iter().any(|c| c.starts_with("SETERROR")));
+        assert!(credentials
+            .attempt(&mut attempts, url, Some("git"), CredentialType::SSH_KEY)
+            .is_err());
+    }
+}
@@ -0,0 +1,109 @@
+//! Keeping secrets out of core dumps (with the `hardening` feature)
+//!
+//! A process that crashes while holding a passphrase writes it to disk with the rest of its memory, where it may
+//! end up in crash reports. [`disable_core_dumps()`] turns core dumps off for the calling process (and the processes
+//! it starts), [`exclude_from_core_dumps()`] keeps single buffers out of them while leaving core dumps enabled
+//! otherwise (Linux only).
+//!
+//! With [`PinentryBuilder::disable_core_dumps`](super::PinentryBuilder::disable_core_dumps), the pinentry processes
+//! of the sessions started from the builder cannot dump core, and the PINs they return are excluded from core dumps
+//! of the calling process:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # #[cfg(feature = "process")]
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use pinentry_rs::pinentry;
+//!
+//! let pin = pinentry().disable_core_dumps(true).pin("PIN:")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The buffers the protocol is read through are not covered; call [`disable_core_dumps()`] for complete protection.
+
+use std::io;
+#[cfg(feature = "process")]
+use std::os::unix::process::CommandExt;
+#[cfg(feature = "process")]
+use std::process::Command;
+
+/// Disable core dumps of the calling process, and of the processes it starts from now on
+///
+/// Both limits are lowered, so the setting cannot be undone by the process (or its children) without privileges.
+pub fn disable_core_dumps() -> io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: setrlimit only reads the structure passed to it
+    match unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Leave the memory pages of `buf` out of core dumps of the calling process (Linux only, elsewhere this does nothing)
+///
+/// The whole pages containing `buf` are excluded, including other data on them. The exclusion stays in place once the
+/// buffer is freed, until the memory is returned to the system.
+pub fn exclude_from_core_dumps(buf: &[u8]) -> io::Result<()> {
+    if buf.is_empty() {
+        return Ok(());
+    }
+    exclude_pages(buf)
+}
+
+#[cfg(target_os = "linux")]
+fn exclude_pages(buf: &[u8]) -> io::Result<()> {
+    // SAFETY: sysconf has no memory effects
+    let page = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => return Err(io::Error::last_os_error()),
+    };
+    let start = buf.as_ptr() as usize & !(page - 1);
+    let end = buf.as_ptr() as usize + buf.len();
+    // SAFETY: MADV_DONTDUMP only changes how the pages are dumped, not their contents or mapping
+    match unsafe { libc::madvise(start as *mut libc::c_void, end - start, libc::MADV_DONTDUMP) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn exclude_pages(_: &[u8]) -> io::Result<()> {
+    Ok(())
+}
+
+/// Start `cmd` with core dumps disabled (for the started process only)
+#[cfg(feature = "process")]
+pub fn without_core_dumps(mut cmd: Command) -> Command {
+    // SAFETY: the closure only calls setrlimit, which is async-signal-safe
+    unsafe {
+        cmd.pre_exec(disable_core_dumps);
+    }
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exclude_from_core_dumps() {
+        let secret = vec![7u8; 10000];
+        exclude_from_core_dumps(&secret).unwrap();
+        exclude_from_core_dumps(&secret[4097..4098]).unwrap();
+        exclude_from_core_dumps(&[]).unwrap();
+    }
+
+    #[cfg(feature = "process")]
+    #[test]
+    fn test_without_core_dumps() {
+        let output = without_core_dumps(Command::new("sh"))
+            .args(["-c", "ulimit -c"])
+            .output()
+            .unwrap();
+        assert_eq!("0", String::from_utf8_lossy(&output.stdout).trim());
+    }
+}
@@ -0,0 +1,170 @@
+//! Deriving keys from passphrases without handing the passphrase to the caller
+//!
+//! For applications that only ever need a key derived from the passphrase (e.g. to decrypt a vault), the
+//! `pin_derived` methods run the KDF inside the library and zeroize the passphrase straight away:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # #[cfg(feature = "process")]
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use pinentry_rs::kdf::KdfParams;
+//! use pinentry_rs::pinentry;
+//!
+//! let salt = b"per-vault random salt".to_vec();
+//! let key = pinentry().pin_derived("Vault passphrase:", &KdfParams::argon2id(salt))?;
+//! # Ok(())
+//! # }
+//! ```
+
+use argon2::{Algorithm, Argon2, Params, Version};
+
+use super::session::{PinentrySession, SessionPrompt};
+#[cfg(feature = "process")]
+use super::PinentryBuilder;
+use super::{Error, Result, SecretPin};
+
+/// Key derivation function and its parameters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KdfParams {
+    /// Argon2id (version 0x13)
+    Argon2id {
+        /// Salt, at least 8 bytes (should be random and unique per key)
+        salt: Vec<u8>,
+        /// Memory size in KiB
+        memory_kib: u32,
+        /// Number of passes
+        iterations: u32,
+        /// Degree of parallelism
+        parallelism: u32,
+        /// Length of the derived key in bytes
+        key_len: usize,
+    },
+}
+
+impl KdfParams {
+    /// Argon2id with the parameters recommended by OWASP (19 MiB, 2 passes, 1 lane) and a 32-byte key
+    pub fn argon2id(salt: Vec<u8>) -> Self {
+        KdfParams::Argon2id {
+            salt,
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+            key_len: 32,
+        }
+    }
+
+    /// Derive a key from `passphrase`
+    pub fn derive(&self, passphrase: &[u8]) -> Result<SecretKey> {
+        match self {
+            KdfParams::Argon2id {
+                salt,
+                memory_kib,
+                iterations,
+                parallelism,
+                key_len,
+            } => {
+                let params = Params::new(*memory_kib, *iterations, *parallelism, Some(*key_len)).map_err(kdf_error)?;
+                let mut key = vec![0; *key_len];
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(passphrase, salt, &mut key)
+                    .map_err(kdf_error)?;
+                Ok(SecretKey(SecretPin::new(key)))
+            }
+        }
+    }
+}
+
+/// A key derived from a passphrase, zeroized when dropped
+#[derive(Debug, Clone, PartialEq)]
+pub struct SecretKey(SecretPin);
+
+impl SecretKey {
+    /// Wrap a key obtained elsewhere (e.g. from the OS keyring)
+    pub fn new(key: SecretPin) -> Self {
+        SecretKey(key)
+    }
+
+    /// Borrow the key (unsecure - the returned slice must not be copied around)
+    pub fn unsecure(&self) -> &[u8] {
+        self.0.unsecure()
+    }
+}
+
+#[cfg(feature = "process")]
+impl PinentryBuilder {
+    /// Prompt for a passphrase and return the key derived from it
+    ///
+    /// The passphrase itself is zeroized as soon as the key has been derived.
+    pub fn pin_derived<S: Into<String>>(self, prompt: S, kdf: &KdfParams) -> Result<SecretKey> {
+        self.pin_and_use(prompt, |passphrase| kdf.derive(passphrase))
+    }
+}
+
+impl PinentrySession {
+    /// Prompt for a passphrase and return the key derived from it
+    ///
+    /// The passphrase itself is zeroized as soon as the key has been derived.
+    pub fn pin_derived<S: Into<String>>(&mut self, prompt: S, kdf: &KdfParams) -> Result<SecretKey> {
+        self.pin_and_use(prompt, |passphrase| kdf.derive(passphrase))
+    }
+}
+
+impl SessionPrompt<'_> {
+    /// Prompt for a passphrase and return the key derived from it
+    ///
+    /// The passphrase itself is zeroized as soon as the key has been derived.
+    pub fn pin_derived<S: Into<String>>(self, prompt: S, kdf: &KdfParams) -> Result<SecretKey> {
+        self.pin_and_use(prompt, |passphrase| kdf.derive(passphrase))
+    }
+}
+
+fn kdf_error(e: argon2::Error) -> Error {
+    Error::KdfError(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(salt: &[u8]) -> KdfParams {
+        // cheap parameters to keep the tests fast
+        KdfParams::Argon2id {
+            salt: salt.to_vec(),
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+            key_len: 16,
+        }
+    }
+
+    #[test]
+    fn test_derive() {
+        let key = params(b"saltsalt").derive(b"passphrase").expect("key is derived");
+        assert_eq!(16, key.unsecure().len());
+        assert_eq!(key, params(b"saltsalt").derive(b"passphrase").unwrap());
+        assert_ne!(key, params(b"saltsalt").derive(b"Passphrase").unwrap());
+        assert_ne!(key, params(b"pepperpepper").derive(b"passphrase").unwrap());
+    }
+
+    #[test]
+    fn test_derive_invalid_params() {
+        match params(b"salt").derive(b"passphrase") {
+            Err(Error::KdfError(_)) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[cfg(all(unix, feature = "process"))]
+    #[test]
+    fn test_session_pin_derived() {
+        use super::super::pinentry;
+        use super::super::test_util::FakePinentry;
+
+        let fake = FakePinentry::new(&[("GETPIN", r#"echo "D passphrase"; echo OK"#)]);
+        let mut session = pinentry().exe(fake.exe()).connect().expect("session is started");
+        let key = session
+            .pin_derived("Passphrase:", &params(b"saltsalt"))
+            .expect("key is derived");
+        assert_eq!(params(b"saltsalt").derive(b"passphrase").unwrap(), key);
+    }
+}
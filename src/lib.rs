@@ -5,14 +5,13 @@
 //!
 //! ```
 //! # extern crate pinentry_rs;
-//! # extern crate secstr;
-//! use pinentry_rs::pinentry;
-//! use secstr::SecStr;
+//! use pinentry_rs::{pinentry, SecretPin};
 //!
 //! # use pinentry_rs::Result;
-//! # fn read_pw() -> Result<SecStr> {
-//! // Read a password into a `SecStr`
-//! let pw = pinentry().pin("Please enter password:".to_string())?;
+//! # #[cfg(feature = "process")]
+//! # fn read_pw() -> Result<SecretPin> {
+//! // Read a password into a `SecretPin` (a `secstr::SecStr` with the default features)
+//! let pw = pinentry().pin("Please enter password:")?;
 //! # Ok(pw)
 //! # }
 //!
@@ -20,24 +19,142 @@
 
 #![deny(warnings)]
 #![warn(unused_must_use)]
+#[cfg(feature = "kdf")]
+extern crate argon2;
+#[cfg(feature = "codec")]
+extern crate bytes;
+#[cfg(feature = "disk-cache")]
+extern crate chacha20poly1305;
+#[cfg(feature = "fluent")]
+extern crate fluent_bundle;
+#[cfg(feature = "fluent")]
+extern crate fluent_langneg;
+#[cfg(feature = "git2")]
+extern crate git2;
+#[cfg(feature = "gtk")]
+extern crate gtk4;
+#[cfg(all(feature = "hardening", unix))]
+extern crate libc;
+#[cfg(feature = "log")]
+extern crate log;
+#[cfg(feature = "secstr")]
 extern crate secstr;
+#[cfg(any(feature = "daemon", feature = "disk-cache"))]
+extern crate serde;
+#[cfg(any(feature = "daemon", feature = "disk-cache"))]
+extern crate serde_json;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "codec")]
+extern crate tokio_util;
+#[cfg(feature = "fluent")]
+extern crate unic_langid;
+extern crate unicode_normalization;
+#[cfg(feature = "unicode-width")]
+extern crate unicode_width;
+#[cfg(all(feature = "credui", windows))]
+extern crate windows_sys;
+#[cfg(feature = "dbus")]
+extern crate zbus;
+extern crate zeroize;
 
-/// Assuan protocol used by pinentry
-///
-/// _Note_ the module is deliberately left private currently
-mod assuan;
+#[macro_use]
+mod logging;
+
+#[cfg(all(feature = "agent", unix))]
+pub mod agent;
+#[cfg(all(feature = "ask-password", unix))]
+pub mod ask_password;
+pub mod assuan;
+pub mod backend;
+#[cfg(feature = "process")]
+pub mod cancel;
+
+#[cfg(feature = "codec")]
+pub mod codec;
+#[cfg(feature = "compat")]
+pub mod compat;
+#[cfg(feature = "daemon")]
+pub mod daemon;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+#[cfg(feature = "process")]
+pub mod diagnostics;
+#[cfg(feature = "process")]
+pub mod discovery;
+#[cfg(feature = "disk-cache")]
+pub mod disk_cache;
+#[cfg(feature = "git2")]
+pub mod git;
+#[cfg(all(feature = "hardening", unix))]
+pub mod hardening;
+#[cfg(feature = "kdf")]
+pub mod kdf;
+#[cfg(feature = "fluent")]
+pub mod localization;
+pub mod luks;
+pub mod manager;
+mod messages;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+pub mod normalize;
+pub mod pages;
+pub mod passphrase;
+pub mod policy;
+pub mod rate_limit;
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+pub mod sandbox;
+pub mod secret;
+mod session;
+pub mod single_flight;
+pub mod ssh;
+pub mod template;
+#[cfg(all(test, unix, feature = "process"))]
+mod test_util;
+#[cfg(feature = "unicode-width")]
+pub mod text;
+pub mod token;
+pub mod transport;
+pub mod unlock;
 
 use std::error;
-use std::ffi::OsStr;
+#[cfg(feature = "process")]
+use std::ffi::{OsStr, OsString};
 use std::fmt::{Display, Formatter};
 use std::io;
-use std::io::{BufRead, BufReader};
-use std::process::{Child, Command, Stdio};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "process")]
+use std::process::Command;
 use std::result;
+use std::sync::Arc;
+#[cfg(feature = "process")]
+use std::sync::Mutex;
+use std::time::Duration;
 
-use secstr::SecStr;
+use assuan::{AssuanCommand, AssuanError, Button, CommandFilter};
+use messages::Message;
+use normalize::Normalization;
+use policy::{PromptPolicy, SharedPolicy};
+use rate_limit::RateLimit;
+#[cfg(feature = "process")]
+use transport::ProcessTransport;
+use transport::Transport;
+#[cfg(feature = "process")]
+use unlock::{UnlockError, VerifyError};
 
-use assuan::{AssuanCommand, AssuanResponse, Button};
+#[cfg(feature = "process")]
+pub use diagnostics::diagnose;
+#[cfg(feature = "process")]
+pub use discovery::discover;
+pub use secret::{PinBytes, SecretPin};
+use session::Connector;
+pub use session::{
+    CachedPin, Confirmation, PinOutcome, PinentryInfo, PinentrySession, RawResponse, RepeatedPin, SessionPrompt,
+    SuggestedPin,
+};
+use session::{CheckFn, GenPinFn, QualityFn};
 
 pub type Result<T> = result::Result<T, Error>;
 
@@ -48,6 +165,75 @@ pub enum Error {
     IoError(io::Error),
     /// Protocol error (unable to parse protocol, broken pinentry output, etc.)
     ProtocolError(String),
+    /// Pinentry crashed, and respawning it (or retrying the prompt) failed as well
+    RecoveryFailed(Box<Error>),
+    /// Deriving a key from the passphrase failed (invalid KDF parameters)
+    KdfError(String),
+    /// The settings contradict each other or do not apply to the prompt - detected before pinentry is started
+    InvalidConfiguration(String),
+    /// The passphrase cache is corrupt, or was opened with the wrong key
+    CacheError(String),
+    /// gpg-agent rejected a request
+    AgentError(AssuanError),
+    /// The [prompt policy](policy) did not allow the dialog, for the given reason
+    PolicyDenied(String),
+    /// Too many dialogs were shown recently (see [`rate_limit`]) - another one may be shown after the given time
+    RateLimited(Duration),
+    /// The user cancelled the prompt (or closed the dialog)
+    Cancelled,
+    /// The prompt timed out before the user answered (see `timeout()` and `hard_timeout()`)
+    Timeout,
+    /// The user did not confirm (pressed the 'Not OK' button)
+    NotConfirmed,
+    /// Pinentry returned another error
+    PinentryError(AssuanError),
+    /// The prompt was aborted by the program, e.g. with a [`CancelToken`](cancel::CancelToken)
+    Aborted,
+    /// Pinentry answered with a response that does not fit the request (e.g. data for a confirmation), described
+    /// without the data
+    UnexpectedResponse(String),
+    /// None of the PINs entered was accepted, in the given number of attempts (see
+    /// [`pin_with_retries`](SessionPrompt::pin_with_retries))
+    MaxAttemptsExceeded(u32),
+}
+
+impl Error {
+    /// The error for an `ERR` line returned by pinentry (the line itself if it is not an `ERR` line)
+    pub(crate) fn from_response(error: String) -> Error {
+        match assuan::Line::parse(error.as_bytes()) {
+            Ok(assuan::Line::Err(e)) => match e.error_code() {
+                AssuanError::CANCELED | AssuanError::FULLY_CANCELED => Error::Cancelled,
+                AssuanError::TIMEOUT => Error::Timeout,
+                AssuanError::NOT_CONFIRMED => Error::NotConfirmed,
+                _ => Error::PinentryError(e),
+            },
+            _ => Error::ProtocolError(error),
+        }
+    }
+
+    /// The error for a response that does not fit the request - the data of a `PIN` response is dropped (and wiped)
+    pub(crate) fn unexpected_response(res: assuan::AssuanResponse) -> Error {
+        Error::UnexpectedResponse(match res {
+            assuan::AssuanResponse::PIN(_) => "data".to_string(),
+            assuan::AssuanResponse::OK => "OK".to_string(),
+            assuan::AssuanResponse::NOTOK(error) => error,
+        })
+    }
+
+    /// The `ERR` reply behind an error returned by pinentry
+    #[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+    pub(crate) fn assuan_error(&self) -> Option<AssuanError> {
+        let pinentry = |code, description: &str| {
+            AssuanError::new(AssuanError::SOURCE_PINENTRY, code, Some(description.to_string()))
+        };
+        match self {
+            Error::Cancelled => Some(pinentry(AssuanError::CANCELED, "Operation cancelled")),
+            Error::Timeout => Some(pinentry(AssuanError::TIMEOUT, "Timeout")),
+            Error::NotConfirmed => Some(pinentry(AssuanError::NOT_CONFIRMED, "Not confirmed")),
+            Error::PinentryError(ref e) => Some(e.clone()),
+            _ => None,
+        }
+    }
 }
 
 impl From<io::Error> for Error {
@@ -61,6 +247,26 @@ impl Display for Error {
         match self {
             Error::IoError(ref cause) => write!(f, "Pinentry I/O error: {}", cause),
             Error::ProtocolError(ref cause) => write!(f, "A pinentry protocol error has occurred: {}", cause),
+            Error::RecoveryFailed(ref cause) => write!(f, "Pinentry crashed and could not be recovered: {}", cause),
+            Error::KdfError(ref cause) => write!(f, "Key derivation failed: {}", cause),
+            Error::InvalidConfiguration(ref cause) => write!(f, "Invalid pinentry configuration: {}", cause),
+            Error::CacheError(ref cause) => write!(f, "Passphrase cache error: {}", cause),
+            Error::AgentError(ref cause) => write!(f, "gpg-agent returned an error: {}", cause),
+            Error::PolicyDenied(ref reason) => write!(f, "{}", Message::PolicyDenied { reason }),
+            Error::RateLimited(ref retry_after) => write!(
+                f,
+                "{}",
+                Message::RateLimited {
+                    seconds: retry_after.as_secs().max(1)
+                }
+            ),
+            Error::Cancelled => write!(f, "The prompt was cancelled"),
+            Error::Timeout => write!(f, "The prompt timed out"),
+            Error::NotConfirmed => write!(f, "The prompt was not confirmed"),
+            Error::PinentryError(ref cause) => write!(f, "Pinentry returned an error: {}", cause),
+            Error::Aborted => write!(f, "The prompt was aborted"),
+            Error::UnexpectedResponse(ref response) => write!(f, "Pinentry sent an unexpected response: {}", response),
+            Error::MaxAttemptsExceeded(attempts) => write!(f, "{}", Message::LockedOut { attempts: *attempts }),
         }
     }
 }
@@ -69,6 +275,7 @@ impl error::Error for Error {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match self {
             Error::IoError(ref cause) => Some(cause),
+            Error::RecoveryFailed(ref cause) => Some(cause.as_ref()),
             _ => None,
         }
     }
@@ -80,117 +287,1405 @@ pub fn pinentry() -> PinentryBuilder {
 }
 
 /// Builder for pinentry execution
+///
+/// The setters come in two forms: consuming ones for chaining (e.g. [`description`](PinentryBuilder::description)),
+/// and `&mut self` ones for configuring a builder step by step (e.g.
+/// [`set_description`](PinentryBuilder::set_description)). Texts shown in the dialog may contain line breaks, while
+/// the values of options (e.g. [`tty_name`](PinentryBuilder::tty_name)) may not - starting a session fails with
+/// [`Error::InvalidConfiguration`] then.
+#[derive(Clone)]
 pub struct PinentryBuilder {
+    // shows the dialogs in-process instead of starting pinentry
+    #[cfg(feature = "process")]
+    backend: Option<BackendFactory>,
+    #[cfg(feature = "process")]
+    cancel: Option<cancel::CancelToken>,
+    #[cfg(feature = "process")]
+    exe: OsString,
+    filter: Option<CommandFilter>,
+    #[cfg(feature = "process")]
+    hard_timeout: Option<Duration>,
+    // a touch file that is not valid UTF-8, refused when a session is started
+    invalid_touch_file: Option<PathBuf>,
+    #[cfg(feature = "process")]
+    launch: LaunchSettings,
+    #[cfg(all(feature = "hardening", unix))]
+    no_core_dumps: bool,
+    // OPTION commands sent right after the greeting
+    options: Vec<AssuanCommand>,
+    policy: Option<SharedPolicy>,
+    rate_limit: Option<RateLimit>,
+    respawn: bool,
+    #[cfg(all(feature = "sandbox", target_os = "linux"))]
+    sandbox: Option<sandbox::Sandbox>,
+    settings: PromptSettings,
+    #[cfg(all(feature = "tty-fallback", unix))]
+    tty_fallback: bool,
+}
+
+/// Creates the in-process transport of a session, see [`PinentryBuilder::backend`]
+#[cfg(feature = "process")]
+type BackendFactory = Arc<dyn Fn() -> Box<dyn Transport> + Send + Sync>;
+
+/// How the pinentry process is started
+#[cfg(feature = "process")]
+#[derive(Clone, Default)]
+struct LaunchSettings {
+    args: Vec<String>,
+    clear_env: bool,
+    current_dir: Option<PathBuf>,
+    env: Vec<(String, String)>,
+}
+
+#[cfg(feature = "process")]
+impl LaunchSettings {
+    /// Add the arguments and set the environment and working directory of `cmd`
+    fn apply(&self, cmd: &mut Command) {
+        cmd.args(&self.args);
+        if self.clear_env {
+            cmd.env_clear();
+        }
+        cmd.envs(self.env.iter().map(|(key, value)| (key, value)));
+        if let Some(ref dir) = self.current_dir {
+            cmd.current_dir(dir);
+        }
+    }
+}
+
+/// Settings of the dialog shown by pinentry
+#[derive(Clone, Default)]
+struct PromptSettings {
+    constraints_fn: Option<CheckFn>,
     description: Option<String>,
     error_text: Option<String>,
-    exe: String,
+    external_cache: bool,
+    genpin: Option<String>,
+    genpin_fn: Option<GenPinFn>,
+    genpin_tooltip: Option<String>,
+    key_info: Option<String>,
     label_cancel: Option<String>,
     label_notok: Option<String>,
     label_ok: Option<String>,
+    max_attempts: Option<u32>,
+    normalization: Option<Normalization>,
+    quality_bar: Option<String>,
+    quality_fn: Option<QualityFn>,
+    repeat: Option<String>,
+    repeat_error: Option<String>,
     timeout: Option<u32>,
     window_title: Option<String>,
 }
 
 impl PinentryBuilder {
     /// Set the descriptive text of the prompt
-    pub fn description(mut self, desc: String) -> Self {
-        self.description = Some(desc);
+    pub fn description<S: Into<String>>(mut self, desc: S) -> Self {
+        self.set_description(desc);
         self
     }
 
     /// Set the text that gets the displayed in case of error
-    pub fn error_text(mut self, error_text: String) -> Self {
-        self.error_text = Some(error_text);
+    pub fn error_text<S: Into<String>>(mut self, error_text: S) -> Self {
+        self.set_error_text(error_text);
         self
     }
 
     /// Override the path to the `pinentry` executable (by default just `pinentry`, looked up using `PATH` environment
     /// variable)
-    pub fn exe(mut self, exe: String) -> Self {
-        self.exe = exe;
+    #[cfg(feature = "process")]
+    pub fn exe<S: AsRef<OsStr>>(mut self, exe: S) -> Self {
+        self.set_exe(exe);
+        self
+    }
+
+    /// Pass an argument to pinentry, e.g. `--display` (followed by the display as another argument) or `--ttyname`
+    #[cfg(feature = "process")]
+    pub fn arg<S: Into<String>>(mut self, arg: S) -> Self {
+        self.add_arg(arg);
+        self
+    }
+
+    /// Set an environment variable for pinentry, e.g. `DISPLAY`, `WAYLAND_DISPLAY` or `DBUS_SESSION_BUS_ADDRESS`
+    /// for prompts shown on behalf of a service
+    #[cfg(feature = "process")]
+    pub fn env<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.set_env(key, value);
+        self
+    }
+
+    /// Set several environment variables for pinentry, see [`env`](PinentryBuilder::env)
+    #[cfg(feature = "process")]
+    pub fn envs<I: IntoIterator<Item = (K, V)>, K: Into<String>, V: Into<String>>(mut self, vars: I) -> Self {
+        self.set_envs(vars);
+        self
+    }
+
+    /// Start pinentry with no environment variables other than those set with [`env`](PinentryBuilder::env) (by
+    /// default, pinentry inherits the environment)
+    #[cfg(feature = "process")]
+    pub fn clear_env(mut self) -> Self {
+        self.set_clear_env(true);
+        self
+    }
+
+    /// Start pinentry in the directory `dir` (by default, the current directory)
+    #[cfg(feature = "process")]
+    pub fn current_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.set_current_dir(dir);
+        self
+    }
+
+    /// Prompt on the controlling terminal (see [`backend::tty`]) if the pinentry executable is not found (off by
+    /// default)
+    ///
+    /// Lets command line tools work on machines without pinentry through the same code path. Only applies to
+    /// pinentry started by the builder (one-shot prompts and [`connect`](PinentryBuilder::connect)).
+    #[cfg(all(feature = "tty-fallback", unix))]
+    pub fn allow_tty_fallback(mut self, allow: bool) -> Self {
+        self.set_allow_tty_fallback(allow);
+        self
+    }
+
+    /// Use the executable found by [`discover()`] instead of `pinentry`
+    #[cfg(feature = "process")]
+    pub fn discover_exe(mut self) -> Result<Self> {
+        self.exe = discover()?.into_os_string();
+        Ok(self)
+    }
+
+    /// Keep PINs out of core dumps: the pinentry processes cannot dump core, and the PINs returned are excluded from
+    /// core dumps of the calling process (off by default, see [`hardening`])
+    #[cfg(all(feature = "hardening", unix))]
+    pub fn disable_core_dumps(mut self, disable: bool) -> Self {
+        self.set_disable_core_dumps(disable);
+        self
+    }
+
+    /// Show terminal-based flavors (e.g. `pinentry-curses`) on the terminal `tty` (`OPTION ttyname`)
+    pub fn tty_name<S: Into<String>>(mut self, tty: S) -> Self {
+        self.set_tty_name(tty);
+        self
+    }
+
+    /// Set the type of the terminal (`OPTION ttytype`, e.g. `xterm-256color`)
+    pub fn tty_type<S: Into<String>>(mut self, term: S) -> Self {
+        self.set_tty_type(term);
+        self
+    }
+
+    /// Show graphical flavors on the X display `display` (`OPTION display`, e.g. `:0`)
+    pub fn display<S: Into<String>>(mut self, display: S) -> Self {
+        self.set_display(display);
+        self
+    }
+
+    /// Set the character encoding of the terminal (`OPTION lc-ctype`, e.g. `de_DE.UTF-8`)
+    pub fn lc_ctype<S: Into<String>>(mut self, locale: S) -> Self {
+        self.set_lc_ctype(locale);
+        self
+    }
+
+    /// Set the locale for the texts of pinentry itself (`OPTION lc-messages`, e.g. `de_DE.UTF-8`)
+    pub fn lc_messages<S: Into<String>>(mut self, locale: S) -> Self {
+        self.set_lc_messages(locale);
         self
     }
 
+    /// Set both locale options: the character encoding of the terminal and the locale for the texts of pinentry
+    /// itself (see [`lc_ctype`](PinentryBuilder::lc_ctype) and [`lc_messages`](PinentryBuilder::lc_messages))
+    pub fn locale<S: Into<String>, T: Into<String>>(mut self, lc_ctype: S, lc_messages: T) -> Self {
+        self.set_locale(lc_ctype, lc_messages);
+        self
+    }
+
+    /// Set the default label of the 'OK' button (`OPTION default-ok`), used unless the prompt sets a label of its
+    /// own
+    ///
+    /// Like the default labels pinentry ships with, labels may mark the accelerator key with an underscore (e.g.
+    /// `_Weiter`). The same applies to the other `default_*` labels.
+    pub fn default_ok<S: Into<String>>(mut self, label: S) -> Self {
+        self.set_default_ok(label);
+        self
+    }
+
+    /// Set the default label of the 'Cancel' button (`OPTION default-cancel`)
+    pub fn default_cancel<S: Into<String>>(mut self, label: S) -> Self {
+        self.set_default_cancel(label);
+        self
+    }
+
+    /// Set the default label of the 'Yes' button of confirmations (`OPTION default-yes`)
+    pub fn default_yes<S: Into<String>>(mut self, label: S) -> Self {
+        self.set_default_yes(label);
+        self
+    }
+
+    /// Set the default label of the 'No' button of confirmations (`OPTION default-no`)
+    pub fn default_no<S: Into<String>>(mut self, label: S) -> Self {
+        self.set_default_no(label);
+        self
+    }
+
+    /// Set the default prompt of PIN entries (`OPTION default-prompt`)
+    pub fn default_prompt<S: Into<String>>(mut self, prompt: S) -> Self {
+        self.set_default_prompt(prompt);
+        self
+    }
+
+    /// Set the label of the checkbox for saving the passphrase in the password manager (`OPTION default-pwmngr`, see
+    /// [`allow_external_cache`](PinentryBuilder::allow_external_cache))
+    pub fn default_pwmngr<S: Into<String>>(mut self, label: S) -> Self {
+        self.set_default_pwmngr(label);
+        self
+    }
+
+    /// Make pinentry check passphrases against the constraints of the caller before accepting them (`OPTION
+    /// constraints-enforce`), see [`constraints_fn`](PinentryBuilder::constraints_fn)
+    ///
+    /// Gives the user a chance to choose another passphrase in the same dialog, rather than being told after the fact.
+    pub fn constraints_enforce(mut self, enforce: bool) -> Self {
+        self.set_constraints_enforce(enforce);
+        self
+    }
+
+    /// Set a short hint on the constraints of passphrases, shown with the PIN entry (`OPTION constraints-hint-short`,
+    /// e.g. `At least 12 characters`)
+    pub fn constraints_hint_short<S: Into<String>>(mut self, hint: S) -> Self {
+        self.set_constraints_hint_short(hint);
+        self
+    }
+
+    /// Set a longer explanation of the constraints of passphrases, e.g. shown as a tooltip (`OPTION
+    /// constraints-hint-long`)
+    pub fn constraints_hint_long<S: Into<String>>(mut self, hint: S) -> Self {
+        self.set_constraints_hint_long(hint);
+        self
+    }
+
+    /// Set the title of the message shown when a passphrase is refused (`OPTION constraints-error-title`)
+    pub fn constraints_error_title<S: Into<String>>(mut self, title: S) -> Self {
+        self.set_constraints_error_title(title);
+        self
+    }
+
+    /// Show the passphrase typed in groups of five characters, like recovery keys are written down, where the flavor
+    /// supports it (`OPTION formatted-passphrase`, off by default)
+    ///
+    /// Pinentry versions and flavors that do not know this option show the passphrase as usual.
+    pub fn formatted_passphrase(mut self, formatted: bool) -> Self {
+        self.set_formatted_passphrase(formatted);
+        self
+    }
+
+    /// Set the hint shown below a [formatted passphrase](PinentryBuilder::formatted_passphrase)
+    /// (`OPTION formatted-passphrase-hint`), left out like the option itself where it is not supported
+    pub fn formatted_passphrase_hint<S: Into<String>>(mut self, hint: S) -> Self {
+        self.set_formatted_passphrase_hint(hint);
+        self
+    }
+
+    /// Mask the characters typed with `c` instead of the default of the flavor (`OPTION invisible-char`)
+    ///
+    /// Pinentry versions and flavors that do not know this option use their default. Whitespace and control
+    /// characters cannot be used: starting a session fails with [`Error::InvalidConfiguration`] then.
+    pub fn invisible_char(mut self, c: char) -> Self {
+        self.set_invisible_char(c);
+        self
+    }
+
+    /// Let pinentry touch `path` (update its modification time) when the dialog is closed (`OPTION touch-file`), like
+    /// gpg-agent does with its socket to notice that the user was active
+    ///
+    /// Paths that are not valid UTF-8 cannot be passed to pinentry: starting a session fails with
+    /// [`Error::InvalidConfiguration`] then.
+    pub fn touch_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.set_touch_file(path);
+        self
+    }
+
+    /// Tell pinentry which process the dialog is shown for (`OPTION owner`), e.g. so that it can be placed in front
+    /// of the window of that process
+    pub fn owner_pid(mut self, pid: u32) -> Self {
+        self.set_owner_pid(pid);
+        self
+    }
+
+    /// Set the terminal, display and locale options that have not been set yet from the environment, like gpg does:
+    /// `GPG_TTY`, `TERM`, `DISPLAY`, and `LC_ALL`, `LC_CTYPE`/`LC_MESSAGES` or `LANG`
+    pub fn inherit_terminal_env(mut self) -> Self {
+        self.apply_terminal_env();
+        self
+    }
+
+    fn option(&mut self, name: &str, value: String) {
+        self.remove_option(name);
+        self.options.push(AssuanCommand::Option(name.to_string(), Some(value)));
+    }
+
+    /// Set (or unset) an option without a value
+    fn flag(&mut self, name: &str, on: bool) {
+        self.remove_option(name);
+        if on {
+            self.options.push(AssuanCommand::Option(name.to_string(), None));
+        }
+    }
+
+    fn remove_option(&mut self, name: &str) {
+        self.options
+            .retain(|cmd| !matches!(cmd, AssuanCommand::Option(ref n, _) if n == name));
+    }
+
+    /// Option values are sent as they are (unlike the texts of the dialog, which are escaped), so they cannot span
+    /// lines
+    fn check_options(&self) -> Result<()> {
+        if let Some(ref path) = self.invalid_touch_file {
+            return Err(invalid(&format!(
+                "the touch file {} is not valid UTF-8",
+                path.display()
+            )));
+        }
+        for cmd in &self.options {
+            if let AssuanCommand::Option(ref name, Some(ref value)) = *cmd {
+                if value.contains(['\r', '\n']) {
+                    return Err(invalid(&format!(
+                        "the value of option {} may not contain line breaks",
+                        name
+                    )));
+                }
+                if name == "invisible-char" && value.chars().any(|c| c.is_whitespace() || c.is_control()) {
+                    return Err(invalid(
+                        "the invisible character may not be whitespace or a control character",
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn has_option(&self, name: &str) -> bool {
+        self.options
+            .iter()
+            .any(|cmd| matches!(cmd, AssuanCommand::Option(ref n, _) if n == name))
+    }
+
     /// Set the label of the 'Cancel' button
-    pub fn label_cancel(mut self, label: String) -> Self {
-        self.label_cancel = Some(label);
+    pub fn label_cancel<S: Into<String>>(mut self, label: S) -> Self {
+        self.set_label_cancel(label);
         self
     }
 
     /// Set the label of the 'Not OK' button
-    pub fn label_notok(mut self, label: String) -> Self {
-        self.label_notok = Some(label);
+    pub fn label_notok<S: Into<String>>(mut self, label: S) -> Self {
+        self.set_label_notok(label);
         self
     }
 
     /// Set the label of the 'OK' button
-    pub fn label_ok(mut self, label: String) -> Self {
-        self.label_ok = Some(label);
+    pub fn label_ok<S: Into<String>>(mut self, label: S) -> Self {
+        self.set_label_ok(label);
+        self
+    }
+
+    /// Ask for the PIN a second time with `prompt`, and only accept it if both entries match (`SETREPEAT`)
+    ///
+    /// Use [`pin_repeated()`](PinentryBuilder::pin_repeated) to find out whether pinentry did check the entries.
+    pub fn repeat<S: Into<String>>(mut self, prompt: S) -> Self {
+        self.set_repeat(prompt);
+        self
+    }
+
+    /// Set the error text shown when the repeated PIN does not match (`SETREPEATERROR`)
+    pub fn repeat_error<S: Into<String>>(mut self, error_text: S) -> Self {
+        self.set_repeat_error(error_text);
+        self
+    }
+
+    /// Identify the passphrase asked for by `key_info` (`SETKEYINFO`, e.g. `n/0123456789ABCDEF`), the key under which
+    /// pinentry stores it in the external password cache
+    ///
+    /// Use [`pin_cacheable()`](PinentryBuilder::pin_cacheable) to find out whether the PIN came from the cache. The
+    /// cache has to be allowed with [`allow_external_cache`](PinentryBuilder::allow_external_cache), or prompting
+    /// fails with [`Error::InvalidConfiguration`].
+    pub fn keyinfo<S: Into<String>>(mut self, key_info: S) -> Self {
+        self.set_keyinfo(key_info);
+        self
+    }
+
+    /// Let pinentry take the PIN from (and offer to save it in) an external password cache, such as the GNOME
+    /// keyring or KWallet (`OPTION allow-external-password-cache`, off by default)
+    ///
+    /// Only applies to prompts with a [`keyinfo`](PinentryBuilder::keyinfo).
+    pub fn allow_external_cache(mut self, allow: bool) -> Self {
+        self.set_allow_external_cache(allow);
+        self
+    }
+
+    /// Show a quality bar with `tooltip` (`SETQUALITYBAR`), filled in by the
+    /// [`quality_fn`](PinentryBuilder::quality_fn)
+    ///
+    /// Prompting fails with [`Error::InvalidConfiguration`] without a quality function, as the bar would never move.
+    pub fn quality_bar<S: Into<String>>(mut self, tooltip: S) -> Self {
+        self.set_quality_bar(tooltip);
+        self
+    }
+
+    /// Rate the PIN typed so far from -100 (unacceptable) to 100 (excellent), whenever pinentry updates the
+    /// quality bar (by answering its `QUALITY` inquiries)
+    ///
+    /// Without it the quality bar stays empty. Inquiries for PINs that are not valid UTF-8 are cancelled.
+    pub fn quality_fn<F: Fn(&str) -> i32 + Send + Sync + 'static>(mut self, quality: F) -> Self {
+        self.set_quality_fn(quality);
+        self
+    }
+
+    /// Offer a button with `label` that fills in a generated PIN (`SETGENPIN`), made by the
+    /// [`genpin_fn`](PinentryBuilder::genpin_fn)
+    ///
+    /// Use [`pin_suggested()`](PinentryBuilder::pin_suggested) to find out whether the user took the generated PIN.
+    pub fn genpin_label<S: Into<String>>(mut self, label: S) -> Self {
+        self.set_genpin_label(label);
+        self
+    }
+
+    /// Set the tooltip of the button offering a generated PIN (`SETGENPIN_TT`)
+    pub fn genpin_tooltip<S: Into<String>>(mut self, tooltip: S) -> Self {
+        self.set_genpin_tooltip(tooltip);
+        self
+    }
+
+    /// Make the PIN suggested when the user presses the [`genpin_label`](PinentryBuilder::genpin_label) button (by
+    /// answering pinentry's `GENPIN` inquiries)
+    ///
+    /// Without it, PINs are made by [`random_passphrase`](passphrase::random_passphrase) with the `genpin`
+    /// feature - otherwise the button does nothing.
+    pub fn genpin_fn<F: Fn() -> SecretPin + Send + Sync + 'static>(mut self, generate: F) -> Self {
+        self.set_genpin_fn(generate);
+        self
+    }
+
+    /// Check passphrases against the constraints when pinentry asks for it (by answering its `CHECKPIN` inquiries, made
+    /// with [`constraints_enforce`](PinentryBuilder::constraints_enforce) turned on)
+    ///
+    /// `check` returns why the passphrase is refused, which pinentry shows to the user, or `None` to accept it. Without
+    /// it the inquiries are cancelled, as are the inquiries for passphrases that are not valid UTF-8.
+    pub fn constraints_fn<F: Fn(&str) -> Option<String> + Send + Sync + 'static>(mut self, check: F) -> Self {
+        self.set_constraints_fn(check);
+        self
+    }
+
+    /// Set how many PINs `unlock()` lets the user try (3 by default)
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.set_max_attempts(attempts);
+        self
+    }
+
+    /// Normalize captured PINs (Unicode normalization, trimming, rejecting empty input) before they are returned
+    pub fn normalization(mut self, normalization: Normalization) -> Self {
+        self.set_normalization(normalization);
         self
     }
 
     /// Set timeout for prompt (in seconds)
     pub fn timeout(mut self, secs: u32) -> Self {
-        self.timeout = Some(secs);
+        self.set_timeout(secs);
         self
     }
 
     /// Set the window title of the prompt
-    pub fn window_title(mut self, title: String) -> Self {
-        self.window_title = Some(title);
+    pub fn window_title<S: Into<String>>(mut self, title: S) -> Self {
+        self.set_window_title(title);
+        self
+    }
+
+    /// Only send the commands (and accept the responses) allowed by `filter`, see [`CommandFilter`]
+    ///
+    /// The settings of the builder are checked as well, when the session is started.
+    pub fn command_filter(mut self, filter: CommandFilter) -> Self {
+        self.set_command_filter(filter);
+        self
+    }
+
+    /// Consult `policy` before each dialog is shown (by all sessions started from this builder), see [`policy`]
+    pub fn policy<P: PromptPolicy + 'static>(mut self, policy: P) -> Self {
+        self.set_policy(policy);
+        self
+    }
+
+    /// Limit how many dialogs are shown (by all sessions sharing `limit`), see [`rate_limit`]
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.set_rate_limit(limit);
+        self
+    }
+
+    /// Respawn pinentry if it crashes in the middle of a prompt (off by default)
+    ///
+    /// The settings made so far are replayed to the new process and the prompt is retried once. Cannot be used for
+    /// sessions started with `connect_transport()`, as there is nothing to respawn.
+    pub fn respawn(mut self, respawn: bool) -> Self {
+        self.set_respawn(respawn);
         self
     }
 
+    /// Start pinentry in `sandbox` (see [`sandbox`])
+    #[cfg(all(feature = "sandbox", target_os = "linux"))]
+    pub fn sandbox(mut self, sandbox: sandbox::Sandbox) -> Self {
+        self.set_sandbox(sandbox);
+        self
+    }
+
+    /// Show the dialogs of one-shot prompts and of sessions started by [`connect`](PinentryBuilder::connect) with
+    /// `backend` instead of starting pinentry (see [`backend`](mod@backend)), e.g. a
+    /// [`MockPinentry`](backend::mock::MockPinentry) in tests
+    ///
+    /// Each session gets a clone of `backend`.
+    #[cfg(feature = "process")]
+    pub fn backend<B: backend::Backend + Clone + 'static>(mut self, backend: B) -> Self {
+        self.set_backend(backend);
+        self
+    }
+
+    /// Let `token` abort the prompts, from another thread (see [`cancel`])
+    ///
+    /// Only pinentry started by the builder (one-shot prompts and [`connect`](PinentryBuilder::connect)) can be
+    /// aborted.
+    #[cfg(feature = "process")]
+    pub fn cancel_token(mut self, token: cancel::CancelToken) -> Self {
+        self.set_cancel_token(token);
+        self
+    }
+
+    /// Kill pinentry if a prompt is not answered within `timeout`, failing it with [`Error::Timeout`]
+    ///
+    /// Unlike [`timeout`](PinentryBuilder::timeout), this does not rely on pinentry (some flavors ignore
+    /// `SETTIMEOUT`). Each prompt gets the full time, and a session started with `respawn` enabled starts pinentry
+    /// again for the next prompt. Only pinentry started by the builder (one-shot prompts and
+    /// [`connect`](PinentryBuilder::connect)) can be killed.
+    #[cfg(feature = "process")]
+    pub fn hard_timeout(mut self, timeout: Duration) -> Self {
+        self.set_hard_timeout(timeout);
+        self
+    }
+
+    /// Start pinentry and keep it running for several prompts
+    ///
+    /// The settings of the builder become the defaults of the session, applied to all prompts made through it.
+    #[cfg(feature = "process")]
+    pub fn connect(self) -> Result<PinentrySession> {
+        if let Some(backend) = self.backend.clone() {
+            self.check_killable()?;
+            return self.open(Some(Box::new(move || Ok(backend()))), None);
+        }
+        let exe = self.exe.clone();
+        let launch = self.launch.clone();
+        #[cfg(all(feature = "sandbox", target_os = "linux"))]
+        let sandbox = self.sandbox.clone();
+        #[cfg(all(feature = "hardening", unix))]
+        let no_core_dumps = self.no_core_dumps;
+        let cancel = self.cancel.clone();
+        // the pinentry of this session alone, for its hard timeout (the token may be shared with other sessions)
+        let child = cancel::SessionChild::default();
+        let current = child.clone();
+        #[cfg(all(feature = "tty-fallback", unix))]
+        let tty_fallback = self.tty_fallback;
+        let connector = Box::new(move || {
+            #[cfg(all(feature = "sandbox", target_os = "linux"))]
+            let mut cmd = match sandbox {
+                Some(ref sandbox) => sandbox.command(&exe),
+                None => Command::new(&exe),
+            };
+            #[cfg(not(all(feature = "sandbox", target_os = "linux")))]
+            let mut cmd = Command::new(&exe);
+            // the sandbox passes the arguments following pinentry on to it
+            launch.apply(&mut cmd);
+            #[cfg(all(feature = "hardening", unix))]
+            let cmd = if no_core_dumps {
+                hardening::without_core_dumps(cmd)
+            } else {
+                cmd
+            };
+            let transport = match ProcessTransport::from_command(cmd) {
+                #[cfg(all(feature = "tty-fallback", unix))]
+                Err(e) if tty_fallback && e.kind() == io::ErrorKind::NotFound => {
+                    debug!("{} not found, prompting on the terminal", Path::new(&exe).display());
+                    return Ok(Box::new(backend::InProcess::new(backend::tty::Tty::new())) as Box<dyn Transport>);
+                }
+                res => res?,
+            };
+            current.set(transport.child());
+            if let Some(ref cancel) = cancel {
+                cancel.watch(transport.child());
+            }
+            Ok(Box::new(transport) as Box<dyn Transport>)
+        });
+        let hard_timeout = self.hard_timeout;
+        let mut session = self.open(Some(connector), None)?;
+        session.hard_timeout = hard_timeout.map(|timeout| (timeout, child));
+        Ok(session)
+    }
+
+    /// Start a session over a transport created by `connect` (which is called again to reconnect if the transport
+    /// breaks and `respawn` is enabled)
+    pub fn connect_with<T, F>(self, mut connect: F) -> Result<PinentrySession>
+    where
+        T: Transport + 'static,
+        F: FnMut() -> io::Result<T> + Send + 'static,
+    {
+        #[cfg(feature = "process")]
+        self.check_killable()?;
+        let connector = Box::new(move || Ok(Box::new(connect()?) as Box<dyn Transport>));
+        self.open(Some(connector), None)
+    }
+
+    /// Start a session over the Unix socket at `path`, on which an Assuan server (e.g. a pinentry run by a socket
+    /// activation service) is listening
+    ///
+    /// With `respawn` enabled, the socket is connected to again if the connection breaks.
+    #[cfg(unix)]
+    pub fn connect_socket<P: AsRef<Path>>(self, path: P) -> Result<PinentrySession> {
+        let path = path.as_ref().to_path_buf();
+        self.connect_with(move || UnixStream::connect(&path))
+    }
+
+    /// Start a session over an existing transport (e.g. a socket)
+    pub fn connect_transport<T: Transport + 'static>(self, transport: T) -> Result<PinentrySession> {
+        #[cfg(feature = "process")]
+        self.check_killable()?;
+        if self.respawn {
+            return Err(invalid(
+                "respawn needs a way to reconnect, use connect_with() instead of connect_transport()",
+            ));
+        }
+        self.open(None, Some(Box::new(transport)))
+    }
+
+    /// Cancel tokens and hard timeouts need to kill pinentry, which only works if the builder starts it
+    #[cfg(feature = "process")]
+    fn check_killable(&self) -> Result<()> {
+        if self.cancel.is_some() {
+            return Err(invalid("cancel tokens only apply to pinentry started by connect()"));
+        }
+        if self.hard_timeout.is_some() {
+            return Err(invalid("hard timeouts only apply to pinentry started by connect()"));
+        }
+        Ok(())
+    }
+
+    fn open(mut self, connector: Option<Connector>, transport: Option<Box<dyn Transport>>) -> Result<PinentrySession> {
+        self.settings.validate(None)?;
+        self.check_options()?;
+        if let Some(ref limit) = self.rate_limit {
+            limit.check()?;
+        }
+        let max_attempts = self.settings.max_attempts.take();
+        let normalization = self.settings.normalization.take();
+        let quality = self.settings.quality_fn.take();
+        let generate = self.settings.genpin_fn.take();
+        let check = self.settings.constraints_fn.take();
+        let mut state = self.options;
+        state.extend(self.settings.into_commands());
+        #[cfg(feature = "process")]
+        let cancel = self.cancel;
+        let session = PinentrySession::open(connector, transport, state, self.respawn, self.filter);
+        #[cfg(feature = "process")]
+        let session = match (session, cancel) {
+            (Err(_), Some(ref cancel)) if cancel.is_cancelled() => return Err(Error::Aborted),
+            (Ok(mut session), Some(cancel)) => {
+                session.cancel = Some(cancel);
+                Ok(session)
+            }
+            (session, _) => session,
+        };
+        let mut session = session?;
+        if let Some(attempts) = max_attempts {
+            session.set_max_attempts(attempts);
+        }
+        if let Some(normalization) = normalization {
+            session.set_normalization(normalization);
+        }
+        if let Some(quality) = quality {
+            session.set_shared_quality_fn(quality);
+        }
+        if let Some(generate) = generate {
+            session.set_shared_genpin_fn(generate);
+        }
+        if let Some(check) = check {
+            session.set_shared_constraints_fn(check);
+        }
+        if let Some(policy) = self.policy {
+            session.set_shared_policy(policy);
+        }
+        #[cfg(all(feature = "hardening", unix))]
+        {
+            session.exclude_from_core_dumps = self.no_core_dumps;
+        }
+        if let Some(limit) = self.rate_limit {
+            session.set_rate_limit(limit);
+        }
+        Ok(session)
+    }
+
+    /// Let the user pick one of `options`
+    ///
+    /// See [`SessionPrompt::choose`].
+    #[cfg(feature = "process")]
+    pub fn choose(mut self, options: &[&str]) -> Result<Option<usize>> {
+        self.settings.validate(Some(PromptKind::Choice))?;
+        session::check_choices(options)?;
+        // the description is combined with the options, so it needs to be passed on to the prompt
+        let desc = self.settings.description.take();
+        let mut session = self.connect()?;
+        let mut prompt = session.prompt();
+        if let Some(desc) = desc {
+            prompt = prompt.description(desc);
+        }
+        prompt.choose(options)
+    }
+
     /// Prompt for confirmation
     ///
     /// The text for the confirmation should be set using `.description()`
-    pub fn confirm_yes_no(mut self) -> Result<bool> {
-        let mut commands = self.build_commands();
-        commands.push(AssuanCommand::Confirm);
+    #[cfg(feature = "process")]
+    pub fn confirm_yes_no(self) -> Result<bool> {
+        self.settings.validate(Some(PromptKind::Confirm))?;
+        self.connect()?.confirm_yes_no()
+    }
 
-        let pinentry = start_pinentry(&self.exe)?;
+    /// Prompt for confirmation, telling the buttons apart
+    ///
+    /// See [`SessionPrompt::confirm`].
+    #[cfg(feature = "process")]
+    pub fn confirm(self) -> Result<Confirmation> {
+        self.settings.validate(Some(PromptKind::Confirm))?;
+        self.connect()?.confirm()
+    }
 
-        let res = process_commands(pinentry, &commands)?;
-        match res {
-            AssuanResponse::OK => Ok(true),
-            AssuanResponse::NOTOK(_) => Ok(false),
-            x => panic!("BUG: unexpected response {:?}", x),
-        }
+    /// Ask for confirmation with a single button
+    ///
+    /// See [`SessionPrompt::confirm_one_button`].
+    #[cfg(feature = "process")]
+    pub fn confirm_one_button(self) -> Result<Confirmation> {
+        self.settings.validate(Some(PromptKind::Confirm))?;
+        self.connect()?.confirm_one_button()
     }
 
     /// Prompt for a PIN
-    pub fn pin(mut self, prompt: String) -> Result<SecStr> {
-        let mut commands = self.build_commands();
-        commands.push(AssuanCommand::SetPrompt(prompt));
-        commands.push(AssuanCommand::GetPin);
+    #[cfg(feature = "process")]
+    pub fn pin<S: Into<String>>(self, prompt: S) -> Result<SecretPin> {
+        self.settings.validate(Some(PromptKind::Pin))?;
+        self.connect()?.pin(prompt)
+    }
 
-        let pinentry = start_pinentry(&self.exe)?;
+    /// Prompt for a PIN on another thread, returning a handle to wait for it and a token to abort it with (see
+    /// [`cancel`])
+    #[cfg(feature = "process")]
+    pub fn pin_cancellable<S: Into<String>>(self, prompt: S) -> (cancel::PinHandle, cancel::CancelToken) {
+        let token = self.cancel.clone().unwrap_or_default();
+        let (builder, prompt) = (self.cancel_token(token.clone()), prompt.into());
+        let handle = std::thread::spawn(move || builder.pin(prompt));
+        (cancel::PinHandle(handle), token)
+    }
 
-        let res = process_commands(pinentry, &commands)?;
-        match res {
-            AssuanResponse::PIN(pin) => Ok(pin),
-            AssuanResponse::NOTOK(error) => Err(Error::ProtocolError(error)),
-            AssuanResponse::OK => panic!("BUG: got OK result but asked for PIN"),
-        }
+    /// Prompt for a PIN to be entered twice
+    ///
+    /// See [`SessionPrompt::pin_repeated`].
+    #[cfg(feature = "process")]
+    pub fn pin_repeated<S: Into<String>>(self, prompt: S) -> Result<RepeatedPin> {
+        self.settings.validate(Some(PromptKind::Pin))?;
+        self.connect()?.pin_repeated(prompt)
+    }
+
+    /// Prompt for a PIN, offering a generated one
+    ///
+    /// See [`SessionPrompt::pin_suggested`].
+    #[cfg(feature = "process")]
+    pub fn pin_suggested<S: Into<String>>(self, prompt: S) -> Result<SuggestedPin> {
+        self.settings.validate(Some(PromptKind::Pin))?;
+        self.connect()?.pin_suggested(prompt)
+    }
+
+    /// Prompt for a PIN that may come from the external password cache
+    ///
+    /// See [`SessionPrompt::pin_cacheable`].
+    #[cfg(feature = "process")]
+    pub fn pin_cacheable<S: Into<String>>(self, prompt: S) -> Result<CachedPin> {
+        self.settings.validate(Some(PromptKind::Pin))?;
+        self.connect()?.pin_cacheable(prompt)
+    }
+
+    /// Remove the passphrase stored under `key_info` from the external password cache
+    ///
+    /// See [`PinentrySession::clear_cached_passphrase`].
+    #[cfg(feature = "process")]
+    pub fn clear_cached_passphrase<S: Into<String>>(self, key_info: S) -> Result<()> {
+        self.connect()?.clear_cached_passphrase(key_info)
+    }
+
+    /// Ask pinentry about itself
+    ///
+    /// See [`PinentrySession::get_info`].
+    #[cfg(feature = "process")]
+    pub fn get_info(self) -> Result<PinentryInfo> {
+        self.connect()?.get_info()
+    }
+
+    /// Prompt for a PIN, offering an alternate action on the 'Not OK' button
+    ///
+    /// See [`SessionPrompt::pin_or_alternate`].
+    #[cfg(feature = "process")]
+    pub fn pin_or_alternate<S: Into<String>, L: Into<String>>(
+        self,
+        prompt: S,
+        alternate_label: L,
+    ) -> Result<PinOutcome> {
+        self.settings.validate(Some(PromptKind::Pin))?;
+        self.connect()?.pin_or_alternate(prompt, alternate_label)
+    }
+
+    /// Prompt for a PIN and hand it to `f` without returning it
+    ///
+    /// The PIN is zeroized as soon as `f` returns, see [`SessionPrompt::pin_and_use`].
+    #[cfg(feature = "process")]
+    pub fn pin_and_use<T, E, F, S: Into<String>>(self, prompt: S, f: F) -> result::Result<T, E>
+    where
+        E: From<Error>,
+        F: FnOnce(&[u8]) -> result::Result<T, E>,
+    {
+        self.settings.validate(Some(PromptKind::Pin))?;
+        self.connect()?.pin_and_use(prompt, f)
+    }
+
+    /// Prompt for a PIN until `verify` accepts it
+    ///
+    /// See [`SessionPrompt::unlock`].
+    #[cfg(feature = "process")]
+    pub fn unlock<T, E, F, S: Into<String>>(self, prompt: S, verify: F) -> result::Result<T, UnlockError<E>>
+    where
+        F: FnMut(&[u8]) -> result::Result<T, VerifyError<E>>,
+    {
+        self.settings.validate(Some(PromptKind::Unlock))?;
+        self.connect()?.unlock(prompt, verify)
+    }
+
+    /// Prompt for a PIN until `validate` accepts it, at most `max_attempts` times
+    ///
+    /// See [`SessionPrompt::pin_with_retries`].
+    #[cfg(feature = "process")]
+    pub fn pin_with_retries<T, F, S: Into<String>>(self, prompt: S, max_attempts: u32, validate: F) -> Result<T>
+    where
+        F: FnMut(&SecretPin) -> result::Result<T, String>,
+    {
+        self.settings.validate(Some(PromptKind::Unlock))?;
+        self.connect()?.pin_with_retries(prompt, max_attempts, validate)
     }
 
     /// Show a message
     ///
     /// The text for the message should be set using `.description()`
-    pub fn show_message(mut self) -> Result<()> {
-        let mut commands = self.build_commands();
-        commands.push(AssuanCommand::ShowMessage);
+    #[cfg(feature = "process")]
+    pub fn show_message(self) -> Result<()> {
+        self.settings.validate(Some(PromptKind::Message))?;
+        self.connect()?.show_message()
+    }
+}
 
-        let pinentry = start_pinentry(&self.exe)?;
+/// Non-consuming setters, e.g. for configuring a builder conditionally
+impl PinentryBuilder {
+    /// Like [`description`](PinentryBuilder::description), without consuming the builder
+    pub fn set_description<S: Into<String>>(&mut self, desc: S) -> &mut Self {
+        self.settings.description = Some(desc.into());
+        self
+    }
 
-        let res = process_commands(pinentry, &commands)?;
-        match res {
-            AssuanResponse::OK => Ok(()),
-            x => panic!("BUG: unexpected response {:?}", x),
+    /// Like [`error_text`](PinentryBuilder::error_text), without consuming the builder
+    pub fn set_error_text<S: Into<String>>(&mut self, error_text: S) -> &mut Self {
+        self.settings.error_text = Some(error_text.into());
+        self
+    }
+
+    /// Like [`exe`](PinentryBuilder::exe), without consuming the builder
+    #[cfg(feature = "process")]
+    pub fn set_exe<S: AsRef<OsStr>>(&mut self, exe: S) -> &mut Self {
+        self.exe = exe.as_ref().to_os_string();
+        self
+    }
+
+    /// Like [`arg`](PinentryBuilder::arg), without consuming the builder
+    #[cfg(feature = "process")]
+    pub fn add_arg<S: Into<String>>(&mut self, arg: S) -> &mut Self {
+        self.launch.args.push(arg.into());
+        self
+    }
+
+    /// Like [`env`](PinentryBuilder::env), without consuming the builder
+    #[cfg(feature = "process")]
+    pub fn set_env<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) -> &mut Self {
+        self.launch.env.push((key.into(), value.into()));
+        self
+    }
+
+    /// Like [`envs`](PinentryBuilder::envs), without consuming the builder
+    #[cfg(feature = "process")]
+    pub fn set_envs<I: IntoIterator<Item = (K, V)>, K: Into<String>, V: Into<String>>(&mut self, vars: I) -> &mut Self {
+        self.launch
+            .env
+            .extend(vars.into_iter().map(|(key, value)| (key.into(), value.into())));
+        self
+    }
+
+    /// Start pinentry with a cleared environment or not, see [`clear_env`](PinentryBuilder::clear_env)
+    #[cfg(feature = "process")]
+    pub fn set_clear_env(&mut self, clear: bool) -> &mut Self {
+        self.launch.clear_env = clear;
+        self
+    }
+
+    /// Like [`current_dir`](PinentryBuilder::current_dir), without consuming the builder
+    #[cfg(feature = "process")]
+    pub fn set_current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.launch.current_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Like [`allow_tty_fallback`](PinentryBuilder::allow_tty_fallback), without consuming the builder
+    #[cfg(all(feature = "tty-fallback", unix))]
+    pub fn set_allow_tty_fallback(&mut self, allow: bool) -> &mut Self {
+        self.tty_fallback = allow;
+        self
+    }
+
+    /// Like [`disable_core_dumps`](PinentryBuilder::disable_core_dumps), without consuming the builder
+    #[cfg(all(feature = "hardening", unix))]
+    pub fn set_disable_core_dumps(&mut self, disable: bool) -> &mut Self {
+        self.no_core_dumps = disable;
+        self
+    }
+
+    /// Like [`tty_name`](PinentryBuilder::tty_name), without consuming the builder
+    pub fn set_tty_name<S: Into<String>>(&mut self, tty: S) -> &mut Self {
+        self.option("ttyname", tty.into());
+        self
+    }
+
+    /// Like [`tty_type`](PinentryBuilder::tty_type), without consuming the builder
+    pub fn set_tty_type<S: Into<String>>(&mut self, term: S) -> &mut Self {
+        self.option("ttytype", term.into());
+        self
+    }
+
+    /// Like [`display`](PinentryBuilder::display), without consuming the builder
+    pub fn set_display<S: Into<String>>(&mut self, display: S) -> &mut Self {
+        self.option("display", display.into());
+        self
+    }
+
+    /// Like [`lc_ctype`](PinentryBuilder::lc_ctype), without consuming the builder
+    pub fn set_lc_ctype<S: Into<String>>(&mut self, locale: S) -> &mut Self {
+        self.option("lc-ctype", locale.into());
+        self
+    }
+
+    /// Like [`lc_messages`](PinentryBuilder::lc_messages), without consuming the builder
+    pub fn set_lc_messages<S: Into<String>>(&mut self, locale: S) -> &mut Self {
+        self.option("lc-messages", locale.into());
+        self
+    }
+
+    /// Like [`locale`](PinentryBuilder::locale), without consuming the builder
+    pub fn set_locale<S: Into<String>, T: Into<String>>(&mut self, lc_ctype: S, lc_messages: T) -> &mut Self {
+        self.set_lc_ctype(lc_ctype).set_lc_messages(lc_messages)
+    }
+
+    /// Like [`default_ok`](PinentryBuilder::default_ok), without consuming the builder
+    pub fn set_default_ok<S: Into<String>>(&mut self, label: S) -> &mut Self {
+        self.option("default-ok", label.into());
+        self
+    }
+
+    /// Like [`default_cancel`](PinentryBuilder::default_cancel), without consuming the builder
+    pub fn set_default_cancel<S: Into<String>>(&mut self, label: S) -> &mut Self {
+        self.option("default-cancel", label.into());
+        self
+    }
+
+    /// Like [`default_yes`](PinentryBuilder::default_yes), without consuming the builder
+    pub fn set_default_yes<S: Into<String>>(&mut self, label: S) -> &mut Self {
+        self.option("default-yes", label.into());
+        self
+    }
+
+    /// Like [`default_no`](PinentryBuilder::default_no), without consuming the builder
+    pub fn set_default_no<S: Into<String>>(&mut self, label: S) -> &mut Self {
+        self.option("default-no", label.into());
+        self
+    }
+
+    /// Like [`default_prompt`](PinentryBuilder::default_prompt), without consuming the builder
+    pub fn set_default_prompt<S: Into<String>>(&mut self, prompt: S) -> &mut Self {
+        self.option("default-prompt", prompt.into());
+        self
+    }
+
+    /// Like [`default_pwmngr`](PinentryBuilder::default_pwmngr), without consuming the builder
+    pub fn set_default_pwmngr<S: Into<String>>(&mut self, label: S) -> &mut Self {
+        self.option("default-pwmngr", label.into());
+        self
+    }
+
+    /// Like [`constraints_enforce`](PinentryBuilder::constraints_enforce), without consuming the builder
+    pub fn set_constraints_enforce(&mut self, enforce: bool) -> &mut Self {
+        self.flag("constraints-enforce", enforce);
+        self
+    }
+
+    /// Like [`constraints_hint_short`](PinentryBuilder::constraints_hint_short), without consuming the builder
+    pub fn set_constraints_hint_short<S: Into<String>>(&mut self, hint: S) -> &mut Self {
+        self.option("constraints-hint-short", assuan::plus_escape(&hint.into()));
+        self
+    }
+
+    /// Like [`constraints_hint_long`](PinentryBuilder::constraints_hint_long), without consuming the builder
+    pub fn set_constraints_hint_long<S: Into<String>>(&mut self, hint: S) -> &mut Self {
+        self.option("constraints-hint-long", assuan::plus_escape(&hint.into()));
+        self
+    }
+
+    /// Like [`constraints_error_title`](PinentryBuilder::constraints_error_title), without consuming the builder
+    pub fn set_constraints_error_title<S: Into<String>>(&mut self, title: S) -> &mut Self {
+        self.option("constraints-error-title", assuan::plus_escape(&title.into()));
+        self
+    }
+
+    /// Like [`formatted_passphrase`](PinentryBuilder::formatted_passphrase), without consuming the builder
+    pub fn set_formatted_passphrase(&mut self, formatted: bool) -> &mut Self {
+        self.flag("formatted-passphrase", formatted);
+        self
+    }
+
+    /// Like [`formatted_passphrase_hint`](PinentryBuilder::formatted_passphrase_hint), without consuming the builder
+    pub fn set_formatted_passphrase_hint<S: Into<String>>(&mut self, hint: S) -> &mut Self {
+        self.option("formatted-passphrase-hint", assuan::plus_escape(&hint.into()));
+        self
+    }
+
+    /// Like [`invisible_char`](PinentryBuilder::invisible_char), without consuming the builder
+    pub fn set_invisible_char(&mut self, c: char) -> &mut Self {
+        self.option("invisible-char", c.to_string());
+        self
+    }
+
+    /// Like [`touch_file`](PinentryBuilder::touch_file), without consuming the builder
+    pub fn set_touch_file<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        let path = path.as_ref();
+        match path.to_str() {
+            Some(value) => {
+                self.invalid_touch_file = None;
+                self.option("touch-file", value.to_string());
+            }
+            None => {
+                self.invalid_touch_file = Some(path.to_path_buf());
+                self.remove_option("touch-file");
+            }
+        }
+        self
+    }
+
+    /// Like [`owner_pid`](PinentryBuilder::owner_pid), without consuming the builder
+    pub fn set_owner_pid(&mut self, pid: u32) -> &mut Self {
+        self.option("owner", pid.to_string());
+        self
+    }
+
+    /// Like [`inherit_terminal_env`](PinentryBuilder::inherit_terminal_env), without consuming the builder
+    pub fn apply_terminal_env(&mut self) -> &mut Self {
+        for (name, value) in terminal_options(|var| std::env::var(var).ok()) {
+            if !self.has_option(name) {
+                self.option(name, value);
+            }
+        }
+        self
+    }
+
+    /// Like [`label_cancel`](PinentryBuilder::label_cancel), without consuming the builder
+    pub fn set_label_cancel<S: Into<String>>(&mut self, label: S) -> &mut Self {
+        self.settings.label_cancel = Some(label.into());
+        self
+    }
+
+    /// Like [`label_notok`](PinentryBuilder::label_notok), without consuming the builder
+    pub fn set_label_notok<S: Into<String>>(&mut self, label: S) -> &mut Self {
+        self.settings.label_notok = Some(label.into());
+        self
+    }
+
+    /// Like [`label_ok`](PinentryBuilder::label_ok), without consuming the builder
+    pub fn set_label_ok<S: Into<String>>(&mut self, label: S) -> &mut Self {
+        self.settings.label_ok = Some(label.into());
+        self
+    }
+
+    /// Like [`repeat`](PinentryBuilder::repeat), without consuming the builder
+    pub fn set_repeat<S: Into<String>>(&mut self, prompt: S) -> &mut Self {
+        self.settings.repeat = Some(prompt.into());
+        self
+    }
+
+    /// Like [`repeat_error`](PinentryBuilder::repeat_error), without consuming the builder
+    pub fn set_repeat_error<S: Into<String>>(&mut self, error_text: S) -> &mut Self {
+        self.settings.repeat_error = Some(error_text.into());
+        self
+    }
+
+    /// Like [`keyinfo`](PinentryBuilder::keyinfo), without consuming the builder
+    pub fn set_keyinfo<S: Into<String>>(&mut self, key_info: S) -> &mut Self {
+        self.settings.key_info = Some(key_info.into());
+        self
+    }
+
+    /// Like [`allow_external_cache`](PinentryBuilder::allow_external_cache), without consuming the builder
+    pub fn set_allow_external_cache(&mut self, allow: bool) -> &mut Self {
+        self.settings.external_cache = allow;
+        self
+    }
+
+    /// Like [`quality_bar`](PinentryBuilder::quality_bar), without consuming the builder
+    pub fn set_quality_bar<S: Into<String>>(&mut self, tooltip: S) -> &mut Self {
+        self.settings.quality_bar = Some(tooltip.into());
+        self
+    }
+
+    /// Like [`quality_fn`](PinentryBuilder::quality_fn), without consuming the builder
+    pub fn set_quality_fn<F: Fn(&str) -> i32 + Send + Sync + 'static>(&mut self, quality: F) -> &mut Self {
+        self.settings.quality_fn = Some(Arc::new(quality));
+        self
+    }
+
+    /// Like [`genpin_label`](PinentryBuilder::genpin_label), without consuming the builder
+    pub fn set_genpin_label<S: Into<String>>(&mut self, label: S) -> &mut Self {
+        self.settings.genpin = Some(label.into());
+        self
+    }
+
+    /// Like [`genpin_tooltip`](PinentryBuilder::genpin_tooltip), without consuming the builder
+    pub fn set_genpin_tooltip<S: Into<String>>(&mut self, tooltip: S) -> &mut Self {
+        self.settings.genpin_tooltip = Some(tooltip.into());
+        self
+    }
+
+    /// Like [`genpin_fn`](PinentryBuilder::genpin_fn), without consuming the builder
+    pub fn set_genpin_fn<F: Fn() -> SecretPin + Send + Sync + 'static>(&mut self, generate: F) -> &mut Self {
+        self.settings.genpin_fn = Some(Arc::new(generate));
+        self
+    }
+
+    /// Like [`constraints_fn`](PinentryBuilder::constraints_fn), without consuming the builder
+    pub fn set_constraints_fn<F: Fn(&str) -> Option<String> + Send + Sync + 'static>(&mut self, check: F) -> &mut Self {
+        self.settings.constraints_fn = Some(Arc::new(check));
+        self
+    }
+
+    /// Like [`max_attempts`](PinentryBuilder::max_attempts), without consuming the builder
+    pub fn set_max_attempts(&mut self, attempts: u32) -> &mut Self {
+        self.settings.max_attempts = Some(attempts);
+        self
+    }
+
+    /// Like [`normalization`](PinentryBuilder::normalization), without consuming the builder
+    pub fn set_normalization(&mut self, normalization: Normalization) -> &mut Self {
+        self.settings.normalization = Some(normalization);
+        self
+    }
+
+    /// Like [`timeout`](PinentryBuilder::timeout), without consuming the builder
+    pub fn set_timeout(&mut self, secs: u32) -> &mut Self {
+        self.settings.timeout = Some(secs);
+        self
+    }
+
+    /// Like [`window_title`](PinentryBuilder::window_title), without consuming the builder
+    pub fn set_window_title<S: Into<String>>(&mut self, title: S) -> &mut Self {
+        self.settings.window_title = Some(title.into());
+        self
+    }
+
+    /// Like [`command_filter`](PinentryBuilder::command_filter), without consuming the builder
+    pub fn set_command_filter(&mut self, filter: CommandFilter) -> &mut Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Like [`policy`](PinentryBuilder::policy), without consuming the builder
+    pub fn set_policy<P: PromptPolicy + 'static>(&mut self, policy: P) -> &mut Self {
+        self.policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Like [`rate_limit`](PinentryBuilder::rate_limit), without consuming the builder
+    pub fn set_rate_limit(&mut self, limit: RateLimit) -> &mut Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Like [`respawn`](PinentryBuilder::respawn), without consuming the builder
+    pub fn set_respawn(&mut self, respawn: bool) -> &mut Self {
+        self.respawn = respawn;
+        self
+    }
+
+    /// Like [`sandbox`](PinentryBuilder::sandbox), without consuming the builder
+    #[cfg(all(feature = "sandbox", target_os = "linux"))]
+    pub fn set_sandbox(&mut self, sandbox: sandbox::Sandbox) -> &mut Self {
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    /// Like [`backend`](PinentryBuilder::backend), without consuming the builder
+    #[cfg(feature = "process")]
+    pub fn set_backend<B: backend::Backend + Clone + 'static>(&mut self, backend: B) -> &mut Self {
+        let backend = Mutex::new(backend);
+        self.backend = Some(Arc::new(move || {
+            let backend = backend.lock().unwrap_or_else(|e| e.into_inner()).clone();
+            Box::new(backend::InProcess::new(backend)) as Box<dyn Transport>
+        }));
+        self
+    }
+
+    /// Like [`cancel_token`](PinentryBuilder::cancel_token), without consuming the builder
+    #[cfg(feature = "process")]
+    pub fn set_cancel_token(&mut self, token: cancel::CancelToken) -> &mut Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Like [`hard_timeout`](PinentryBuilder::hard_timeout), without consuming the builder
+    #[cfg(feature = "process")]
+    pub fn set_hard_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.hard_timeout = Some(timeout);
+        self
+    }
+}
+
+#[cfg_attr(not(feature = "process"), allow(clippy::derivable_impls))]
+impl Default for PinentryBuilder {
+    fn default() -> Self {
+        PinentryBuilder {
+            #[cfg(feature = "process")]
+            backend: None,
+            #[cfg(feature = "process")]
+            cancel: None,
+            #[cfg(feature = "process")]
+            exe: OsString::from("pinentry"),
+            filter: None,
+            #[cfg(feature = "process")]
+            hard_timeout: None,
+            invalid_touch_file: None,
+            #[cfg(feature = "process")]
+            launch: LaunchSettings::default(),
+            #[cfg(all(feature = "hardening", unix))]
+            no_core_dumps: false,
+            options: Vec::new(),
+            policy: None,
+            rate_limit: None,
+            respawn: false,
+            #[cfg(all(feature = "sandbox", target_os = "linux"))]
+            sandbox: None,
+            settings: PromptSettings::default(),
+            #[cfg(all(feature = "tty-fallback", unix))]
+            tty_fallback: false,
         }
     }
+}
+
+/// The options describing the terminal and the locale, from the environment variables returned by `var`
+fn terminal_options<F: Fn(&str) -> Option<String>>(var: F) -> Vec<(&'static str, String)> {
+    let var = |name: &str| var(name).filter(|value| !value.is_empty());
+    let locale = |category: &str| var("LC_ALL").or_else(|| var(category)).or_else(|| var("LANG"));
+    [
+        ("ttyname", var("GPG_TTY")),
+        ("ttytype", var("TERM")),
+        ("display", var("DISPLAY")),
+        ("lc-ctype", locale("LC_CTYPE")),
+        ("lc-messages", locale("LC_MESSAGES")),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| value.map(|value| (name, value)))
+    .collect()
+}
+
+/// Kinds of prompts, for checking whether settings apply to them
+#[derive(Clone, Copy, PartialEq)]
+enum PromptKind {
+    Pin,
+    Unlock,
+    Choice,
+    Confirm,
+    Message,
+}
+
+/// What the defaults of a session provide to the prompts made through it, for validating their settings
+#[derive(Clone, Copy, Default)]
+struct Inherited {
+    quality_fn: bool,
+    external_cache: bool,
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidConfiguration(reason.to_string())
+}
+
+impl PromptSettings {
+    /// Reject settings that contradict each other, or that have no effect on `kind` of prompt (if known already)
+    fn validate(&self, kind: Option<PromptKind>) -> Result<()> {
+        self.validate_on(kind, Inherited::default())
+    }
 
-    fn build_commands(&mut self) -> Vec<AssuanCommand> {
+    /// Same as `validate`, for settings layered on the defaults of a session
+    fn validate_on(&self, kind: Option<PromptKind>, inherited: Inherited) -> Result<()> {
+        if self.max_attempts == Some(0) {
+            return Err(invalid("max_attempts must be at least 1"));
+        }
+        if let Some(ref key_info) = self.key_info {
+            if key_info.is_empty() || key_info.contains(char::is_whitespace) {
+                return Err(invalid("the key info must be a single word"));
+            }
+            if !self.external_cache && !inherited.external_cache {
+                return Err(invalid("the key info only applies with allow_external_cache"));
+            }
+        }
+        // the bar would never move
+        if self.quality_bar.is_some() && self.quality_fn.is_none() && !inherited.quality_fn {
+            return Err(invalid("the quality bar needs a quality_fn"));
+        }
+        let kind = match kind {
+            Some(kind) => kind,
+            None => return Ok(()),
+        };
+        if self.max_attempts.is_some() && kind != PromptKind::Unlock {
+            return Err(invalid("max_attempts only applies to unlock()"));
+        }
+        if self.normalization.is_some() && !matches!(kind, PromptKind::Pin | PromptKind::Unlock) {
+            return Err(invalid("normalization only applies to PIN prompts"));
+        }
+        if self.repeat.is_some() && !matches!(kind, PromptKind::Pin | PromptKind::Unlock) {
+            return Err(invalid("repeat only applies to PIN prompts"));
+        }
+        let has_quality = self.quality_bar.is_some() || self.quality_fn.is_some();
+        if has_quality && !matches!(kind, PromptKind::Pin | PromptKind::Unlock) {
+            return Err(invalid("the quality bar only applies to PIN prompts"));
+        }
+        let has_genpin = self.genpin.is_some() || self.genpin_tooltip.is_some() || self.genpin_fn.is_some();
+        if has_genpin && !matches!(kind, PromptKind::Pin | PromptKind::Unlock) {
+            return Err(invalid("generated PINs only apply to PIN prompts"));
+        }
+        if self.constraints_fn.is_some() && !matches!(kind, PromptKind::Pin | PromptKind::Unlock) {
+            return Err(invalid("passphrase constraints only apply to PIN prompts"));
+        }
+        let has_cache = self.key_info.is_some() || self.external_cache;
+        if has_cache && !matches!(kind, PromptKind::Pin | PromptKind::Unlock) {
+            return Err(invalid("the password cache only applies to PIN prompts"));
+        }
+        Ok(())
+    }
+
+    fn into_commands(mut self) -> Vec<AssuanCommand> {
         let mut cmds = Vec::new();
 
         if let Some(desc) = self.description.take() {
@@ -208,61 +1703,255 @@ impl PinentryBuilder {
         if let Some(ok_label) = self.label_ok.take() {
             cmds.push(AssuanCommand::SetButtonLabel(Button::OK, ok_label));
         }
+        if let Some(tooltip) = self.quality_bar.take() {
+            cmds.push(AssuanCommand::SetQualityBar(None));
+            cmds.push(AssuanCommand::SetQualityBarTooltip(tooltip));
+        }
+        if let Some(prompt) = self.repeat.take() {
+            cmds.push(AssuanCommand::SetRepeat(Some(prompt)));
+        }
+        if let Some(text) = self.repeat_error.take() {
+            cmds.push(AssuanCommand::SetRepeatError(text));
+        }
+        if let Some(label) = self.genpin.take() {
+            cmds.push(AssuanCommand::SetGenPin(label));
+        }
+        if let Some(tooltip) = self.genpin_tooltip.take() {
+            cmds.push(AssuanCommand::SetGenPinTooltip(tooltip));
+        }
         if let Some(timeout) = self.timeout {
             cmds.push(AssuanCommand::SetTimeout(timeout));
         }
         if let Some(title) = self.window_title.take() {
             cmds.push(AssuanCommand::SetWindowTitle(title));
         }
+        if self.external_cache {
+            cmds.push(AssuanCommand::Option("allow-external-password-cache".to_string(), None));
+        }
+        if let Some(key_info) = self.key_info.take() {
+            cmds.push(AssuanCommand::SetKeyInfo(key_info));
+        }
 
         cmds
     }
 }
 
-impl Default for PinentryBuilder {
-    fn default() -> Self {
-        PinentryBuilder {
-            description: None,
-            error_text: None,
-            exe: "pinentry".to_string(),
-            label_cancel: None,
-            label_notok: None,
-            label_ok: None,
-            timeout: None,
-            window_title: None,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid_reason(res: Result<()>) -> String {
+        match res {
+            Err(Error::InvalidConfiguration(reason)) => reason,
+            x => panic!("unexpected result {:?}", x),
         }
     }
-}
 
-fn start_pinentry<S: AsRef<OsStr>>(exe: S) -> Result<Child> {
-    let mut pinentry = Command::new(exe)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .expect("failed to execute pinentry");
+    #[test]
+    fn test_terminal_options() {
+        let env = |var: &str| match var {
+            "GPG_TTY" => Some("/dev/pts/3".to_string()),
+            "TERM" => Some("xterm".to_string()),
+            "DISPLAY" => Some(String::new()),
+            "LC_MESSAGES" => Some("de_DE.UTF-8".to_string()),
+            "LANG" => Some("C.UTF-8".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            vec![
+                ("ttyname", "/dev/pts/3".to_string()),
+                ("ttytype", "xterm".to_string()),
+                ("lc-ctype", "C.UTF-8".to_string()),
+                ("lc-messages", "de_DE.UTF-8".to_string()),
+            ],
+            terminal_options(env)
+        );
 
-    {
-        // Check whether next line starts with OK
-        let stdout = pinentry.stdout.as_mut().expect("failed to get stdout");
-        let mut reader = BufReader::new(stdout);
+        let builder = pinentry()
+            .default_ok("_Weiter")
+            .default_cancel("_Abbrechen")
+            .locale("de_DE.UTF-8", "de_DE.UTF-8".to_string());
+        assert_eq!(
+            vec![
+                AssuanCommand::Option("default-ok".to_string(), Some("_Weiter".to_string())),
+                AssuanCommand::Option("default-cancel".to_string(), Some("_Abbrechen".to_string())),
+                AssuanCommand::Option("lc-ctype".to_string(), Some("de_DE.UTF-8".to_string())),
+                AssuanCommand::Option("lc-messages".to_string(), Some("de_DE.UTF-8".to_string())),
+            ],
+            builder.options
+        );
 
-        let mut line = String::with_capacity(32);
-        let _ = reader.read_line(&mut line)?;
-        if !line.starts_with("OK") {
-            return Err(Error::ProtocolError(line));
+        let builder = pinentry().tty_name("/dev/tty1").lc_ctype("C").tty_name("/dev/tty2");
+        assert_eq!(
+            vec![
+                AssuanCommand::Option("lc-ctype".to_string(), Some("C".to_string())),
+                AssuanCommand::Option("ttyname".to_string(), Some("/dev/tty2".to_string())),
+            ],
+            builder.options
+        );
+    }
+
+    #[test]
+    fn test_error_from_response() {
+        let error = |line: &str| Error::from_response(line.to_string());
+        assert!(matches!(
+            error("ERR 83886179 Operation cancelled <Pinentry>"),
+            Error::Cancelled
+        ));
+        assert!(matches!(error("ERR 83886142 Timeout <Pinentry>"), Error::Timeout));
+        assert!(matches!(
+            error("ERR 83886194 Not confirmed <Pinentry>"),
+            Error::NotConfirmed
+        ));
+        match error("ERR 536871187 Unknown IPC command <User defined source 1>") {
+            Error::PinentryError(e) => assert_eq!(AssuanError::ASS_UNKNOWN_CMD, e.error_code()),
+            x => panic!("unexpected error {:?}", x),
         }
+        assert!(matches!(error("unexpected data"), Error::ProtocolError(_)));
+        assert_eq!(
+            Some(AssuanError::CANCELED),
+            Error::Cancelled.assuan_error().map(|e| e.error_code())
+        );
     }
 
-    Ok(pinentry)
-}
+    #[test]
+    fn test_validate_settings() {
+        let settings = PromptSettings {
+            max_attempts: Some(5),
+            normalization: Some(Normalization::new()),
+            ..PromptSettings::default()
+        };
+        assert!(settings.validate(None).is_ok());
+        assert!(settings.validate(Some(PromptKind::Unlock)).is_ok());
+        assert_eq!(
+            "max_attempts only applies to unlock()",
+            invalid_reason(settings.validate(Some(PromptKind::Pin)))
+        );
+
+        let settings = PromptSettings {
+            normalization: Some(Normalization::new()),
+            ..PromptSettings::default()
+        };
+        assert!(settings.validate(Some(PromptKind::Pin)).is_ok());
+        assert_eq!(
+            "normalization only applies to PIN prompts",
+            invalid_reason(settings.validate(Some(PromptKind::Confirm)))
+        );
 
-fn process_commands(mut pinentry: Child, cmds: &[AssuanCommand]) -> Result<AssuanResponse> {
-    let res = {
-        let mut stdout = BufReader::new(pinentry.stdout.as_mut().expect("failed to get stdout"));
-        let stdin = pinentry.stdin.as_mut().expect("failed to get stdin");
+        let settings = PromptSettings {
+            max_attempts: Some(0),
+            ..PromptSettings::default()
+        };
+        assert_eq!(
+            "max_attempts must be at least 1",
+            invalid_reason(settings.validate(None))
+        );
 
-        assuan::process_commands(cmds.iter(), stdin, &mut stdout)?
-    };
-    pinentry.kill()?;
-    Ok(res)
+        let settings = PromptSettings {
+            repeat: Some("Repeat:".to_string()),
+            ..PromptSettings::default()
+        };
+        assert!(settings.validate(Some(PromptKind::Pin)).is_ok());
+        assert_eq!(
+            "repeat only applies to PIN prompts",
+            invalid_reason(settings.validate(Some(PromptKind::Message)))
+        );
+
+        let settings = PromptSettings {
+            quality_bar: Some("Strength".to_string()),
+            quality_fn: Some(Arc::new(|pin: &str| pin.len() as i32)),
+            ..PromptSettings::default()
+        };
+        assert!(settings.validate(Some(PromptKind::Unlock)).is_ok());
+        assert_eq!(
+            "the quality bar only applies to PIN prompts",
+            invalid_reason(settings.validate(Some(PromptKind::Confirm)))
+        );
+
+        // a quality bar is filled in by a quality_fn, of the prompt or of the session
+        let settings = PromptSettings {
+            quality_bar: Some("Strength".to_string()),
+            ..PromptSettings::default()
+        };
+        assert_eq!(
+            "the quality bar needs a quality_fn",
+            invalid_reason(settings.validate(None))
+        );
+        let inherited = Inherited {
+            quality_fn: true,
+            ..Inherited::default()
+        };
+        assert!(settings.validate_on(Some(PromptKind::Pin), inherited).is_ok());
+
+        // the key info only identifies the passphrase in the external password cache
+        let settings = PromptSettings {
+            key_info: Some("n/0123ABCD".to_string()),
+            ..PromptSettings::default()
+        };
+        assert_eq!(
+            "the key info only applies with allow_external_cache",
+            invalid_reason(settings.validate(Some(PromptKind::Pin)))
+        );
+        let inherited = Inherited {
+            external_cache: true,
+            ..Inherited::default()
+        };
+        assert!(settings.validate_on(Some(PromptKind::Pin), inherited).is_ok());
+        let settings = PromptSettings {
+            external_cache: true,
+            ..settings
+        };
+        assert!(settings.validate(Some(PromptKind::Pin)).is_ok());
+    }
+
+    #[test]
+    fn test_mut_setters() {
+        let mut builder = pinentry();
+        builder
+            .set_description("Unlock the backup volume")
+            .set_label_ok("_Unlock");
+        if builder.options.is_empty() {
+            builder.set_tty_name(String::from("/dev/tty1"));
+        }
+        let builder = builder.window_title("Backup");
+        assert_eq!(
+            Some("Unlock the backup volume"),
+            builder.settings.description.as_deref()
+        );
+        assert_eq!(Some("_Unlock"), builder.settings.label_ok.as_deref());
+        assert_eq!(Some("Backup"), builder.settings.window_title.as_deref());
+        assert!(builder.has_option("ttyname"));
+        assert!(builder.check_options().is_ok());
+
+        // texts are escaped when they are sent, but option values cannot be
+        let builder = pinentry().description("Line 1\nLine 2").tty_name("/dev/tty1\nGETPIN");
+        assert_eq!(
+            "the value of option ttyname may not contain line breaks",
+            invalid_reason(builder.check_options())
+        );
+        assert!(pinentry().invisible_char('•').check_options().is_ok());
+        assert_eq!(
+            "the invisible character may not be whitespace or a control character",
+            invalid_reason(pinentry().invisible_char(' ').check_options())
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_touch_file_not_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(OsStr::from_bytes(b"/run/user/1000/S.\xff"));
+        let builder = pinentry().touch_file("/run/user/1000/S.gpg-agent").touch_file(path);
+        assert!(!builder.has_option("touch-file"));
+        assert_eq!(
+            "the touch file /run/user/1000/S.\u{FFFD} is not valid UTF-8",
+            invalid_reason(builder.check_options())
+        );
+        // a valid path replaces the invalid one
+        let builder = builder.touch_file("/run/user/1000/S.gpg-agent");
+        assert!(builder.has_option("touch-file"));
+        assert!(builder.check_options().is_ok());
+    }
 }
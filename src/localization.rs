@@ -0,0 +1,254 @@
+//! Translations of the texts generated by the library, from [Fluent](https://projectfluent.org) resources (with the
+//! `fluent` feature)
+//!
+//! The library generates default titles, descriptions, prompts and labels (e.g. for [`NewPassphrase`] or
+//! [`TokenPin`]), retry messages, the errors of its own checks and lockout notices. They are in English unless a
+//! [`Localization`] is installed:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use pinentry_rs::localization::{self, Localization};
+//!
+//! localization::install(Localization::from_env().load_dir("/usr/share/myapp/locales")?);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! `locales/en/pinentry-rs.ftl` in the source of this crate has all messages with their English texts, as the template
+//! for translations. Messages missing from the translations are shown in English.
+//!
+//! [`NewPassphrase`]: super::passphrase::NewPassphrase
+//! [`TokenPin`]: super::token::TokenPin
+
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use fluent_bundle::concurrent::FluentBundle;
+use fluent_bundle::{FluentArgs, FluentResource};
+use fluent_langneg::{negotiate_languages, NegotiationStrategy};
+use unic_langid::LanguageIdentifier;
+
+use super::{invalid, Error, Result};
+
+/// The localization used for the texts of the library, if one is installed
+static INSTALLED: RwLock<Option<Arc<Localization>>> = RwLock::new(None);
+
+/// Fluent resources for a number of locales, and the locales requested by the user
+pub struct Localization {
+    requested: Vec<LanguageIdentifier>,
+    bundles: Vec<(LanguageIdentifier, FluentBundle<FluentResource>)>,
+}
+
+impl Localization {
+    /// Translate to the `requested` locales (e.g. `de-CH`, `de`), in order of preference
+    ///
+    /// Tags that are not language identifiers (such as `C` or `POSIX`) are ignored.
+    pub fn new<I, S>(requested: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        Localization {
+            requested: requested.into_iter().filter_map(|tag| locale(tag.as_ref())).collect(),
+            bundles: Vec::new(),
+        }
+    }
+
+    /// Translate to the locales of the environment: `LANGUAGE` (a colon-separated list), then `LC_ALL`,
+    /// `LC_MESSAGES` or `LANG` (e.g. `de_CH.UTF-8`)
+    pub fn from_env() -> Self {
+        let mut requested: Vec<String> = std::env::var("LANGUAGE")
+            .map(|languages| languages.split(':').map(str::to_string).collect())
+            .unwrap_or_default();
+        if let Some(lang) = ["LC_ALL", "LC_MESSAGES", "LANG"]
+            .iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|lang| !lang.is_empty())
+        {
+            requested.push(lang);
+        }
+        Localization::new(requested)
+    }
+
+    /// Add the messages of a Fluent resource for `locale`, overriding the messages of previous resources
+    pub fn add_resource(mut self, locale_tag: &str, source: &str) -> Result<Self> {
+        let locale = locale(locale_tag).ok_or_else(|| invalid(&format!("invalid locale: {}", locale_tag)))?;
+        let resource = FluentResource::try_new(source.to_string()).map_err(|(_, errors)| {
+            Error::InvalidConfiguration(format!("invalid Fluent resource for {}: {:?}", locale_tag, errors[0]))
+        })?;
+        match self.bundles.iter_mut().find(|(l, _)| *l == locale) {
+            Some((_, bundle)) => bundle.add_resource_overriding(resource),
+            None => {
+                let mut bundle = FluentBundle::new_concurrent(vec![locale.clone()]);
+                // the texts are sent to pinentry, which shows them as they are
+                bundle.set_use_isolating(false);
+                bundle.add_resource_overriding(resource);
+                self.bundles.push((locale, bundle));
+            }
+        }
+        Ok(self)
+    }
+
+    /// Add the resources in `dir/<locale>/*.ftl` (e.g. `dir/de-CH/myapp.ftl`)
+    ///
+    /// Entries of `dir` that are not named after a locale are ignored.
+    pub fn load_dir<P: AsRef<Path>>(mut self, dir: P) -> Result<Self> {
+        let mut locales = fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        locales.sort();
+        for path in locales.into_iter().filter(|path| path.is_dir()) {
+            let tag = match path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .filter(|tag| locale(tag).is_some())
+            {
+                Some(tag) => tag.to_string(),
+                None => continue,
+            };
+            let mut files = fs::read_dir(&path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<std::io::Result<Vec<_>>>()?;
+            files.sort();
+            for file in files
+                .into_iter()
+                .filter(|file| file.extension().is_some_and(|ext| ext == "ftl"))
+            {
+                self = self.add_resource(&tag, &fs::read_to_string(file)?)?;
+            }
+        }
+        Ok(self)
+    }
+
+    /// The locales with resources that are used, in order of preference
+    pub fn locales(&self) -> Vec<&LanguageIdentifier> {
+        let available: Vec<&LanguageIdentifier> = self.bundles.iter().map(|(locale, _)| locale).collect();
+        negotiate_languages(&self.requested, &available, None, NegotiationStrategy::Filtering)
+            .into_iter()
+            .copied()
+            .collect()
+    }
+
+    /// The message `id` in the most preferred locale that has it
+    pub(crate) fn format(&self, id: &str, args: &FluentArgs) -> Option<String> {
+        self.locales().into_iter().find_map(|locale| {
+            let (_, bundle) = self.bundles.iter().find(|(l, _)| l == locale)?;
+            let pattern = bundle.get_message(id)?.value()?;
+            let mut errors = Vec::new();
+            let text = bundle.format_pattern(pattern, Some(args), &mut errors);
+            match errors.is_empty() {
+                true => Some(text.into_owned()),
+                false => {
+                    debug!("Could not format {} for {}: {:?}", id, locale, errors);
+                    None
+                }
+            }
+        })
+    }
+}
+
+impl Debug for Localization {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let locales: Vec<&LanguageIdentifier> = self.bundles.iter().map(|(locale, _)| locale).collect();
+        f.debug_struct("Localization")
+            .field("requested", &self.requested)
+            .field("bundles", &locales)
+            .finish()
+    }
+}
+
+/// Use `localization` for the texts of the library, in the whole process
+pub fn install(localization: Localization) {
+    *INSTALLED.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(localization));
+}
+
+/// Go back to the English texts
+pub fn uninstall() {
+    *INSTALLED.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// The message `id` translated by the installed localization
+pub(crate) fn translate(id: &str, args: &FluentArgs) -> Option<String> {
+    let installed = INSTALLED.read().unwrap_or_else(|e| e.into_inner()).clone();
+    installed.and_then(|localization| localization.format(id, args))
+}
+
+/// Parse a language tag, accepting POSIX locale names (`de_CH.UTF-8@euro`)
+fn locale(tag: &str) -> Option<LanguageIdentifier> {
+    let tag = tag.split(['.', '@']).next().unwrap_or_default().replace('_', "-");
+    tag.parse().ok().filter(|_| !tag.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::unlock::UnlockError;
+    use super::*;
+
+    const GERMAN: &str = r#"
+locked-out = Nach { $attempts } falschen Versuchen gesperrt
+attempts-left = { $message } ({ $left ->
+    [one] noch 1 Versuch
+   *[other] noch { $left } Versuche
+})
+"#;
+
+    #[test]
+    fn test_locale() {
+        assert_eq!(Some("de-CH".parse().unwrap()), locale("de_CH.UTF-8@euro"));
+        assert_eq!(Some("fr".parse().unwrap()), locale("fr"));
+        assert_eq!(None, locale("C"));
+        assert_eq!(None, locale(""));
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let localization = Localization::new(["de-CH", "fr"])
+            .add_resource("fr", "locked-out = Verrouillé")
+            .unwrap()
+            .add_resource("de", GERMAN)
+            .unwrap()
+            .add_resource("it", "locked-out = Bloccato")
+            .unwrap();
+        let locales: Vec<String> = localization.locales().iter().map(|l| l.to_string()).collect();
+        assert_eq!(vec!["de", "fr"], locales);
+
+        let mut args = FluentArgs::new();
+        args.set("message", "Falsche PIN");
+        args.set("left", 1);
+        assert_eq!(
+            Some("Falsche PIN (noch 1 Versuch)".to_string()),
+            localization.format("attempts-left", &args)
+        );
+        args.set("left", 2);
+        assert_eq!(
+            Some("Falsche PIN (noch 2 Versuche)".to_string()),
+            localization.format("attempts-left", &args)
+        );
+        assert_eq!(None, localization.format("luks-title", &args));
+    }
+
+    #[test]
+    fn test_invalid_resource() {
+        match Localization::new(["de"]).add_resource("de", "locked-out = {") {
+            Err(Error::InvalidConfiguration(_)) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_install() {
+        // only locked-out is translated, so other tests see their English texts
+        let german = "locked-out = Nach { $attempts } falschen Versuchen gesperrt";
+        install(Localization::new(["de"]).add_resource("de", german).unwrap());
+        let text = UnlockError::<Error>::LockedOut { attempts: 3 }.to_string();
+        uninstall();
+        assert_eq!("Nach 3 falschen Versuchen gesperrt", text);
+        assert_eq!(
+            "Locked out after 3 wrong attempts",
+            UnlockError::<Error>::LockedOut { attempts: 3 }.to_string()
+        );
+    }
+}
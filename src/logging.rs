@@ -0,0 +1,83 @@
+//! Diagnostics through the [`log`](https://docs.rs/log) crate (with the `log` feature) or as
+//! [`tracing`](https://docs.rs/tracing) events (with the `tracing` feature)
+//!
+//! Protocol lines are logged at `trace` level and the lifecycle of pinentry (started, closed, respawned) and failures
+//! at `debug` level, so normal operation is silent. The contents of data lines (PINs) and the parameters of
+//! inquiries (which may carry the PIN typed so far) are never logged.
+//!
+//! Without either feature the macros expand to nothing (the arguments are still type-checked).
+
+macro_rules! debug {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "log")]
+        ::log::debug!(target: "pinentry_rs", $($arg)*);
+        #[cfg(feature = "tracing")]
+        ::tracing::debug!(target: "pinentry_rs", $($arg)*);
+        #[cfg(not(any(feature = "log", feature = "tracing")))]
+        if false {
+            let _ = format!($($arg)*);
+        }
+    }};
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "log")]
+        ::log::trace!(target: "pinentry_rs", $($arg)*);
+        #[cfg(feature = "tracing")]
+        ::tracing::trace!(target: "pinentry_rs", $($arg)*);
+        #[cfg(not(any(feature = "log", feature = "tracing")))]
+        if false {
+            let _ = format!($($arg)*);
+        }
+    }};
+}
+
+#[cfg(all(test, unix, feature = "log", feature = "process"))]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::super::SecretPin;
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+
+    use super::super::pinentry;
+    use super::super::test_util::FakePinentry;
+
+    static RECORDS: Mutex<Vec<(Level, String)>> = Mutex::new(Vec::new());
+
+    struct Capture;
+
+    impl Log for Capture {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            if record.target() == "pinentry_rs" {
+                let mut records = RECORDS.lock().unwrap();
+                records.push((record.level(), record.args().to_string()));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_log_is_redacted() {
+        log::set_logger(&Capture).expect("no logger is set yet");
+        log::set_max_level(LevelFilter::Trace);
+
+        let fake = FakePinentry::new(&[("GETPIN", r#"echo "D hunter2-logged"; echo OK"#)]);
+        let pin = pinentry().exe(fake.exe()).pin("PIN:").unwrap();
+        assert_eq!(SecretPin::from("hunter2-logged"), pin);
+
+        let records = RECORDS.lock().unwrap();
+        let has = |level: Level, msg: &str| records.iter().any(|(l, m)| *l == level && m == msg);
+        assert!(has(Level::Trace, "> GETPIN"));
+        assert!(has(Level::Trace, "< D [redacted]"));
+        assert!(records
+            .iter()
+            .all(|(l, m)| *l <= Level::Debug || !m.contains("hunter2")));
+        assert!(records.iter().all(|(_, m)| !m.contains("hunter2")));
+    }
+}
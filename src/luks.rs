@@ -0,0 +1,270 @@
+//! Unlocking LUKS volumes (e.g. with [`libcryptsetup-rs`](https://crates.io/crates/libcryptsetup-rs))
+//!
+//! [`LuksVolume`] describes the volume to unlock; [`unlock_volume()`](SessionPrompt::unlock_volume) then prompts with
+//! the usual disk-unlock texts and hands each passphrase to an activation callback, the way `systemd-cryptsetup` and
+//! `cryptsetup open` do:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # #[cfg(feature = "process")]
+//! # fn run() -> Result<(), pinentry_rs::unlock::UnlockError<std::io::Error>> {
+//! use pinentry_rs::luks::{verify_activation, LuksVolume};
+//! use pinentry_rs::pinentry;
+//!
+//! # fn activate_by_passphrase(_: &str, _: &[u8]) -> std::io::Result<()> { Ok(()) }
+//! let volume = LuksVolume::new("cryptroot".to_string())
+//!     .uuid("0a1b2c3d-4e5f-6789-abcd-ef0123456789")
+//!     .device("/dev/nvme0n1p2".into());
+//! pinentry().unlock_volume(&volume, |passphrase| {
+//!     verify_activation(activate_by_passphrase("cryptroot", passphrase))
+//! })?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! If the volume has a UUID, it is used as the key for the external password cache of pinentry (e.g. the GNOME
+//! keyring), so the user can choose to have the passphrase remembered; a remembered passphrase that no longer works
+//! is cleared. Run `cargo run --example luks-keyscript` for a `crypttab` keyscript built on this.
+
+use std::io;
+use std::path::PathBuf;
+use std::result;
+
+use super::assuan::AssuanCommand;
+use super::messages::Message;
+use super::session::{attempts_left, set_error_text, PinentrySession, SessionPrompt};
+use super::unlock::{UnlockError, VerifyError};
+#[cfg(feature = "process")]
+use super::PinentryBuilder;
+use super::{PromptKind, Result, SecretPin};
+
+/// The error text shown when the activation rejects a passphrase
+pub const WRONG_PASSPHRASE: &str = "No key available with this passphrase";
+
+/// A LUKS volume to unlock
+#[derive(Debug, Clone)]
+pub struct LuksVolume {
+    name: String,
+    uuid: Option<String>,
+    device: Option<PathBuf>,
+}
+
+impl LuksVolume {
+    /// The volume with the given (mapper) name, e.g. `cryptroot`
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        LuksVolume {
+            name: name.into(),
+            uuid: None,
+            device: None,
+        }
+    }
+
+    /// Set the UUID of the volume, which identifies its passphrase in the external password cache
+    pub fn uuid<S: Into<String>>(mut self, uuid: S) -> Self {
+        self.uuid = Some(uuid.into());
+        self
+    }
+
+    /// Set the underlying device, shown in the description
+    pub fn device(mut self, device: PathBuf) -> Self {
+        self.device = Some(device);
+        self
+    }
+
+    /// `Please enter the passphrase for disk <name> (<device>)`
+    pub fn description(&self) -> String {
+        match self.device {
+            Some(ref device) => Message::LuksDescriptionDevice {
+                name: &self.name,
+                device: &device.display().to_string(),
+            }
+            .text(),
+            None => Message::LuksDescription { name: &self.name }.text(),
+        }
+    }
+
+    /// The key of the passphrase in the external password cache (`SETKEYINFO`), if the UUID is known
+    pub fn key_info(&self) -> Option<String> {
+        self.uuid.as_ref().map(|uuid| format!("n/luks-{}", uuid))
+    }
+
+    /// Commands enabling the external password cache for this volume
+    fn cache_commands(&self) -> Vec<AssuanCommand> {
+        match self.key_info() {
+            Some(key_info) => vec![
+                AssuanCommand::Option("allow-external-password-cache".to_string(), None),
+                AssuanCommand::SetKeyInfo(key_info),
+            ],
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Classify the result of an activation: a permission error (`EPERM`, which libcryptsetup returns when no key slot
+/// matches) means the passphrase is wrong, everything else is fatal
+pub fn verify_activation<T>(res: io::Result<T>) -> result::Result<T, VerifyError<io::Error>> {
+    match res {
+        Ok(activated) => Ok(activated),
+        Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied || e.raw_os_error() == Some(1) => {
+            Err(VerifyError::Retry(Message::LuksWrongPassphrase.text()))
+        }
+        Err(e) => Err(VerifyError::Fatal(e)),
+    }
+}
+
+impl PinentrySession {
+    /// Ask for the passphrase of `volume` once
+    ///
+    /// See [`SessionPrompt::volume_passphrase`].
+    pub fn volume_passphrase(&mut self, volume: &LuksVolume) -> Result<SecretPin> {
+        self.prompt().volume_passphrase(volume)
+    }
+
+    /// Prompt for the passphrase of `volume` until `activate` accepts it
+    ///
+    /// See [`SessionPrompt::unlock_volume`].
+    pub fn unlock_volume<T, E, F>(&mut self, volume: &LuksVolume, activate: F) -> result::Result<T, UnlockError<E>>
+    where
+        F: FnMut(&[u8]) -> result::Result<T, VerifyError<E>>,
+    {
+        self.prompt().unlock_volume(volume, activate)
+    }
+}
+
+impl SessionPrompt<'_> {
+    /// Ask for the passphrase of `volume` once, for callers that cannot check it themselves (e.g. keyscripts)
+    ///
+    /// The description of the volume replaces the one of this prompt, and the window title defaults to
+    /// `Disk unlock`.
+    pub fn volume_passphrase(mut self, volume: &LuksVolume) -> Result<SecretPin> {
+        self.preset(volume);
+        self.validate(PromptKind::Pin)?;
+        let normalization = self.take_normalization();
+        let mut overrides = self.settings.into_commands();
+        overrides.extend(volume.cache_commands());
+        self.session
+            .read_pin(&mut overrides, &normalization, &Message::PassphrasePrompt.text())
+    }
+
+    /// Prompt for the passphrase of `volume` until `activate` accepts it
+    ///
+    /// Works like [`unlock()`](SessionPrompt::unlock), with the texts of
+    /// [`volume_passphrase()`](SessionPrompt::volume_passphrase): after `max_attempts` (3 by default, as for
+    /// `systemd-cryptsetup`) rejected passphrases, [`UnlockError::LockedOut`] is returned. A rejected passphrase is
+    /// removed from the external password cache.
+    pub fn unlock_volume<T, E, F>(mut self, volume: &LuksVolume, mut activate: F) -> result::Result<T, UnlockError<E>>
+    where
+        F: FnMut(&[u8]) -> result::Result<T, VerifyError<E>>,
+    {
+        self.preset(volume);
+        self.validate(PromptKind::Unlock)?;
+        let normalization = self.take_normalization();
+        let max_attempts = self
+            .settings
+            .max_attempts
+            .take()
+            .unwrap_or(self.session.max_attempts)
+            .max(1);
+        let mut overrides = self.settings.into_commands();
+        overrides.extend(volume.cache_commands());
+
+        for attempt in 1..=max_attempts {
+            let passphrase =
+                self.session
+                    .read_pin(&mut overrides, &normalization, &Message::PassphrasePrompt.text())?;
+            let res = activate(passphrase.unsecure());
+            drop(passphrase);
+
+            match res {
+                Ok(activated) => return Ok(activated),
+                Err(VerifyError::Fatal(e)) => return Err(UnlockError::Failed(e)),
+                Err(VerifyError::Retry(message)) => {
+                    if let Some(key_info) = volume.key_info() {
+                        self.session
+                            .run_prompt(vec![AssuanCommand::ClearPassphrase(key_info)], Vec::new())?;
+                    }
+                    set_error_text(&mut overrides, attempts_left(&message, max_attempts - attempt));
+                }
+            }
+        }
+        Err(UnlockError::LockedOut { attempts: max_attempts })
+    }
+
+    /// Apply the disk-unlock texts of `volume`
+    fn preset(&mut self, volume: &LuksVolume) {
+        self.settings.description = Some(volume.description());
+        self.settings
+            .window_title
+            .get_or_insert_with(|| Message::LuksTitle.text());
+    }
+}
+
+#[cfg(feature = "process")]
+impl PinentryBuilder {
+    /// Ask for the passphrase of `volume` once
+    ///
+    /// See [`SessionPrompt::volume_passphrase`].
+    pub fn volume_passphrase(self, volume: &LuksVolume) -> Result<SecretPin> {
+        self.settings.validate(Some(PromptKind::Pin))?;
+        self.connect()?.volume_passphrase(volume)
+    }
+
+    /// Prompt for the passphrase of `volume` until `activate` accepts it
+    ///
+    /// See [`SessionPrompt::unlock_volume`].
+    pub fn unlock_volume<T, E, F>(self, volume: &LuksVolume, activate: F) -> result::Result<T, UnlockError<E>>
+    where
+        F: FnMut(&[u8]) -> result::Result<T, VerifyError<E>>,
+    {
+        self.settings.validate(Some(PromptKind::Unlock))?;
+        self.connect()?.unlock_volume(volume, activate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_activation() {
+        assert!(matches!(verify_activation(Ok(1)), Ok(1)));
+        assert!(matches!(
+            verify_activation::<()>(Err(io::Error::from_raw_os_error(1))),
+            Err(VerifyError::Retry(_))
+        ));
+        assert!(matches!(
+            verify_activation::<()>(Err(io::Error::new(io::ErrorKind::NotFound, "no such device"))),
+            Err(VerifyError::Fatal(_))
+        ));
+    }
+
+    #[cfg(all(unix, feature = "process"))]
+    #[test]
+    fn test_unlock_volume() {
+        use super::super::pinentry;
+        use super::super::test_util::FakePinentry;
+
+        let fake = FakePinentry::new(&[(
+            "GETPIN",
+            r#"n=$((n+1)); if [ $n = 1 ]; then echo "S PASSWORD_FROM_CACHE"; echo "D stale"; else echo "D right"; fi; echo OK"#,
+        )]);
+        let volume = LuksVolume::new("cryptroot".to_string())
+            .uuid("1234")
+            .device("/dev/sda2".into());
+        let res = pinentry()
+            .exe(fake.exe())
+            .unlock_volume(&volume, |passphrase| match passphrase {
+                b"right" => Ok(()),
+                _ => verify_activation(Err(io::Error::from_raw_os_error(1))),
+            });
+        assert!(res.is_ok());
+
+        let commands = fake.commands();
+        assert!(commands.contains(&"SETDESC Please enter the passphrase for disk cryptroot (/dev/sda2)".to_string()));
+        assert!(commands.contains(&"SETTITLE Disk unlock".to_string()));
+        assert!(commands.contains(&"SETKEYINFO n/luks-1234".to_string()));
+        assert!(commands.contains(&"OPTION allow-external-password-cache".to_string()));
+        assert!(commands.contains(&"CLEARPASSPHRASE n/luks-1234".to_string()));
+        assert!(commands.contains(&format!("SETERROR {} (2 attempts left)", WRONG_PASSPHRASE)));
+    }
+}
@@ -0,0 +1,284 @@
+//! Prompts from several threads, one dialog at a time
+//!
+//! A [`PinentryManager`] shared between threads (e.g. the request handlers of a daemon) hands its sessions to one
+//! prompt at a time, so simultaneous requests queue up instead of opening overlapping dialogs. Sessions are started
+//! when first needed and kept for later prompts; by default there is one,
+//! [`max_sessions`](PinentryManager::max_sessions) allows a few dialogs side by side.
+//!
+//! With [`coalesce_keyinfo`](PinentryManager::coalesce_keyinfo), threads asking for the PIN of a key that is already
+//! being prompted for wait for that dialog and receive the same PIN:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # #[cfg(feature = "process")]
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use std::sync::Arc;
+//! use std::thread;
+//!
+//! use pinentry_rs::manager::PinentryManager;
+//! use pinentry_rs::pinentry;
+//!
+//! let manager = Arc::new(PinentryManager::new(pinentry().window_title("Unlock")).coalesce_keyinfo(true));
+//! let workers: Vec<_> = ["n/0123ABCD", "n/0123ABCD", "n/4567CDEF"]
+//!     .into_iter()
+//!     .map(|key| {
+//!         let manager = manager.clone();
+//!         thread::spawn(move || {
+//!             manager.pin(Some(key), |prompt| prompt.description(format!("Unlock {}", key)).pin("PIN:"))
+//!         })
+//!     })
+//!     .collect();
+//! for worker in workers {
+//!     // two dialogs, one after the other
+//!     worker.join().unwrap()?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::sync::{Condvar, Mutex};
+
+use super::session::{PinentrySession, SessionPrompt};
+use super::single_flight::SingleFlight;
+#[cfg(feature = "process")]
+use super::PinentryBuilder;
+use super::{Error, Result, SecretPin};
+
+type ConnectFn = Box<dyn FnMut() -> Result<PinentrySession> + Send>;
+
+/// Serializes prompts from several threads onto a few sessions, see the [module documentation](self)
+pub struct PinentryManager {
+    connect: Mutex<ConnectFn>,
+    max_sessions: usize,
+    coalesce: bool,
+    pool: Mutex<Pool>,
+    returned: Condvar,
+    flights: SingleFlight<String>,
+}
+
+/// The sessions of a manager
+struct Pool {
+    idle: Vec<PinentrySession>,
+    // sessions started, idle or in use
+    open: usize,
+}
+
+impl PinentryManager {
+    /// Prompt through sessions started with [`connect`](PinentryBuilder::connect) on copies of `builder`
+    #[cfg(feature = "process")]
+    pub fn new(builder: PinentryBuilder) -> Self {
+        PinentryManager::with_connect(move || builder.clone().connect())
+    }
+
+    /// Prompt through sessions started by `connect` (e.g. with
+    /// [`connect_socket`](super::PinentryBuilder::connect_socket))
+    pub fn with_connect<F>(connect: F) -> Self
+    where
+        F: FnMut() -> Result<PinentrySession> + Send + 'static,
+    {
+        PinentryManager {
+            connect: Mutex::new(Box::new(connect)),
+            max_sessions: 1,
+            coalesce: false,
+            pool: Mutex::new(Pool {
+                idle: Vec::new(),
+                open: 0,
+            }),
+            returned: Condvar::new(),
+            flights: SingleFlight::new(),
+        }
+    }
+
+    /// Run up to `sessions` prompts at the same time (1 by default, 0 is taken as 1)
+    pub fn max_sessions(mut self, sessions: usize) -> Self {
+        self.max_sessions = sessions.max(1);
+        self
+    }
+
+    /// Share one dialog between the threads asking [`pin`](PinentryManager::pin) for the same key at the same time
+    /// (off by default)
+    pub fn coalesce_keyinfo(mut self, coalesce: bool) -> Self {
+        self.coalesce = coalesce;
+        self
+    }
+
+    /// Ask for the PIN of the key `key_info` (if known) with `ask`, which is given a prompt of the session
+    ///
+    /// Waits for a session if all of them are in use. With [`coalesce_keyinfo`](PinentryManager::coalesce_keyinfo),
+    /// a prompt already open for `key_info` is waited for instead and its outcome returned. The key only tells prompts
+    /// apart: `ask` sets [`keyinfo`](SessionPrompt::keyinfo) itself if the external password cache is to be used.
+    pub fn pin<F>(&self, key_info: Option<&str>, ask: F) -> Result<SecretPin>
+    where
+        F: FnOnce(SessionPrompt<'_>) -> Result<SecretPin>,
+    {
+        let prompt = || self.with_session(|session| ask(session.prompt()));
+        match key_info {
+            Some(key_info) if self.coalesce => self.flights.run(key_info.to_string(), prompt),
+            _ => prompt(),
+        }
+    }
+
+    /// Run `f` with a session of its own, waiting for one if all of them are in use
+    ///
+    /// A session is started if none is idle and fewer than [`max_sessions`](PinentryManager::max_sessions) are
+    /// running. Sessions are kept for later calls unless `f` panics or fails with an error that may have left the
+    /// pinentry unusable (an I/O error, a failed recovery, an aborted or timed out prompt).
+    pub fn with_session<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut PinentrySession) -> Result<T>,
+    {
+        let mut checkout = Checkout {
+            manager: self,
+            session: Some(self.checkout()?),
+            keep: false,
+        };
+        let res = f(checkout.session.as_mut().expect("BUG: session was just checked out"));
+        match res {
+            Err(ref e @ (Error::IoError(_) | Error::RecoveryFailed(_) | Error::Aborted | Error::Timeout)) => {
+                debug!("dropping a session after an error: {}", e);
+            }
+            _ => checkout.keep = true,
+        }
+        res
+    }
+
+    /// The number of sessions running, idle or in use
+    pub fn sessions(&self) -> usize {
+        self.pool.lock().expect("pool lock is not poisoned").open
+    }
+
+    fn checkout(&self) -> Result<PinentrySession> {
+        let mut pool = self.pool.lock().expect("pool lock is not poisoned");
+        loop {
+            if let Some(session) = pool.idle.pop() {
+                return Ok(session);
+            }
+            if pool.open < self.max_sessions {
+                break;
+            }
+            debug!("waiting for a session");
+            pool = self.returned.wait(pool).expect("pool lock is not poisoned");
+        }
+        // started outside the pool lock, so that returned sessions can be handed out meanwhile
+        pool.open += 1;
+        drop(pool);
+        let session = (self.connect.lock().expect("connect lock is not poisoned"))();
+        if session.is_err() {
+            self.pool.lock().expect("pool lock is not poisoned").open -= 1;
+            self.returned.notify_one();
+        }
+        session
+    }
+}
+
+/// Returns a session to the pool when dropped - or, unless it is to be kept (e.g. as the prompt panicked), closes it
+/// and frees its place
+struct Checkout<'a> {
+    manager: &'a PinentryManager,
+    session: Option<PinentrySession>,
+    keep: bool,
+}
+
+impl Drop for Checkout<'_> {
+    fn drop(&mut self) {
+        let session = self.session.take();
+        if let Ok(mut pool) = self.manager.pool.lock() {
+            match session {
+                Some(session) if self.keep => pool.idle.push(session),
+                _ => pool.open -= 1,
+            }
+        }
+        // a session not returned is closed here, outside the pool lock
+        self.manager.returned.notify_one();
+    }
+}
+
+#[cfg(all(test, unix, feature = "process"))]
+mod tests {
+    use super::*;
+
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    use super::super::pinentry;
+    use super::super::test_util::FakePinentry;
+
+    #[test]
+    fn test_manager_coalesce_keyinfo() {
+        // the first GETPIN is held open until the last prompt has queued up
+        let fake = FakePinentry::new(&[("GETPIN", r#"sleep 0.5; echo "D secret"; echo OK"#)]);
+        let manager = Arc::new(PinentryManager::new(pinentry().exe(fake.exe())).coalesce_keyinfo(true));
+        let barrier = Arc::new(Barrier::new(4));
+
+        let workers: Vec<_> = ["a", "a", "a", "b"]
+            .into_iter()
+            .map(|key| {
+                let (manager, barrier) = (manager.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    manager.pin(Some(key), |prompt| {
+                        prompt.description(format!("Unlock {}", key)).pin("PIN:")
+                    })
+                })
+            })
+            .collect();
+        for worker in workers {
+            assert_eq!(b"secret", worker.join().unwrap().unwrap().unsecure());
+        }
+        // one dialog per key, one after the other in the same pinentry
+        let commands = fake.commands();
+        assert_eq!(2, commands.iter().filter(|cmd| *cmd == "GETPIN").count());
+        assert!(commands.iter().any(|cmd| cmd == "SETDESC Unlock a"));
+        assert!(commands.iter().any(|cmd| cmd == "SETDESC Unlock b"));
+        assert_eq!(1, fake.spawn_count());
+        assert_eq!(1, manager.sessions());
+    }
+
+    #[test]
+    fn test_manager_sessions() {
+        let fake = FakePinentry::new(&[("GETPIN", r#"echo "D secret"; echo OK"#)]);
+        let manager = PinentryManager::new(pinentry().exe(fake.exe())).max_sessions(2);
+        assert_eq!(0, manager.sessions());
+
+        // without coalescing, every call prompts
+        for _ in 0..2 {
+            let pin = manager.pin(Some("a"), |prompt| prompt.pin("PIN:")).unwrap();
+            assert_eq!(b"secret", pin.unsecure());
+        }
+        assert_eq!(2, fake.commands().iter().filter(|cmd| *cmd == "GETPIN").count());
+        // an idle session is reused before another is started
+        assert_eq!(1, manager.sessions());
+
+        // a session is started for a call made while the other one is in use
+        let nested = manager.with_session(|_| manager.with_session(|session| session.pin("PIN:")));
+        assert_eq!(b"secret", nested.unwrap().unsecure());
+        assert_eq!(2, manager.sessions());
+        assert_eq!(2, fake.spawn_count());
+    }
+
+    #[test]
+    fn test_manager_drops_broken_sessions() {
+        let fake = FakePinentry::new(&[("GETPIN", r#"echo "D secret"; echo OK"#)]);
+        let manager = PinentryManager::new(pinentry().exe(fake.exe()));
+
+        // a prompt that panics gives up its session instead of blocking the next one
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            manager.pin(None, |_| -> Result<SecretPin> { panic!("the prompt panicked") })
+        }));
+        assert!(res.is_err());
+        assert_eq!(0, manager.sessions());
+        let pin = manager.pin(None, |prompt| prompt.pin("PIN:")).unwrap();
+        assert_eq!(b"secret", pin.unsecure());
+        assert_eq!(1, manager.sessions());
+
+        // as does one that may have left pinentry unusable
+        for error in [Error::Aborted, Error::Timeout] {
+            assert!(manager.with_session(|_| -> Result<()> { Err(error) }).is_err());
+            assert_eq!(0, manager.sessions());
+            assert!(manager.pin(None, |prompt| prompt.pin("PIN:")).is_ok());
+        }
+        // the one given up by the panicking prompt, the first one, and one after each error
+        assert_eq!(4, fake.spawn_count());
+    }
+}
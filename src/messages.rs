@@ -0,0 +1,316 @@
+//! The texts generated by the library, in English unless translated (see [`localization`](super::localization)
+//! with the `fluent` feature)
+
+use std::fmt::{Display, Formatter};
+
+#[cfg(feature = "fluent")]
+use fluent_bundle::FluentArgs;
+
+use super::luks::WRONG_PASSPHRASE;
+use super::normalize::EMPTY_ERROR;
+use super::pages::CONTINUE_LABEL;
+
+/// A text shown to the user (or in errors), with its parameters
+///
+/// Each variant is a message of the Fluent resource `locales/en/pinentry-rs.ftl`, which has the English texts
+/// returned by [`english()`](Message::english).
+// some of the messages are only used with optional features
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Message<'a> {
+    AttemptsLeft { message: &'a str, left: u32 },
+    ChoicePrompt { count: usize },
+    ChoiceOutOfRange { count: usize },
+    ContinueLabel,
+    PageIndicator { page: usize, pages: usize },
+    EmptyPassphrase,
+    PassphrasePrompt,
+    PasswordPrompt,
+    UsernamePrompt,
+    NewPassphraseTitle,
+    NewPassphraseDescription,
+    NewPassphraseRepeat,
+    NewPassphraseMismatch,
+    NewPassphraseQuality,
+    PassphraseTooShort { min: usize },
+    PassphraseUnchanged,
+    TokenDescription,
+    TokenDigitsOnly,
+    TokenLengthExact { length: usize, numeric: bool },
+    TokenLengthRange { min: usize, max: usize, numeric: bool },
+    TokenLengthMin { min: usize, numeric: bool },
+    TokenLockout { attempts: u32 },
+    SshPassphrase { path: &'a str },
+    SshPassphraseFingerprint { path: &'a str, fingerprint: &'a str },
+    SshConfirm { key: &'a str },
+    SshFingerprint { fingerprint: &'a str },
+    LuksTitle,
+    LuksDescription { name: &'a str },
+    LuksDescriptionDevice { name: &'a str, device: &'a str },
+    LuksWrongPassphrase,
+    GitAuthFailed,
+    GitPassword { user: &'a str, url: &'a str },
+    GitUsername { url: &'a str },
+    PolicyDenied { reason: &'a str },
+    RateLimited { seconds: u64 },
+    LockedOut { attempts: u32 },
+    UnlockFailed { cause: &'a str },
+}
+
+impl Message<'_> {
+    /// The text in the language of the installed localization, or else in English
+    pub(crate) fn text(&self) -> String {
+        #[cfg(feature = "fluent")]
+        if let Some(text) = super::localization::translate(self.id(), &self.args()) {
+            return text;
+        }
+        self.english()
+    }
+
+    /// The id of the message in the Fluent resources
+    #[cfg_attr(not(feature = "fluent"), allow(dead_code))]
+    pub(crate) fn id(&self) -> &'static str {
+        match self {
+            Message::AttemptsLeft { .. } => "attempts-left",
+            Message::ChoicePrompt { .. } => "choice-prompt",
+            Message::ChoiceOutOfRange { .. } => "choice-out-of-range",
+            Message::ContinueLabel => "continue-label",
+            Message::PageIndicator { .. } => "page-indicator",
+            Message::EmptyPassphrase => "empty-passphrase",
+            Message::PassphrasePrompt => "passphrase-prompt",
+            Message::PasswordPrompt => "password-prompt",
+            Message::UsernamePrompt => "username-prompt",
+            Message::NewPassphraseTitle => "new-passphrase-title",
+            Message::NewPassphraseDescription => "new-passphrase-description",
+            Message::NewPassphraseRepeat => "new-passphrase-repeat",
+            Message::NewPassphraseMismatch => "new-passphrase-mismatch",
+            Message::NewPassphraseQuality => "new-passphrase-quality",
+            Message::PassphraseTooShort { .. } => "passphrase-too-short",
+            Message::PassphraseUnchanged => "passphrase-unchanged",
+            Message::TokenDescription => "token-description",
+            Message::TokenDigitsOnly => "token-digits-only",
+            Message::TokenLengthExact { .. } => "token-length-exact",
+            Message::TokenLengthRange { .. } => "token-length-range",
+            Message::TokenLengthMin { .. } => "token-length-min",
+            Message::TokenLockout { .. } => "token-lockout",
+            Message::SshPassphrase { .. } => "ssh-passphrase",
+            Message::SshPassphraseFingerprint { .. } => "ssh-passphrase-fingerprint",
+            Message::SshConfirm { .. } => "ssh-confirm",
+            Message::SshFingerprint { .. } => "ssh-fingerprint",
+            Message::LuksTitle => "luks-title",
+            Message::LuksDescription { .. } => "luks-description",
+            Message::LuksDescriptionDevice { .. } => "luks-description-device",
+            Message::LuksWrongPassphrase => "luks-wrong-passphrase",
+            Message::GitAuthFailed => "git-auth-failed",
+            Message::GitPassword { .. } => "git-password",
+            Message::GitUsername { .. } => "git-username",
+            Message::PolicyDenied { .. } => "policy-denied",
+            Message::RateLimited { .. } => "rate-limited",
+            Message::LockedOut { .. } => "locked-out",
+            Message::UnlockFailed { .. } => "unlock-failed",
+        }
+    }
+
+    /// The English text
+    pub(crate) fn english(&self) -> String {
+        let unit = |numeric: bool| if numeric { "digits" } else { "characters" };
+        match *self {
+            Message::AttemptsLeft { message, left: 1 } => format!("{} (1 attempt left)", message),
+            Message::AttemptsLeft { message, left } => format!("{} ({} attempts left)", message, left),
+            Message::ChoicePrompt { count } => format!("Choice (1-{}):", count),
+            Message::ChoiceOutOfRange { count } => format!("Please enter a number between 1 and {}", count),
+            Message::ContinueLabel => CONTINUE_LABEL.to_string(),
+            Message::PageIndicator { page, pages } => format!("(Page {} of {})", page, pages),
+            Message::EmptyPassphrase => EMPTY_ERROR.to_string(),
+            Message::PassphrasePrompt => "Passphrase:".to_string(),
+            Message::PasswordPrompt => "Password:".to_string(),
+            Message::UsernamePrompt => "Username:".to_string(),
+            Message::NewPassphraseTitle => "New passphrase".to_string(),
+            Message::NewPassphraseDescription => "Please enter the new passphrase".to_string(),
+            Message::NewPassphraseRepeat => "Repeat:".to_string(),
+            Message::NewPassphraseMismatch => "The passphrases do not match".to_string(),
+            Message::NewPassphraseQuality => "Quality:".to_string(),
+            Message::PassphraseTooShort { min } => format!("The passphrase must be at least {} characters long", min),
+            Message::PassphraseUnchanged => "The new passphrase must differ from the current one".to_string(),
+            Message::TokenDescription => "Please enter the PIN of your security token".to_string(),
+            Message::TokenDigitsOnly => "The PIN may only contain digits".to_string(),
+            Message::TokenLengthExact { length, numeric } => format!("The PIN must have {} {}", length, unit(numeric)),
+            Message::TokenLengthRange { min, max, numeric } => {
+                format!("The PIN must have {} to {} {}", min, max, unit(numeric))
+            }
+            Message::TokenLengthMin { min, numeric } => format!("The PIN must have at least {} {}", min, unit(numeric)),
+            Message::TokenLockout { attempts: 1 } => "1 attempt remaining before lockout".to_string(),
+            Message::TokenLockout { attempts } => format!("{} attempts remaining before lockout", attempts),
+            Message::SshPassphrase { path } => format!("Enter passphrase for {}", path),
+            Message::SshPassphraseFingerprint { path, fingerprint } => {
+                format!("Enter passphrase for {} ({})", path, fingerprint)
+            }
+            Message::SshConfirm { key } => format!("Allow use of key {}?", key),
+            Message::SshFingerprint { fingerprint } => format!("Key fingerprint {}.", fingerprint),
+            Message::LuksTitle => "Disk unlock".to_string(),
+            Message::LuksDescription { name } => format!("Please enter the passphrase for disk {}", name),
+            Message::LuksDescriptionDevice { name, device } => {
+                format!("Please enter the passphrase for disk {} ({})", name, device)
+            }
+            Message::LuksWrongPassphrase => WRONG_PASSPHRASE.to_string(),
+            Message::GitAuthFailed => "Authentication failed, please try again".to_string(),
+            Message::GitPassword { user, url } => format!("Password for {} at {}", user, url),
+            Message::GitUsername { url } => format!("Username for {}", url),
+            Message::PolicyDenied { reason } => format!("The prompt was denied by policy: {}", reason),
+            Message::RateLimited { seconds } => {
+                format!("Too many prompts, not showing another one for {} seconds", seconds)
+            }
+            Message::LockedOut { attempts } => format!("Locked out after {} wrong attempts", attempts),
+            Message::UnlockFailed { cause } => format!("Unlocking failed: {}", cause),
+        }
+    }
+
+    /// The parameters passed to the Fluent messages
+    #[cfg(feature = "fluent")]
+    pub(crate) fn args(&self) -> FluentArgs<'_> {
+        let mut args = FluentArgs::new();
+        let unit = |numeric: bool| if numeric { "digits" } else { "characters" };
+        match *self {
+            Message::AttemptsLeft { message, left } => {
+                args.set("message", message);
+                args.set("left", left);
+            }
+            Message::ChoicePrompt { count } | Message::ChoiceOutOfRange { count } => args.set("count", count),
+            Message::PageIndicator { page, pages } => {
+                args.set("page", page);
+                args.set("pages", pages);
+            }
+            Message::PassphraseTooShort { min } => args.set("min", min),
+            Message::TokenLengthExact { length, numeric } => {
+                args.set("length", length);
+                args.set("unit", unit(numeric));
+            }
+            Message::TokenLengthRange { min, max, numeric } => {
+                args.set("min", min);
+                args.set("max", max);
+                args.set("unit", unit(numeric));
+            }
+            Message::TokenLengthMin { min, numeric } => {
+                args.set("min", min);
+                args.set("unit", unit(numeric));
+            }
+            Message::TokenLockout { attempts } | Message::LockedOut { attempts } => args.set("attempts", attempts),
+            Message::SshPassphrase { path } => args.set("path", path),
+            Message::SshPassphraseFingerprint { path, fingerprint } => {
+                args.set("path", path);
+                args.set("fingerprint", fingerprint);
+            }
+            Message::SshConfirm { key } => args.set("key", key),
+            Message::SshFingerprint { fingerprint } => args.set("fingerprint", fingerprint),
+            Message::LuksDescription { name } => args.set("name", name),
+            Message::LuksDescriptionDevice { name, device } => {
+                args.set("name", name);
+                args.set("device", device);
+            }
+            Message::GitPassword { user, url } => {
+                args.set("user", user);
+                args.set("url", url);
+            }
+            Message::GitUsername { url } => args.set("url", url),
+            Message::PolicyDenied { reason } => args.set("reason", reason),
+            Message::RateLimited { seconds } => args.set("seconds", seconds),
+            Message::UnlockFailed { cause } => args.set("cause", cause),
+            _ => (),
+        }
+        args
+    }
+}
+
+impl Display for Message<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text())
+    }
+}
+
+#[cfg(all(test, feature = "fluent"))]
+mod tests {
+    use super::super::localization::Localization;
+    use super::*;
+
+    #[test]
+    fn test_english_resource() {
+        let english = Localization::new(["en"])
+            .add_resource("en", include_str!("../locales/en/pinentry-rs.ftl"))
+            .unwrap();
+        let messages = [
+            Message::AttemptsLeft {
+                message: "Wrong PIN",
+                left: 1,
+            },
+            Message::AttemptsLeft {
+                message: "Wrong PIN",
+                left: 2,
+            },
+            Message::ChoicePrompt { count: 3 },
+            Message::ChoiceOutOfRange { count: 3 },
+            Message::ContinueLabel,
+            Message::PageIndicator { page: 1, pages: 2 },
+            Message::EmptyPassphrase,
+            Message::PassphrasePrompt,
+            Message::PasswordPrompt,
+            Message::UsernamePrompt,
+            Message::NewPassphraseTitle,
+            Message::NewPassphraseDescription,
+            Message::NewPassphraseRepeat,
+            Message::NewPassphraseMismatch,
+            Message::NewPassphraseQuality,
+            Message::PassphraseTooShort { min: 12 },
+            Message::PassphraseUnchanged,
+            Message::TokenDescription,
+            Message::TokenDigitsOnly,
+            Message::TokenLengthExact {
+                length: 6,
+                numeric: true,
+            },
+            Message::TokenLengthRange {
+                min: 6,
+                max: 8,
+                numeric: false,
+            },
+            Message::TokenLengthMin { min: 4, numeric: false },
+            Message::TokenLockout { attempts: 1 },
+            Message::TokenLockout { attempts: 3 },
+            Message::SshPassphrase { path: "id_ed25519" },
+            Message::SshPassphraseFingerprint {
+                path: "id_ed25519",
+                fingerprint: "SHA256:abc",
+            },
+            Message::SshConfirm { key: "user@host" },
+            Message::SshFingerprint {
+                fingerprint: "SHA256:abc",
+            },
+            Message::LuksTitle,
+            Message::LuksDescription { name: "root" },
+            Message::LuksDescriptionDevice {
+                name: "root",
+                device: "/dev/sda2",
+            },
+            Message::LuksWrongPassphrase,
+            Message::GitAuthFailed,
+            Message::GitPassword {
+                user: "user",
+                url: "https://example.com",
+            },
+            Message::GitUsername {
+                url: "https://example.com",
+            },
+            Message::PolicyDenied { reason: "not now" },
+            Message::RateLimited { seconds: 30 },
+            Message::LockedOut { attempts: 3 },
+            Message::UnlockFailed { cause: "no disk" },
+        ];
+        for message in messages {
+            assert_eq!(
+                Some(message.english()),
+                english.format(message.id(), &message.args()),
+                "{:?}",
+                message
+            );
+        }
+    }
+}
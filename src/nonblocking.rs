@@ -0,0 +1,633 @@
+//! Asynchronous prompts on top of [`tokio`](https://tokio.rs)
+//!
+//! The futures returned by [`AsyncSession`] are cancellation-safe: dropping one in the middle of a prompt (e.g.
+//! because another branch of `tokio::select!` or a `tokio::time::timeout` completed first) kills the pinentry
+//! process, which closes the dialog, and wipes the buffered protocol data. The child process is reaped by tokio in
+//! the background, and the session starts a fresh pinentry for the next prompt.
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # extern crate tokio;
+//! # async fn run() -> pinentry_rs::Result<()> {
+//! use std::time::Duration;
+//!
+//! use pinentry_rs::pinentry;
+//!
+//! let mut session = pinentry().window_title("Unlock").connect_async().await?;
+//! match tokio::time::timeout(Duration::from_secs(30), session.pin("PIN:")).await {
+//!     Ok(pin) => println!("got a PIN of {} bytes", pin?.unsecure().len()),
+//!     // the dialog has been closed
+//!     Err(_) => println!("too slow"),
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Single prompts can be made without a session, with [`pin_async()`](PinentryBuilder::pin_async),
+//! [`confirm_yes_no_async()`](PinentryBuilder::confirm_yes_no_async) and
+//! [`show_message_async()`](PinentryBuilder::show_message_async).
+
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::hash::Hash;
+use std::io;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+
+use bytes::BytesMut;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::task::JoinSet;
+use tokio_util::codec::{Decoder, Encoder};
+use zeroize::Zeroize;
+
+use super::assuan::{describe, redacted, AssuanCommand, AssuanResponse, Inquiry, Line, Reply, ReplyLine};
+use super::codec::AssuanCodec;
+use super::messages::Message;
+use super::normalize::Normalization;
+use super::policy::{self, SharedPolicy};
+use super::rate_limit::RateLimit;
+use super::session::confirmation;
+use super::{invalid, Confirmation, Error, LaunchSettings, PinentryBuilder, PromptKind, Result, SecretPin};
+
+impl PinentryBuilder {
+    /// Start pinentry for asynchronous prompts
+    ///
+    /// The settings of the builder become the defaults of the session, applied to all prompts made through it.
+    /// Respawning, the sandbox, command filters, quality and constraints functions and `max_attempts` are not
+    /// supported (there is no asynchronous `unlock()`).
+    pub async fn connect_async(mut self) -> Result<AsyncSession> {
+        self.settings.validate(None)?;
+        self.check_options()?;
+        if self.respawn {
+            return Err(invalid("respawn is not supported by asynchronous sessions"));
+        }
+        #[cfg(all(feature = "sandbox", target_os = "linux"))]
+        if self.sandbox.is_some() {
+            return Err(invalid("the sandbox is not supported by asynchronous sessions"));
+        }
+        if self.filter.is_some() {
+            return Err(invalid("command filters are not supported by asynchronous sessions"));
+        }
+        if self.hard_timeout.is_some() {
+            return Err(invalid(
+                "hard timeouts are not supported by asynchronous sessions, use tokio::time::timeout() instead",
+            ));
+        }
+        if self.cancel.is_some() {
+            return Err(invalid(
+                "cancel tokens are not supported by asynchronous sessions, drop the prompt instead",
+            ));
+        }
+        if self.settings.quality_fn.is_some() {
+            return Err(invalid("quality_fn is not supported by asynchronous sessions"));
+        }
+        if self.settings.genpin_fn.is_some() {
+            return Err(invalid("genpin_fn is not supported by asynchronous sessions"));
+        }
+        if self.settings.constraints_fn.is_some() {
+            return Err(invalid("constraints_fn is not supported by asynchronous sessions"));
+        }
+        if self.settings.max_attempts.is_some() {
+            return Err(invalid("max_attempts only applies to unlock()"));
+        }
+        if let Some(ref limit) = self.rate_limit {
+            limit.check()?;
+        }
+        let normalization = self.settings.normalization.take().unwrap_or_default();
+        let mut session = AsyncSession {
+            exe: self.exe.clone(),
+            launch: self.launch.clone(),
+            connection: None,
+            state: self.options.into_iter().chain(self.settings.into_commands()).collect(),
+            dirty: false,
+            normalization,
+            policy: self.policy.take(),
+            rate_limit: self.rate_limit.take(),
+        };
+        session.connection().await?;
+        Ok(session)
+    }
+
+    /// Prompt for confirmation asynchronously, in a pinentry started for this prompt
+    ///
+    /// The text for the confirmation should be set using `.description()`
+    pub async fn confirm_yes_no_async(self) -> Result<bool> {
+        self.settings.validate(Some(PromptKind::Confirm))?;
+        self.connect_async().await?.confirm_yes_no().await
+    }
+
+    /// Prompt for a PIN asynchronously, in a pinentry started for this prompt
+    pub async fn pin_async<S: Into<String>>(self, prompt: S) -> Result<SecretPin> {
+        self.settings.validate(Some(PromptKind::Pin))?;
+        self.connect_async().await?.pin(prompt).await
+    }
+
+    /// Show a message asynchronously, in a pinentry started for this prompt
+    ///
+    /// The text for the message should be set using `.description()`
+    pub async fn show_message_async(self) -> Result<()> {
+        self.settings.validate(Some(PromptKind::Message))?;
+        self.connect_async().await?.show_message().await
+    }
+}
+
+/// A running pinentry used for asynchronous prompts
+///
+/// Created with [`PinentryBuilder::connect_async`]. The pinentry process is killed when the session is dropped.
+pub struct AsyncSession {
+    exe: OsString,
+    launch: LaunchSettings,
+    connection: Option<AsyncConnection>,
+    // default settings, sent to every new pinentry and after each RESET
+    state: Vec<AssuanCommand>,
+    // whether the last prompt changed settings that need to be reset before the next one
+    dirty: bool,
+    normalization: Normalization,
+    policy: Option<SharedPolicy>,
+    rate_limit: Option<RateLimit>,
+}
+
+impl AsyncSession {
+    /// Prompt for confirmation
+    ///
+    /// The text for the confirmation should be set using `.description()` on the builder.
+    pub async fn confirm_yes_no(&mut self) -> Result<bool> {
+        match self.run_prompt(Vec::new(), AssuanCommand::Confirm).await? {
+            AssuanResponse::OK => Ok(true),
+            AssuanResponse::NOTOK(_) => Ok(false),
+            x => Err(Error::unexpected_response(x)),
+        }
+    }
+
+    /// Prompt for confirmation, telling the buttons apart
+    ///
+    /// See [`SessionPrompt::confirm`](super::SessionPrompt::confirm).
+    pub async fn confirm(&mut self) -> Result<Confirmation> {
+        confirmation(self.run_prompt(Vec::new(), AssuanCommand::Confirm).await?)
+    }
+
+    /// Ask for confirmation with a single button
+    ///
+    /// See [`SessionPrompt::confirm_one_button`](super::SessionPrompt::confirm_one_button).
+    pub async fn confirm_one_button(&mut self) -> Result<Confirmation> {
+        confirmation(self.run_prompt(Vec::new(), AssuanCommand::ConfirmOneButton).await?)
+    }
+
+    /// Prompt for a PIN
+    ///
+    /// If the PIN is rejected by the normalization (empty input), the prompt is repeated with an error text.
+    pub async fn pin<S: Into<String>>(&mut self, prompt: S) -> Result<SecretPin> {
+        self.read_pin(vec![AssuanCommand::SetPrompt(prompt.into())]).await
+    }
+
+    /// Show a message
+    ///
+    /// The text for the message should be set using `.description()` on the builder.
+    pub async fn show_message(&mut self) -> Result<()> {
+        match self.run_prompt(Vec::new(), AssuanCommand::ShowMessage).await? {
+            AssuanResponse::OK => Ok(()),
+            AssuanResponse::NOTOK(error) => Err(Error::from_response(error)),
+            x => Err(Error::unexpected_response(x)),
+        }
+    }
+
+    /// Prompt for a PIN (with the prompt settings in `overrides`) until it passes the normalization
+    async fn read_pin(&mut self, mut overrides: Vec<AssuanCommand>) -> Result<SecretPin> {
+        loop {
+            let pin = match self.run_prompt(overrides.clone(), AssuanCommand::GetPin).await? {
+                AssuanResponse::PIN(pin) => pin,
+                AssuanResponse::NOTOK(error) => return Err(Error::from_response(error)),
+                res => return Err(Error::unexpected_response(res)),
+            };
+            match self.normalization.apply(pin) {
+                Some(pin) => return Ok(pin),
+                None => {
+                    overrides.retain(|c| !matches!(c, AssuanCommand::SetErrorText(_)));
+                    overrides.push(AssuanCommand::SetErrorText(Message::EmptyPassphrase.text()));
+                }
+            }
+        }
+    }
+
+    async fn run_prompt(&mut self, overrides: Vec<AssuanCommand>, terminal: AssuanCommand) -> Result<AssuanResponse> {
+        if let Some(ref policy) = self.policy {
+            let settings = self.state.iter().chain(&overrides).chain([&terminal]);
+            if let Some(answer) = policy::apply(policy.as_ref(), settings)? {
+                return Ok(answer);
+            }
+        }
+        if let Some(ref limit) = self.rate_limit {
+            limit.acquire()?;
+        }
+        self.connection().await?;
+        let mut cmds = Vec::new();
+        if self.dirty {
+            cmds.push(AssuanCommand::Reset);
+            cmds.extend(self.state.iter().cloned());
+        }
+        let has_overrides = !overrides.is_empty();
+        cmds.extend(overrides);
+
+        // until the prompt completes, dropping the future drops the guard, which kills pinentry
+        let mut guard = InFlight(self.connection.as_mut());
+        let res = guard.connection().process(&cmds, Some(terminal)).await;
+        guard.0 = None;
+        drop(guard);
+
+        self.dirty = has_overrides || res.is_err();
+        if res.is_err() {
+            // the connection may be out of sync
+            self.connection = None;
+        }
+        res
+    }
+
+    /// The connection to pinentry, started (with the default settings) if there is none
+    async fn connection(&mut self) -> Result<&mut AsyncConnection> {
+        if self.connection.as_ref().is_some_and(|c| c.aborted) {
+            self.connection = None;
+        }
+        if self.connection.is_none() {
+            let mut connection = AsyncConnection::spawn(&self.exe, &self.launch).await?;
+            match connection.process(&self.state, None).await? {
+                AssuanResponse::OK => (),
+                AssuanResponse::NOTOK(error) => return Err(Error::from_response(error)),
+                x => return Err(Error::unexpected_response(x)),
+            }
+            self.dirty = false;
+            self.connection = Some(connection);
+        }
+        Ok(self.connection.as_mut().expect("BUG: connection was just started"))
+    }
+}
+
+/// A PIN prompt scheduled by [`prompt_many`]
+#[derive(Debug, Clone)]
+pub struct PinRequest<K> {
+    key: K,
+    prompt: String,
+    description: Option<String>,
+}
+
+impl<K> PinRequest<K> {
+    /// Prompt for a PIN, whose result is keyed by `key`
+    pub fn new<S: Into<String>>(key: K, prompt: S) -> Self {
+        PinRequest {
+            key,
+            prompt: prompt.into(),
+            description: None,
+        }
+    }
+
+    /// Set the descriptive text of this prompt (e.g. which key or volume the PIN is for)
+    pub fn description<S: Into<String>>(mut self, desc: S) -> Self {
+        self.description = Some(desc.into());
+        self
+    }
+}
+
+/// Prompt for several PINs, with at most `max_concurrent` dialogs (pinentry processes) open at a time
+///
+/// The requests are prompted in order, using sessions started from `builder`. The result of each prompt - including
+/// cancellation - is returned keyed by the request, so one failed prompt does not stop the others. Must be called
+/// from within a tokio runtime, as the prompts run as tasks.
+pub async fn prompt_many<K, I>(
+    builder: &PinentryBuilder,
+    requests: I,
+    max_concurrent: usize,
+) -> Result<HashMap<K, Result<SecretPin>>>
+where
+    K: Eq + Hash + Send + 'static,
+    I: IntoIterator<Item = PinRequest<K>>,
+{
+    if max_concurrent == 0 {
+        return Err(invalid("max_concurrent must be at least 1"));
+    }
+    let queue: VecDeque<_> = requests.into_iter().collect();
+    let workers = max_concurrent.min(queue.len());
+    let queue = Arc::new(Mutex::new(queue));
+
+    let mut tasks = JoinSet::new();
+    for _ in 0..workers {
+        let builder = builder.clone();
+        let queue = queue.clone();
+        tasks.spawn(async move {
+            let mut results = Vec::new();
+            let mut session: Option<AsyncSession> = None;
+            while let Some(request) = next_request(&queue) {
+                let res = match session {
+                    Some(ref mut session) => session.pin_request(request.prompt, request.description).await,
+                    None => match builder.clone().connect_async().await {
+                        Ok(started) => {
+                            session
+                                .insert(started)
+                                .pin_request(request.prompt, request.description)
+                                .await
+                        }
+                        Err(e) => Err(e),
+                    },
+                };
+                results.push((request.key, res));
+            }
+            results
+        });
+    }
+
+    let mut results = HashMap::new();
+    while let Some(res) = tasks.join_next().await {
+        let done = res.map_err(|e| Error::IoError(io::Error::other(e)))?;
+        results.extend(done);
+    }
+    Ok(results)
+}
+
+fn next_request<K>(queue: &Mutex<VecDeque<PinRequest<K>>>) -> Option<PinRequest<K>> {
+    queue.lock().expect("request queue lock is not poisoned").pop_front()
+}
+
+impl AsyncSession {
+    async fn pin_request(&mut self, prompt: String, description: Option<String>) -> Result<SecretPin> {
+        let mut overrides: Vec<_> = description.into_iter().map(AssuanCommand::SetDescriptiveText).collect();
+        overrides.push(AssuanCommand::SetPrompt(prompt));
+        self.read_pin(overrides).await
+    }
+}
+
+/// Kills pinentry unless disarmed (by taking the connection) when the prompt completes
+struct InFlight<'a>(Option<&'a mut AsyncConnection>);
+
+impl InFlight<'_> {
+    fn connection(&mut self) -> &mut AsyncConnection {
+        self.0.as_mut().expect("BUG: prompt already completed")
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if let Some(connection) = self.0.take() {
+            connection.abort();
+        }
+    }
+}
+
+/// A pinentry child process
+struct AsyncConnection {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: ChildStdout,
+    codec: AssuanCodec,
+    buf: BytesMut,
+    // set once the process has been killed by a cancelled prompt
+    aborted: bool,
+}
+
+impl AsyncConnection {
+    async fn spawn(exe: &OsStr, launch: &LaunchSettings) -> Result<AsyncConnection> {
+        let mut cmd = std::process::Command::new(exe);
+        launch.apply(&mut cmd);
+        let mut child = Command::from(cmd)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take().expect("BUG: stdin is piped");
+        let stdout = child.stdout.take().expect("BUG: stdout is piped");
+        let mut connection = AsyncConnection {
+            child,
+            stdin: Some(stdin),
+            stdout,
+            codec: AssuanCodec::new(),
+            buf: BytesMut::new(),
+            aborted: false,
+        };
+        match connection.next_line().await? {
+            Line::Ok(_) => Ok(connection),
+            line => Err(Error::ProtocolError(format!(
+                "unexpected greeting: {}",
+                describe(&line)
+            ))),
+        }
+    }
+
+    /// Send the settings `cmds` (pipelined) followed by the `terminal` command (if any), and read the response
+    async fn process(&mut self, cmds: &[AssuanCommand], terminal: Option<AssuanCommand>) -> Result<AssuanResponse> {
+        if self.aborted {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "pinentry was killed").into());
+        }
+        let mut buf = BytesMut::new();
+        for cmd in cmds.iter().chain(terminal.as_ref()) {
+            let line = cmd.to_line();
+            trace!("> {}", redacted(&line));
+            self.codec.encode(&line, &mut buf)?;
+        }
+        let stdin = self.stdin.as_mut().expect("BUG: stdin is open until dropped");
+        let res = stdin.write_all(&buf).await.and(stdin.flush().await);
+        buf[..].zeroize();
+        res?;
+
+        // all responses need to be read to keep the connection in sync, even after an error
+        let mut error = None;
+        for _ in cmds {
+            let reply = self.read_reply().await?;
+            if error.is_none() {
+                error = reply.error;
+            }
+        }
+
+        let res = match terminal {
+            None => AssuanResponse::OK,
+            Some(cmd) => self.read_reply().await?.into_response(cmd == AssuanCommand::GetPin)?,
+        };
+        Ok(match error {
+            Some(error) => AssuanResponse::NOTOK(error),
+            None => res,
+        })
+    }
+
+    /// Read lines up to the end of a reply (`OK` or `ERR`), keeping the data (long PINs are split over several `D`
+    /// lines) - status lines and comments are skipped, and inquiries cancelled
+    async fn read_reply(&mut self) -> Result<Reply> {
+        let mut reply = Reply::default();
+        loop {
+            match ReplyLine::from(self.next_line().await?) {
+                ReplyLine::Done => return Ok(reply),
+                ReplyLine::Failed(error) => {
+                    reply.error = Some(error);
+                    return Ok(reply);
+                }
+                ReplyLine::Data(chunk) => reply.data.push(chunk),
+                ReplyLine::Inquire(inquiry) => self.cancel_inquiry(inquiry).await?,
+                ReplyLine::Status(_) | ReplyLine::Skip => (),
+            }
+        }
+    }
+
+    async fn cancel_inquiry(&mut self, inquiry: Inquiry) -> Result<()> {
+        debug!("cancelling the inquiry {}", inquiry.keyword);
+        // the parameters may contain the PIN typed so far, and are wiped when dropped
+        drop(inquiry);
+        let mut buf = BytesMut::new();
+        self.codec.encode(&Line::Cancel, &mut buf)?;
+        let stdin = self.stdin.as_mut().expect("BUG: stdin is open until dropped");
+        stdin.write_all(&buf).await?;
+        stdin.flush().await?;
+        Ok(())
+    }
+
+    async fn next_line(&mut self) -> Result<Line> {
+        loop {
+            if let Some(line) = self.codec.decode(&mut self.buf)? {
+                trace!("< {}", redacted(&line));
+                return Ok(line);
+            }
+            if self.stdout.read_buf(&mut self.buf).await? == 0 {
+                return match self.codec.decode_eof(&mut self.buf)? {
+                    Some(line) => Ok(line),
+                    None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "pinentry closed the connection").into()),
+                };
+            }
+        }
+    }
+
+    /// Kill pinentry straight away (closing the dialog) and wipe anything read from it
+    fn abort(&mut self) {
+        debug!("prompt was cancelled, killing pinentry");
+        self.aborted = true;
+        let _ = self.child.start_kill();
+        self.wipe();
+    }
+
+    fn wipe(&mut self) {
+        self.buf[..].zeroize();
+        self.buf.clear();
+    }
+}
+
+impl Drop for AsyncConnection {
+    fn drop(&mut self) {
+        // closing stdin lets pinentry exit by itself; `kill_on_drop` takes care of it otherwise, and tokio reaps the
+        // process in the background
+        self.stdin.take();
+        self.wipe();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use super::super::pinentry;
+    use super::super::test_util::FakePinentry;
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("runtime is created")
+    }
+
+    #[test]
+    fn test_async_prompts() {
+        let fake = FakePinentry::new(&[("GETPIN", r#"echo "D secret"; echo OK"#)]);
+        runtime().block_on(async {
+            let mut session = pinentry()
+                .window_title("Title")
+                .exe(fake.exe())
+                .connect_async()
+                .await
+                .expect("session is started");
+            assert_eq!(SecretPin::from("secret"), session.pin("PIN:").await.unwrap());
+            assert!(session.confirm_yes_no().await.unwrap());
+        });
+        assert_eq!(
+            vec![
+                "SETTITLE Title",
+                "SETPROMPT PIN:",
+                "GETPIN",
+                "RESET",
+                "SETTITLE Title",
+                "CONFIRM"
+            ],
+            fake.commands()
+        );
+    }
+
+    #[test]
+    fn test_async_long_pin() {
+        let long = "x".repeat(900);
+        let fake = FakePinentry::new(&[(
+            "GETPIN",
+            &format!(
+                r##"echo "# typing"; echo "S PIN_REPEATED"; echo "D {}"; echo "D 100%25"; echo OK"##,
+                long
+            ),
+        )]);
+        let pin = runtime().block_on(async { pinentry().exe(fake.exe()).pin_async("PIN:").await });
+        assert_eq!(SecretPin::from(format!("{}100%", long)), pin.expect("PIN is returned"));
+    }
+
+    #[test]
+    fn test_async_one_shot_prompts() {
+        let fake = FakePinentry::new(&[("GETPIN", r#"echo "D secret"; echo OK"#)]);
+        runtime().block_on(async {
+            let pin = pinentry().exe(fake.exe()).pin_async("PIN:").await;
+            assert_eq!(SecretPin::from("secret"), pin.unwrap());
+            let confirm = pinentry().exe(fake.exe()).description("Sure?");
+            assert!(confirm.confirm_yes_no_async().await.unwrap());
+            pinentry().exe(fake.exe()).show_message_async().await.unwrap();
+        });
+        assert_eq!(
+            vec!["SETPROMPT PIN:", "GETPIN", "SETDESC Sure?", "CONFIRM", "MESSAGE"],
+            fake.commands()
+        );
+        assert_eq!(3, fake.spawn_count());
+    }
+
+    #[test]
+    fn test_dropped_prompt_kills_pinentry() {
+        // the PIN is never answered, so the prompt only ends when it is cancelled
+        let fake = FakePinentry::new(&[("GETPIN", "exec sleep 30"), ("CONFIRM", "echo OK")]);
+        runtime().block_on(async {
+            let mut session = pinentry().exe(fake.exe()).connect_async().await.unwrap();
+            let prompt = session.pin("PIN:");
+            assert!(tokio::time::timeout(Duration::from_millis(500), prompt).await.is_err());
+
+            // a new pinentry is started for the next prompt
+            assert!(session.confirm_yes_no().await.unwrap());
+        });
+        assert_eq!(2, fake.spawn_count());
+    }
+
+    #[test]
+    fn test_prompt_many() {
+        // answers with a PIN made from the description, and cancels the prompt for "c"
+        let fake = FakePinentry::new(&[
+            ("SETDESC*", r#"desc="${line#SETDESC }"; echo OK"#),
+            (
+                "GETPIN",
+                r#"if [ "$desc" = c ]; then echo "ERR 83886179 Operation cancelled"; else echo "D pin-$desc"; echo OK; fi"#,
+            ),
+        ]);
+        let requests = ["a", "b", "c"]
+            .iter()
+            .map(|key| PinRequest::new(key.to_string(), "PIN:").description(*key));
+
+        let builder = pinentry().exe(fake.exe());
+        let mut results = runtime()
+            .block_on(prompt_many(&builder, requests, 2))
+            .expect("prompts are scheduled");
+        assert_eq!(3, results.len());
+        assert_eq!(SecretPin::from("pin-a"), results.remove("a").unwrap().unwrap());
+        assert_eq!(SecretPin::from("pin-b"), results.remove("b").unwrap().unwrap());
+        assert!(results.remove("c").unwrap().is_err());
+        assert_eq!(2, fake.spawn_count());
+
+        match runtime().block_on(prompt_many(&builder, Vec::<PinRequest<u32>>::new(), 0)) {
+            Err(Error::InvalidConfiguration(_)) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+}
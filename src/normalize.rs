@@ -0,0 +1,167 @@
+//! Normalization of captured passphrases
+//!
+//! The same passphrase can be typed as different byte sequences - e.g. `é` as one composed code point on one
+//! platform and as `e` plus a combining accent on another - which makes passphrases set on one machine fail on
+//! the next. A [`Normalization`] canonicalizes the secret before it is returned:
+//!
+//! ```
+//! # extern crate pinentry_rs;
+//! use pinentry_rs::normalize::{Normalization, UnicodeForm};
+//!
+//! let normalization = Normalization::new()
+//!     .unicode(UnicodeForm::Nfc)
+//!     .trim_trailing_whitespace(true)
+//!     .reject_empty(true);
+//! ```
+
+use unicode_normalization::UnicodeNormalization;
+use zeroize::Zeroize;
+
+use super::SecretPin;
+
+/// Error text shown when an empty passphrase is rejected
+pub(crate) const EMPTY_ERROR: &str = "The passphrase must not be empty";
+
+/// Unicode normalization forms
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnicodeForm {
+    /// Canonical composition
+    Nfc,
+    /// Canonical decomposition
+    Nfd,
+    /// Compatibility composition
+    Nfkc,
+    /// Compatibility decomposition
+    Nfkd,
+}
+
+/// How a captured passphrase is normalized before it is returned (nothing is changed by default)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Normalization {
+    reject_empty: bool,
+    trim: bool,
+    unicode: Option<UnicodeForm>,
+}
+
+impl Normalization {
+    /// Leave the passphrase as it is
+    pub fn new() -> Self {
+        Normalization::default()
+    }
+
+    /// Re-prompt (with an error text) when the passphrase is empty, after trimming
+    pub fn reject_empty(mut self, reject: bool) -> Self {
+        self.reject_empty = reject;
+        self
+    }
+
+    /// Remove trailing whitespace, including newlines
+    pub fn trim_trailing_whitespace(mut self, trim: bool) -> Self {
+        self.trim = trim;
+        self
+    }
+
+    /// Convert the passphrase to the given Unicode normalization form (passphrases that are not valid UTF-8 are left
+    /// unchanged)
+    pub fn unicode(mut self, form: UnicodeForm) -> Self {
+        self.unicode = Some(form);
+        self
+    }
+
+    /// Normalize `pin`, returning `None` if it is empty and empty passphrases are rejected
+    pub fn apply(&self, pin: SecretPin) -> Option<SecretPin> {
+        let mut pin = match (self.unicode, std::str::from_utf8(pin.unsecure())) {
+            (Some(form), Ok(s)) => normalize_unicode(s, form),
+            _ => pin,
+        };
+
+        if self.trim {
+            let len = trimmed_len(pin.unsecure());
+            if len < pin.unsecure().len() {
+                pin = SecretPin::from(&pin.unsecure()[..len]);
+            }
+        }
+
+        if self.reject_empty && pin.unsecure().is_empty() {
+            return None;
+        }
+        Some(pin)
+    }
+}
+
+fn normalize_unicode(s: &str, form: UnicodeForm) -> SecretPin {
+    let chars: Box<dyn Iterator<Item = char>> = match form {
+        UnicodeForm::Nfc => Box::new(s.nfc()),
+        UnicodeForm::Nfd => Box::new(s.nfd()),
+        UnicodeForm::Nfkc => Box::new(s.nfkc()),
+        UnicodeForm::Nfkd => Box::new(s.nfkd()),
+    };
+
+    // reserve generously up front, so that no copies of the secret are left behind by reallocations
+    let mut buf = Vec::with_capacity(s.len() * 4);
+    let mut utf8 = [0; 4];
+    for c in chars {
+        if buf.len() + 4 > buf.capacity() {
+            let mut bigger = Vec::with_capacity(buf.capacity() * 2);
+            bigger.extend_from_slice(&buf);
+            buf.zeroize();
+            buf = bigger;
+        }
+        buf.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+    }
+    utf8.zeroize();
+    SecretPin::new(buf)
+}
+
+/// Length of `pin` without trailing whitespace
+fn trimmed_len(pin: &[u8]) -> usize {
+    match std::str::from_utf8(pin) {
+        Ok(s) => s.trim_end().len(),
+        Err(_) => pin.len() - pin.iter().rev().take_while(|b| b.is_ascii_whitespace()).count(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(normalization: &Normalization, pin: &[u8]) -> Option<Vec<u8>> {
+        normalization
+            .apply(SecretPin::from(pin))
+            .map(|pin| pin.unsecure().to_vec())
+    }
+
+    #[test]
+    fn test_unicode_forms() {
+        let composed = "caf\u{e9} \u{fb01}".as_bytes();
+        let decomposed = "cafe\u{301} \u{fb01}".as_bytes();
+
+        let nfc = Normalization::new().unicode(UnicodeForm::Nfc);
+        assert_eq!(Some(composed.to_vec()), apply(&nfc, decomposed));
+        assert_eq!(Some(composed.to_vec()), apply(&nfc, composed));
+
+        let nfd = Normalization::new().unicode(UnicodeForm::Nfd);
+        assert_eq!(Some(decomposed.to_vec()), apply(&nfd, composed));
+
+        // compatibility forms also replace the ligature
+        let nfkc = Normalization::new().unicode(UnicodeForm::Nfkc);
+        assert_eq!(Some("caf\u{e9} fi".as_bytes().to_vec()), apply(&nfkc, decomposed));
+        let nfkd = Normalization::new().unicode(UnicodeForm::Nfkd);
+        assert_eq!(Some("cafe\u{301} fi".as_bytes().to_vec()), apply(&nfkd, composed));
+
+        // invalid UTF-8 is left alone
+        assert_eq!(Some(b"\xffabc".to_vec()), apply(&nfc, b"\xffabc"));
+    }
+
+    #[test]
+    fn test_trim_and_reject_empty() {
+        let normalization = Normalization::new().trim_trailing_whitespace(true).reject_empty(true);
+        assert_eq!(Some(b" secret".to_vec()), apply(&normalization, b" secret \r\n"));
+        assert_eq!(Some(b"\xffsecret".to_vec()), apply(&normalization, b"\xffsecret\n"));
+        assert_eq!(None, apply(&normalization, b" \n"));
+        assert_eq!(None, apply(&normalization, b""));
+
+        assert_eq!(Some(b"".to_vec()), apply(&Normalization::new(), b""));
+        assert_eq!(Some(b"pass \n".to_vec()), apply(&Normalization::new(), b"pass \n"));
+    }
+}
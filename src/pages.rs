@@ -0,0 +1,198 @@
+//! Showing texts too long for a single dialog
+//!
+//! [`show_message_paginated()`](SessionPrompt::show_message_paginated) splits a text (e.g. terms of use, or release
+//! notes) into pages of a given size and shows them one after another, with 'Continue' and 'Cancel' buttons:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # #[cfg(feature = "process")]
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use pinentry_rs::pinentry;
+//!
+//! # let terms = "";
+//! let read = pinentry()
+//!     .window_title("Terms of use")
+//!     .show_message_paginated(terms, 600)?;
+//! if !read {
+//!     println!("stopped reading early");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use super::assuan::{AssuanCommand, AssuanResponse, Button};
+use super::messages::Message;
+use super::session::{PinentrySession, SessionPrompt};
+#[cfg(feature = "process")]
+use super::PinentryBuilder;
+use super::{invalid, Error, PromptKind, Result};
+
+/// Label of the 'OK' button on all pages but the last
+pub const CONTINUE_LABEL: &str = "Continue";
+
+/// Split `text` into pages of at most `page_size` characters, breaking at line ends or else between words where
+/// possible
+///
+/// There is always at least one page, and there are no empty pages unless the text is empty.
+pub fn paginate(text: &str, page_size: usize) -> Vec<String> {
+    let page_size = page_size.max(1);
+    let mut pages = Vec::new();
+    let mut page = String::new();
+    let mut page_len = 0;
+
+    for line in text.lines() {
+        let mut line = line;
+        loop {
+            let line_len = line.chars().count();
+            // +1 for the line break joining it to the page
+            let needed = if page.is_empty() { line_len } else { line_len + 1 };
+            if page_len + needed <= page_size {
+                if !page.is_empty() {
+                    page.push('\n');
+                }
+                page.push_str(line);
+                page_len += needed;
+                break;
+            }
+            if !page.is_empty() {
+                pages.push(std::mem::take(&mut page));
+                page_len = 0;
+                continue;
+            }
+            // the line alone does not fit a page
+            let (head, tail) = split_words(line, page_size);
+            if !head.trim_end().is_empty() {
+                pages.push(head.trim_end().to_string());
+            }
+            line = tail.trim_start();
+            if line.is_empty() {
+                break;
+            }
+        }
+    }
+    if !page.is_empty() || pages.is_empty() {
+        pages.push(page);
+    }
+    pages
+}
+
+/// Split `line` after at most `max_chars` characters, at the last whitespace if there is one
+fn split_words(line: &str, max_chars: usize) -> (&str, &str) {
+    let cut = line.char_indices().nth(max_chars).map_or(line.len(), |(i, _)| i);
+    // a space right after the limit is a fine place to break, too
+    let end = line[cut..].chars().next().map_or(cut, |c| cut + c.len_utf8());
+    match line[..end].rfind(char::is_whitespace) {
+        Some(i) if i > 0 => line.split_at(i),
+        _ => line.split_at(cut),
+    }
+}
+
+impl PinentrySession {
+    /// Show `text` in pages of at most `page_size` characters
+    ///
+    /// See [`SessionPrompt::show_message_paginated`].
+    pub fn show_message_paginated(&mut self, text: &str, page_size: usize) -> Result<bool> {
+        self.prompt().show_message_paginated(text, page_size)
+    }
+}
+
+impl SessionPrompt<'_> {
+    /// Show `text` in pages of at most `page_size` characters (see [`paginate()`]), returning whether the user
+    /// went through all of them
+    ///
+    /// Each page is a confirmation dialog with 'Continue' and 'Cancel' buttons; the last page has the 'OK' label of
+    /// this prompt (pinentry's default if not set). Cancelling any page - including the last one - stops and returns
+    /// `false`. The text replaces the description of this prompt, and a page indicator is added to each page if
+    /// there are several.
+    pub fn show_message_paginated(mut self, text: &str, page_size: usize) -> Result<bool> {
+        self.validate(PromptKind::Message)?;
+        if page_size == 0 {
+            return Err(invalid("page_size must be at least 1"));
+        }
+        self.settings.description = None;
+        let last_label = self.settings.label_ok.take();
+        let overrides = self.settings.into_commands();
+
+        let pages = paginate(text, page_size);
+        for (i, page) in pages.iter().enumerate() {
+            let mut desc = page.to_string();
+            if pages.len() > 1 {
+                desc.push_str("\n\n");
+                desc.push_str(
+                    &Message::PageIndicator {
+                        page: i + 1,
+                        pages: pages.len(),
+                    }
+                    .text(),
+                );
+            }
+            let mut cmds = overrides.clone();
+            cmds.push(AssuanCommand::SetDescriptiveText(desc));
+            let label = if i + 1 < pages.len() {
+                Some(Message::ContinueLabel.text())
+            } else {
+                last_label.clone()
+            };
+            cmds.extend(label.map(|label| AssuanCommand::SetButtonLabel(Button::OK, label)));
+
+            match self.session.run_prompt(cmds, vec![AssuanCommand::Confirm])? {
+                AssuanResponse::OK => (),
+                AssuanResponse::NOTOK(_) => return Ok(false),
+                x => return Err(Error::unexpected_response(x)),
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(feature = "process")]
+impl PinentryBuilder {
+    /// Show `text` in pages of at most `page_size` characters
+    ///
+    /// See [`SessionPrompt::show_message_paginated`].
+    pub fn show_message_paginated(self, text: &str, page_size: usize) -> Result<bool> {
+        self.settings.validate(Some(PromptKind::Message))?;
+        self.connect()?.show_message_paginated(text, page_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paginate() {
+        assert_eq!(vec![""], paginate("", 10));
+        assert_eq!(vec!["one\ntwo"], paginate("one\ntwo", 10));
+        assert_eq!(vec!["one\ntwo", "three"], paginate("one\ntwo\nthree", 10));
+        // long lines are broken between words, or anywhere if they have to be
+        assert_eq!(vec!["a long", "line"], paginate("a long line", 7));
+        assert_eq!(vec!["abcd", "efgh", "ij"], paginate("abcdefghij", 4));
+        assert_eq!(vec!["ää", "öö"], paginate("ää öö", 2));
+    }
+
+    #[cfg(all(unix, feature = "process"))]
+    #[test]
+    fn test_show_message_paginated() {
+        use super::super::pinentry;
+        use super::super::test_util::FakePinentry;
+
+        // cancel the third page
+        let fake = FakePinentry::new(&[
+            ("SETDESC*", r#"desc="$line"; echo OK"#),
+            (
+                "CONFIRM",
+                r#"case "$desc" in *"Page 3"*) echo "ERR 83886179 Operation cancelled";; *) echo OK;; esac"#,
+            ),
+        ]);
+        let builder = pinentry().exe(fake.exe()).label_ok("Done");
+        assert!(builder.clone().show_message_paginated("first\nsecond", 6).unwrap());
+        assert!(!builder.show_message_paginated("first\nsecond\nthird", 6).unwrap());
+
+        let commands = fake.commands();
+        assert!(commands.contains(&"SETDESC first%0A%0A(Page 1 of 2)".to_string()));
+        assert!(commands.contains(&"SETOK Continue".to_string()));
+        assert!(commands.contains(&"SETOK Done".to_string()));
+        assert!(commands.contains(&"SETDESC third%0A%0A(Page 3 of 3)".to_string()));
+    }
+}
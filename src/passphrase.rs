@@ -0,0 +1,711 @@
+//! Composite flows for choosing a new passphrase
+//!
+//! Asking for a new passphrase well takes most of the protocol: a quality bar (answering pinentry's `QUALITY`
+//! inquiries with an estimate), a button suggesting a generated passphrase (`GENPIN`), asking for the passphrase
+//! twice (`SETREPEAT`, or two prompts where pinentry does not support it) and re-prompting until the passphrase
+//! satisfies the caller's policy. [`NewPassphrase`] describes such a flow:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # #[cfg(feature = "process")]
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use pinentry_rs::passphrase::NewPassphrase;
+//! use pinentry_rs::pinentry;
+//!
+//! let flow = NewPassphrase::new()
+//!     .description("Choose the passphrase protecting your vault")
+//!     .min_length(12)
+//!     .policy(|pin| {
+//!         if pin.iter().any(u8::is_ascii_digit) {
+//!             Ok(())
+//!         } else {
+//!             Err("The passphrase must contain a digit".to_string())
+//!         }
+//!     });
+//! let passphrase = pinentry().new_passphrase(flow)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! `change_passphrase()` first asks for the current passphrase (like `unlock()`, with a verifier and a limited number
+//! of attempts) and then runs the new-passphrase flow over the same pinentry, for key rotation. The prompts for the
+//! new passphrase are those of the flow, and the result converts into the (current, new) pair:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # #[cfg(feature = "process")]
+//! # fn run() -> Result<(), pinentry_rs::unlock::UnlockError<std::io::Error>> {
+//! use pinentry_rs::passphrase::NewPassphrase;
+//! use pinentry_rs::pinentry;
+//! use pinentry_rs::unlock::{UnlockError, VerifyError};
+//!
+//! # fn check_vault(_: &[u8]) -> bool { true }
+//! # fn rekey_vault(_: &[u8], _: &[u8]) -> Result<(), std::io::Error> { Ok(()) }
+//! let mut session = pinentry().connect()?;
+//! let (current, new) = session
+//!     .change_passphrase(
+//!         "Current passphrase:",
+//!         |current| {
+//!             if check_vault(current) {
+//!                 Ok(())
+//!             } else {
+//!                 Err(VerifyError::Retry("Wrong passphrase".to_string()))
+//!             }
+//!         },
+//!         NewPassphrase::new().prompt("New passphrase:").repeat_prompt("Repeat:").min_length(12),
+//!     )?
+//!     .into();
+//! rekey_vault(current.unsecure(), new.unsecure()).map_err(UnlockError::Failed)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Where the current passphrase can only be checked by using it,
+//! [`PinentryBuilder::change_passphrase`](super::PinentryBuilder::change_passphrase) takes just the two prompts and
+//! returns the pair - a typo in the current passphrase then shows when re-encrypting fails.
+//!
+//! `unlock_or_create()` handles the first run of an application as well: depending on whether the protected resource
+//! exists, it runs the unlock loop or the new-passphrase flow, each with its own texts.
+
+#[cfg(feature = "process")]
+use std::convert::Infallible;
+use std::result;
+
+use super::assuan::{AssuanCommand, AssuanResponse, Inquiry};
+use super::messages::Message;
+use super::session::{is_cancel, set_error_text, PinentrySession, SessionPrompt};
+use super::unlock::{UnlockError, VerifyError};
+#[cfg(feature = "process")]
+use super::PinentryBuilder;
+use super::{Error, PromptKind, Result, SecretPin};
+
+/// Checks a new passphrase, returning the error text to show if it is not acceptable
+type Policy<'a> = Box<dyn FnMut(&[u8]) -> result::Result<(), String> + 'a>;
+/// Estimates the quality of a passphrase, from -100 to 100
+type Estimator<'a> = Box<dyn FnMut(&[u8]) -> i32 + 'a>;
+/// Makes a passphrase to suggest
+type Generator<'a> = Box<dyn FnMut() -> SecretPin + 'a>;
+
+/// A flow asking for a new passphrase, see the [module documentation](self)
+pub struct NewPassphrase<'a> {
+    title: Option<String>,
+    description: String,
+    prompt: String,
+    repeat_prompt: String,
+    mismatch_error: String,
+    quality_bar: Option<String>,
+    estimator: Estimator<'a>,
+    generator: Option<(String, Generator<'a>)>,
+    min_length: usize,
+    policy: Option<Policy<'a>>,
+    // the current passphrase, which the new one has to differ from
+    current: Option<SecretPin>,
+}
+
+impl Default for NewPassphrase<'_> {
+    fn default() -> Self {
+        NewPassphrase {
+            title: Some(Message::NewPassphraseTitle.text()),
+            description: Message::NewPassphraseDescription.text(),
+            prompt: Message::PassphrasePrompt.text(),
+            repeat_prompt: Message::NewPassphraseRepeat.text(),
+            mismatch_error: Message::NewPassphraseMismatch.text(),
+            quality_bar: Some(Message::NewPassphraseQuality.text()),
+            estimator: Box::new(estimate_quality),
+            generator: None,
+            min_length: 0,
+            policy: None,
+            current: None,
+        }
+    }
+}
+
+impl<'a> NewPassphrase<'a> {
+    /// A flow with the default texts, a quality bar using [`estimate_quality`] and no policy
+    pub fn new() -> Self {
+        NewPassphrase::default()
+    }
+
+    /// Set the window title (`None` keeps the title set on the session)
+    pub fn title(mut self, title: Option<String>) -> Self {
+        self.title = title;
+        self
+    }
+
+    /// Set the descriptive text
+    pub fn description<S: Into<String>>(mut self, desc: S) -> Self {
+        self.description = desc.into();
+        self
+    }
+
+    /// Set the prompt of the passphrase entry
+    pub fn prompt<S: Into<String>>(mut self, prompt: S) -> Self {
+        self.prompt = prompt.into();
+        self
+    }
+
+    /// Set the prompt for repeating the passphrase
+    pub fn repeat_prompt<S: Into<String>>(mut self, prompt: S) -> Self {
+        self.repeat_prompt = prompt.into();
+        self
+    }
+
+    /// Set the error text shown when the repeated passphrase does not match
+    pub fn mismatch_error<S: Into<String>>(mut self, error: S) -> Self {
+        self.mismatch_error = error.into();
+        self
+    }
+
+    /// Set the label of the quality bar (`None` hides it)
+    pub fn quality_bar(mut self, label: Option<String>) -> Self {
+        self.quality_bar = label;
+        self
+    }
+
+    /// Estimate the quality of the passphrase typed so far, from -100 (unacceptable) to 100 (excellent) - shown in
+    /// the quality bar
+    pub fn estimator<F: FnMut(&[u8]) -> i32 + 'a>(mut self, estimator: F) -> Self {
+        self.estimator = Box::new(estimator);
+        self
+    }
+
+    /// Offer a button (with the given label) that fills in a passphrase made by `generate`
+    pub fn suggest<S: Into<String>, F: FnMut() -> SecretPin + 'a>(mut self, label: S, generate: F) -> Self {
+        self.generator = Some((label.into(), Box::new(generate)));
+        self
+    }
+
+    /// Require the passphrase to be at least `chars` characters long
+    pub fn min_length(mut self, chars: usize) -> Self {
+        self.min_length = chars;
+        self
+    }
+
+    /// Require the passphrase to satisfy `policy`, which returns the error text to re-prompt with otherwise
+    pub fn policy<F: FnMut(&[u8]) -> result::Result<(), String> + 'a>(mut self, policy: F) -> Self {
+        self.policy = Some(Box::new(policy));
+        self
+    }
+
+    /// The settings of the prompt, including the optional features that not all pinentries support
+    fn overrides(&self, fallback: bool) -> Vec<AssuanCommand> {
+        let mut cmds = Vec::new();
+        if let Some(ref title) = self.title {
+            cmds.push(AssuanCommand::SetWindowTitle(title.clone()));
+        }
+        cmds.push(AssuanCommand::SetDescriptiveText(self.description.clone()));
+        cmds.push(AssuanCommand::SetPrompt(self.prompt.clone()));
+        if fallback {
+            return cmds;
+        }
+        cmds.push(AssuanCommand::SetRepeat(Some(self.repeat_prompt.clone())));
+        cmds.push(AssuanCommand::SetRepeatError(self.mismatch_error.clone()));
+        if let Some(ref label) = self.quality_bar {
+            cmds.push(AssuanCommand::SetQualityBar(Some(label.clone())));
+        }
+        if let Some((ref label, _)) = self.generator {
+            cmds.push(AssuanCommand::SetGenPin(label.clone()));
+        }
+        cmds
+    }
+
+    /// The error text to re-prompt with, if `pin` violates the policy
+    fn check(&mut self, pin: &[u8]) -> Option<String> {
+        let len = match std::str::from_utf8(pin) {
+            Ok(s) => s.chars().count(),
+            Err(_) => pin.len(),
+        };
+        if len < self.min_length {
+            return Some(Message::PassphraseTooShort { min: self.min_length }.text());
+        }
+        if self.current.as_ref().is_some_and(|current| current.unsecure() == pin) {
+            return Some(Message::PassphraseUnchanged.text());
+        }
+        self.policy.as_mut().and_then(|policy| policy(pin).err())
+    }
+
+    fn answer(&mut self, inquiry: &Inquiry) -> Option<SecretPin> {
+        match inquiry.keyword.as_str() {
+            "QUALITY" => {
+                let pin = inquiry.secret_params().ok()?;
+                let quality = (self.estimator)(pin.unsecure()).clamp(-100, 100);
+                Some(SecretPin::from(quality.to_string()))
+            }
+            "GENPIN" => self.generator.as_mut().map(|(_, generate)| generate()),
+            _ => None,
+        }
+    }
+}
+
+/// A rough estimate of the strength of `pin` for the quality bar, from 0 to 100 (reached at 80 bits)
+///
+/// The estimate assumes the passphrase is random over the character classes it uses (lower case, upper case, digits,
+/// other ASCII and non-ASCII), so it is optimistic for dictionary words - plug in a real estimator with
+/// [`NewPassphrase::estimator`] where that matters.
+pub fn estimate_quality(pin: &[u8]) -> i32 {
+    let text = String::from_utf8_lossy(pin);
+    // sizes of the character classes used: lower case, upper case, digits, other ASCII, non-ASCII
+    let mut used = [false; 5];
+    for c in text.chars() {
+        let class = match c {
+            'a'..='z' => 0,
+            'A'..='Z' => 1,
+            '0'..='9' => 2,
+            _ if c.is_ascii() => 3,
+            _ => 4,
+        };
+        used[class] = true;
+    }
+    let alphabet: u32 = [26, 26, 10, 33, 100]
+        .iter()
+        .zip(used)
+        .filter(|(_, used)| *used)
+        .map(|(size, _)| size)
+        .sum();
+    if alphabet == 0 {
+        return 0;
+    }
+    let bits = text.chars().count() as f64 * f64::from(alphabet).log2();
+    ((bits / 80.0 * 100.0) as i32).min(100)
+}
+
+/// The characters of [`random_passphrase`]: lower case letters and digits, without the easily confused `l`, `o`,
+/// `0` and `1`
+#[cfg(feature = "genpin")]
+const PASSPHRASE_ALPHABET: &[u8; 32] = b"abcdefghijkmnpqrstuvwxyz23456789";
+
+/// A random passphrase of 4 groups of 5 characters (e.g. `k7fq2-mxa9r-...`), with 100 bits of entropy (with the
+/// `genpin` feature)
+///
+/// Suggested for the generate button of pinentry (`SETGENPIN`) unless a
+/// [`genpin_fn`](super::PinentryBuilder::genpin_fn) is set.
+#[cfg(feature = "genpin")]
+pub fn random_passphrase() -> Result<SecretPin> {
+    let mut random = SecretPin::new(vec![0; 20]);
+    getrandom::getrandom(random.unsecure_mut()).map_err(|e| Error::IoError(std::io::Error::other(e.to_string())))?;
+    let mut passphrase = SecretPin::new(vec![0; 23]);
+    let out = passphrase.unsecure_mut();
+    for (i, b) in random.unsecure().iter().enumerate() {
+        // 32 characters, so every one is equally likely
+        out[i + i / 5] = PASSPHRASE_ALPHABET[(b & 31) as usize];
+    }
+    for dash in [5, 11, 17] {
+        out[dash] = b'-';
+    }
+    Ok(passphrase)
+}
+
+impl PinentrySession {
+    /// Ask for a new passphrase, following `flow`
+    ///
+    /// The passphrase is asked for twice, by pinentry itself (`SETREPEAT`) or - if pinentry does not support the
+    /// optional features of the flow - with a second prompt. Fails with the pinentry error if the prompt is
+    /// cancelled.
+    pub fn new_passphrase(&mut self, mut flow: NewPassphrase<'_>) -> Result<SecretPin> {
+        let mut fallback = false;
+        let mut error_text = None;
+        loop {
+            let mut overrides = flow.overrides(fallback);
+            if let Some(text) = error_text.take() {
+                set_error_text(&mut overrides, text);
+            }
+            let res = self.run_prompt_with(overrides, vec![AssuanCommand::GetPin], &mut |inquiry| {
+                flow.answer(inquiry)
+            })?;
+            let pin = match res {
+                AssuanResponse::PIN(pin) => pin,
+                // an old pinentry rejecting SETREPEAT, SETQUALITYBAR or SETGENPIN: do without them
+                AssuanResponse::NOTOK(error) if !fallback && !is_cancel(&error) => {
+                    debug!(
+                        "pinentry does not support the new passphrase dialog ({}), falling back",
+                        error
+                    );
+                    fallback = true;
+                    continue;
+                }
+                AssuanResponse::NOTOK(error) => return Err(Error::from_response(error)),
+                res => return Err(Error::unexpected_response(res)),
+            };
+
+            if fallback {
+                let res = self.run_prompt(
+                    vec![AssuanCommand::SetPrompt(flow.repeat_prompt.clone())],
+                    vec![AssuanCommand::GetPin],
+                )?;
+                match res {
+                    AssuanResponse::PIN(repeated) if repeated == pin => (),
+                    AssuanResponse::PIN(_) => {
+                        error_text = Some(flow.mismatch_error.clone());
+                        continue;
+                    }
+                    AssuanResponse::NOTOK(error) => return Err(Error::from_response(error)),
+                    res => return Err(Error::unexpected_response(res)),
+                }
+            }
+
+            match flow.check(pin.unsecure()) {
+                Some(text) => error_text = Some(text),
+                None => return Ok(pin),
+            }
+        }
+    }
+
+    /// Ask for the current passphrase until `verify` accepts it, then for a new one following `flow`
+    ///
+    /// See [`SessionPrompt::change_passphrase`].
+    pub fn change_passphrase<E, F, S: Into<String>>(
+        &mut self,
+        prompt: S,
+        verify: F,
+        flow: NewPassphrase<'_>,
+    ) -> result::Result<PassphraseChange, UnlockError<E>>
+    where
+        F: FnMut(&[u8]) -> result::Result<(), VerifyError<E>>,
+    {
+        self.prompt().change_passphrase(prompt, verify, flow)
+    }
+
+    /// Unlock the protected resource if it exists already, or ask for the passphrase of a new one
+    ///
+    /// See [`SessionPrompt::unlock_or_create`].
+    pub fn unlock_or_create<T, E, X, F, S: Into<String>>(
+        &mut self,
+        exists: X,
+        prompt: S,
+        verify: F,
+        flow: NewPassphrase<'_>,
+    ) -> result::Result<UnlockOrCreate<T>, UnlockError<E>>
+    where
+        X: FnOnce() -> bool,
+        F: FnMut(&[u8]) -> result::Result<T, VerifyError<E>>,
+    {
+        self.prompt().unlock_or_create(exists, prompt, verify, flow)
+    }
+}
+
+/// Which branch [`unlock_or_create()`](PinentrySession::unlock_or_create) took
+#[derive(Debug)]
+pub enum UnlockOrCreate<T> {
+    /// The resource existed and was unlocked, with the value returned by the verifier
+    Unlocked(T),
+    /// The resource did not exist; this is the passphrase chosen for it
+    Created(SecretPin),
+}
+
+/// The passphrases entered by [`change_passphrase()`](PinentrySession::change_passphrase)
+#[derive(Debug)]
+pub struct PassphraseChange {
+    /// The current passphrase, as accepted by the verifier
+    pub current: SecretPin,
+    /// The new passphrase
+    pub new: SecretPin,
+}
+
+impl From<PassphraseChange> for (SecretPin, SecretPin) {
+    /// The current and the new passphrase, in this order
+    fn from(change: PassphraseChange) -> Self {
+        (change.current, change.new)
+    }
+}
+
+impl SessionPrompt<'_> {
+    /// Ask for the current passphrase until `verify` accepts it, then for a new one following `flow`
+    ///
+    /// The settings of this prompt apply to asking for the current passphrase, which works like
+    /// [`unlock()`](SessionPrompt::unlock) (including the number of attempts). The new passphrase has to differ from
+    /// the current one. Both are returned so the caller can re-encrypt whatever they protect.
+    pub fn change_passphrase<E, F, S: Into<String>>(
+        self,
+        prompt: S,
+        mut verify: F,
+        mut flow: NewPassphrase<'_>,
+    ) -> result::Result<PassphraseChange, UnlockError<E>>
+    where
+        F: FnMut(&[u8]) -> result::Result<(), VerifyError<E>>,
+    {
+        let SessionPrompt { session, settings } = self;
+        let current = SessionPrompt {
+            session: &mut *session,
+            settings,
+        }
+        .unlock(prompt, |pin| verify(pin).map(|_| SecretPin::from(pin)))?;
+
+        flow.current = Some(current.clone());
+        let new = session.new_passphrase(flow)?;
+        Ok(PassphraseChange { current, new })
+    }
+
+    /// Unlock the protected resource if it exists already, or ask for the passphrase of a new one
+    ///
+    /// If `exists` returns true, this works like [`unlock()`](SessionPrompt::unlock) (using the settings of this
+    /// prompt); otherwise the new-passphrase `flow` is run (with its own texts), so the first and the following runs
+    /// of an application each get a fitting dialog.
+    pub fn unlock_or_create<T, E, X, F, S: Into<String>>(
+        self,
+        exists: X,
+        prompt: S,
+        verify: F,
+        flow: NewPassphrase<'_>,
+    ) -> result::Result<UnlockOrCreate<T>, UnlockError<E>>
+    where
+        X: FnOnce() -> bool,
+        F: FnMut(&[u8]) -> result::Result<T, VerifyError<E>>,
+    {
+        if exists() {
+            self.unlock(prompt, verify).map(UnlockOrCreate::Unlocked)
+        } else {
+            self.validate(PromptKind::Unlock)?;
+            Ok(UnlockOrCreate::Created(self.session.new_passphrase(flow)?))
+        }
+    }
+}
+
+#[cfg(feature = "process")]
+impl PinentryBuilder {
+    /// Ask for a new passphrase, following `flow`
+    ///
+    /// See [`PinentrySession::new_passphrase`].
+    pub fn new_passphrase(self, flow: NewPassphrase<'_>) -> Result<SecretPin> {
+        self.settings.validate(Some(PromptKind::Pin))?;
+        self.connect()?.new_passphrase(flow)
+    }
+
+    /// Ask for the current passphrase, then for a new one, returning the (current, new) pair
+    ///
+    /// Both are asked for in one pinentry: `old_prompt` for the current passphrase, `new_prompt` for the new one,
+    /// which is asked for twice (`SETREPEAT`, or a second prompt) and asked for again with an error text if the two
+    /// do not match. The current passphrase is not checked - use
+    /// [`change_passphrase_with`](PinentryBuilder::change_passphrase_with) to verify it first.
+    pub fn change_passphrase<S: Into<String>, N: Into<String>>(
+        self,
+        old_prompt: S,
+        new_prompt: N,
+    ) -> Result<(SecretPin, SecretPin)> {
+        let flow = NewPassphrase::new().prompt(new_prompt);
+        match self.change_passphrase_with(old_prompt, |_| Ok::<_, VerifyError<Infallible>>(()), flow) {
+            Ok(change) => Ok(change.into()),
+            Err(UnlockError::Pinentry(e)) => Err(e),
+            Err(UnlockError::LockedOut { attempts }) => Err(Error::MaxAttemptsExceeded(attempts)),
+            Err(UnlockError::Failed(never)) => match never {},
+        }
+    }
+
+    /// Ask for the current passphrase until `verify` accepts it, then for a new one following `flow`
+    ///
+    /// This is the flow for changing a passphrase in one session: `prompt` asks for the current one, the prompts of
+    /// `flow` ([`prompt`](NewPassphrase::prompt), [`repeat_prompt`](NewPassphrase::repeat_prompt)) for the new one,
+    /// which pinentry asks for twice. The result converts into the (current, new) pair. See
+    /// [`SessionPrompt::change_passphrase`].
+    pub fn change_passphrase_with<E, F, S: Into<String>>(
+        self,
+        prompt: S,
+        verify: F,
+        flow: NewPassphrase<'_>,
+    ) -> result::Result<PassphraseChange, UnlockError<E>>
+    where
+        F: FnMut(&[u8]) -> result::Result<(), VerifyError<E>>,
+    {
+        self.settings.validate(Some(PromptKind::Unlock))?;
+        self.connect()?.change_passphrase(prompt, verify, flow)
+    }
+
+    /// Unlock the protected resource if it exists already, or ask for the passphrase of a new one
+    ///
+    /// See [`SessionPrompt::unlock_or_create`].
+    pub fn unlock_or_create<T, E, X, F, S: Into<String>>(
+        self,
+        exists: X,
+        prompt: S,
+        verify: F,
+        flow: NewPassphrase<'_>,
+    ) -> result::Result<UnlockOrCreate<T>, UnlockError<E>>
+    where
+        X: FnOnce() -> bool,
+        F: FnMut(&[u8]) -> result::Result<T, VerifyError<E>>,
+    {
+        self.settings.validate(Some(PromptKind::Unlock))?;
+        self.connect()?.unlock_or_create(exists, prompt, verify, flow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_quality() {
+        assert_eq!(0, estimate_quality(b""));
+        assert!(estimate_quality(b"abc") < estimate_quality(b"aB3"));
+        assert!(estimate_quality(b"abcdef") < estimate_quality(b"abcdefgh"));
+        assert_eq!(100, estimate_quality("correct horse battery staple".as_bytes()));
+    }
+
+    #[cfg(feature = "genpin")]
+    #[test]
+    fn test_random_passphrase() {
+        let first = random_passphrase().unwrap();
+        let groups: Vec<&[u8]> = first.unsecure().split(|&b| b == b'-').collect();
+        assert_eq!(4, groups.len());
+        for group in groups {
+            assert_eq!(5, group.len());
+            assert!(group.iter().all(|b| PASSPHRASE_ALPHABET.contains(b)));
+        }
+        assert_ne!(first, random_passphrase().unwrap());
+    }
+
+    #[cfg(all(unix, feature = "process"))]
+    #[test]
+    fn test_new_passphrase() {
+        use super::super::pinentry;
+        use super::super::test_util::FakePinentry;
+
+        // asks for the quality of the typed passphrase and a suggestion, then returns a short passphrase first
+        let fake = FakePinentry::new(&[(
+            "GETPIN",
+            r#"echo "INQUIRE QUALITY ab%25"; read -r q; read -r end; echo "$q" >> "$DIR/answers"
+echo "INQUIRE GENPIN"; read -r g; read -r end; echo "$g" >> "$DIR/answers"
+n=$((n+1)); echo "S PIN_REPEATED"; if [ $n = 1 ]; then echo "D short"; else echo "D long enough"; fi; echo OK"#,
+        )]);
+        let flow = NewPassphrase::new()
+            .estimator(|pin| pin.len() as i32 * 10)
+            .suggest("_Generate", || SecretPin::from("generated"))
+            .min_length(8);
+        let pin = pinentry().exe(fake.exe()).new_passphrase(flow).unwrap();
+        assert_eq!(SecretPin::from("long enough"), pin);
+
+        let answers = std::fs::read_to_string(std::path::Path::new(&fake.exe()).with_file_name("answers")).unwrap();
+        assert_eq!("D 30\nD generated\nD 30\nD generated\n", answers);
+        let commands = fake.commands();
+        assert!(commands.contains(&"SETQUALITYBAR Quality:".to_string()));
+        assert!(commands.contains(&"SETGENPIN _Generate".to_string()));
+        assert!(commands.contains(&"SETERROR The passphrase must be at least 8 characters long".to_string()));
+    }
+
+    #[cfg(all(unix, feature = "process"))]
+    #[test]
+    fn test_change_passphrase() {
+        use super::super::pinentry;
+        use super::super::test_util::FakePinentry;
+
+        // a wrong current passphrase, the right one, the current one again as the new one, then a new one
+        let fake = FakePinentry::new(&[(
+            "GETPIN",
+            r#"n=$((n+1)); case $n in 1) echo "D wrong";; 2|3) echo "D old";; *) echo "D new";; esac; echo OK"#,
+        )]);
+        let mut session = pinentry().exe(fake.exe()).connect().unwrap();
+        let change = session
+            .prompt()
+            .description("Current")
+            .change_passphrase(
+                "Current passphrase:",
+                |pin| match pin {
+                    b"old" => Ok(()),
+                    _ => Err(VerifyError::<()>::Retry("Wrong passphrase".to_string())),
+                },
+                NewPassphrase::new(),
+            )
+            .unwrap();
+        let (current, new) = change.into();
+        assert_eq!(SecretPin::from("old"), current);
+        assert_eq!(SecretPin::from("new"), new);
+
+        let commands = fake.commands();
+        assert!(commands.contains(&"SETDESC Current".to_string()));
+        assert!(commands.contains(&"SETERROR Wrong passphrase (2 attempts left)".to_string()));
+        assert!(commands.contains(&"SETERROR The new passphrase must differ from the current one".to_string()));
+    }
+
+    #[cfg(all(unix, feature = "process"))]
+    #[test]
+    fn test_unlock_or_create() {
+        use super::super::pinentry;
+        use super::super::test_util::FakePinentry;
+
+        let fake = FakePinentry::new(&[("GETPIN", r#"echo "D passphrase"; echo OK"#)]);
+        let mut session = pinentry().exe(fake.exe()).connect().unwrap();
+        let verify = |pin: &[u8]| Ok::<_, VerifyError<()>>(pin.len());
+
+        let res = session.prompt().description("Unlock the vault").unlock_or_create(
+            || false,
+            "Passphrase:",
+            verify,
+            NewPassphrase::new().description("Create the vault"),
+        );
+        assert!(matches!(res, Ok(UnlockOrCreate::Created(ref pin)) if *pin == SecretPin::from("passphrase")));
+
+        let res = session.prompt().description("Unlock the vault").unlock_or_create(
+            || true,
+            "Passphrase:",
+            verify,
+            NewPassphrase::new().description("Create the vault"),
+        );
+        assert!(matches!(res, Ok(UnlockOrCreate::Unlocked(10))));
+
+        let descriptions: Vec<_> = fake
+            .commands()
+            .into_iter()
+            .filter(|c| c.starts_with("SETDESC"))
+            .collect();
+        assert_eq!(
+            vec!["SETDESC Create the vault", "SETDESC Unlock the vault"],
+            descriptions
+        );
+    }
+
+    #[cfg(all(unix, feature = "process"))]
+    #[test]
+    fn test_new_passphrase_fallback() {
+        use super::super::pinentry;
+        use super::super::test_util::FakePinentry;
+
+        // an old pinentry without SETREPEAT, and a user mistyping the passphrase the first time
+        let fake = FakePinentry::new(&[
+            (
+                "SETREPEAT*",
+                r#"echo "ERR 536871187 Unknown IPC command <User defined source 1>""#,
+            ),
+            (
+                "GETPIN",
+                r#"n=$((n+1)); case $n in 2) echo "D typo";; *) echo "D passphrase";; esac; echo OK"#,
+            ),
+        ]);
+        let pin = pinentry().exe(fake.exe()).new_passphrase(NewPassphrase::new()).unwrap();
+        assert_eq!(SecretPin::from("passphrase"), pin);
+
+        let commands = fake.commands();
+        assert_eq!(4, commands.iter().filter(|c| *c == "GETPIN").count());
+        assert!(commands.contains(&"SETERROR The passphrases do not match".to_string()));
+    }
+
+    #[cfg(all(unix, feature = "process"))]
+    #[test]
+    fn test_change_passphrase_prompts() {
+        use super::super::pinentry;
+        use super::super::test_util::FakePinentry;
+
+        // an old pinentry without SETREPEAT: the current passphrase, then the new one mistyped when repeating it
+        let fake = FakePinentry::new(&[
+            (
+                "SETREPEAT*",
+                r#"echo "ERR 536871187 Unknown IPC command <User defined source 1>""#,
+            ),
+            (
+                "GETPIN",
+                r#"n=$((n+1)); case $n in 1) echo "D old";; 3) echo "D typo";; *) echo "D new";; esac; echo OK"#,
+            ),
+        ]);
+        let (current, new) = pinentry()
+            .exe(fake.exe())
+            .change_passphrase("Current passphrase:", "New passphrase:")
+            .unwrap();
+        assert_eq!(SecretPin::from("old"), current);
+        assert_eq!(SecretPin::from("new"), new);
+
+        let commands = fake.commands();
+        assert_eq!(1, fake.spawn_count());
+        assert_eq!(5, commands.iter().filter(|c| *c == "GETPIN").count());
+        assert!(commands.contains(&"SETPROMPT Current passphrase:".to_string()));
+        assert!(commands.contains(&"SETPROMPT New passphrase:".to_string()));
+        assert!(commands.contains(&"SETERROR The passphrases do not match".to_string()));
+    }
+}
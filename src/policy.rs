@@ -0,0 +1,217 @@
+//! Central control over which prompts are shown
+//!
+//! Applications embedding this crate in managed environments may need to decide centrally when users can be
+//! prompted: only during office hours, never for certain keys, or answered from an approved source (e.g. a
+//! hardware-backed store) instead of asking. A [`PromptPolicy`] set on the builder is consulted with the metadata of
+//! each dialog before it is shown, and decides what happens to it:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # #[cfg(feature = "process")]
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use pinentry_rs::pinentry;
+//! use pinentry_rs::policy::{Decision, DialogKind, PromptRequest};
+//!
+//! let builder = pinentry().policy(|request: &PromptRequest| match request.kind {
+//!     DialogKind::Pin if request.key_info.as_deref() == Some("n/backup") => {
+//!         Decision::Deny("backup keys may not be unlocked interactively".to_string())
+//!     }
+//!     _ => Decision::Allow,
+//! });
+//! let pin = builder.pin("PIN:")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Denied prompts fail with [`Error::PolicyDenied`]. Every dialog is decided on
+//! separately, including the repeated prompts of `unlock()`; answered dialogs are not shown and do not count towards
+//! a [rate limit](super::rate_limit).
+
+use std::fmt;
+use std::sync::Arc;
+
+use super::assuan::{describe, AssuanCommand, AssuanError, AssuanResponse, Line};
+use super::{invalid, Error, Result, SecretPin};
+
+/// The kind of dialog about to be shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DialogKind {
+    /// Asks for a PIN or passphrase
+    Pin,
+    /// Asks for a confirmation
+    Confirm,
+    /// Shows a message
+    Message,
+}
+
+/// A dialog about to be shown, as passed to [`PromptPolicy::decide`]
+///
+/// The texts are the ones pinentry would show, including the defaults of the session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PromptRequest {
+    /// The kind of dialog
+    pub kind: DialogKind,
+    /// The descriptive text
+    pub description: Option<String>,
+    /// The prompt next to the PIN entry
+    pub prompt: Option<String>,
+    /// The window title
+    pub title: Option<String>,
+    /// The error text (e.g. about a previously rejected PIN)
+    pub error_text: Option<String>,
+    /// The cache key of the passphrase (`SETKEYINFO`), identifying what it is for
+    pub key_info: Option<String>,
+}
+
+impl PromptRequest {
+    /// The request for the dialog shown by `cmds` (settings in the order they are applied, then the dialog command),
+    /// `None` if no dialog is shown
+    pub(crate) fn from_commands<'a, I>(cmds: I) -> Option<PromptRequest>
+    where
+        I: IntoIterator<Item = &'a AssuanCommand>,
+    {
+        let mut request = PromptRequest {
+            kind: DialogKind::Message,
+            description: None,
+            prompt: None,
+            title: None,
+            error_text: None,
+            key_info: None,
+        };
+        for cmd in cmds {
+            match cmd {
+                AssuanCommand::SetDescriptiveText(text) => request.description = Some(text.clone()),
+                AssuanCommand::SetPrompt(text) => request.prompt = Some(text.clone()),
+                AssuanCommand::SetWindowTitle(text) => request.title = Some(text.clone()),
+                AssuanCommand::SetErrorText(text) => request.error_text = Some(text.clone()),
+                AssuanCommand::SetKeyInfo(text) => request.key_info = Some(text.clone()),
+                AssuanCommand::GetPin => request.kind = DialogKind::Pin,
+                AssuanCommand::Confirm | AssuanCommand::ConfirmOneButton => request.kind = DialogKind::Confirm,
+                AssuanCommand::ShowMessage => request.kind = DialogKind::Message,
+                _ => continue,
+            }
+            if cmd.is_terminal() {
+                return Some(request);
+            }
+        }
+        None
+    }
+}
+
+/// What to do with a dialog
+pub enum Decision {
+    /// Show the dialog
+    Allow,
+    /// Do not show the dialog, fail with [`Error::PolicyDenied`] carrying the reason
+    Deny(String),
+    /// Do not show the PIN dialog, but return this PIN as if it had been entered
+    Pin(SecretPin),
+    /// Do not show the confirmation (or message) dialog, but return as if it had been confirmed (`true`) or
+    /// declined (`false`)
+    Confirmed(bool),
+}
+
+impl fmt::Debug for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Decision::Allow => write!(f, "Allow"),
+            Decision::Deny(reason) => f.debug_tuple("Deny").field(reason).finish(),
+            Decision::Pin(_) => write!(f, "Pin([redacted])"),
+            Decision::Confirmed(confirmed) => f.debug_tuple("Confirmed").field(confirmed).finish(),
+        }
+    }
+}
+
+/// Decides whether dialogs are shown, see the [module documentation](self)
+///
+/// Implemented for closures taking a [`PromptRequest`].
+pub trait PromptPolicy: Send + Sync {
+    /// Decide what to do with the dialog described by `request`
+    fn decide(&self, request: &PromptRequest) -> Decision;
+}
+
+impl<F> PromptPolicy for F
+where
+    F: Fn(&PromptRequest) -> Decision + Send + Sync,
+{
+    fn decide(&self, request: &PromptRequest) -> Decision {
+        self(request)
+    }
+}
+
+/// A policy shared by a builder and the sessions started from it
+pub(crate) type SharedPolicy = Arc<dyn PromptPolicy>;
+
+/// Consult `policy` about the dialog shown by `cmds`: `None` if it may be shown, otherwise the response to return
+/// instead of showing it
+pub(crate) fn apply<'a, I>(policy: &dyn PromptPolicy, cmds: I) -> Result<Option<AssuanResponse>>
+where
+    I: IntoIterator<Item = &'a AssuanCommand>,
+{
+    let request = match PromptRequest::from_commands(cmds) {
+        Some(request) => request,
+        None => return Ok(None),
+    };
+    let decision = policy.decide(&request);
+    debug!("policy decision for a {:?} prompt: {:?}", request.kind, decision);
+    match (decision, request.kind) {
+        (Decision::Allow, _) => Ok(None),
+        (Decision::Deny(reason), _) => Err(Error::PolicyDenied(reason)),
+        (Decision::Pin(pin), DialogKind::Pin) => Ok(Some(AssuanResponse::PIN(pin))),
+        (Decision::Confirmed(true), DialogKind::Confirm | DialogKind::Message) => Ok(Some(AssuanResponse::OK)),
+        (Decision::Confirmed(false), DialogKind::Confirm) => {
+            let declined = AssuanError::new(
+                AssuanError::SOURCE_PINENTRY,
+                AssuanError::NOT_CONFIRMED,
+                Some("Not confirmed".to_string()),
+            );
+            Ok(Some(AssuanResponse::NOTOK(describe(&Line::Err(declined)))))
+        }
+        (decision, kind) => Err(invalid(&format!(
+            "the policy answered a {:?} prompt with {:?}",
+            kind, decision
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_commands() {
+        let cmds = [
+            AssuanCommand::SetDescriptiveText("default".to_string()),
+            AssuanCommand::SetWindowTitle("Title".to_string()),
+            AssuanCommand::SetDescriptiveText("Unlock the key".to_string()),
+            AssuanCommand::SetKeyInfo("n/key".to_string()),
+            AssuanCommand::SetPrompt("PIN:".to_string()),
+            AssuanCommand::GetPin,
+        ];
+        let request = PromptRequest::from_commands(&cmds).unwrap();
+        assert_eq!(DialogKind::Pin, request.kind);
+        assert_eq!(Some("Unlock the key"), request.description.as_deref());
+        assert_eq!(Some("Title"), request.title.as_deref());
+        assert_eq!(Some("n/key"), request.key_info.as_deref());
+        assert!(PromptRequest::from_commands(&cmds[..5]).is_none());
+    }
+
+    #[test]
+    fn test_apply() {
+        let confirm = [AssuanCommand::Confirm];
+        let decline = |_: &PromptRequest| Decision::Confirmed(false);
+        match apply(&decline, &confirm) {
+            Ok(Some(AssuanResponse::NOTOK(error))) => assert!(error.starts_with("ERR 83886194")),
+            x => panic!("unexpected result {:?}", x),
+        }
+        let deny = |_: &PromptRequest| Decision::Deny("not now".to_string());
+        assert!(matches!(apply(&deny, &confirm), Err(Error::PolicyDenied(_))));
+        // a PIN is no answer to a confirmation
+        let answer = |_: &PromptRequest| Decision::Pin(SecretPin::from("1234"));
+        assert!(matches!(apply(&answer, &confirm), Err(Error::InvalidConfiguration(_))));
+        assert!(matches!(
+            apply(&answer, &[AssuanCommand::GetPin]),
+            Ok(Some(AssuanResponse::PIN(_)))
+        ));
+    }
+}
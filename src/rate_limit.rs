@@ -0,0 +1,126 @@
+//! Limiting how often dialogs are shown
+//!
+//! A background task that keeps failing (or is being abused) can bury the user under password dialogs - and a user
+//! tired of dismissing them may eventually type a password just to make them stop, which "MFA fatigue" attacks rely
+//! on. A [`RateLimit`] caps the number of dialogs per period; once it is used up, prompts fail with
+//! [`Error::RateLimited`] until the oldest dialog is old enough:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # #[cfg(feature = "process")]
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use std::time::Duration;
+//!
+//! use pinentry_rs::rate_limit::RateLimit;
+//! use pinentry_rs::{pinentry, Error};
+//!
+//! // shared by every session started from `builder` (and from its clones)
+//! let builder = pinentry().rate_limit(RateLimit::new(5, Duration::from_secs(60)));
+//! match builder.clone().pin("PIN:") {
+//!     Err(Error::RateLimited(retry_after)) => println!("not asking again for {:?}", retry_after),
+//!     res => drop(res?),
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Every dialog counts, including the repeated prompts of `unlock()` after a wrong PIN. Settings and other commands
+//! that show nothing are not limited.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{invalid, Error, Result};
+
+/// A limit on the number of dialogs per period, shared by all its clones
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    max_prompts: usize,
+    period: Duration,
+    // when the dialogs of the current period were shown, oldest first
+    shown: Arc<Mutex<VecDeque<Instant>>>,
+}
+
+impl RateLimit {
+    /// Allow at most `max_prompts` dialogs within any `period`
+    pub fn new(max_prompts: usize, period: Duration) -> Self {
+        RateLimit {
+            max_prompts,
+            period,
+            shown: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    /// At most `max_prompts` dialogs per minute
+    pub fn per_minute(max_prompts: usize) -> Self {
+        RateLimit::new(max_prompts, Duration::from_secs(60))
+    }
+
+    /// Count a dialog about to be shown, or fail with [`Error::RateLimited`] if the limit is used up
+    pub fn acquire(&self) -> Result<()> {
+        self.acquire_at(Instant::now())
+    }
+
+    /// How long until the next dialog may be shown (zero if one may be shown now)
+    pub fn retry_after(&self) -> Duration {
+        let now = Instant::now();
+        let mut shown = self.shown.lock().expect("rate limit lock is not poisoned");
+        self.expire(&mut shown, now);
+        self.cooldown(&shown, now)
+    }
+
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.max_prompts == 0 {
+            return Err(invalid("a rate limit must allow at least one prompt"));
+        }
+        Ok(())
+    }
+
+    fn acquire_at(&self, now: Instant) -> Result<()> {
+        let mut shown = self.shown.lock().expect("rate limit lock is not poisoned");
+        self.expire(&mut shown, now);
+        if shown.len() >= self.max_prompts {
+            let retry_after = self.cooldown(&shown, now);
+            debug!("too many prompts, refusing to show another one for {:?}", retry_after);
+            return Err(Error::RateLimited(retry_after));
+        }
+        shown.push_back(now);
+        Ok(())
+    }
+
+    fn expire(&self, shown: &mut VecDeque<Instant>, now: Instant) {
+        while shown.front().is_some_and(|t| now.duration_since(*t) >= self.period) {
+            shown.pop_front();
+        }
+    }
+
+    fn cooldown(&self, shown: &VecDeque<Instant>, now: Instant) -> Duration {
+        match shown.front() {
+            Some(oldest) if shown.len() >= self.max_prompts => self.period.saturating_sub(now.duration_since(*oldest)),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let limit = RateLimit::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+        limit.acquire_at(start).unwrap();
+        // shared by clones
+        limit.clone().acquire_at(start + Duration::from_secs(10)).unwrap();
+        match limit.acquire_at(start + Duration::from_secs(20)) {
+            Err(Error::RateLimited(retry_after)) => assert_eq!(Duration::from_secs(40), retry_after),
+            res => panic!("unexpected result {:?}", res),
+        }
+        // the first dialog no longer counts after a minute
+        limit.acquire_at(start + Duration::from_secs(60)).unwrap();
+        assert!(limit.acquire_at(start + Duration::from_secs(65)).is_err());
+        assert!(RateLimit::per_minute(0).check().is_err());
+    }
+}
@@ -0,0 +1,234 @@
+//! Running pinentry in a sandbox (Linux, with the `sandbox` feature)
+//!
+//! pinentry handles the most sensitive input there is, so it should not be able to do anything else: a compromised
+//! or malicious pinentry (e.g. a tampered binary earlier in `PATH`) could otherwise read the user's files or send the
+//! passphrase over the network. With a [`Sandbox`] set on the builder, pinentry is started through
+//! [bubblewrap](https://github.com/containers/bubblewrap) (`bwrap`) with a restrictive profile: the system directories
+//! read-only, an empty `/tmp`, no home directory, no network and its own PID and IPC namespaces.
+//!
+//! What a flavor needs to show its dialog differs - the terminal for `pinentry-curses`, the display server (and
+//! often the session bus) for GUI flavors - so it is granted with [`Access`]:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use pinentry_rs::pinentry;
+//! use pinentry_rs::sandbox::Sandbox;
+//!
+//! let pin = pinentry()
+//!     .exe("pinentry-gnome3")
+//!     .sandbox(Sandbox::gui())
+//!     .pin("PIN:")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! `bwrap` has to be installed (and unprivileged user namespaces enabled). bubblewrap also sets `no_new_privs`, so
+//! pinentry cannot gain privileges through setuid binaries inside the sandbox.
+
+use std::env;
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use super::transport::ProcessTransport;
+
+/// System directories available (read-only) to pinentry, if they exist
+const SYSTEM_DIRS: &[&str] = &["/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64"];
+
+/// Files and directories under `/etc` needed for libraries, fonts and user names
+const SYSTEM_CONFIG: &[&str] = &[
+    "/etc/ld.so.cache",
+    "/etc/ld.so.conf",
+    "/etc/ld.so.conf.d",
+    "/etc/fonts",
+    "/etc/localtime",
+    "/etc/passwd",
+    "/etc/group",
+    "/etc/nsswitch.conf",
+    "/etc/alternatives",
+];
+
+/// Something pinentry may use from inside the sandbox
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// The terminal (`/dev/tty` and the pseudo-terminals), for `pinentry-curses` and `pinentry-tty`
+    Terminal,
+    /// The X11 sockets, and the file named by `XAUTHORITY`
+    X11,
+    /// The Wayland socket named by `WAYLAND_DISPLAY` (`wayland-0` if not set)
+    Wayland,
+    /// The D-Bus session bus, used by `pinentry-gnome3` and for the external password cache
+    SessionBus,
+    /// The network (e.g. for X11 over TCP)
+    Network,
+}
+
+/// The profile pinentry is started with, see the [module documentation](self)
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    bwrap: String,
+    access: Vec<Access>,
+    read_only: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    /// The bare profile, which lets pinentry show nothing - add [`Access`] with [`allow()`](Sandbox::allow)
+    pub fn new() -> Self {
+        Sandbox {
+            bwrap: "bwrap".to_string(),
+            access: Vec::new(),
+            read_only: Vec::new(),
+        }
+    }
+
+    /// The profile for terminal flavors (`pinentry-curses`, `pinentry-tty`)
+    pub fn terminal() -> Self {
+        Sandbox::new().allow(Access::Terminal)
+    }
+
+    /// The profile for GUI flavors (`pinentry-gtk-2`, `pinentry-gnome3`, `pinentry-qt`, ...)
+    pub fn gui() -> Self {
+        Sandbox::new()
+            .allow(Access::X11)
+            .allow(Access::Wayland)
+            .allow(Access::SessionBus)
+    }
+
+    /// Let pinentry use `access`
+    pub fn allow(mut self, access: Access) -> Self {
+        if !self.access.contains(&access) {
+            self.access.push(access);
+        }
+        self
+    }
+
+    /// Make `path` available read-only (e.g. a pinentry installed outside the system directories)
+    pub fn read_only<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.read_only.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Override the path to the `bwrap` executable (by default just `bwrap`, looked up using `PATH` environment
+    /// variable)
+    pub fn bwrap<S: Into<String>>(mut self, bwrap: S) -> Self {
+        self.bwrap = bwrap.into();
+        self
+    }
+
+    /// Start `exe` in the sandbox
+    pub fn spawn<S: AsRef<OsStr>>(&self, exe: S) -> io::Result<ProcessTransport> {
+        ProcessTransport::from_command(self.command(exe))
+    }
+
+    /// The `bwrap` command starting `exe` in the sandbox
+    pub fn command<S: AsRef<OsStr>>(&self, exe: S) -> Command {
+        let mut cmd = Command::new(&self.bwrap);
+        cmd.args(["--die-with-parent", "--unshare-all"]);
+        if self.allows(Access::Network) {
+            cmd.arg("--share-net");
+        }
+        for dir in SYSTEM_DIRS.iter().chain(SYSTEM_CONFIG) {
+            ro_bind(&mut cmd, Path::new(dir));
+        }
+        cmd.args(["--proc", "/proc", "--dev", "/dev", "--tmpfs", "/tmp"]);
+        for path in &self.read_only {
+            ro_bind(&mut cmd, path);
+        }
+
+        let runtime_dir = env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from);
+        if let Some(ref dir) = runtime_dir {
+            if self.allows(Access::Wayland) || self.allows(Access::SessionBus) {
+                // the sockets are bound individually, the rest of the directory stays hidden
+                cmd.arg("--tmpfs").arg(dir);
+            }
+        }
+        for access in &self.access {
+            match access {
+                Access::Terminal => {
+                    cmd.args(["--dev-bind", "/dev/tty", "/dev/tty"]);
+                    cmd.args(["--dev-bind-try", "/dev/pts", "/dev/pts"]);
+                }
+                Access::X11 => {
+                    ro_bind(&mut cmd, Path::new("/tmp/.X11-unix"));
+                    if let Some(xauthority) = env::var_os("XAUTHORITY") {
+                        ro_bind(&mut cmd, Path::new(&xauthority));
+                    }
+                }
+                Access::Wayland => {
+                    if let Some(ref dir) = runtime_dir {
+                        let display = env::var_os("WAYLAND_DISPLAY").unwrap_or_else(|| "wayland-0".into());
+                        ro_bind(&mut cmd, &dir.join(display));
+                    }
+                }
+                Access::SessionBus => {
+                    if let Some(socket) = session_bus_socket(runtime_dir.as_deref()) {
+                        ro_bind(&mut cmd, &socket);
+                    }
+                }
+                Access::Network => (),
+            }
+        }
+        cmd.arg("--").arg(exe);
+        cmd
+    }
+
+    fn allows(&self, access: Access) -> bool {
+        self.access.contains(&access)
+    }
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox::new()
+    }
+}
+
+/// Bind `path` read-only to the same place inside the sandbox, if it exists
+fn ro_bind(cmd: &mut Command, path: &Path) {
+    cmd.arg("--ro-bind-try").arg(path).arg(path);
+}
+
+/// The socket of the session bus: from `DBUS_SESSION_BUS_ADDRESS` if it names one, else `$XDG_RUNTIME_DIR/bus`
+fn session_bus_socket(runtime_dir: Option<&Path>) -> Option<PathBuf> {
+    let from_address = env::var("DBUS_SESSION_BUS_ADDRESS").ok().and_then(|address| {
+        address
+            .split(';')
+            .flat_map(|transport| transport.strip_prefix("unix:"))
+            .flat_map(|params| params.split(','))
+            .find_map(|param| param.strip_prefix("path=").map(PathBuf::from))
+    });
+    from_address.or_else(|| runtime_dir.map(|dir| dir.join("bus")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(cmd: &Command) -> Vec<String> {
+        cmd.get_args().map(|a| a.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn test_command() {
+        let bare = args(&Sandbox::new().read_only("/opt/pinentry").command("pinentry-tty"));
+        assert_eq!(["--die-with-parent", "--unshare-all"], bare[..2]);
+        assert!(!bare.contains(&"--share-net".to_string()));
+        assert!(bare.windows(3).any(|w| w == ["--ro-bind-try", "/usr", "/usr"]));
+        assert!(bare
+            .windows(3)
+            .any(|w| w == ["--ro-bind-try", "/opt/pinentry", "/opt/pinentry"]));
+        assert!(!bare.contains(&"/dev/tty".to_string()));
+        assert_eq!(["--", "pinentry-tty"], bare[bare.len() - 2..]);
+
+        let terminal = args(&Sandbox::terminal().allow(Access::Network).command("pinentry-curses"));
+        assert!(terminal.contains(&"--share-net".to_string()));
+        assert!(terminal.windows(3).any(|w| w == ["--dev-bind", "/dev/tty", "/dev/tty"]));
+
+        let gui = args(&Sandbox::gui().command("pinentry-gnome3"));
+        assert!(gui
+            .windows(3)
+            .any(|w| w == ["--ro-bind-try", "/tmp/.X11-unix", "/tmp/.X11-unix"]));
+    }
+}
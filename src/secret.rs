@@ -0,0 +1,379 @@
+//! Helpers for handling the secrets returned by pinentry
+
+use std::error;
+use std::ffi::{CStr, OsStr};
+use std::fmt::{self, Debug, Display, Formatter};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// The PINs (and other secrets) returned by this crate: `secstr::SecStr` with the `secstr` feature (on by default),
+/// which keeps them in locked memory - otherwise [`PinBytes`], so applications need not depend on `secstr`
+///
+/// Both are used the same way: [`unsecure()`](PinBytes::unsecure) for the bytes, `SecretPin::from` for creating one.
+#[cfg(feature = "secstr")]
+pub type SecretPin = secstr::SecStr;
+
+/// The PINs (and other secrets) returned by this crate: [`PinBytes`] without the `secstr` feature
+#[cfg(not(feature = "secstr"))]
+pub type SecretPin = PinBytes;
+
+/// A secret that is zeroized when dropped, the [`SecretPin`] without the `secstr` feature
+///
+/// Like `secstr::SecStr` it is compared in constant time and never printed, and growing it moves the secret to a new
+/// buffer after wiping the old one; unlike it, its memory is not locked (so it may be swapped out). It implements
+/// [`Zeroize`], e.g. for keeping it in a `secrecy::SecretBox`.
+///
+/// ```
+/// # extern crate pinentry_rs;
+/// use pinentry_rs::PinBytes;
+///
+/// let pin = PinBytes::from("hunter2");
+/// assert_eq!(b"hunter2", pin.unsecure());
+/// assert_eq!("***SECRET***", format!("{:?}", pin));
+/// ```
+#[derive(Clone)]
+pub struct PinBytes(Vec<u8>);
+
+impl PinBytes {
+    /// Take ownership of `bytes`
+    pub fn new(bytes: Vec<u8>) -> Self {
+        PinBytes(bytes)
+    }
+
+    /// The bytes of the secret
+    pub fn unsecure(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The bytes of the secret, for changing them in place
+    pub fn unsecure_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+
+    /// Truncate the secret to `new_len` bytes, or extend it with `value`
+    pub fn resize(&mut self, new_len: usize, value: u8) {
+        if new_len <= self.0.len() {
+            self.0.truncate(new_len);
+            return;
+        }
+        // not grown in place, as the allocator would free the old buffer without wiping it
+        let mut grown = vec![value; new_len];
+        grown[..self.0.len()].copy_from_slice(&self.0);
+        self.0.zeroize();
+        self.0 = grown;
+    }
+
+    /// Overwrite the secret with zeros, leaving it empty (done when it is dropped as well)
+    pub fn zero_out(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<T: Into<Vec<u8>>> From<T> for PinBytes {
+    fn from(bytes: T) -> Self {
+        PinBytes(bytes.into())
+    }
+}
+
+impl PartialEq for PinBytes {
+    /// Compares in constant time (for secrets of the same length)
+    fn eq(&self, other: &Self) -> bool {
+        self.0.len() == other.0.len() && self.0.iter().zip(&other.0).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+}
+
+impl Eq for PinBytes {}
+
+impl Debug for PinBytes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("***SECRET***")
+    }
+}
+
+impl Display for PinBytes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("***SECRET***")
+    }
+}
+
+impl Zeroize for PinBytes {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for PinBytes {}
+
+impl Drop for PinBytes {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// A secret that is zeroized once its time-to-live has passed (or when [`expire()`](ExpiringSecret::expire) is
+/// called), so it does not stay in memory longer than intended
+///
+/// The secret is wiped by a background thread when the TTL passes, whether or not it is accessed again.
+///
+/// ```
+/// # extern crate pinentry_rs;
+/// use std::time::Duration;
+///
+/// use pinentry_rs::secret::ExpiringSecret;
+/// use pinentry_rs::SecretPin;
+///
+/// let secret = ExpiringSecret::new(SecretPin::from("hunter2"), Duration::from_secs(60));
+/// assert_eq!(Some(7), secret.with_secret(|s| s.len()));
+///
+/// secret.expire();
+/// assert_eq!(None, secret.with_secret(|s| s.len()));
+/// ```
+#[derive(Debug)]
+pub struct ExpiringSecret {
+    expires_at: Instant,
+    secret: Arc<Mutex<Option<SecretPin>>>,
+    // dropping the sender wakes up the expiry thread, so it does not outlive the secret
+    _stop: mpsc::Sender<()>,
+}
+
+impl ExpiringSecret {
+    /// Wrap `secret`, zeroizing it after `ttl`
+    pub fn new(secret: SecretPin, ttl: Duration) -> Self {
+        let secret = Arc::new(Mutex::new(Some(secret)));
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        let expiring = Arc::downgrade(&secret);
+        thread::spawn(move || {
+            if let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(ttl) {
+                if let Some(secret) = expiring.upgrade() {
+                    wipe(&secret);
+                }
+            }
+        });
+
+        ExpiringSecret {
+            expires_at: Instant::now() + ttl,
+            secret,
+            _stop: stop,
+        }
+    }
+
+    /// Call `f` with the secret, unless it has expired
+    pub fn with_secret<T, F: FnOnce(&[u8]) -> T>(&self, f: F) -> Option<T> {
+        if self.is_expired() {
+            wipe(&self.secret);
+            return None;
+        }
+        let secret = self.secret.lock().expect("secret lock is not poisoned");
+        secret.as_ref().map(|s| f(s.unsecure()))
+    }
+
+    /// Zeroize the secret now
+    pub fn expire(&self) {
+        wipe(&self.secret);
+    }
+
+    /// Whether the secret is gone
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.expires_at || self.secret.lock().expect("secret lock is not poisoned").is_none()
+    }
+
+    /// Time left until the secret expires
+    pub fn remaining(&self) -> Duration {
+        if self.is_expired() {
+            Duration::ZERO
+        } else {
+            self.expires_at.saturating_duration_since(Instant::now())
+        }
+    }
+}
+
+/// Call `f` with the secret as a NUL-terminated C string, e.g. to pass it to a C API
+///
+/// The secret is copied exactly once, into a buffer of the right size (so it is never reallocated), which is
+/// zeroized when `f` returns or panics. Use it to avoid `CString::new(pin.unsecure().to_vec())`, which leaves
+/// copies behind:
+///
+/// ```
+/// # extern crate pinentry_rs;
+/// use pinentry_rs::secret::with_c_str;
+///
+/// let len = with_c_str(b"hunter2", |c_str| c_str.to_bytes().len()).expect("no NUL in the secret");
+/// assert_eq!(7, len);
+/// ```
+pub fn with_c_str<T, F: FnOnce(&CStr) -> T>(secret: &[u8], f: F) -> Result<T, InteriorNul> {
+    if let Some(position) = secret.iter().position(|&b| b == 0) {
+        return Err(InteriorNul { position });
+    }
+
+    let mut buf = WipeOnDrop(Vec::with_capacity(secret.len() + 1));
+    buf.0.extend_from_slice(secret);
+    buf.0.push(0);
+    let c_str = CStr::from_bytes_with_nul(&buf.0).expect("BUG: NUL bytes were checked");
+    Ok(f(c_str))
+}
+
+/// Call `f` with the secret as an `OsStr`, e.g. to pass it to an API taking paths or environment values
+///
+/// On unix no copy is made. Elsewhere the secret has to be valid UTF-8 (`None` is returned otherwise) and is not
+/// copied either.
+pub fn with_os_str<T, F: FnOnce(&OsStr) -> T>(secret: &[u8], f: F) -> Option<T> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Some(f(OsStr::from_bytes(secret)))
+    }
+    #[cfg(not(unix))]
+    {
+        std::str::from_utf8(secret).ok().map(|s| f(OsStr::new(s)))
+    }
+}
+
+/// Conversion of PINs into the secret types of the [`secrecy`](https://crates.io/crates/secrecy) crate (with the
+/// `secrecy` feature)
+///
+/// PINs are returned as [`SecretPin`]s either way, as the read path keeps them in its own buffers from the moment they
+/// are read - this only saves applications storing their secrets in `secrecy` types from copying them by hand:
+///
+/// ```
+/// # extern crate pinentry_rs;
+/// # extern crate secrecy;
+/// use pinentry_rs::secret::IntoSecrecy;
+/// use pinentry_rs::SecretPin;
+/// use secrecy::ExposeSecret;
+///
+/// # let pin = SecretPin::from("hunter2");
+/// let pin = pin.into_secret_string().expect("the PIN is valid UTF-8");
+/// assert_eq!("hunter2", pin.expose_secret());
+/// ```
+#[cfg(feature = "secrecy")]
+pub trait IntoSecrecy: Sized {
+    /// The secret as a `SecretString`, or the secret itself if it is not valid UTF-8
+    fn into_secret_string(self) -> Result<secrecy::SecretString, Self>;
+
+    /// The secret as a `SecretSlice` of bytes
+    fn into_secret_bytes(self) -> secrecy::SecretSlice<u8>;
+}
+
+#[cfg(feature = "secrecy")]
+impl IntoSecrecy for SecretPin {
+    fn into_secret_string(self) -> Result<secrecy::SecretString, Self> {
+        if std::str::from_utf8(self.unsecure()).is_err() {
+            return Err(self);
+        }
+        // copied exactly once, into a buffer of the right size (so it is not reallocated when it is boxed), and the
+        // PIN wipes its own copy when dropped here
+        let text = String::from_utf8(self.unsecure().to_vec()).expect("BUG: UTF-8 was checked");
+        Ok(secrecy::SecretString::from(text))
+    }
+
+    fn into_secret_bytes(self) -> secrecy::SecretSlice<u8> {
+        secrecy::SecretSlice::from(self.unsecure().to_vec())
+    }
+}
+
+/// The secret contains a NUL byte, so it cannot be passed on as a C string
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InteriorNul {
+    /// Position of the first NUL byte
+    pub position: usize,
+}
+
+impl Display for InteriorNul {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "secret contains a NUL byte at position {}", self.position)
+    }
+}
+
+impl error::Error for InteriorNul {}
+
+/// A buffer holding a copy of a secret, zeroized when dropped
+struct WipeOnDrop(Vec<u8>);
+
+impl Drop for WipeOnDrop {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+fn wipe(secret: &Mutex<Option<SecretPin>>) {
+    // the secure string zeroizes its contents when dropped
+    secret.lock().expect("secret lock is not poisoned").take();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiring_secret_ttl() {
+        let secret = ExpiringSecret::new(SecretPin::from("secret"), Duration::from_millis(50));
+        assert_eq!(Some(b"secret".to_vec()), secret.with_secret(|s| s.to_vec()));
+        assert!(secret.remaining() > Duration::ZERO);
+
+        thread::sleep(Duration::from_millis(200));
+        // wiped by the background thread, without being accessed
+        assert!(secret.secret.lock().unwrap().is_none());
+        assert!(secret.is_expired());
+        assert_eq!(Duration::ZERO, secret.remaining());
+        assert_eq!(None, secret.with_secret(|s| s.to_vec()));
+    }
+
+    #[test]
+    fn test_pin_bytes() {
+        let mut pin = PinBytes::from("1234");
+        assert_eq!(PinBytes::new(b"1234".to_vec()), pin);
+        assert_ne!(PinBytes::from("1235"), pin);
+        assert_ne!(PinBytes::from("12345"), pin);
+        assert_eq!("***SECRET***", pin.to_string());
+
+        pin.resize(6, b'0');
+        assert_eq!(b"123400", pin.unsecure());
+        pin.unsecure_mut()[0] = b'9';
+        pin.resize(2, 0);
+        assert_eq!(b"92", pin.unsecure());
+        pin.zeroize();
+        assert!(pin.unsecure().is_empty());
+    }
+
+    #[test]
+    fn test_with_c_str() {
+        let bytes = with_c_str(b"secret", |c_str| c_str.to_bytes_with_nul().to_vec()).unwrap();
+        assert_eq!(b"secret\0".to_vec(), bytes);
+        assert_eq!(Err(InteriorNul { position: 3 }), with_c_str(b"sec\0ret", |_| ()));
+    }
+
+    #[test]
+    fn test_with_os_str() {
+        assert_eq!(
+            Some(Some("secret".to_string())),
+            with_os_str(b"secret", |s| s.to_str().map(|s| s.to_string()))
+        );
+    }
+
+    #[cfg(feature = "secrecy")]
+    #[test]
+    fn test_into_secrecy() {
+        use secrecy::ExposeSecret;
+
+        let pin = SecretPin::from("hunter2").into_secret_string().unwrap();
+        assert_eq!("hunter2", pin.expose_secret());
+        let invalid = SecretPin::new(vec![0xff, 0xfe]);
+        assert_eq!(Err(invalid.clone()), invalid.into_secret_string().map(|_| ()));
+        let bytes = SecretPin::new(vec![0xff, 0xfe]).into_secret_bytes();
+        assert_eq!(&[0xff, 0xfe], bytes.expose_secret());
+    }
+
+    #[test]
+    fn test_expiring_secret_expire() {
+        let secret = ExpiringSecret::new(SecretPin::from("secret"), Duration::from_secs(3600));
+        assert!(!secret.is_expired());
+        secret.expire();
+        assert!(secret.is_expired());
+        assert_eq!(None, secret.with_secret(|s| s.to_vec()));
+    }
+}
@@ -0,0 +1,2116 @@
+use std::convert::Infallible;
+use std::io;
+use std::result;
+use std::sync::Arc;
+#[cfg(feature = "process")]
+use std::time::Duration;
+
+use super::assuan::{AssuanCommand, AssuanError, AssuanResponse, CommandFilter, Inquiry, InquiryHandler, Line, Status};
+#[cfg(feature = "process")]
+use super::cancel::{CancelToken, SessionChild, Watchdog};
+#[cfg(all(feature = "hardening", unix))]
+use super::hardening;
+use super::messages::Message;
+use super::normalize::Normalization;
+use super::policy::{self, PromptPolicy, SharedPolicy};
+use super::rate_limit::RateLimit;
+use super::transport::{Connection, Transport};
+use super::unlock::{UnlockError, VerifyError, DEFAULT_MAX_ATTEMPTS};
+use super::{invalid, Error, Inherited, PromptKind, PromptSettings, Result, SecretPin};
+
+/// Creates a new transport when (re)connecting
+pub(crate) type Connector = Box<dyn FnMut() -> Result<Box<dyn Transport>> + Send>;
+
+/// A running pinentry that can be used for several prompts
+///
+/// Created with [`PinentryBuilder::connect`](super::PinentryBuilder::connect). The session holds default settings
+/// (title, labels, options, ...) that apply to every prompt made through it; settings for a single prompt can be
+/// layered on top using [`prompt()`](PinentrySession::prompt). The pinentry process is stopped when the session is
+/// dropped, or asked to exit with [`close()`](PinentrySession::close).
+pub struct PinentrySession {
+    connector: Option<Connector>,
+    connection: Connection,
+    respawn: bool,
+    // default settings, re-sent after each RESET and replayed after a respawn
+    state: Vec<AssuanCommand>,
+    // whether the last prompt changed settings that need to be reset before the next one
+    dirty: bool,
+    pub(crate) max_attempts: u32,
+    normalization: Normalization,
+    filter: Option<CommandFilter>,
+    policy: Option<SharedPolicy>,
+    quality: Option<QualityFn>,
+    generate: Option<GenPinFn>,
+    check: Option<CheckFn>,
+    // whether the PIN last read is one generated for it
+    generated: bool,
+    rate_limit: Option<RateLimit>,
+    #[cfg(feature = "process")]
+    pub(crate) cancel: Option<CancelToken>,
+    // the hard timeout, and the pinentry process it kills
+    #[cfg(feature = "process")]
+    pub(crate) hard_timeout: Option<(Duration, SessionChild)>,
+    // whether PINs are excluded from core dumps
+    #[cfg(all(feature = "hardening", unix))]
+    pub(crate) exclude_from_core_dumps: bool,
+}
+
+impl PinentrySession {
+    /// Open a session over `transport` (or a transport created by `connector` if not given)
+    pub(crate) fn open(
+        mut connector: Option<Connector>,
+        transport: Option<Box<dyn Transport>>,
+        state: Vec<AssuanCommand>,
+        respawn: bool,
+        filter: Option<CommandFilter>,
+    ) -> Result<PinentrySession> {
+        let transport = match (transport, connector.as_mut()) {
+            (Some(transport), _) => transport,
+            (None, Some(connect)) => connect()?,
+            (None, None) => unreachable!("BUG: neither a transport nor a connector given"),
+        };
+        let connection = Connection::open(transport)?;
+        let mut session = PinentrySession {
+            connector,
+            connection,
+            respawn,
+            state,
+            dirty: false,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            normalization: Normalization::default(),
+            filter,
+            policy: None,
+            quality: None,
+            generate: None,
+            check: None,
+            generated: false,
+            rate_limit: None,
+            #[cfg(feature = "process")]
+            cancel: None,
+            #[cfg(feature = "process")]
+            hard_timeout: None,
+            #[cfg(all(feature = "hardening", unix))]
+            exclude_from_core_dumps: false,
+        };
+        session.replay_state()?;
+        Ok(session)
+    }
+
+    /// Change a default setting (e.g. `AssuanCommand::SetWindowTitle`) for all following prompts in this session
+    ///
+    /// A previous value of the same setting is replaced.
+    pub fn set(&mut self, cmd: AssuanCommand) -> Result<()> {
+        if cmd.is_terminal() {
+            return Err(Error::ProtocolError(format!("{} is not a setting", cmd.name())));
+        }
+        expect_ok(self.run(std::slice::from_ref(&cmd))?)?;
+
+        match self.state.iter_mut().find(|c| same_setting(c, &cmd)) {
+            Some(c) => *c = cmd,
+            None => self.state.push(cmd),
+        }
+        Ok(())
+    }
+
+    /// Only send the commands (and accept the responses) allowed by `filter` from now on, see [`CommandFilter`]
+    pub fn set_command_filter(&mut self, filter: CommandFilter) {
+        self.filter = Some(filter);
+    }
+
+    /// Consult `policy` before each dialog shown through this session, see [`policy`](super::policy)
+    pub fn set_policy<P: PromptPolicy + 'static>(&mut self, policy: P) {
+        self.policy = Some(Arc::new(policy));
+    }
+
+    pub(crate) fn set_shared_policy(&mut self, policy: SharedPolicy) {
+        self.policy = Some(policy);
+    }
+
+    /// Limit how many dialogs are shown through this session (and all others sharing `limit`), see
+    /// [`rate_limit`](super::rate_limit)
+    pub fn set_rate_limit(&mut self, limit: RateLimit) {
+        self.rate_limit = Some(limit);
+    }
+
+    /// Change how many PINs `unlock()` lets the user try for all following prompts in this session
+    pub fn set_max_attempts(&mut self, attempts: u32) {
+        self.max_attempts = attempts;
+    }
+
+    /// Change how PINs are normalized for all following prompts in this session
+    pub fn set_normalization(&mut self, normalization: Normalization) {
+        self.normalization = normalization;
+    }
+
+    /// Rate the PINs typed in all following prompts of this session for the quality bar, see
+    /// [`PinentryBuilder::quality_fn`](super::PinentryBuilder::quality_fn)
+    pub fn set_quality_fn<F: Fn(&str) -> i32 + Send + Sync + 'static>(&mut self, quality: F) {
+        self.quality = Some(Arc::new(quality));
+    }
+
+    pub(crate) fn set_shared_quality_fn(&mut self, quality: QualityFn) {
+        self.quality = Some(quality);
+    }
+
+    /// Make the PINs suggested in all following prompts of this session, see
+    /// [`PinentryBuilder::genpin_fn`](super::PinentryBuilder::genpin_fn)
+    pub fn set_genpin_fn<F: Fn() -> SecretPin + Send + Sync + 'static>(&mut self, generate: F) {
+        self.generate = Some(Arc::new(generate));
+    }
+
+    pub(crate) fn set_shared_genpin_fn(&mut self, generate: GenPinFn) {
+        self.generate = Some(generate);
+    }
+
+    /// Check the passphrases typed in all following prompts of this session against the constraints, see
+    /// [`PinentryBuilder::constraints_fn`](super::PinentryBuilder::constraints_fn)
+    pub fn set_constraints_fn<F: Fn(&str) -> Option<String> + Send + Sync + 'static>(&mut self, check: F) {
+        self.check = Some(Arc::new(check));
+    }
+
+    pub(crate) fn set_shared_constraints_fn(&mut self, check: CheckFn) {
+        self.check = Some(check);
+    }
+
+    /// Start a prompt with settings that only apply to it (on top of the defaults of the session)
+    pub fn prompt(&mut self) -> SessionPrompt<'_> {
+        SessionPrompt {
+            session: self,
+            settings: PromptSettings::default(),
+        }
+    }
+
+    /// Prompt for confirmation
+    ///
+    /// The text for the confirmation should be set using `.description()`
+    pub fn confirm_yes_no(&mut self) -> Result<bool> {
+        self.prompt().confirm_yes_no()
+    }
+
+    /// Prompt for confirmation, telling the buttons apart
+    ///
+    /// See [`SessionPrompt::confirm`].
+    pub fn confirm(&mut self) -> Result<Confirmation> {
+        self.prompt().confirm()
+    }
+
+    /// Ask for confirmation with a single button
+    ///
+    /// See [`SessionPrompt::confirm_one_button`].
+    pub fn confirm_one_button(&mut self) -> Result<Confirmation> {
+        self.prompt().confirm_one_button()
+    }
+
+    /// Prompt for a PIN
+    pub fn pin<S: Into<String>>(&mut self, prompt: S) -> Result<SecretPin> {
+        self.prompt().pin(prompt)
+    }
+
+    /// Prompt for a PIN to be entered twice
+    ///
+    /// See [`SessionPrompt::pin_repeated`].
+    pub fn pin_repeated<S: Into<String>>(&mut self, prompt: S) -> Result<RepeatedPin> {
+        self.prompt().pin_repeated(prompt)
+    }
+
+    /// Let the user pick one of `options`
+    ///
+    /// See [`SessionPrompt::choose`].
+    pub fn choose(&mut self, options: &[&str]) -> Result<Option<usize>> {
+        self.prompt().choose(options)
+    }
+
+    /// Prompt for a PIN, offering an alternate action on the 'Not OK' button
+    ///
+    /// See [`SessionPrompt::pin_or_alternate`].
+    pub fn pin_or_alternate<S: Into<String>, L: Into<String>>(
+        &mut self,
+        prompt: S,
+        alternate_label: L,
+    ) -> Result<PinOutcome> {
+        self.prompt().pin_or_alternate(prompt, alternate_label)
+    }
+
+    /// Prompt for a PIN and hand it to `f` without returning it
+    ///
+    /// See [`SessionPrompt::pin_and_use`].
+    pub fn pin_and_use<T, E, F, S: Into<String>>(&mut self, prompt: S, f: F) -> result::Result<T, E>
+    where
+        E: From<Error>,
+        F: FnOnce(&[u8]) -> result::Result<T, E>,
+    {
+        self.prompt().pin_and_use(prompt, f)
+    }
+
+    /// Prompt for a PIN until `verify` accepts it
+    ///
+    /// See [`SessionPrompt::unlock`].
+    pub fn unlock<T, E, F, S: Into<String>>(&mut self, prompt: S, verify: F) -> result::Result<T, UnlockError<E>>
+    where
+        F: FnMut(&[u8]) -> result::Result<T, VerifyError<E>>,
+    {
+        self.prompt().unlock(prompt, verify)
+    }
+
+    /// Prompt for a PIN until `validate` accepts it, at most `max_attempts` times
+    ///
+    /// See [`SessionPrompt::pin_with_retries`].
+    pub fn pin_with_retries<T, F, S: Into<String>>(&mut self, prompt: S, max_attempts: u32, validate: F) -> Result<T>
+    where
+        F: FnMut(&SecretPin) -> result::Result<T, String>,
+    {
+        self.prompt().pin_with_retries(prompt, max_attempts, validate)
+    }
+
+    /// Show a message
+    ///
+    /// The text for the message should be set using `.description()`
+    pub fn show_message(&mut self) -> Result<()> {
+        self.prompt().show_message()
+    }
+
+    /// Prompt for a PIN, offering a generated one
+    ///
+    /// See [`SessionPrompt::pin_suggested`].
+    pub fn pin_suggested<S: Into<String>>(&mut self, prompt: S) -> Result<SuggestedPin> {
+        self.prompt().pin_suggested(prompt)
+    }
+
+    /// Prompt for a PIN that may come from the external password cache
+    ///
+    /// See [`SessionPrompt::pin_cacheable`].
+    pub fn pin_cacheable<S: Into<String>>(&mut self, prompt: S) -> Result<CachedPin> {
+        self.prompt().pin_cacheable(prompt)
+    }
+
+    /// Remove the passphrase stored under `key_info` from the external password cache (`CLEARPASSPHRASE`), e.g.
+    /// after it has been rejected
+    pub fn clear_cached_passphrase<S: Into<String>>(&mut self, key_info: S) -> Result<()> {
+        match self.run_prompt(Vec::new(), vec![AssuanCommand::ClearPassphrase(key_info.into())])? {
+            AssuanResponse::NOTOK(error) => Err(Error::from_response(error)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Ask pinentry about itself (`GETINFO version`, `flavor`, `pid` and `ttyinfo`), e.g. to find out whether the
+    /// flavor in use supports quality bars or repeat prompts before enabling them
+    ///
+    /// Values pinentry does not know are `None`.
+    pub fn get_info(&mut self) -> Result<PinentryInfo> {
+        Ok(PinentryInfo {
+            version: self.info("version")?,
+            flavor: self.info("flavor")?,
+            pid: self.info("pid")?.and_then(|pid| pid.trim().parse().ok()),
+            ttyinfo: self.info("ttyinfo")?,
+        })
+    }
+
+    /// Send `request` (a command line without the newline, e.g. `OPTION no-grab`), for commands this crate does not
+    /// model, and return the whole reply
+    ///
+    /// The command filter of the session applies, and inquiries are cancelled. An `ERR` reply is not an error, but
+    /// ends up in [`RawResponse::error`]; fails with [`Error::InvalidConfiguration`] if `request` is not a client
+    /// command. Settings changed by `request` are not tracked by the session: they last until pinentry is reset (which
+    /// happens before a prompt following one with settings of its own) or respawned.
+    pub fn raw_command(&mut self, request: &str) -> Result<RawResponse> {
+        let mut reply = self.connection.request_raw_reply(request, self.filter.as_ref())?;
+        if let Some(denied) = reply.denied.take() {
+            return Err(denied);
+        }
+        let data = reply.take_data();
+        let error = match reply.error.take() {
+            Some(error) => match Line::parse(error.as_bytes()) {
+                Ok(Line::Err(e)) => Some(e),
+                _ => return Err(Error::ProtocolError(error)),
+            },
+            None => None,
+        };
+        if let Some(ref data) = data {
+            self.protect(data);
+        }
+        Ok(RawResponse {
+            data,
+            statuses: self.connection.statuses().to_vec(),
+            error,
+        })
+    }
+
+    /// End the session with `BYE`, reporting whether pinentry exited cleanly
+    ///
+    /// Dropping the session says goodbye as well, but ignores errors.
+    pub fn close(mut self) -> Result<()> {
+        self.connection.bye()
+    }
+
+    /// Prompt for a PIN until it passes the normalization
+    pub(crate) fn read_pin(
+        &mut self,
+        overrides: &mut Vec<AssuanCommand>,
+        normalization: &Normalization,
+        prompt: &str,
+    ) -> Result<SecretPin> {
+        let quality = self.quality.clone();
+        let generate = self.generate.clone();
+        self.read_pin_with(overrides, normalization, prompt, quality.as_ref(), generate.as_ref())
+    }
+
+    /// Same as `read_pin`, rating the PIN typed so far with `quality` and suggesting PINs made by `generate` when
+    /// pinentry asks for it
+    pub(crate) fn read_pin_with(
+        &mut self,
+        overrides: &mut Vec<AssuanCommand>,
+        normalization: &Normalization,
+        prompt: &str,
+        quality: Option<&QualityFn>,
+        generate: Option<&GenPinFn>,
+    ) -> Result<SecretPin> {
+        let check = self.check.clone();
+        loop {
+            let mut suggested = Vec::new();
+            let res = self.run_prompt_with(
+                overrides.clone(),
+                vec![AssuanCommand::SetPrompt(prompt.to_string()), AssuanCommand::GetPin],
+                &mut |inquiry| match inquiry.keyword.as_str() {
+                    "GENPIN" => {
+                        let pin = generate.map(|generate| generate()).or_else(default_genpin)?;
+                        suggested.push(pin.clone());
+                        Some(pin)
+                    }
+                    "CHECKPIN" => check.as_ref().and_then(|check| answer_check(check.as_ref(), inquiry)),
+                    _ => quality.and_then(|quality| answer_quality(quality.as_ref(), inquiry)),
+                },
+            )?;
+            let pin = match res {
+                AssuanResponse::PIN(pin) => pin,
+                AssuanResponse::NOTOK(error) => return Err(Error::from_response(error)),
+                res => return Err(Error::unexpected_response(res)),
+            };
+            self.generated = suggested.contains(&pin);
+            match normalization.apply(pin) {
+                Some(pin) => {
+                    self.protect(&pin);
+                    return Ok(pin);
+                }
+                None => set_error_text(overrides, Message::EmptyPassphrase.text()),
+            }
+        }
+    }
+
+    pub(crate) fn run_prompt(
+        &mut self,
+        overrides: Vec<AssuanCommand>,
+        terminal: Vec<AssuanCommand>,
+    ) -> Result<AssuanResponse> {
+        self.run_prompt_with(overrides, terminal, &mut |_| None)
+    }
+
+    /// Same as `run_prompt`, answering the inquiries made during `GetPin` with `on_inquire`
+    pub(crate) fn run_prompt_with(
+        &mut self,
+        overrides: Vec<AssuanCommand>,
+        terminal: Vec<AssuanCommand>,
+        on_inquire: &mut InquiryHandler<'_>,
+    ) -> Result<AssuanResponse> {
+        if let Some(ref policy) = self.policy {
+            let settings = self.state.iter().chain(&overrides).chain(&terminal);
+            if let Some(answer) = policy::apply(policy.as_ref(), settings)? {
+                self.connection.clear_statuses();
+                return Ok(answer);
+            }
+        }
+        if let Some(ref limit) = self.rate_limit {
+            if terminal.iter().any(AssuanCommand::is_terminal) {
+                limit.acquire()?;
+            }
+        }
+        let mut cmds = Vec::new();
+        if self.dirty {
+            cmds.push(AssuanCommand::Reset);
+            cmds.extend(self.state.iter().cloned());
+        }
+        let has_overrides = !overrides.is_empty();
+        cmds.extend(overrides);
+        cmds.extend(terminal);
+
+        let res = self.run_with(&cmds, on_inquire);
+        // be conservative if the prompt failed part-way
+        self.dirty = has_overrides || res.is_err();
+        if let Ok(AssuanResponse::PIN(ref pin)) = res {
+            self.protect(pin);
+        }
+        res
+    }
+
+    /// Exclude `pin` from core dumps, if enabled
+    fn protect(&self, _pin: &SecretPin) {
+        #[cfg(all(feature = "hardening", unix))]
+        if self.exclude_from_core_dumps {
+            if let Err(e) = hardening::exclude_from_core_dumps(_pin.unsecure()) {
+                debug!("failed to exclude the PIN from core dumps: {}", e);
+            }
+        }
+    }
+
+    fn run(&mut self, cmds: &[AssuanCommand]) -> Result<AssuanResponse> {
+        self.run_with(cmds, &mut |_| None)
+    }
+
+    fn run_with(&mut self, cmds: &[AssuanCommand], on_inquire: &mut InquiryHandler<'_>) -> Result<AssuanResponse> {
+        #[cfg(feature = "process")]
+        let watchdog = (self.hard_timeout.as_ref()).map(|(timeout, child)| child.kill_after(*timeout));
+        let res = self
+            .connection
+            .process_commands_with(cmds, on_inquire, self.filter.as_ref());
+        #[cfg(feature = "process")]
+        let timed_out = watchdog.is_some_and(Watchdog::stop);
+        #[cfg(not(feature = "process"))]
+        let timed_out = false;
+
+        match res {
+            Err(_) if self.is_aborted() => Err(Error::Aborted),
+            Err(_) if timed_out => {
+                // pinentry has been killed, so the next prompt needs a new one
+                self.connection.close();
+                Err(Error::Timeout)
+            }
+            Err(ref e) if self.respawn && self.connector.is_some() && is_disconnect(e) => {
+                debug!("pinentry went away ({}), respawning it", e);
+                self.recover(cmds, on_inquire).map_err(|e| match self.is_aborted() {
+                    true => Error::Aborted,
+                    false => Error::RecoveryFailed(Box::new(e)),
+                })
+            }
+            res => res,
+        }
+    }
+
+    /// Whether the prompts have been aborted with the cancel token
+    fn is_aborted(&self) -> bool {
+        #[cfg(feature = "process")]
+        return self.cancel.as_ref().is_some_and(CancelToken::is_cancelled);
+        #[cfg(not(feature = "process"))]
+        false
+    }
+
+    fn recover(&mut self, cmds: &[AssuanCommand], on_inquire: &mut InquiryHandler<'_>) -> Result<AssuanResponse> {
+        self.connection.close();
+        let connect = self.connector.as_mut().expect("BUG: recovering without a connector");
+        self.connection = Connection::open(connect()?)?;
+        self.replay_state()?;
+        self.connection
+            .process_commands_with(cmds, on_inquire, self.filter.as_ref())
+    }
+
+    /// Value of `GETINFO <what>`, or `None` if pinentry does not know it - checked with the command filter, and asked
+    /// again of a respawned pinentry like a prompt
+    fn info(&mut self, what: &str) -> Result<Option<String>> {
+        Ok(match self.run(&[AssuanCommand::GetInfo(what.to_string())])? {
+            AssuanResponse::PIN(data) => Some(String::from_utf8_lossy(data.unsecure()).into_owned()),
+            AssuanResponse::OK | AssuanResponse::NOTOK(_) => None,
+        })
+    }
+
+    fn replay_state(&mut self) -> Result<()> {
+        expect_ok(self.connection.process_commands(&self.state, self.filter.as_ref())?)
+    }
+}
+
+/// Outcome of a PIN prompt that offers an alternate action
+#[derive(Debug)]
+pub enum PinOutcome {
+    /// A PIN was entered
+    Entered(SecretPin),
+    /// The alternate action (the 'Not OK' button) was chosen
+    AlternateAction,
+    /// The prompt was cancelled
+    Cancelled,
+}
+
+/// Answer to a confirmation prompt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Confirmation {
+    /// The 'OK' button was pressed
+    Ok,
+    /// The 'Not OK' button was pressed (only shown if its label is set, see [`SessionPrompt::label_notok`])
+    NotOk,
+    /// The prompt was cancelled (with the 'Cancel' button, or by closing the dialog)
+    Cancelled,
+}
+
+/// A PIN entered in a prompt with a repeat prompt (see [`SessionPrompt::repeat`])
+#[derive(Debug)]
+pub struct RepeatedPin {
+    /// The PIN entered
+    pub pin: SecretPin,
+    /// Whether pinentry made the user enter the PIN twice and checked that both entries match (`S PIN_REPEATED`)
+    ///
+    /// Flavors that do not support repeating accept the repeat prompt but only ask once.
+    pub repeated: bool,
+}
+
+/// A PIN entered in a prompt that may use the external password cache (see [`SessionPrompt::pin_cacheable`])
+#[derive(Debug)]
+pub struct CachedPin {
+    /// The PIN
+    pub pin: SecretPin,
+    /// Whether pinentry took the PIN from the external password cache instead of asking the user
+    pub from_cache: bool,
+}
+
+/// A PIN entered in a prompt offering a generated PIN (see [`SessionPrompt::pin_suggested`])
+#[derive(Debug)]
+pub struct SuggestedPin {
+    /// The PIN
+    pub pin: SecretPin,
+    /// Whether the user took the generated PIN as it was suggested
+    pub generated: bool,
+}
+
+/// What pinentry reports about itself (see [`PinentrySession::get_info`])
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PinentryInfo {
+    /// Version of pinentry, e.g. `1.2.1`
+    pub version: Option<String>,
+    /// The flavor showing the prompts, e.g. `gtk2` or `curses` (reported as `gtk2:curses` when a graphical flavor
+    /// falls back to the terminal)
+    pub flavor: Option<String>,
+    /// Process id of pinentry
+    pub pid: Option<u32>,
+    /// The terminal pinentry is attached to, as `<tty> <term> <display>`
+    pub ttyinfo: Option<String>,
+}
+
+/// The reply to a request sent with [`PinentrySession::raw_command`]
+#[derive(Debug)]
+pub struct RawResponse {
+    /// The data sent back in `D` lines, unescaped and joined (`None` if there were none)
+    pub data: Option<SecretPin>,
+    /// The status lines sent back, in order
+    pub statuses: Vec<Status>,
+    /// The `ERR` pinentry answered with, or `None` for `OK`
+    pub error: Option<AssuanError>,
+}
+
+impl RawResponse {
+    /// Whether pinentry answered with `OK`
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// A single prompt made in a [`PinentrySession`]
+///
+/// Settings made here override the defaults of the session for this prompt only.
+pub struct SessionPrompt<'a> {
+    pub(crate) session: &'a mut PinentrySession,
+    pub(crate) settings: PromptSettings,
+}
+
+impl SessionPrompt<'_> {
+    /// Set the descriptive text of the prompt
+    pub fn description<S: Into<String>>(mut self, desc: S) -> Self {
+        self.settings.description = Some(desc.into());
+        self
+    }
+
+    /// Set the text that gets the displayed in case of error
+    pub fn error_text<S: Into<String>>(mut self, error_text: S) -> Self {
+        self.settings.error_text = Some(error_text.into());
+        self
+    }
+
+    /// Set the label of the 'Cancel' button
+    pub fn label_cancel<S: Into<String>>(mut self, label: S) -> Self {
+        self.settings.label_cancel = Some(label.into());
+        self
+    }
+
+    /// Set the label of the 'Not OK' button
+    pub fn label_notok<S: Into<String>>(mut self, label: S) -> Self {
+        self.settings.label_notok = Some(label.into());
+        self
+    }
+
+    /// Set the label of the 'OK' button
+    pub fn label_ok<S: Into<String>>(mut self, label: S) -> Self {
+        self.settings.label_ok = Some(label.into());
+        self
+    }
+
+    /// Ask for the PIN a second time with `prompt`, and only accept it if both entries match (`SETREPEAT`)
+    ///
+    /// Use [`pin_repeated()`](SessionPrompt::pin_repeated) to find out whether pinentry did check the entries.
+    pub fn repeat<S: Into<String>>(mut self, prompt: S) -> Self {
+        self.settings.repeat = Some(prompt.into());
+        self
+    }
+
+    /// Set the error text shown when the repeated PIN does not match (`SETREPEATERROR`)
+    pub fn repeat_error<S: Into<String>>(mut self, error_text: S) -> Self {
+        self.settings.repeat_error = Some(error_text.into());
+        self
+    }
+
+    /// Identify the passphrase asked for by `key_info` (`SETKEYINFO`, e.g. `n/0123456789ABCDEF`), the key under which
+    /// pinentry stores it in the external password cache
+    ///
+    /// Use [`pin_cacheable()`](SessionPrompt::pin_cacheable) to find out whether the PIN came from the cache. The
+    /// cache has to be allowed with [`allow_external_cache`](SessionPrompt::allow_external_cache) (here or as a
+    /// default of the session), or prompting fails with [`Error::InvalidConfiguration`].
+    pub fn keyinfo<S: Into<String>>(mut self, key_info: S) -> Self {
+        self.settings.key_info = Some(key_info.into());
+        self
+    }
+
+    /// Let pinentry take the PIN from (and offer to save it in) an external password cache, such as the GNOME
+    /// keyring or KWallet (`OPTION allow-external-password-cache`, off by default)
+    ///
+    /// Only applies to prompts with a [`keyinfo`](SessionPrompt::keyinfo).
+    pub fn allow_external_cache(mut self, allow: bool) -> Self {
+        self.settings.external_cache = allow;
+        self
+    }
+
+    /// Show a quality bar with `tooltip` (`SETQUALITYBAR`), filled in by the
+    /// [`quality_fn`](SessionPrompt::quality_fn) (here or as a default of the session)
+    ///
+    /// Prompting fails with [`Error::InvalidConfiguration`] without a quality function, as the bar would never move.
+    pub fn quality_bar<S: Into<String>>(mut self, tooltip: S) -> Self {
+        self.settings.quality_bar = Some(tooltip.into());
+        self
+    }
+
+    /// Offer a button with `label` that fills in a generated PIN (`SETGENPIN`), made by the
+    /// [`genpin_fn`](SessionPrompt::genpin_fn)
+    ///
+    /// Use [`pin_suggested()`](SessionPrompt::pin_suggested) to find out whether the user took the generated PIN.
+    pub fn genpin_label<S: Into<String>>(mut self, label: S) -> Self {
+        self.settings.genpin = Some(label.into());
+        self
+    }
+
+    /// Set the tooltip of the button offering a generated PIN (`SETGENPIN_TT`)
+    pub fn genpin_tooltip<S: Into<String>>(mut self, tooltip: S) -> Self {
+        self.settings.genpin_tooltip = Some(tooltip.into());
+        self
+    }
+
+    /// Make the PIN suggested when the user presses the [`genpin_label`](SessionPrompt::genpin_label) button (by
+    /// answering pinentry's `GENPIN` inquiries)
+    ///
+    /// Without it, PINs are made by [`random_passphrase`](super::passphrase::random_passphrase) with the `genpin`
+    /// feature - otherwise the button does nothing.
+    pub fn genpin_fn<F: Fn() -> SecretPin + Send + Sync + 'static>(mut self, generate: F) -> Self {
+        self.settings.genpin_fn = Some(Arc::new(generate));
+        self
+    }
+
+    /// Rate the PIN typed so far from -100 (unacceptable) to 100 (excellent), whenever pinentry updates the
+    /// quality bar
+    pub fn quality_fn<F: Fn(&str) -> i32 + Send + Sync + 'static>(mut self, quality: F) -> Self {
+        self.settings.quality_fn = Some(Arc::new(quality));
+        self
+    }
+
+    /// Normalize the captured PIN (Unicode normalization, trimming, rejecting empty input) before it is returned
+    pub fn normalization(mut self, normalization: Normalization) -> Self {
+        self.settings.normalization = Some(normalization);
+        self
+    }
+
+    /// Set how many PINs [`unlock()`](SessionPrompt::unlock) lets the user try
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.settings.max_attempts = Some(attempts);
+        self
+    }
+
+    /// Set timeout for prompt (in seconds)
+    pub fn timeout(mut self, secs: u32) -> Self {
+        self.settings.timeout = Some(secs);
+        self
+    }
+
+    /// Set the window title of the prompt
+    pub fn window_title<S: Into<String>>(mut self, title: S) -> Self {
+        self.settings.window_title = Some(title.into());
+        self
+    }
+
+    /// Prompt for confirmation
+    ///
+    /// The text for the confirmation should be set using `.description()`
+    pub fn confirm_yes_no(self) -> Result<bool> {
+        self.validate(PromptKind::Confirm)?;
+        let res = self
+            .session
+            .run_prompt(self.settings.into_commands(), vec![AssuanCommand::Confirm])?;
+        match res {
+            AssuanResponse::OK => Ok(true),
+            AssuanResponse::NOTOK(_) => Ok(false),
+            x => Err(Error::unexpected_response(x)),
+        }
+    }
+
+    /// Prompt for confirmation, telling the 'OK' and 'Not OK' buttons and cancelling apart (unlike
+    /// [`confirm_yes_no`](SessionPrompt::confirm_yes_no), which returns `false` for both)
+    ///
+    /// The text for the confirmation should be set using `.description()`
+    pub fn confirm(self) -> Result<Confirmation> {
+        self.validate(PromptKind::Confirm)?;
+        let res = self
+            .session
+            .run_prompt(self.settings.into_commands(), vec![AssuanCommand::Confirm])?;
+        confirmation(res)
+    }
+
+    /// Ask for confirmation with a single button (`CONFIRM --one-button`), e.g. for informational dialogs that
+    /// pinentry should still report being dismissed for
+    ///
+    /// Returns [`Confirmation::Ok`] or [`Confirmation::Cancelled`].
+    pub fn confirm_one_button(self) -> Result<Confirmation> {
+        self.validate(PromptKind::Confirm)?;
+        let res = self
+            .session
+            .run_prompt(self.settings.into_commands(), vec![AssuanCommand::ConfirmOneButton])?;
+        confirmation(res)
+    }
+
+    /// Prompt for a PIN
+    ///
+    /// If the PIN is rejected by the normalization (empty input), the prompt is repeated with an error text.
+    pub fn pin<S: Into<String>>(mut self, prompt: S) -> Result<SecretPin> {
+        self.validate(PromptKind::Pin)?;
+        let normalization = self.take_normalization();
+        let quality = self.take_quality_fn();
+        let generate = self.take_genpin_fn();
+        let mut overrides = self.settings.into_commands();
+        self.session.read_pin_with(
+            &mut overrides,
+            &normalization,
+            &prompt.into(),
+            quality.as_ref(),
+            generate.as_ref(),
+        )
+    }
+
+    /// Prompt for a PIN to be entered twice, reporting whether pinentry checked that both entries match
+    ///
+    /// Needs a repeat prompt, set with [`repeat()`](SessionPrompt::repeat) here or as a default of the session.
+    pub fn pin_repeated<S: Into<String>>(mut self, prompt: S) -> Result<RepeatedPin> {
+        self.validate(PromptKind::Pin)?;
+        let has_repeat = self.settings.repeat.is_some()
+            || self
+                .session
+                .state
+                .iter()
+                .any(|cmd| matches!(cmd, AssuanCommand::SetRepeat(_)));
+        if !has_repeat {
+            return Err(invalid("pin_repeated() needs a repeat prompt"));
+        }
+        let normalization = self.take_normalization();
+        let quality = self.take_quality_fn();
+        let generate = self.take_genpin_fn();
+        let mut overrides = self.settings.into_commands();
+        let pin = self.session.read_pin_with(
+            &mut overrides,
+            &normalization,
+            &prompt.into(),
+            quality.as_ref(),
+            generate.as_ref(),
+        )?;
+        let repeated = self
+            .session
+            .connection
+            .statuses()
+            .iter()
+            .any(|status| status.keyword == "PIN_REPEATED");
+        Ok(RepeatedPin { pin, repeated })
+    }
+
+    /// Prompt for a PIN, offering a generated one (see [`genpin_label()`](SessionPrompt::genpin_label)), reporting
+    /// whether the user took it
+    ///
+    /// A generated PIN that the user edited before accepting it is not reported as generated.
+    pub fn pin_suggested<S: Into<String>>(mut self, prompt: S) -> Result<SuggestedPin> {
+        self.validate(PromptKind::Pin)?;
+        let normalization = self.take_normalization();
+        let quality = self.take_quality_fn();
+        let generate = self.take_genpin_fn();
+        let mut overrides = self.settings.into_commands();
+        let pin = self.session.read_pin_with(
+            &mut overrides,
+            &normalization,
+            &prompt.into(),
+            quality.as_ref(),
+            generate.as_ref(),
+        )?;
+        Ok(SuggestedPin {
+            pin,
+            generated: self.session.generated,
+        })
+    }
+
+    /// Prompt for a PIN that may come from the external password cache, reporting whether it did
+    /// (`S PASSWORD_FROM_CACHE`)
+    ///
+    /// Needs a [`keyinfo`](SessionPrompt::keyinfo), here or as a default of the session; the cache has to be allowed
+    /// with [`allow_external_cache`](SessionPrompt::allow_external_cache). A cached PIN that turns out to be wrong
+    /// should be removed with [`PinentrySession::clear_cached_passphrase`], or pinentry keeps returning it.
+    pub fn pin_cacheable<S: Into<String>>(mut self, prompt: S) -> Result<CachedPin> {
+        self.validate(PromptKind::Pin)?;
+        let has_key_info = self.settings.key_info.is_some()
+            || self
+                .session
+                .state
+                .iter()
+                .any(|cmd| matches!(cmd, AssuanCommand::SetKeyInfo(_)));
+        if !has_key_info {
+            return Err(invalid("pin_cacheable() needs a key info"));
+        }
+        let normalization = self.take_normalization();
+        let quality = self.take_quality_fn();
+        let generate = self.take_genpin_fn();
+        let mut overrides = self.settings.into_commands();
+        let pin = self.session.read_pin_with(
+            &mut overrides,
+            &normalization,
+            &prompt.into(),
+            quality.as_ref(),
+            generate.as_ref(),
+        )?;
+        let from_cache = self
+            .session
+            .connection
+            .statuses()
+            .iter()
+            .any(|status| status.keyword == "PASSWORD_FROM_CACHE");
+        Ok(CachedPin { pin, from_cache })
+    }
+
+    /// Let the user pick one of `options`, returning its index (or `None` if the prompt is cancelled)
+    ///
+    /// Pinentry has no selection dialog, so this is emulated: the options are listed (numbered) in the description,
+    /// after the description set for this prompt (if any), and the number of the choice is entered as the PIN.
+    /// Fails with [`Error::InvalidConfiguration`] if there are no options.
+    pub fn choose(mut self, options: &[&str]) -> Result<Option<usize>> {
+        self.validate(PromptKind::Choice)?;
+        check_choices(options)?;
+        let mut lines = Vec::new();
+        if let Some(desc) = self.settings.description.take() {
+            lines.push(desc);
+            lines.push(String::new());
+        }
+        lines.extend(
+            options
+                .iter()
+                .enumerate()
+                .map(|(i, option)| format!("{}) {}", i + 1, option)),
+        );
+        self.settings.description = Some(lines.join("\n"));
+        let prompt = Message::ChoicePrompt { count: options.len() }.text();
+
+        let normalization = Normalization::new().trim_trailing_whitespace(true);
+        let mut overrides = self.settings.into_commands();
+        loop {
+            let choice = match self.session.read_pin(&mut overrides, &normalization, &prompt) {
+                Ok(choice) => choice,
+                Err(Error::Cancelled) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            let index = std::str::from_utf8(choice.unsecure())
+                .ok()
+                .and_then(|s| s.trim().parse::<usize>().ok())
+                .filter(|n| (1..=options.len()).contains(n));
+            match index {
+                Some(n) => return Ok(Some(n - 1)),
+                None => set_error_text(
+                    &mut overrides,
+                    Message::ChoiceOutOfRange { count: options.len() }.text(),
+                ),
+            }
+        }
+    }
+
+    /// Prompt for a PIN, offering an alternate action (e.g. "Use keyfile instead...") on the 'Not OK' button
+    ///
+    /// Cancelling the prompt is reported as [`PinOutcome::Cancelled`] rather than as an error.
+    pub fn pin_or_alternate<S: Into<String>, L: Into<String>>(
+        mut self,
+        prompt: S,
+        alternate_label: L,
+    ) -> Result<PinOutcome> {
+        self.settings.label_notok = Some(alternate_label.into());
+        match self.pin(prompt) {
+            Ok(pin) => Ok(PinOutcome::Entered(pin)),
+            Err(Error::NotConfirmed) => Ok(PinOutcome::AlternateAction),
+            Err(Error::Cancelled) => Ok(PinOutcome::Cancelled),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Prompt for a PIN and hand it to `f` without returning it
+    ///
+    /// The PIN only lives for the duration of the call to `f` and is zeroized as soon as `f` returns (or panics),
+    /// so it cannot escape into the rest of the program by accident. Errors while prompting are converted into `E`.
+    pub fn pin_and_use<T, E, F, S: Into<String>>(self, prompt: S, f: F) -> result::Result<T, E>
+    where
+        E: From<Error>,
+        F: FnOnce(&[u8]) -> result::Result<T, E>,
+    {
+        let pin = self.pin(prompt)?;
+        let res = f(pin.unsecure());
+        drop(pin);
+        res
+    }
+
+    /// Prompt for a PIN until `verify` accepts it
+    ///
+    /// `verify` attempts the actual unlock with the PIN. When it returns [`VerifyError::Retry`] the prompt is shown
+    /// again with the message as error text, up to `max_attempts` times (3 by default) - after that
+    /// [`UnlockError::LockedOut`] is returned. The PIN is zeroized after each attempt.
+    pub fn unlock<T, E, F, S: Into<String>>(self, prompt: S, mut verify: F) -> result::Result<T, UnlockError<E>>
+    where
+        F: FnMut(&[u8]) -> result::Result<T, VerifyError<E>>,
+    {
+        self.retry(prompt.into(), |pin| verify(pin.unsecure()))
+    }
+
+    /// Prompt for a PIN until `validate` accepts it, at most `max_attempts` times
+    ///
+    /// A simpler form of [`unlock`](SessionPrompt::unlock): when `validate` rejects the PIN, the prompt is shown
+    /// again in the same pinentry with the message returned as error text. Fails with
+    /// [`Error::MaxAttemptsExceeded`] if none of the PINs is accepted.
+    pub fn pin_with_retries<T, F, S: Into<String>>(mut self, prompt: S, max_attempts: u32, mut validate: F) -> Result<T>
+    where
+        F: FnMut(&SecretPin) -> result::Result<T, String>,
+    {
+        self.settings.max_attempts = Some(max_attempts);
+        self.retry(prompt.into(), |pin| {
+            validate(pin).map_err(VerifyError::<Infallible>::Retry)
+        })
+        .map_err(|e| match e {
+            UnlockError::Pinentry(e) => e,
+            UnlockError::LockedOut { attempts } => Error::MaxAttemptsExceeded(attempts),
+            UnlockError::Failed(never) => match never {},
+        })
+    }
+
+    /// Show a message
+    ///
+    /// The text for the message should be set using `.description()`
+    pub fn show_message(self) -> Result<()> {
+        self.validate(PromptKind::Message)?;
+        let res = self
+            .session
+            .run_prompt(self.settings.into_commands(), vec![AssuanCommand::ShowMessage])?;
+        match res {
+            AssuanResponse::OK => Ok(()),
+            AssuanResponse::NOTOK(error) => Err(Error::from_response(error)),
+            x => Err(Error::unexpected_response(x)),
+        }
+    }
+}
+
+impl SessionPrompt<'_> {
+    /// The loop of `unlock`, passing the PIN itself to `verify`
+    fn retry<T, E, F>(mut self, prompt: String, mut verify: F) -> result::Result<T, UnlockError<E>>
+    where
+        F: FnMut(&SecretPin) -> result::Result<T, VerifyError<E>>,
+    {
+        self.validate(PromptKind::Unlock)?;
+        let normalization = self.take_normalization();
+        let max_attempts = self
+            .settings
+            .max_attempts
+            .take()
+            .unwrap_or(self.session.max_attempts)
+            .max(1);
+        let mut overrides = self.settings.into_commands();
+
+        for attempt in 1..=max_attempts {
+            let pin = self
+                .session
+                .read_pin(&mut overrides, &normalization, &prompt)
+                .map_err(UnlockError::Pinentry)?;
+            let res = verify(&pin);
+            drop(pin);
+
+            match res {
+                Ok(unlocked) => return Ok(unlocked),
+                Err(VerifyError::Fatal(e)) => return Err(UnlockError::Failed(e)),
+                Err(VerifyError::Retry(message)) => {
+                    set_error_text(&mut overrides, attempts_left(&message, max_attempts - attempt));
+                }
+            }
+        }
+        Err(UnlockError::LockedOut { attempts: max_attempts })
+    }
+
+    pub(crate) fn take_normalization(&mut self) -> Normalization {
+        match self.settings.normalization.take() {
+            Some(normalization) => normalization,
+            None => self.session.normalization.clone(),
+        }
+    }
+
+    /// Validate the settings of the prompt for `kind`, on top of the defaults of the session
+    pub(crate) fn validate(&self, kind: PromptKind) -> Result<()> {
+        let inherited = Inherited {
+            quality_fn: self.session.quality.is_some(),
+            external_cache: self.session.state.iter().any(
+                |cmd| matches!(cmd, AssuanCommand::Option(ref name, None) if name == "allow-external-password-cache"),
+            ),
+        };
+        self.settings.validate_on(Some(kind), inherited)
+    }
+
+    fn take_quality_fn(&mut self) -> Option<QualityFn> {
+        self.settings.quality_fn.take().or_else(|| self.session.quality.clone())
+    }
+
+    fn take_genpin_fn(&mut self) -> Option<GenPinFn> {
+        self.settings.genpin_fn.take().or_else(|| self.session.generate.clone())
+    }
+}
+
+/// Makes a PIN to suggest
+pub(crate) type GenPinFn = Arc<dyn Fn() -> SecretPin + Send + Sync>;
+
+/// The PIN suggested without a `genpin_fn`, if any
+fn default_genpin() -> Option<SecretPin> {
+    #[cfg(feature = "genpin")]
+    return super::passphrase::random_passphrase()
+        .map_err(|e| debug!("could not generate a PIN: {}", e))
+        .ok();
+    #[cfg(not(feature = "genpin"))]
+    None
+}
+
+/// Rates a PIN for the quality bar, from -100 to 100
+pub(crate) type QualityFn = Arc<dyn Fn(&str) -> i32 + Send + Sync>;
+
+/// Answer a `QUALITY` inquiry with the rating of the PIN typed so far
+fn answer_quality(quality: &(dyn Fn(&str) -> i32 + Send + Sync), inquiry: &Inquiry) -> Option<SecretPin> {
+    if inquiry.keyword != "QUALITY" {
+        return None;
+    }
+    let pin = inquiry.secret_params().ok()?;
+    let rating = quality(std::str::from_utf8(pin.unsecure()).ok()?).clamp(-100, 100);
+    Some(SecretPin::from(rating.to_string()))
+}
+
+/// Checks a passphrase against the constraints, returning why it is refused
+pub(crate) type CheckFn = Arc<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+/// Answer a `CHECKPIN` inquiry with why the passphrase is refused, or nothing if it is accepted
+fn answer_check(check: &(dyn Fn(&str) -> Option<String> + Send + Sync), inquiry: &Inquiry) -> Option<SecretPin> {
+    let pin = inquiry.secret_params().ok()?;
+    let refused = check(std::str::from_utf8(pin.unsecure()).ok()?);
+    Some(SecretPin::from(refused.unwrap_or_default()))
+}
+
+impl Drop for PinentrySession {
+    fn drop(&mut self) {
+        self.connection.close();
+    }
+}
+
+/// The answer to a `CONFIRM` command
+pub(crate) fn confirmation(res: AssuanResponse) -> Result<Confirmation> {
+    match res {
+        AssuanResponse::OK => Ok(Confirmation::Ok),
+        AssuanResponse::NOTOK(error) => match Error::from_response(error) {
+            Error::NotConfirmed => Ok(Confirmation::NotOk),
+            Error::Cancelled => Ok(Confirmation::Cancelled),
+            e => Err(e),
+        },
+        x => Err(Error::unexpected_response(x)),
+    }
+}
+
+fn expect_ok(res: AssuanResponse) -> Result<()> {
+    match res {
+        AssuanResponse::OK => Ok(()),
+        AssuanResponse::NOTOK(error) => Err(Error::from_response(error)),
+        x => Err(Error::unexpected_response(x)),
+    }
+}
+
+/// Code of the error in an `ERR` line returned by pinentry
+pub(crate) fn error_code(error: &str) -> Option<u32> {
+    match Line::parse(error.as_bytes()) {
+        Ok(Line::Err(e)) => Some(e.error_code()),
+        _ => None,
+    }
+}
+
+/// Whether an `ERR` line returned by pinentry means that the prompt was cancelled
+pub(crate) fn is_cancel(error: &str) -> bool {
+    matches!(
+        error_code(error),
+        Some(AssuanError::CANCELED) | Some(AssuanError::FULLY_CANCELED)
+    )
+}
+
+/// The error text for a rejected PIN, with the number of attempts left
+pub(crate) fn attempts_left(message: &str, left: u32) -> String {
+    Message::AttemptsLeft { message, left }.text()
+}
+
+/// Replace the error text of a prompt
+pub(crate) fn set_error_text(overrides: &mut Vec<AssuanCommand>, text: String) {
+    overrides.retain(|c| !matches!(c, AssuanCommand::SetErrorText(_)));
+    overrides.push(AssuanCommand::SetErrorText(text));
+}
+
+/// Whether both commands change the same setting
+fn same_setting(a: &AssuanCommand, b: &AssuanCommand) -> bool {
+    match (a, b) {
+        (AssuanCommand::Option(a, _), AssuanCommand::Option(b, _)) => a == b,
+        (AssuanCommand::SetButtonLabel(a, _), AssuanCommand::SetButtonLabel(b, _)) => a == b,
+        _ => a.name() == b.name(),
+    }
+}
+
+/// There needs to be something to choose from, or every answer is out of range
+pub(crate) fn check_choices(options: &[&str]) -> Result<()> {
+    match options.is_empty() {
+        true => Err(invalid("there are no options to choose from")),
+        false => Ok(()),
+    }
+}
+
+/// Whether the error means that the pinentry process has gone away
+fn is_disconnect(e: &Error) -> bool {
+    match e {
+        Error::IoError(e) => matches!(
+            e.kind(),
+            io::ErrorKind::BrokenPipe
+                | io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+        ),
+        _ => false,
+    }
+}
+
+#[cfg(all(test, unix, feature = "process"))]
+mod tests {
+    use super::super::{pinentry, Error};
+    use super::*;
+
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::str;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use super::super::normalize::EMPTY_ERROR;
+    use super::super::test_util::FakePinentry;
+
+    // crash on the first GETPIN, succeed on the next one
+    const CRASH_ONCE: &str =
+        r#"if [ -e "$DIR/crashed" ]; then echo "D secret"; echo OK; else touch "$DIR/crashed"; exit 1; fi"#;
+
+    #[test]
+    fn test_session_multiple_prompts() {
+        let fake = FakePinentry::new(&[("GETPIN", r#"echo "D secret"; echo OK"#)]);
+        let mut session = pinentry()
+            .exe(fake.exe())
+            .window_title("Session")
+            .connect()
+            .expect("session is started");
+
+        for _ in 0..2 {
+            let pin = session.pin("PIN:").expect("PIN is returned");
+            assert_eq!("secret", str::from_utf8(pin.unsecure()).unwrap());
+        }
+        assert!(session.confirm_yes_no().expect("confirmation is returned"));
+        assert_eq!(
+            vec![
+                "SETTITLE Session",
+                "SETPROMPT PIN:",
+                "GETPIN",
+                "SETPROMPT PIN:",
+                "GETPIN",
+                "CONFIRM"
+            ],
+            fake.commands()
+        );
+        assert_eq!(1, fake.spawn_count());
+    }
+
+    #[test]
+    fn test_session_launch_settings() {
+        let fake = FakePinentry::new(&[("GETPIN", r#"echo "D $*|$FOO|$HOME|$(pwd)"; echo OK"#)]);
+        let dir = std::env::temp_dir().canonicalize().unwrap();
+        let pin = pinentry()
+            .exe(fake.exe())
+            .arg("--ttyname")
+            .arg("/dev/pts/9")
+            .clear_env()
+            .env("FOO", "bar".to_string())
+            .current_dir(&dir)
+            .pin("PIN:")
+            .expect("PIN is returned");
+        assert_eq!(
+            format!("--ttyname /dev/pts/9|bar||{}", dir.display()).as_bytes(),
+            pin.unsecure()
+        );
+
+        let pin = pinentry()
+            .exe(fake.exe())
+            .envs(vec![("FOO".to_string(), "baz".to_string())])
+            .pin("PIN:")
+            .expect("PIN is returned");
+        assert!(str::from_utf8(pin.unsecure()).unwrap().starts_with("|baz|"));
+    }
+
+    #[test]
+    fn test_session_unexpected_responses() {
+        let fake = FakePinentry::new(&[
+            ("CONFIRM", r#"echo "D junk"; echo OK"#),
+            ("MESSAGE", r#"echo "D junk"; echo OK"#),
+            ("GETPIN", "echo FROB"),
+            ("'SETTITLE Garbage'", r#"printf 'no\001line'; echo"#),
+        ]);
+        let mut session = pinentry().exe(fake.exe()).connect().expect("session is started");
+
+        for res in [session.confirm_yes_no().map(|_| ()), session.show_message()] {
+            match res {
+                Err(Error::UnexpectedResponse(response)) => assert_eq!("data", response),
+                x => panic!("unexpected result {:?}", x),
+            }
+        }
+        assert!(matches!(session.confirm(), Err(Error::UnexpectedResponse(_))));
+        assert!(matches!(session.pin("PIN:"), Err(Error::ProtocolError(_))));
+        assert!(session.prompt().window_title("Garbage").show_message().is_err());
+    }
+
+    #[test]
+    fn test_session_close() {
+        let fake = FakePinentry::new(&[]);
+        let mut session = pinentry().exe(fake.exe()).connect().expect("session is started");
+        session.show_message().expect("message is shown");
+        session.close().expect("pinentry says goodbye");
+        assert_eq!(vec!["MESSAGE", "BYE"], fake.commands());
+
+        let fake = FakePinentry::new(&[("BYE", "exit 1")]);
+        let session = pinentry().exe(fake.exe()).connect().expect("session is started");
+        assert!(session.close().is_err());
+    }
+
+    #[test]
+    fn test_session_drop_says_bye() {
+        let fake = FakePinentry::new(&[]);
+        pinentry()
+            .exe(fake.exe())
+            .confirm_yes_no()
+            .expect("confirmation is returned");
+        assert_eq!(vec!["CONFIRM", "BYE"], fake.commands());
+
+        // a pinentry that does not exit is killed eventually
+        let fake = FakePinentry::new(&[("BYE", "exec sleep 30")]);
+        let start = std::time::Instant::now();
+        drop(pinentry().exe(fake.exe()).connect().expect("session is started"));
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        assert_eq!(vec!["BYE"], fake.commands());
+    }
+
+    #[test]
+    fn test_session_pin_repeated() {
+        // only the second prompt is repeated
+        let fake = FakePinentry::new(&[(
+            "GETPIN",
+            r#"if [ -e "$DIR/asked" ]; then echo "S PIN_REPEATED"; fi; touch "$DIR/asked"; echo "D secret"; echo OK"#,
+        )]);
+        let mut session = pinentry()
+            .exe(fake.exe())
+            .repeat("Repeat:")
+            .connect()
+            .expect("session is started");
+
+        let first = session.pin_repeated("PIN:").expect("PIN is returned");
+        assert!(!first.repeated);
+        let second = session
+            .prompt()
+            .repeat_error("No match")
+            .pin_repeated("PIN:")
+            .expect("PIN is returned");
+        assert_eq!(b"secret", second.pin.unsecure());
+        assert!(second.repeated);
+        assert_eq!(
+            vec![
+                "SETREPEAT Repeat:",
+                "SETPROMPT PIN:",
+                "GETPIN",
+                "SETREPEATERROR No match",
+                "SETPROMPT PIN:",
+                "GETPIN"
+            ],
+            fake.commands()
+        );
+
+        match pinentry().exe(fake.exe()).pin_repeated("PIN:") {
+            Err(Error::InvalidConfiguration(_)) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_session_get_info() {
+        let fake = FakePinentry::new(&[
+            ("'GETINFO version'", r#"echo "D 1.2.1"; echo OK"#),
+            ("'GETINFO flavor'", r#"echo "D gtk2:curses"; echo OK"#),
+            ("'GETINFO pid'", r#"echo "D $$"; echo OK"#),
+            ("'GETINFO ttyinfo'", r#"echo "ERR 83886254 Unknown command""#),
+        ]);
+        let mut session = pinentry().exe(fake.exe()).connect().expect("session is started");
+
+        let info = session.get_info().expect("info is returned");
+        assert_eq!(Some("1.2.1"), info.version.as_deref());
+        assert_eq!(Some("gtk2:curses"), info.flavor.as_deref());
+        assert!(info.pid.is_some_and(|pid| pid > 0));
+        assert_eq!(None, info.ttyinfo);
+        assert_eq!(
+            vec!["GETINFO version", "GETINFO flavor", "GETINFO pid", "GETINFO ttyinfo"],
+            fake.commands()
+        );
+    }
+
+    #[test]
+    fn test_session_get_info_filtered() {
+        // the first GETINFO finds pinentry gone, the flavor comes with a status line
+        let fake = FakePinentry::new(&[
+            (
+                "'GETINFO version'",
+                r#"if [ -e "$DIR/crashed" ]; then echo "D 1.2.1"; echo OK; else touch "$DIR/crashed"; exit 1; fi"#,
+            ),
+            ("'GETINFO flavor'", r#"echo "S PROGRESS"; echo "D gtk2"; echo OK"#),
+        ]);
+        let mut session = pinentry()
+            .exe(fake.exe())
+            .respawn(true)
+            .command_filter(CommandFilter::new().allow_statuses(["PINENTRY_LAUNCHED"]))
+            .connect()
+            .expect("session is started");
+        assert!(matches!(session.get_info(), Err(Error::PolicyDenied(_))));
+        assert_eq!(2, fake.spawn_count());
+
+        session.set_command_filter(CommandFilter::new().allow_commands(["GETPIN"]));
+        assert!(matches!(session.get_info(), Err(Error::PolicyDenied(_))));
+        assert_eq!(
+            vec!["GETINFO version", "GETINFO version", "GETINFO flavor"],
+            fake.commands()
+        );
+    }
+
+    #[test]
+    fn test_session_pin_cacheable() {
+        // the first PIN comes from the cache, the second one is typed
+        let fake = FakePinentry::new(&[(
+            "GETPIN",
+            r#"if [ ! -e "$DIR/asked" ]; then echo "S PASSWORD_FROM_CACHE"; fi; touch "$DIR/asked"; echo "D secret"; echo OK"#,
+        )]);
+        let mut session = pinentry()
+            .exe(fake.exe())
+            .allow_external_cache(true)
+            .keyinfo("n/0123ABCD")
+            .connect()
+            .expect("session is started");
+
+        let cached = session.pin_cacheable("PIN:").expect("PIN is returned");
+        assert_eq!(b"secret", cached.pin.unsecure());
+        assert!(cached.from_cache);
+        session
+            .clear_cached_passphrase("n/0123ABCD")
+            .expect("passphrase is cleared");
+        assert!(!session.pin_cacheable("PIN:").expect("PIN is returned").from_cache);
+        assert_eq!(
+            vec![
+                "OPTION allow-external-password-cache",
+                "SETKEYINFO n/0123ABCD",
+                "SETPROMPT PIN:",
+                "GETPIN",
+                "CLEARPASSPHRASE n/0123ABCD",
+                "SETPROMPT PIN:",
+                "GETPIN"
+            ],
+            fake.commands()
+        );
+
+        for builder in [
+            pinentry().exe(fake.exe()),
+            pinentry().exe(fake.exe()).keyinfo("n/with space"),
+        ] {
+            match builder.pin_cacheable("PIN:") {
+                Err(Error::InvalidConfiguration(_)) => (),
+                x => panic!("unexpected result {:?}", x),
+            }
+        }
+        match pinentry().exe(fake.exe()).keyinfo("n/0123ABCD").confirm_yes_no() {
+            Err(Error::InvalidConfiguration(_)) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_session_pin_suggested() {
+        // the generated PIN is taken the first time only
+        let fake = FakePinentry::new(&[(
+            "GETPIN",
+            r#"echo "INQUIRE GENPIN"; read -r g; read -r end; n=$((n+1)); if [ $n = 1 ]; then echo "$g"; else echo "D edited"; fi; echo OK"#,
+        )]);
+        let mut session = pinentry()
+            .exe(fake.exe())
+            .genpin_label("_Generate")
+            .genpin_tooltip("Suggest a PIN")
+            .genpin_fn(|| SecretPin::from("generated"))
+            .connect()
+            .expect("session is started");
+
+        let first = session.pin_suggested("PIN:").expect("PIN is returned");
+        assert_eq!(b"generated", first.pin.unsecure());
+        assert!(first.generated);
+        let second = session.pin_suggested("PIN:").expect("PIN is returned");
+        assert_eq!(b"edited", second.pin.unsecure());
+        assert!(!second.generated);
+        let commands = fake.commands();
+        assert!(commands.contains(&"SETGENPIN _Generate".to_string()));
+        assert!(commands.contains(&"SETGENPIN_TT Suggest a PIN".to_string()));
+
+        let res = pinentry().exe(fake.exe()).genpin_label("_Generate").confirm_yes_no();
+        assert!(matches!(res, Err(Error::InvalidConfiguration(_))));
+    }
+
+    #[test]
+    fn test_session_quality_bar() {
+        let fake = FakePinentry::new(&[(
+            "GETPIN",
+            r#"echo "INQUIRE QUALITY ab%25"; read -r q; read -r end; echo "$q" >> "$DIR/answers"; echo "D secret"; echo OK"#,
+        )]);
+        let pin = pinentry()
+            .exe(fake.exe())
+            .quality_bar("Strength of the PIN")
+            .quality_fn(|pin| pin.len() as i32 * 10)
+            .pin("PIN:")
+            .expect("PIN is returned");
+        assert_eq!(b"secret", pin.unsecure());
+        assert_eq!(
+            vec![
+                "SETQUALITYBAR",
+                "SETQUALITYBAR_TT Strength of the PIN",
+                "SETPROMPT PIN:",
+                "GETPIN"
+            ],
+            fake.commands()[..4].to_vec()
+        );
+        let answers = std::fs::read_to_string(std::path::Path::new(&fake.exe()).with_file_name("answers")).unwrap();
+        assert_eq!("D 30\n", answers);
+    }
+
+    #[test]
+    fn test_session_constraints() {
+        // pinentry checks a passphrase that is too short, and then one that is long enough
+        let fake = FakePinentry::new(&[(
+            "GETPIN",
+            r#"for pin in short long%25enough; do echo "INQUIRE CHECKPIN $pin"; read -r a; read -r end; echo "$a" >> "$DIR/answers"; done; echo "D long%25enough"; echo OK"#,
+        )]);
+        let pin = pinentry()
+            .exe(fake.exe())
+            .constraints_enforce(true)
+            .constraints_hint_short("At least 8 characters")
+            .constraints_error_title("100% not acceptable")
+            .constraints_fn(|pin| match pin.chars().count() {
+                n if n < 8 => Some(format!("{} characters are too few", n)),
+                _ => None,
+            })
+            .pin("Passphrase:")
+            .expect("PIN is returned");
+        assert_eq!(b"long%enough", pin.unsecure());
+        assert_eq!(
+            vec![
+                "OPTION constraints-enforce",
+                "OPTION constraints-hint-short=At+least+8+characters",
+                "OPTION constraints-error-title=100%25+not+acceptable",
+            ],
+            fake.commands()[..3].to_vec()
+        );
+        let answers = std::fs::read_to_string(std::path::Path::new(&fake.exe()).with_file_name("answers")).unwrap();
+        // an accepted passphrase is answered with empty data
+        assert_eq!("D 5 characters are too few\nD\n", answers);
+
+        let builder = pinentry().constraints_enforce(true).constraints_enforce(false);
+        assert!(builder.options.is_empty());
+    }
+
+    /// Counts the blocks freed while armed that still hold [`MARKER`], i.e. secrets that were not wiped
+    struct ScanningAllocator;
+
+    const MARKER: &[u8] = b"pl41nt3xt-m4rk3r";
+    static ARMED: AtomicBool = AtomicBool::new(false);
+    static UNWIPED: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe impl GlobalAlloc for ScanningAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            if ARMED.load(Ordering::SeqCst) {
+                let block = std::slice::from_raw_parts(ptr, layout.size());
+                if block.windows(MARKER.len()).any(|w| w == MARKER) {
+                    UNWIPED.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: ScanningAllocator = ScanningAllocator;
+
+    #[test]
+    fn test_session_inquiries_wiped() {
+        // the marker is only ever put together by the shell, so that the script does not contain it
+        let fake = FakePinentry::new(&[(
+            "GETPIN",
+            r#"m="pl41nt3xt""-m4rk3r"; for inquiry in QUALITY CHECKPIN; do echo "INQUIRE $inquiry $m"; read -r a; read -r end; done; echo "D $m"; echo OK"#,
+        )]);
+        let builder = pinentry()
+            .exe(fake.exe())
+            .quality_bar("Strength of the PIN")
+            .quality_fn(|pin| pin.len() as i32)
+            .constraints_fn(|_| None);
+        ARMED.store(true, Ordering::SeqCst);
+        let pin = builder.pin("PIN:");
+        ARMED.store(false, Ordering::SeqCst);
+        assert_eq!(MARKER, pin.expect("PIN is returned").unsecure());
+        assert_eq!(0, UNWIPED.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_session_pin_and_use() {
+        let fake = FakePinentry::new(&[
+            ("GETPIN", r#"echo "D secret"; echo OK"#),
+            ("'SETPROMPT Cancel:'", "echo 'ERR 83886179 Operation cancelled'"),
+        ]);
+        let mut session = pinentry().exe(fake.exe()).connect().expect("session is started");
+
+        let len: Result<usize> = session.pin_and_use("PIN:", |pin| {
+            assert_eq!(b"secret", pin);
+            Ok(pin.len())
+        });
+        assert_eq!(6, len.expect("closure result is returned"));
+
+        let res: Result<()> = session.pin_and_use("Cancel:", |_| panic!("PIN is not used"));
+        assert!(matches!(res, Err(Error::Cancelled)));
+    }
+
+    #[test]
+    fn test_session_normalization() {
+        // an empty PIN first, then one with a trailing newline
+        let fake = FakePinentry::new(&[(
+            "GETPIN",
+            r#"if [ -e "$DIR/asked" ]; then echo "D secret%0A"; else touch "$DIR/asked"; echo "D "; fi; echo OK"#,
+        )]);
+        let mut session = pinentry()
+            .exe(fake.exe())
+            .normalization(Normalization::new().trim_trailing_whitespace(true).reject_empty(true))
+            .connect()
+            .expect("session is started");
+
+        let pin = session.pin("PIN:").expect("PIN is returned");
+        assert_eq!(b"secret", pin.unsecure());
+        assert_eq!(
+            vec![
+                "SETPROMPT PIN:",
+                "GETPIN",
+                format!("SETERROR {}", EMPTY_ERROR).as_str(),
+                "SETPROMPT PIN:",
+                "GETPIN"
+            ],
+            fake.commands()
+        );
+
+        // the normalization can be overridden per prompt
+        let pin = session
+            .prompt()
+            .normalization(Normalization::new())
+            .pin("PIN:")
+            .unwrap();
+        assert_eq!(b"secret\n", pin.unsecure());
+    }
+
+    #[test]
+    fn test_session_unlock() {
+        let fake = FakePinentry::new(&[(
+            "GETPIN",
+            r#"echo "D attempt$(grep -c GETPIN "$DIR/commands.log")"; echo OK"#,
+        )]);
+        let mut session = pinentry().exe(fake.exe()).connect().expect("session is started");
+
+        let mut tried = Vec::new();
+        let res: result::Result<usize, UnlockError<()>> = session.unlock("PIN:", |pin| {
+            tried.push(pin.to_vec());
+            match pin {
+                b"attempt2" => Ok(2),
+                _ => Err(VerifyError::Retry("Wrong PIN".to_string())),
+            }
+        });
+        assert_eq!(2, res.expect("second attempt unlocks"));
+        assert_eq!(vec![b"attempt1".to_vec(), b"attempt2".to_vec()], tried);
+        assert_eq!(
+            vec![
+                "SETPROMPT PIN:",
+                "GETPIN",
+                "SETERROR Wrong PIN (2 attempts left)",
+                "SETPROMPT PIN:",
+                "GETPIN"
+            ],
+            fake.commands()
+        );
+    }
+
+    #[test]
+    fn test_session_unlock_lockout() {
+        let fake = FakePinentry::new(&[("GETPIN", r#"echo "D wrong"; echo OK"#)]);
+        let mut session = pinentry().exe(fake.exe()).connect().expect("session is started");
+
+        let res: result::Result<(), UnlockError<()>> = session
+            .prompt()
+            .max_attempts(2)
+            .unlock("PIN:", |_| Err(VerifyError::Retry("Wrong PIN".to_string())));
+        assert!(matches!(res, Err(UnlockError::LockedOut { attempts: 2 })));
+        assert_eq!(2, fake.commands().iter().filter(|c| *c == "GETPIN").count());
+
+        let res: result::Result<(), UnlockError<&str>> =
+            session.unlock("PIN:", |_| Err(VerifyError::Fatal("corrupt volume")));
+        assert!(matches!(res, Err(UnlockError::Failed("corrupt volume"))));
+    }
+
+    #[test]
+    fn test_session_pin_with_retries() {
+        let fake = FakePinentry::new(&[("GETPIN", r#"echo "D $(grep -c GETPIN "$DIR/commands.log")"; echo OK"#)]);
+        let mut session = pinentry().exe(fake.exe()).connect().expect("session is started");
+
+        let even = |pin: &SecretPin| match str::from_utf8(pin.unsecure()).unwrap().parse::<u32>().unwrap() {
+            n if n % 2 == 0 => Ok(n),
+            _ => Err("Not even".to_string()),
+        };
+        assert_eq!(2, session.pin_with_retries("PIN:", 3, even).unwrap());
+        assert!(matches!(
+            session.pin_with_retries("PIN:", 1, even),
+            Err(Error::MaxAttemptsExceeded(1))
+        ));
+        assert_eq!(
+            vec![
+                "SETPROMPT PIN:",
+                "GETPIN",
+                "SETERROR Not even (2 attempts left)",
+                "SETPROMPT PIN:",
+                "GETPIN",
+                "RESET",
+                "SETPROMPT PIN:",
+                "GETPIN"
+            ],
+            fake.commands()
+        );
+        assert!(session.pin_with_retries("PIN:", 0, even).is_err());
+        assert_eq!(1, fake.spawn_count());
+    }
+
+    #[test]
+    fn test_session_pin_or_alternate() {
+        let fake = FakePinentry::new(&[
+            ("'SETPROMPT PIN:'", "echo OK"),
+            ("'SETPROMPT Cancel:'", r#"echo OK; echo "cancel" > "$DIR/mode""#),
+            ("'SETPROMPT Alternate:'", r#"echo OK; echo "alternate" > "$DIR/mode""#),
+            (
+                "GETPIN",
+                r#"case "$(cat "$DIR/mode" 2>/dev/null)" in
+                    cancel) echo "ERR 83886179 Operation cancelled" ;;
+                    alternate) echo "ERR 83886194 Not confirmed" ;;
+                    *) echo "D secret"; echo OK ;;
+                esac; rm -f "$DIR/mode""#,
+            ),
+        ]);
+        let mut session = pinentry().exe(fake.exe()).connect().expect("session is started");
+        let alternate = || "Use keyfile instead".to_string();
+
+        match session.pin_or_alternate("PIN:", alternate()).unwrap() {
+            PinOutcome::Entered(pin) => assert_eq!(b"secret", pin.unsecure()),
+            x => panic!("unexpected outcome {:?}", x),
+        }
+        assert!(matches!(
+            session.pin_or_alternate("Alternate:", alternate()),
+            Ok(PinOutcome::AlternateAction)
+        ));
+        assert!(matches!(
+            session.pin_or_alternate("Cancel:", alternate()),
+            Ok(PinOutcome::Cancelled)
+        ));
+        assert_eq!("SETNOTOK Use keyfile instead", fake.commands()[0]);
+    }
+
+    #[test]
+    fn test_session_confirm() {
+        let fake = FakePinentry::new(&[(
+            "CONFIRM*",
+            r#"case "$(grep -c CONFIRM "$DIR/commands.log")" in
+                1) echo OK ;;
+                2) echo "ERR 83886194 Not confirmed <Pinentry>" ;;
+                3) echo "ERR 83886179 Operation cancelled <Pinentry>" ;;
+                4) echo "ERR 83886142 Timeout <Pinentry>" ;;
+                *) echo OK ;;
+            esac"#,
+        )]);
+        let mut session = pinentry().exe(fake.exe()).connect().expect("session is started");
+
+        assert_eq!(Confirmation::Ok, session.confirm().unwrap());
+        assert_eq!(
+            Confirmation::NotOk,
+            session.prompt().label_notok("Later").confirm().unwrap()
+        );
+        assert_eq!(Confirmation::Cancelled, session.confirm().unwrap());
+        assert!(matches!(session.confirm(), Err(Error::Timeout)));
+        assert_eq!(Confirmation::Ok, session.confirm_one_button().unwrap());
+        assert_eq!(
+            vec![
+                "CONFIRM",
+                "SETNOTOK Later",
+                "CONFIRM",
+                "RESET",
+                "CONFIRM",
+                "CONFIRM",
+                "CONFIRM --one-button"
+            ],
+            fake.commands()
+        );
+    }
+
+    #[test]
+    fn test_session_choose() {
+        // an out-of-range choice first, then a valid one, then cancel
+        let fake = FakePinentry::new(&[(
+            "GETPIN",
+            r#"case "$(grep -c GETPIN "$DIR/commands.log")" in
+                1) echo "D 4" ;;
+                2) echo "D 2 " ;;
+                *) echo "ERR 83886179 Operation cancelled" ;;
+            esac; echo OK"#,
+        )]);
+        let mut session = pinentry().exe(fake.exe()).connect().expect("session is started");
+
+        let choice = session
+            .prompt()
+            .description("Unlock with:")
+            .choose(&["Passphrase", "Keyfile", "Recovery key"])
+            .expect("choice is made");
+        assert_eq!(Some(1), choice);
+        assert_eq!(
+            vec![
+                "SETDESC Unlock with:%0A%0A1) Passphrase%0A2) Keyfile%0A3) Recovery key",
+                "SETPROMPT Choice (1-3):",
+                "GETPIN",
+                "RESET",
+                "SETDESC Unlock with:%0A%0A1) Passphrase%0A2) Keyfile%0A3) Recovery key",
+                "SETERROR Please enter a number between 1 and 3",
+                "SETPROMPT Choice (1-3):",
+                "GETPIN"
+            ],
+            fake.commands()
+        );
+
+        assert_eq!(None, session.choose(&["a", "b"]).expect("cancelling is not an error"));
+
+        // nothing is sent without options
+        let sent = fake.commands().len();
+        assert!(matches!(session.choose(&[]), Err(Error::InvalidConfiguration(_))));
+        assert_eq!(sent, fake.commands().len());
+    }
+
+    #[test]
+    fn test_session_prompt_validation() {
+        let fake = FakePinentry::new(&[]);
+        let mut session = pinentry().exe(fake.exe()).connect().expect("session is started");
+
+        let res = session.prompt().max_attempts(2).confirm_yes_no();
+        assert!(matches!(res, Err(Error::InvalidConfiguration(_))));
+        let res = session.prompt().normalization(Normalization::new()).show_message();
+        assert!(matches!(res, Err(Error::InvalidConfiguration(_))));
+        let res = session.prompt().quality_bar("Strength").pin("PIN:");
+        assert!(matches!(res, Err(Error::InvalidConfiguration(_))));
+        let res = session.prompt().keyinfo("n/0123ABCD").pin("PIN:");
+        assert!(matches!(res, Err(Error::InvalidConfiguration(_))));
+        // nothing is sent to pinentry
+        assert!(fake.commands().is_empty());
+
+        // nor is pinentry started
+        for builder in [
+            pinentry().exe(fake.exe()).quality_bar("Strength"),
+            pinentry().exe(fake.exe()).keyinfo("n/0123ABCD"),
+        ] {
+            assert!(matches!(builder.connect(), Err(Error::InvalidConfiguration(_))));
+        }
+        assert_eq!(1, fake.spawn_count());
+
+        // the session can provide what the prompt lacks
+        let mut session = pinentry()
+            .exe(fake.exe())
+            .quality_fn(|pin| pin.len() as i32)
+            .allow_external_cache(true)
+            .connect()
+            .expect("session is started");
+        let res = session
+            .prompt()
+            .quality_bar("Strength")
+            .keyinfo("n/0123ABCD")
+            .confirm_yes_no();
+        assert!(matches!(res, Err(Error::InvalidConfiguration(reason)) if reason.contains("PIN prompts")));
+    }
+
+    #[test]
+    fn test_session_respawn_replays_state() {
+        let fake = FakePinentry::new(&[("GETPIN", CRASH_ONCE)]);
+        let mut session = pinentry()
+            .exe(fake.exe())
+            .respawn(true)
+            .window_title("Session")
+            .connect()
+            .expect("session is started");
+        session
+            .set(AssuanCommand::SetDescriptiveText("first".to_string()))
+            .unwrap();
+        session
+            .set(AssuanCommand::SetDescriptiveText("second".to_string()))
+            .unwrap();
+
+        let pin = session.pin("PIN:").expect("PIN is returned after respawn");
+        assert_eq!("secret", str::from_utf8(pin.unsecure()).unwrap());
+        assert_eq!(2, fake.spawn_count());
+        assert_eq!(
+            vec![
+                "SETTITLE Session",
+                "SETDESC first",
+                "SETDESC second",
+                "SETPROMPT PIN:",
+                "GETPIN",
+                // state replayed to the new process
+                "SETTITLE Session",
+                "SETDESC second",
+                "SETPROMPT PIN:",
+                "GETPIN",
+            ],
+            fake.commands()
+        );
+    }
+
+    #[test]
+    fn test_session_prompt_overrides() {
+        let fake = FakePinentry::new(&[("GETPIN", r#"echo "D secret"; echo OK"#)]);
+        let mut session = pinentry()
+            .exe(fake.exe())
+            .window_title("App")
+            .label_ok("Unlock")
+            .connect()
+            .unwrap();
+
+        session
+            .prompt()
+            .description("Unlock volume A")
+            .label_ok("Open")
+            .pin("PIN:")
+            .unwrap();
+        // a plain prompt afterwards gets the defaults again
+        session.pin("PIN:").unwrap();
+        session.pin("PIN:").unwrap();
+
+        assert_eq!(
+            vec![
+                "SETOK Unlock",
+                "SETTITLE App",
+                "SETDESC Unlock volume A",
+                "SETOK Open",
+                "SETPROMPT PIN:",
+                "GETPIN",
+                "RESET",
+                "SETOK Unlock",
+                "SETTITLE App",
+                "SETPROMPT PIN:",
+                "GETPIN",
+                "SETPROMPT PIN:",
+                "GETPIN",
+            ],
+            fake.commands()
+        );
+    }
+
+    #[test]
+    fn test_session_set_updates_defaults() {
+        let fake = FakePinentry::new(&[]);
+        let mut session = pinentry().exe(fake.exe()).connect().unwrap();
+
+        session.set(AssuanCommand::SetWindowTitle("First".to_string())).unwrap();
+        session.prompt().description("Sure?").confirm_yes_no().unwrap();
+        session
+            .set(AssuanCommand::SetWindowTitle("Second".to_string()))
+            .unwrap();
+        session.confirm_yes_no().unwrap();
+
+        assert_eq!(
+            vec![
+                "SETTITLE First",
+                "SETDESC Sure?",
+                "CONFIRM",
+                "SETTITLE Second",
+                "RESET",
+                "SETTITLE Second",
+                "CONFIRM",
+            ],
+            fake.commands()
+        );
+    }
+
+    #[test]
+    fn test_session_respawn_failure() {
+        let fake = FakePinentry::new(&[("GETPIN", "exit 1")]);
+        let mut session = pinentry().exe(fake.exe()).respawn(true).connect().unwrap();
+
+        match session.pin("PIN:") {
+            Err(Error::RecoveryFailed(cause)) => assert!(is_disconnect(&cause)),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_session_no_respawn() {
+        let fake = FakePinentry::new(&[("GETPIN", CRASH_ONCE)]);
+        let mut session = pinentry().exe(fake.exe()).connect().unwrap();
+
+        match session.pin("PIN:") {
+            Err(ref e) if is_disconnect(e) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert_eq!(1, fake.spawn_count());
+    }
+
+    #[test]
+    fn test_session_set_rejects_terminal_commands() {
+        let fake = FakePinentry::new(&[]);
+        let mut session = pinentry().exe(fake.exe()).connect().unwrap();
+        assert!(session.set(AssuanCommand::GetPin).is_err());
+    }
+
+    #[test]
+    fn test_session_rate_limit() {
+        use std::time::Duration;
+
+        let fake = FakePinentry::new(&[("GETPIN", r#"echo "D secret"; echo OK"#)]);
+        let limit = RateLimit::new(2, Duration::from_secs(60));
+        let mut session = pinentry().exe(fake.exe()).rate_limit(limit.clone()).connect().unwrap();
+
+        session
+            .set(AssuanCommand::SetWindowTitle("Limited".to_string()))
+            .unwrap();
+        session.pin("PIN:").unwrap();
+        session.confirm_yes_no().unwrap();
+        match session.pin("PIN:") {
+            Err(Error::RateLimited(retry_after)) => assert!(retry_after > Duration::from_secs(50)),
+            x => panic!("unexpected result {:?}", x),
+        }
+        // the refused prompt was not sent
+        assert_eq!(1, fake.commands().iter().filter(|c| *c == "GETPIN").count());
+        assert!(limit.retry_after() > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_session_policy() {
+        use super::super::policy::{Decision, DialogKind, PromptRequest};
+
+        let fake = FakePinentry::new(&[("GETPIN", r#"echo "D typed"; echo OK"#)]);
+        let mut session = pinentry()
+            .exe(fake.exe())
+            .policy(|request: &PromptRequest| match request.kind {
+                DialogKind::Pin if request.prompt.as_deref() == Some("Token:") => {
+                    Decision::Pin(SecretPin::from("from-store"))
+                }
+                DialogKind::Pin => Decision::Allow,
+                _ => Decision::Deny("no confirmations".to_string()),
+            })
+            .connect()
+            .unwrap();
+
+        assert_eq!(b"from-store", session.pin("Token:").unwrap().unsecure());
+        assert_eq!(b"typed", session.pin("PIN:").unwrap().unsecure());
+        assert!(matches!(session.confirm_yes_no(), Err(Error::PolicyDenied(_))));
+        // only the allowed prompt reached pinentry
+        assert_eq!(vec!["SETPROMPT PIN:", "GETPIN"], fake.commands());
+    }
+
+    #[test]
+    fn test_session_command_filter() {
+        let fake = FakePinentry::new(&[("GETPIN", r#"echo "S PASSWORD_FROM_CACHE"; echo "D cached"; echo OK"#)]);
+        let mut session = pinentry()
+            .exe(fake.exe())
+            .command_filter(CommandFilter::new().allow_statuses(["PINENTRY_LAUNCHED"]))
+            .connect()
+            .unwrap();
+
+        // the PIN came from the password cache, not from the user
+        assert!(matches!(session.pin("PIN:"), Err(Error::PolicyDenied(_))));
+
+        session.set_command_filter(CommandFilter::new().allow_options(["ttyname"]));
+        let putenv = AssuanCommand::Option("putenv".to_string(), Some("LD_PRELOAD=x".to_string()));
+        assert!(matches!(session.set(putenv), Err(Error::PolicyDenied(_))));
+        assert!(!fake.commands().iter().any(|c| c.starts_with("OPTION")));
+        assert_eq!(b"cached", session.pin("PIN:").unwrap().unsecure());
+    }
+
+    #[test]
+    fn test_session_raw_command() {
+        let fake = FakePinentry::new(&[
+            (
+                r#""GETINFO frob""#,
+                r#"echo "S FROB_LEVEL 2"; echo "D fr%25"; echo "D ob"; echo OK"#,
+            ),
+            (
+                "FROB*",
+                r#"echo "ERR 536871187 Unknown IPC command <User defined source 1>""#,
+            ),
+            ("GETPIN", r#"echo "D secret"; echo OK"#),
+        ]);
+        let mut session = pinentry().exe(fake.exe()).connect().expect("session is started");
+
+        let res = session.raw_command("GETINFO frob").unwrap();
+        assert!(res.is_ok());
+        assert_eq!(b"fr%ob", res.data.unwrap().unsecure());
+        assert_eq!("FROB_LEVEL", res.statuses[0].keyword);
+        assert_eq!(Some("2"), res.statuses[0].info.as_deref());
+
+        let res = session.raw_command("OPTION no-grab").unwrap();
+        assert!(res.is_ok() && res.data.is_none() && res.statuses.is_empty());
+        let res = session.raw_command("FROB 1").unwrap();
+        assert_eq!(AssuanError::ASS_UNKNOWN_CMD, res.error.unwrap().error_code());
+        assert!(matches!(
+            session.raw_command("GETPIN\nBYE"),
+            Err(Error::InvalidConfiguration(_))
+        ));
+        // the session carries on
+        assert_eq!(b"secret", session.pin("PIN:").unwrap().unsecure());
+    }
+
+    #[test]
+    fn test_session_raw_command_filtered() {
+        let fake = FakePinentry::new(&[]);
+        let mut session = pinentry()
+            .exe(fake.exe())
+            .command_filter(CommandFilter::new().allow_options(["ttyname"]))
+            .connect()
+            .expect("session is started");
+        assert!(matches!(
+            session.raw_command("option no-grab"),
+            Err(Error::PolicyDenied(_))
+        ));
+        assert!(session.raw_command("OPTION ttyname=/dev/pts/1").unwrap().is_ok());
+        assert_eq!(vec!["OPTION ttyname=/dev/pts/1"], fake.commands());
+    }
+}
@@ -0,0 +1,222 @@
+//! At most one prompt per key at a time
+//!
+//! When several threads need the same secret (e.g. the password of one account, or the passphrase of one volume
+//! identified by its UUID), only one of them should ask for it. A [`SingleFlight`] shared between them runs the prompt
+//! of the first caller for a key; callers with the same key arriving while it is open wait for it and receive the
+//! same outcome instead of opening dialogs of their own:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # #[cfg(feature = "process")]
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use std::sync::Arc;
+//! use std::thread;
+//!
+//! use pinentry_rs::pinentry;
+//! use pinentry_rs::single_flight::SingleFlight;
+//!
+//! let flights = Arc::new(SingleFlight::new());
+//! let workers: Vec<_> = (0..4)
+//!     .map(|_| {
+//!         let flights = flights.clone();
+//!         thread::spawn(move || {
+//!             flights.run("alice@example.com", || {
+//!                 pinentry()
+//!                     .description("Password for alice@example.com")
+//!                     .pin("Password:")
+//!             })
+//!         })
+//!     })
+//!     .collect();
+//! for worker in workers {
+//!     // one dialog, four passwords
+//!     worker.join().unwrap()?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Outcomes are not remembered: once a prompt has completed, the next caller for its key prompts again.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+
+use super::{Error, Result, SecretPin};
+
+/// Runs at most one prompt per key at a time, see the [module documentation](self)
+pub struct SingleFlight<K, T = SecretPin> {
+    flights: Mutex<HashMap<K, Arc<Flight<T>>>>,
+}
+
+/// A prompt in flight
+struct Flight<T> {
+    outcome: Mutex<Option<Result<T>>>,
+    done: Condvar,
+}
+
+impl<K: Eq + Hash + Clone, T: Clone> SingleFlight<K, T> {
+    /// No prompts in flight
+    pub fn new() -> Self {
+        SingleFlight {
+            flights: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Run `prompt` for `key`, or wait for the prompt already running for it and return its outcome
+    ///
+    /// Waiters receive a copy of the error if the prompt failed (I/O errors are copied by kind and message).
+    pub fn run<F>(&self, key: K, prompt: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        let (flight, leader) = {
+            let mut flights = self.flights.lock().expect("flights lock is not poisoned");
+            match flights.get(&key) {
+                Some(flight) => (flight.clone(), false),
+                None => {
+                    let flight = Arc::new(Flight {
+                        outcome: Mutex::new(None),
+                        done: Condvar::new(),
+                    });
+                    flights.insert(key.clone(), flight.clone());
+                    (flight, true)
+                }
+            }
+        };
+
+        if !leader {
+            debug!("waiting for the prompt already in flight");
+            let mut outcome = flight.outcome.lock().expect("flight lock is not poisoned");
+            while outcome.is_none() {
+                outcome = flight.done.wait(outcome).expect("flight lock is not poisoned");
+            }
+            return share(outcome.as_ref().expect("BUG: flight completed without an outcome"));
+        }
+
+        let mut landing = Landing {
+            flights: &self.flights,
+            key: Some(key),
+            flight: &flight,
+        };
+        let res = prompt();
+        landing.complete(share(&res));
+        res
+    }
+
+    /// Whether a prompt is running for `key`
+    pub fn in_flight(&self, key: &K) -> bool {
+        self.flights
+            .lock()
+            .expect("flights lock is not poisoned")
+            .contains_key(key)
+    }
+}
+
+impl<K: Eq + Hash + Clone, T: Clone> Default for SingleFlight<K, T> {
+    fn default() -> Self {
+        SingleFlight::new()
+    }
+}
+
+/// Hands the outcome of the leader to the waiters - or an error, if the prompt panicked
+struct Landing<'a, K: Eq + Hash, T> {
+    flights: &'a Mutex<HashMap<K, Arc<Flight<T>>>>,
+    key: Option<K>,
+    flight: &'a Flight<T>,
+}
+
+impl<K: Eq + Hash, T> Landing<'_, K, T> {
+    fn complete(&mut self, outcome: Result<T>) {
+        let key = match self.key.take() {
+            Some(key) => key,
+            None => return,
+        };
+        // new callers start a new prompt from here on
+        if let Ok(mut flights) = self.flights.lock() {
+            flights.remove(&key);
+        }
+        if let Ok(mut slot) = self.flight.outcome.lock() {
+            *slot = Some(outcome);
+        }
+        self.flight.done.notify_all();
+    }
+}
+
+impl<K: Eq + Hash, T> Drop for Landing<'_, K, T> {
+    fn drop(&mut self) {
+        self.complete(Err(Error::IoError(io::Error::other("the prompt in flight panicked"))));
+    }
+}
+
+/// A copy of `res` for another caller
+fn share<T: Clone>(res: &Result<T>) -> Result<T> {
+    match res {
+        Ok(value) => Ok(value.clone()),
+        Err(e) => Err(share_error(e)),
+    }
+}
+
+fn share_error(e: &Error) -> Error {
+    match e {
+        Error::IoError(ref cause) => Error::IoError(io::Error::new(cause.kind(), cause.to_string())),
+        Error::ProtocolError(ref cause) => Error::ProtocolError(cause.clone()),
+        Error::RecoveryFailed(ref cause) => Error::RecoveryFailed(Box::new(share_error(cause))),
+        Error::KdfError(ref cause) => Error::KdfError(cause.clone()),
+        Error::InvalidConfiguration(ref cause) => Error::InvalidConfiguration(cause.clone()),
+        Error::CacheError(ref cause) => Error::CacheError(cause.clone()),
+        Error::AgentError(ref cause) => Error::AgentError(cause.clone()),
+        Error::PolicyDenied(ref reason) => Error::PolicyDenied(reason.clone()),
+        Error::RateLimited(retry_after) => Error::RateLimited(*retry_after),
+        Error::Cancelled => Error::Cancelled,
+        Error::Timeout => Error::Timeout,
+        Error::NotConfirmed => Error::NotConfirmed,
+        Error::PinentryError(ref cause) => Error::PinentryError(cause.clone()),
+        Error::Aborted => Error::Aborted,
+        Error::UnexpectedResponse(ref response) => Error::UnexpectedResponse(response.clone()),
+        Error::MaxAttemptsExceeded(attempts) => Error::MaxAttemptsExceeded(*attempts),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_single_flight() {
+        let flights = Arc::new(SingleFlight::<&str, String>::new());
+        let prompts = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(4));
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let (flights, prompts, barrier) = (flights.clone(), prompts.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    flights.run("account", || {
+                        prompts.fetch_add(1, Ordering::SeqCst);
+                        // keep the prompt open until the others are waiting for it
+                        thread::sleep(Duration::from_millis(200));
+                        Ok("secret".to_string())
+                    })
+                })
+            })
+            .collect();
+        for worker in workers {
+            assert_eq!("secret", worker.join().unwrap().unwrap());
+        }
+        assert_eq!(1, prompts.load(Ordering::SeqCst));
+        assert!(!flights.in_flight(&"account"));
+
+        // errors are shared too, and nothing is remembered
+        let res = flights.run("account", || Err(Error::ProtocolError("cancelled".to_string())));
+        assert!(matches!(res, Err(Error::ProtocolError(_))));
+        assert_eq!("again", flights.run("account", || Ok("again".to_string())).unwrap());
+    }
+}
@@ -0,0 +1,187 @@
+//! Prompts for tools that manage SSH keys (e.g. on behalf of `ssh-agent`)
+//!
+//! [`SshKey`] renders the texts OpenSSH users know: the passphrase prompt of `ssh-add`, and the confirmation asked
+//! for each use of a key added with `ssh-add -c`:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # #[cfg(feature = "process")]
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use pinentry_rs::pinentry;
+//! use pinentry_rs::ssh::SshKey;
+//!
+//! let key = SshKey::new("/home/user/.ssh/id_ed25519")
+//!     .fingerprint("SHA256:p2Y8Q5mC3v3WQ3QbGWVNPoh4pZuVJh0xZ0cE3eXWt4s");
+//! let mut session = pinentry().connect()?;
+//! let passphrase = session.ssh_passphrase(&key)?;
+//! // ... decrypt the key and add it to the agent
+//! if session.ssh_confirm(&key)? {
+//!     // sign
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use super::messages::Message;
+use super::session::{PinentrySession, SessionPrompt};
+#[cfg(feature = "process")]
+use super::{PinentryBuilder, PromptKind};
+use super::{Result, SecretPin};
+
+/// An SSH key, as shown in prompts
+#[derive(Debug, Clone)]
+pub struct SshKey {
+    path: PathBuf,
+    fingerprint: Option<String>,
+    comment: Option<String>,
+}
+
+impl SshKey {
+    /// The key stored at `path`
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        SshKey {
+            path: path.as_ref().to_path_buf(),
+            fingerprint: None,
+            comment: None,
+        }
+    }
+
+    /// Set the fingerprint of the key, as shown by `ssh-keygen -l` (e.g. `SHA256:...`)
+    pub fn fingerprint<S: Into<String>>(mut self, fingerprint: S) -> Self {
+        self.fingerprint = Some(fingerprint.into());
+        self
+    }
+
+    /// Set the comment of the key (e.g. `user@host`) - `ssh-agent` identifies keys by their comment when asking for
+    /// confirmation
+    pub fn comment<S: Into<String>>(mut self, comment: S) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    /// `Enter passphrase for /path/id_ed25519 (SHA256:...)`
+    pub fn passphrase_text(&self) -> String {
+        match self.fingerprint {
+            Some(ref fingerprint) => Message::SshPassphraseFingerprint {
+                path: &self.path.display().to_string(),
+                fingerprint,
+            }
+            .text(),
+            None => Message::SshPassphrase {
+                path: &self.path.display().to_string(),
+            }
+            .text(),
+        }
+    }
+
+    /// `Allow use of key user@host?` and the fingerprint, as asked by `ssh-agent` for keys added with `ssh-add -c`
+    pub fn confirm_text(&self) -> String {
+        let name = match self.comment {
+            Some(ref comment) => comment.clone(),
+            None => self.path.display().to_string(),
+        };
+        match self.fingerprint {
+            Some(ref fingerprint) => format!(
+                "{}\n{}",
+                Message::SshConfirm { key: &name },
+                Message::SshFingerprint { fingerprint }
+            ),
+            None => Message::SshConfirm { key: &name }.text(),
+        }
+    }
+}
+
+impl PinentrySession {
+    /// Ask for the passphrase of an SSH key
+    ///
+    /// See [`SessionPrompt::ssh_passphrase`].
+    pub fn ssh_passphrase(&mut self, key: &SshKey) -> Result<SecretPin> {
+        self.prompt().ssh_passphrase(key)
+    }
+
+    /// Ask whether an SSH key may be used
+    ///
+    /// See [`SessionPrompt::ssh_confirm`].
+    pub fn ssh_confirm(&mut self, key: &SshKey) -> Result<bool> {
+        self.prompt().ssh_confirm(key)
+    }
+}
+
+impl SessionPrompt<'_> {
+    /// Ask for the passphrase of an SSH key, with [`SshKey::passphrase_text`] as the description
+    pub fn ssh_passphrase(self, key: &SshKey) -> Result<SecretPin> {
+        self.description(key.passphrase_text())
+            .pin(Message::PassphrasePrompt.text())
+    }
+
+    /// Ask whether an SSH key may be used (with [`SshKey::confirm_text`] as the description), like `ssh-agent` does
+    /// for keys added with `ssh-add -c`
+    ///
+    /// Returns `false` if the use is denied or the prompt is cancelled.
+    pub fn ssh_confirm(self, key: &SshKey) -> Result<bool> {
+        self.description(key.confirm_text()).confirm_yes_no()
+    }
+}
+
+#[cfg(feature = "process")]
+impl PinentryBuilder {
+    /// Ask for the passphrase of an SSH key
+    ///
+    /// See [`SessionPrompt::ssh_passphrase`].
+    pub fn ssh_passphrase(self, key: &SshKey) -> Result<SecretPin> {
+        self.settings.validate(Some(PromptKind::Pin))?;
+        self.connect()?.ssh_passphrase(key)
+    }
+
+    /// Ask whether an SSH key may be used
+    ///
+    /// See [`SessionPrompt::ssh_confirm`].
+    pub fn ssh_confirm(self, key: &SshKey) -> Result<bool> {
+        self.settings.validate(Some(PromptKind::Confirm))?;
+        self.connect()?.ssh_confirm(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_texts() {
+        let key = SshKey::new("/home/user/.ssh/id_ed25519");
+        assert_eq!("Enter passphrase for /home/user/.ssh/id_ed25519", key.passphrase_text());
+        assert_eq!("Allow use of key /home/user/.ssh/id_ed25519?", key.confirm_text());
+
+        let key = key.fingerprint("SHA256:abc").comment("user@host");
+        assert_eq!(
+            "Enter passphrase for /home/user/.ssh/id_ed25519 (SHA256:abc)",
+            key.passphrase_text()
+        );
+        assert_eq!(
+            "Allow use of key user@host?\nKey fingerprint SHA256:abc.",
+            key.confirm_text()
+        );
+    }
+
+    #[cfg(all(unix, feature = "process"))]
+    #[test]
+    fn test_ssh_prompts() {
+        use super::super::pinentry;
+        use super::super::test_util::FakePinentry;
+
+        let fake = FakePinentry::new(&[
+            ("GETPIN", r#"echo "D secret"; echo OK"#),
+            ("CONFIRM", r#"echo "ERR 83886194 Not confirmed <Pinentry>""#),
+        ]);
+        let key = SshKey::new("/keys/id_rsa").fingerprint("SHA256:abc");
+        let mut session = pinentry().exe(fake.exe()).connect().unwrap();
+        assert_eq!(SecretPin::from("secret"), session.ssh_passphrase(&key).unwrap());
+        assert!(!session.ssh_confirm(&key).unwrap());
+
+        let commands = fake.commands();
+        assert!(commands.contains(&"SETDESC Enter passphrase for /keys/id_rsa (SHA256:abc)".to_string()));
+        assert!(commands.contains(&"SETDESC Allow use of key /keys/id_rsa?%0AKey fingerprint SHA256:abc.".to_string()));
+    }
+}
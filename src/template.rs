@@ -0,0 +1,147 @@
+//! Descriptions with safely substituted values
+//!
+//! Descriptions often mention things that come from elsewhere - a key name, a host, a file name - which may be
+//! controlled by someone else. Formatting them straight into the description lets such values add line breaks, push
+//! the real text out of sight with their length, or reorder the text with bidirectional overrides. A [`Template`] substitutes values after sanitizing them:
+//!
+//! ```
+//! # extern crate pinentry_rs;
+//! use pinentry_rs::template::Template;
+//!
+//! let description = Template::new("Unlock key {key} on {host}")
+//!     .value("key", "deploy\n\nEverything is fine")
+//!     .value("host", "build-01")
+//!     .render()
+//!     .unwrap();
+//! assert_eq!("Unlock key deploy  Everything is fine on build-01", description);
+//! ```
+//!
+//! Values are stripped of control and bidirectional formatting characters (line breaks and tabs become spaces),
+//! shortened to 64 characters (see [`Template::max_value_length`]). `{{` and `}}` stand for literal braces.
+
+use super::{invalid, Result};
+
+/// Default maximum length of a substituted value, in characters
+pub const DEFAULT_MAX_VALUE_LENGTH: usize = 64;
+
+/// A description template with `{name}` placeholders, see the [module documentation](self)
+#[derive(Debug, Clone)]
+pub struct Template<'a> {
+    template: &'a str,
+    values: Vec<(&'a str, String)>,
+    max_value_length: usize,
+}
+
+impl<'a> Template<'a> {
+    /// A template with `{name}` placeholders
+    pub fn new(template: &'a str) -> Self {
+        Template {
+            template,
+            values: Vec::new(),
+            max_value_length: DEFAULT_MAX_VALUE_LENGTH,
+        }
+    }
+
+    /// Substitute `value` for the placeholder `{name}`
+    pub fn value<V: AsRef<str>>(mut self, name: &'a str, value: V) -> Self {
+        self.values.push((name, value.as_ref().to_string()));
+        self
+    }
+
+    /// Shorten values to at most `chars` characters (ending them with `…`)
+    pub fn max_value_length(mut self, chars: usize) -> Self {
+        self.max_value_length = chars;
+        self
+    }
+
+    /// Fill in the placeholders - fails with [`Error::InvalidConfiguration`](super::Error::InvalidConfiguration) if
+    /// the template is malformed or a placeholder has no value
+    pub fn render(&self) -> Result<String> {
+        let mut out = String::new();
+        let mut rest = self.template;
+        while let Some(i) = rest.find(['{', '}']) {
+            out.push_str(&rest[..i]);
+            let brace = &rest[i..i + 1];
+            rest = &rest[i + 1..];
+            if let Some(after) = rest.strip_prefix(brace) {
+                out.push_str(brace);
+                rest = after;
+                continue;
+            }
+            if brace == "}" {
+                return Err(invalid("unmatched '}' in the template"));
+            }
+            let end = rest
+                .find('}')
+                .ok_or_else(|| invalid("unterminated placeholder in the template"))?;
+            let name = &rest[..end];
+            let value = self
+                .values
+                .iter()
+                .rev()
+                .find(|(n, _)| *n == name)
+                .map(|(_, value)| value)
+                .ok_or_else(|| invalid(&format!("no value for the placeholder {{{}}}", name)))?;
+            out.push_str(&sanitize(value, self.max_value_length));
+            rest = &rest[end + 1..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+}
+
+/// `value` without control and bidirectional formatting characters, shortened to `max_chars`
+pub fn sanitize(value: &str, max_chars: usize) -> String {
+    let cleaned: Vec<char> = value
+        .chars()
+        .filter_map(|c| match c {
+            '\t' | '\n' | '\r' | '\u{85}' | '\u{2028}' | '\u{2029}' => Some(' '),
+            _ if c.is_control() || is_bidi_control(c) => None,
+            _ => Some(c),
+        })
+        .collect();
+
+    let mut out = String::new();
+    let shortened = cleaned.len() > max_chars;
+    let keep = if shortened {
+        max_chars.saturating_sub(1)
+    } else {
+        cleaned.len()
+    };
+    out.extend(&cleaned[..keep]);
+    if shortened {
+        out.push('…');
+    }
+    out
+}
+
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{061C}' | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let rendered = Template::new("{{literal}} {a} and {b}")
+            .value("a", "one")
+            .value("b", "line\r\nbreak\u{202E}")
+            .render()
+            .unwrap();
+        assert_eq!("{literal} one and line  break", rendered);
+
+        assert_eq!(
+            "abc…",
+            Template::new("{v}")
+                .value("v", "abcdef")
+                .max_value_length(4)
+                .render()
+                .unwrap()
+        );
+        assert!(Template::new("{missing}").render().is_err());
+        assert!(Template::new("{open").value("open", "x").render().is_err());
+        assert!(Template::new("close}").render().is_err());
+    }
+}
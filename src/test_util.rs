@@ -0,0 +1,74 @@
+//! Helpers for tests that need a pinentry process
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A fake pinentry (a shell script) that answers commands with canned responses
+///
+/// Each handler is a `case` pattern (e.g. `GETPIN` or `SETDESC*`) and the shell code run when a command matches;
+/// all other commands are answered with `OK`. The directory the script lives in is available as `$DIR`.
+pub struct FakePinentry {
+    dir: PathBuf,
+}
+
+impl FakePinentry {
+    pub fn new(handlers: &[(&str, &str)]) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "pinentry-rs-test-{}-{}",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&dir).expect("temporary directory can be created");
+
+        let mut script = format!(
+            "#!/bin/sh\nDIR='{}'\necho spawned >> \"$DIR/spawns.log\"\necho \"OK Pleased to meet you\"\n",
+            dir.display()
+        );
+        script
+            .push_str("while IFS= read -r line; do\n  echo \"$line\" >> \"$DIR/commands.log\"\n  case \"$line\" in\n");
+        for (pattern, action) in handlers {
+            script.push_str(&format!("    {}) {} ;;\n", pattern, action));
+        }
+        script.push_str("    BYE) echo \"OK closing connection\"; exit 0 ;;\n    *) echo OK ;;\n  esac\ndone\n");
+
+        let exe = dir.join("pinentry");
+        fs::write(&exe, script).expect("fake pinentry can be written");
+        fs::set_permissions(&exe, fs::Permissions::from_mode(0o755)).expect("fake pinentry can be made executable");
+
+        FakePinentry { dir }
+    }
+
+    /// Path to the fake pinentry executable
+    pub fn exe(&self) -> String {
+        self.dir.join("pinentry").display().to_string()
+    }
+
+    /// Commands received so far (by all processes)
+    pub fn commands(&self) -> Vec<String> {
+        read_lines(self.dir.join("commands.log"))
+    }
+
+    /// Number of times the fake pinentry has been started
+    pub fn spawn_count(&self) -> usize {
+        read_lines(self.dir.join("spawns.log")).len()
+    }
+}
+
+impl Drop for FakePinentry {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn read_lines(path: PathBuf) -> Vec<String> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .map(|s| s.to_string())
+        .collect()
+}
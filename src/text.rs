@@ -0,0 +1,109 @@
+//! Shortening texts by their display width
+//!
+//! Protocol lines are limited to [`MAX_LINE_LENGTH`] bytes, and window titles much longer than a screen is wide are
+//! not useful either. Cutting such texts by bytes can split a character (or separate an accent from its letter), and
+//! counting characters misjudges CJK and emoji, which take two columns each. [`ellipsize`] and [`ellipsize_bytes`]
+//! cut on character boundaries, keep combining marks with their base character, and end the text with `…`.
+//!
+//! With the `unicode-width` feature enabled, the texts of all commands are shortened like this before they are sent
+//! (titles to [`TITLE_WIDTH`] columns, everything else to fit the line limit), instead of being rejected as too long.
+
+use std::borrow::Cow;
+
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use super::assuan::MAX_LINE_LENGTH;
+
+/// Maximum width of window titles, in columns
+pub const TITLE_WIDTH: usize = 80;
+
+const ELLIPSIS: char = '…';
+
+/// Width of `text` in columns, as displayed in a terminal
+pub fn display_width(text: &str) -> usize {
+    text.width()
+}
+
+/// Shorten `text` to at most `max_width` columns, ending it with `…` if it had to be cut
+pub fn ellipsize(text: &str, max_width: usize) -> Cow<'_, str> {
+    if text.width() <= max_width {
+        return Cow::Borrowed(text);
+    }
+    let budget = max_width.saturating_sub(ELLIPSIS.width().unwrap_or(1));
+    let mut width = 0;
+    let mut end = 0;
+    for (i, c) in text.char_indices() {
+        let w = c.width().unwrap_or(0);
+        if width + w > budget {
+            break;
+        }
+        width += w;
+        end = i + c.len_utf8();
+    }
+    let mut shortened = text[..end].to_string();
+    shortened.push(ELLIPSIS);
+    Cow::Owned(shortened)
+}
+
+/// Shorten `text` to at most `max_bytes` bytes (UTF-8), ending it with `…` if it had to be cut
+pub fn ellipsize_bytes(text: &str, max_bytes: usize) -> Cow<'_, str> {
+    if text.len() <= max_bytes {
+        return Cow::Borrowed(text);
+    }
+    let budget = max_bytes.saturating_sub(ELLIPSIS.len_utf8());
+    let mut end = (0..=budget).rev().find(|i| text.is_char_boundary(*i)).unwrap_or(0);
+    // a combining mark right after the cut belongs to the character before it
+    while end > 0 && text[end..].chars().next().is_some_and(|c| c.width() == Some(0)) {
+        end = text[..end].char_indices().next_back().map_or(0, |(i, _)| i);
+    }
+    let mut shortened = text[..end].to_string();
+    shortened.push(ELLIPSIS);
+    Cow::Owned(shortened)
+}
+
+/// Shorten the parameter of the command `verb` so that its line fits the line limit (and titles [`TITLE_WIDTH`])
+pub(crate) fn fit_command(verb: &str, text: &str) -> String {
+    let text = match verb {
+        "SETTITLE" => ellipsize(text, TITLE_WIDTH),
+        _ => Cow::Borrowed(text),
+    };
+    // "<verb> <text>" has to stay below the limit once `%`, CR and LF are escaped (taking 3 bytes each)
+    let escaped = text.matches(['%', '\r', '\n']).count();
+    let budget = (MAX_LINE_LENGTH - verb.len() - 2).saturating_sub(2 * escaped);
+    ellipsize_bytes(&text, budget).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ellipsize() {
+        assert_eq!("short", ellipsize("short", 10));
+        assert_eq!("long t…", ellipsize("long text", 7));
+        // CJK characters take two columns each
+        assert_eq!(8, display_width("日本語の"));
+        assert_eq!("日本語…", ellipsize("日本語のテキスト", 7));
+        assert_eq!("日本…", ellipsize("日本語のテキスト", 6));
+        // combining marks stay with their letter
+        assert_eq!("ae\u{301}…", ellipsize("ae\u{301}e\u{301}e\u{301}", 3));
+    }
+
+    #[test]
+    fn test_ellipsize_bytes() {
+        assert_eq!("short", ellipsize_bytes("short", 5));
+        // "日" takes 3 bytes, so 8 bytes leave room for one of them and the ellipsis
+        assert_eq!("日…", ellipsize_bytes("日本語", 8));
+        assert_eq!("a…", ellipsize_bytes("ae\u{301}bc", 5));
+        assert!(ellipsize_bytes("ab", 1).len() <= 3);
+    }
+
+    #[test]
+    fn test_fit_command() {
+        let description = "説明".repeat(400);
+        let fitted = fit_command("SETDESC", &description);
+        assert!("SETDESC ".len() + fitted.len() < MAX_LINE_LENGTH);
+        assert!(fitted.ends_with('…'));
+        assert_eq!(TITLE_WIDTH, display_width(&fit_command("SETTITLE", &"x".repeat(200))));
+    }
+}
@@ -0,0 +1,265 @@
+//! PIN prompts for hardware tokens (smartcards, PIV and OpenPGP cards, FIDO2 security keys)
+//!
+//! Token PINs differ from passphrases: the token only allows a few wrong attempts before it locks itself, so the
+//! user should see how many are left, and a PIN of the wrong form should be caught before it costs an attempt.
+//! [`TokenPin`] describes such a prompt:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # #[cfg(feature = "process")]
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use pinentry_rs::pinentry;
+//! use pinentry_rs::token::{TokenPin, TokenPinOutcome};
+//!
+//! let flow = TokenPin::new()
+//!     .description("Please enter the PIN of your YubiKey")
+//!     .retries(3)
+//!     .length(6, 8)
+//!     .touch("Use _touch instead");
+//! match pinentry().token_pin(flow)? {
+//!     TokenPinOutcome::Entered(pin) => { /* VERIFY the PIN */ }
+//!     TokenPinOutcome::Touch => { /* wait for a touch */ }
+//!     TokenPinOutcome::Cancelled => (),
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use super::messages::Message;
+use super::session::{set_error_text, PinentrySession, SessionPrompt};
+#[cfg(feature = "process")]
+use super::PinentryBuilder;
+use super::{invalid, Error, PromptKind, Result, SecretPin};
+
+/// A PIN prompt for a hardware token, see the [module documentation](self)
+#[derive(Debug, Clone)]
+pub struct TokenPin {
+    description: String,
+    prompt: String,
+    retries: Option<u32>,
+    numeric: bool,
+    min_length: usize,
+    max_length: Option<usize>,
+    touch: Option<String>,
+}
+
+impl Default for TokenPin {
+    fn default() -> Self {
+        TokenPin {
+            description: Message::TokenDescription.text(),
+            prompt: "PIN:".to_string(),
+            retries: None,
+            numeric: true,
+            min_length: 4,
+            max_length: None,
+            touch: None,
+        }
+    }
+}
+
+impl TokenPin {
+    /// A numeric PIN of at least 4 digits, without a retry counter or touch option
+    pub fn new() -> Self {
+        TokenPin::default()
+    }
+
+    /// Set the descriptive text (the retry counter is shown below it)
+    pub fn description<S: Into<String>>(mut self, desc: S) -> Self {
+        self.description = desc.into();
+        self
+    }
+
+    /// Set the prompt of the PIN entry
+    pub fn prompt<S: Into<String>>(mut self, prompt: S) -> Self {
+        self.prompt = prompt.into();
+        self
+    }
+
+    /// Show the number of attempts the token has left (e.g. from the retry counter of a PIV or OpenPGP card)
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = Some(retries);
+        self
+    }
+
+    /// Whether the PIN may only contain digits (the default) - FIDO2 PINs, for example, may contain any character
+    pub fn numeric(mut self, numeric: bool) -> Self {
+        self.numeric = numeric;
+        self
+    }
+
+    /// Require the PIN to have between `min` and `max` characters (e.g. 6 and 8 for PIV)
+    pub fn length(mut self, min: usize, max: usize) -> Self {
+        self.min_length = min;
+        self.max_length = Some(max);
+        self
+    }
+
+    /// Offer to use touch instead of the PIN (on the 'Not OK' button, with the given label)
+    pub fn touch<S: Into<String>>(mut self, label: S) -> Self {
+        self.touch = Some(label.into());
+        self
+    }
+
+    /// The description including the retry counter
+    fn full_description(&self) -> String {
+        match self.retries {
+            Some(attempts) => format!("{}\n\n{}", self.description, Message::TokenLockout { attempts }),
+            None => self.description.clone(),
+        }
+    }
+
+    /// The error text to re-prompt with, if `pin` does not have the required form
+    fn check(&self, pin: &[u8]) -> Option<String> {
+        let numeric = self.numeric;
+        if numeric && !pin.iter().all(u8::is_ascii_digit) {
+            return Some(Message::TokenDigitsOnly.text());
+        }
+        let len = match std::str::from_utf8(pin) {
+            Ok(s) => s.chars().count(),
+            Err(_) => pin.len(),
+        };
+        match self.max_length {
+            Some(max) if len < self.min_length || len > max => Some(if self.min_length == max {
+                Message::TokenLengthExact { length: max, numeric }.text()
+            } else {
+                Message::TokenLengthRange {
+                    min: self.min_length,
+                    max,
+                    numeric,
+                }
+                .text()
+            }),
+            None if len < self.min_length => Some(
+                Message::TokenLengthMin {
+                    min: self.min_length,
+                    numeric,
+                }
+                .text(),
+            ),
+            _ => None,
+        }
+    }
+}
+
+/// Outcome of a [`TokenPin`] prompt
+#[derive(Debug)]
+pub enum TokenPinOutcome {
+    /// A PIN of the required form was entered
+    Entered(SecretPin),
+    /// The user chose to use touch instead (only offered with [`TokenPin::touch`])
+    Touch,
+    /// The prompt was cancelled
+    Cancelled,
+}
+
+impl PinentrySession {
+    /// Ask for the PIN of a hardware token, following `flow`
+    ///
+    /// See [`SessionPrompt::token_pin`].
+    pub fn token_pin(&mut self, flow: TokenPin) -> Result<TokenPinOutcome> {
+        self.prompt().token_pin(flow)
+    }
+}
+
+impl SessionPrompt<'_> {
+    /// Ask for the PIN of a hardware token, following `flow`
+    ///
+    /// The description of `flow` replaces the one of this prompt, the other settings apply. A PIN that does not have
+    /// the required form is not returned (so it does not use up an attempt) - the prompt is repeated with an error
+    /// text instead. Fails with [`Error::InvalidConfiguration`] if the token has no attempts left.
+    pub fn token_pin(mut self, flow: TokenPin) -> Result<TokenPinOutcome> {
+        if flow.retries == Some(0) {
+            return Err(invalid("the PIN is blocked: no attempts remaining"));
+        }
+        self.settings.description = Some(flow.full_description());
+        if let Some(ref label) = flow.touch {
+            self.settings.label_notok = Some(label.clone());
+        }
+        self.validate(PromptKind::Pin)?;
+        let normalization = self.take_normalization();
+        let mut overrides = self.settings.into_commands();
+
+        loop {
+            let pin = match self.session.read_pin(&mut overrides, &normalization, &flow.prompt) {
+                Ok(pin) => pin,
+                Err(Error::NotConfirmed) if flow.touch.is_some() => return Ok(TokenPinOutcome::Touch),
+                Err(Error::Cancelled) => return Ok(TokenPinOutcome::Cancelled),
+                Err(e) => return Err(e),
+            };
+            match flow.check(pin.unsecure()) {
+                Some(text) => set_error_text(&mut overrides, text),
+                None => return Ok(TokenPinOutcome::Entered(pin)),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "process")]
+impl PinentryBuilder {
+    /// Ask for the PIN of a hardware token, following `flow`
+    ///
+    /// See [`SessionPrompt::token_pin`].
+    pub fn token_pin(self, flow: TokenPin) -> Result<TokenPinOutcome> {
+        self.settings.validate(Some(PromptKind::Pin))?;
+        self.connect()?.token_pin(flow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let piv = TokenPin::new().length(6, 8);
+        assert_eq!(None, piv.check(b"123456"));
+        assert_eq!(Some("The PIN must have 6 to 8 digits".to_string()), piv.check(b"12345"));
+        assert_eq!(
+            Some("The PIN must have 6 to 8 digits".to_string()),
+            piv.check(b"123456789")
+        );
+        assert_eq!(
+            Some("The PIN may only contain digits".to_string()),
+            piv.check(b"12345a")
+        );
+
+        let fido = TokenPin::new().numeric(false);
+        assert_eq!(None, fido.check("pä55".as_bytes()));
+        assert_eq!(
+            Some("The PIN must have at least 4 characters".to_string()),
+            fido.check(b"abc")
+        );
+
+        assert_eq!(
+            "Enter PIN\n\n1 attempt remaining before lockout",
+            TokenPin::new().description("Enter PIN").retries(1).full_description()
+        );
+    }
+
+    #[cfg(all(unix, feature = "process"))]
+    #[test]
+    fn test_token_pin() {
+        use super::super::pinentry;
+        use super::super::test_util::FakePinentry;
+
+        // a PIN of the wrong form, then a good one, then the touch button
+        let fake = FakePinentry::new(&[(
+            "GETPIN",
+            r#"n=$((n+1)); case $n in 1) echo "D 12a4"; echo OK;; 2) echo "D 123456"; echo OK;; *) echo "ERR 83886194 Not confirmed <Pinentry>";; esac"#,
+        )]);
+        let mut session = pinentry().exe(fake.exe()).connect().unwrap();
+        let flow = TokenPin::new().description("Enter the PIN").retries(3).touch("_Touch");
+        match session.token_pin(flow.clone()).unwrap() {
+            TokenPinOutcome::Entered(pin) => assert_eq!(SecretPin::from("123456"), pin),
+            x => panic!("unexpected outcome {:?}", x),
+        }
+        assert!(matches!(session.token_pin(flow).unwrap(), TokenPinOutcome::Touch));
+        assert!(session.token_pin(TokenPin::new().retries(0)).is_err());
+
+        let commands = fake.commands();
+        assert!(commands.contains(&"SETDESC Enter the PIN%0A%0A3 attempts remaining before lockout".to_string()));
+        assert!(commands.contains(&"SETNOTOK _Touch".to_string()));
+        assert!(commands.contains(&"SETERROR The PIN may only contain digits".to_string()));
+        assert_eq!(3, commands.iter().filter(|c| *c == "GETPIN").count());
+    }
+}
@@ -0,0 +1,751 @@
+//! Transports over which the Assuan protocol is spoken to pinentry
+//!
+//! By default pinentry is spawned as a child process ([`ProcessTransport`], requires the `process` feature), but any
+//! bidirectional byte stream implementing [`Transport`] can be used instead, e.g. with
+//! [`PinentryBuilder::connect_transport`](super::PinentryBuilder::connect_transport).
+//!
+//! On Unix, a [`UnixStream`] connected to an Assuan server is a transport too, see
+//! [`PinentryBuilder::connect_socket`](super::PinentryBuilder::connect_socket) - useful where spawning processes is
+//! not permitted.
+//!
+//! A [`Connection`] (also available as [`assuan::Connection`]) talks to pinentry over a transport without the
+//! builder, for requests that it does not model.
+//!
+//! # Remote pinentry over SSH
+//!
+//! [`Ssh`] runs pinentry on another machine, so a headless host can show the prompt on an administrator's
+//! workstation:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # #[cfg(feature = "process")]
+//! # fn run() -> pinentry_rs::Result<()> {
+//! use pinentry_rs::pinentry;
+//! use pinentry_rs::transport::Ssh;
+//!
+//! let ssh = Ssh::new("admin@workstation".to_string())
+//!     .program("pinentry-curses")
+//!     .remote_arg("--ttyname")
+//!     .remote_arg("/dev/pts/3")
+//!     .connect_timeout(10);
+//! let mut session = pinentry().respawn(true).connect_with(move || ssh.spawn())?;
+//! let pin = session.pin("Passphrase for backup volume:")?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io;
+use std::io::{BufRead, Read, Write};
+#[cfg(unix)]
+use std::net::Shutdown;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+#[cfg(feature = "process")]
+use std::ffi::{OsStr, OsString};
+#[cfg(feature = "process")]
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+#[cfg(feature = "process")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "process")]
+use std::thread;
+use std::time::Duration;
+#[cfg(feature = "process")]
+use std::time::Instant;
+
+use zeroize::Zeroize;
+
+use super::assuan;
+use super::assuan::{AssuanCommand, AssuanResponse, CommandFilter, InquiryHandler, Line, Reply, Status};
+use super::{invalid, Error, Result, SecretPin};
+
+/// A bidirectional byte stream connected to pinentry (or any other Assuan server)
+pub trait Transport: Read + Write + Send {
+    /// Shut the transport down once the conversation is over
+    ///
+    /// The default implementation does nothing.
+    fn close(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn close(&mut self) -> io::Result<()> {
+        (**self).close()
+    }
+}
+
+/// How long pinentry is given to exit once its standard input is closed, before it is killed
+const EXIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often to check whether pinentry has exited
+#[cfg(feature = "process")]
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A pinentry child process, talked to over its standard input and output
+///
+/// When the transport is closed or dropped, the standard input of the process is closed and the process waited for;
+/// it is killed if it does not exit within a few seconds. If it is dropped while the thread panics, the process is
+/// killed (and reaped) right away.
+#[cfg(feature = "process")]
+pub struct ProcessTransport {
+    // shared with the cancel token, which may kill the process from another thread
+    child: Arc<Mutex<Child>>,
+    // taken when the transport is closed, so that pinentry sees the end of its input
+    stdin: Option<ChildStdin>,
+    stdout: ChildStdout,
+}
+
+#[cfg(feature = "process")]
+impl ProcessTransport {
+    /// Spawn the given pinentry executable
+    pub fn spawn<S: AsRef<OsStr>>(exe: S) -> io::Result<ProcessTransport> {
+        ProcessTransport::from_command(Command::new(exe))
+    }
+
+    /// Spawn pinentry using a prepared command (its standard input and output are replaced by pipes)
+    pub fn from_command(mut cmd: Command) -> io::Result<ProcessTransport> {
+        let mut child = cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        Ok(ProcessTransport {
+            child: Arc::new(Mutex::new(child)),
+            stdin: Some(stdin),
+            stdout,
+        })
+    }
+
+    /// The child process, for killing it from another thread
+    pub(crate) fn child(&self) -> &Arc<Mutex<Child>> {
+        &self.child
+    }
+}
+
+#[cfg(feature = "process")]
+impl Read for ProcessTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+#[cfg(feature = "process")]
+impl Write for ProcessTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdin()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin()?.flush()
+    }
+}
+
+#[cfg(feature = "process")]
+impl ProcessTransport {
+    fn stdin(&mut self) -> io::Result<&mut ChildStdin> {
+        self.stdin
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "the transport is closed"))
+    }
+}
+
+#[cfg(feature = "process")]
+impl Transport for ProcessTransport {
+    fn close(&mut self) -> io::Result<()> {
+        // pinentry exits by itself at the end of its input (or after BYE), which leaves the terminal in order
+        self.stdin = None;
+        let deadline = Instant::now() + EXIT_TIMEOUT;
+        loop {
+            // locked for a moment only, so that the cancel token can still kill the process meanwhile
+            let mut child = self.child.lock().unwrap_or_else(|e| e.into_inner());
+            if child.try_wait()?.is_some() {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                debug!("pinentry did not exit within {:?}, killing it", EXIT_TIMEOUT);
+                // the process may have exited just now, in which case kill fails harmlessly
+                let _ = child.kill();
+                child.wait()?;
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "pinentry did not exit"));
+            }
+            drop(child);
+            thread::sleep(EXIT_POLL_INTERVAL);
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn close(&mut self) -> io::Result<()> {
+        // like the end of input for a process: the server answers what it has been sent and hangs up, which is
+        // waited for (for a limited time) when reading the rest
+        self.set_read_timeout(Some(EXIT_TIMEOUT))?;
+        match self.shutdown(Shutdown::Write) {
+            // the server may already have closed the socket
+            Err(ref e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+            res => res,
+        }
+    }
+}
+
+#[cfg(feature = "process")]
+impl Drop for ProcessTransport {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            // the conversation broke off somewhere, so a dialog may still be open: pinentry only notices the end of its
+            // input once it is closed, and unwinding should not be held up until then
+            debug!("dropped while panicking, killing pinentry");
+            let mut child = self.child.lock().unwrap_or_else(|e| e.into_inner());
+            let _ = child.kill();
+            let _ = child.wait();
+            return;
+        }
+        let _ = self.close();
+    }
+}
+
+/// Runs pinentry on a remote machine through `ssh`
+///
+/// The Assuan protocol is spoken over the standard input and output of `ssh`, so no terminal is allocated on the
+/// remote side - terminal-based flavors need to be told which terminal to use (e.g. `--ttyname`).
+#[cfg(feature = "process")]
+#[derive(Debug, Clone)]
+pub struct Ssh {
+    connect_timeout: Option<u32>,
+    destination: String,
+    exe: OsString,
+    program: String,
+    remote_args: Vec<String>,
+    ssh_args: Vec<String>,
+}
+
+#[cfg(feature = "process")]
+impl Ssh {
+    /// Connect to `destination` (`[user@]host`, or a host alias from the ssh configuration)
+    pub fn new<S: Into<String>>(destination: S) -> Self {
+        Ssh {
+            connect_timeout: None,
+            destination: destination.into(),
+            exe: OsString::from("ssh"),
+            program: "pinentry".to_string(),
+            remote_args: Vec::new(),
+            ssh_args: Vec::new(),
+        }
+    }
+
+    /// Override the path to the `ssh` executable (by default just `ssh`, looked up using `PATH` environment variable)
+    pub fn exe<S: AsRef<OsStr>>(mut self, exe: S) -> Self {
+        self.exe = exe.as_ref().to_os_string();
+        self
+    }
+
+    /// Set the pinentry program to run on the remote machine (by default `pinentry`)
+    pub fn program<S: Into<String>>(mut self, program: S) -> Self {
+        self.program = program.into();
+        self
+    }
+
+    /// Add an argument for the remote pinentry program
+    pub fn remote_arg<S: Into<String>>(mut self, arg: S) -> Self {
+        self.remote_args.push(arg.into());
+        self
+    }
+
+    /// Add an argument for `ssh` itself (e.g. `-p 2222` or `-o BatchMode=yes`)
+    pub fn ssh_arg<S: Into<String>>(mut self, arg: S) -> Self {
+        self.ssh_args.push(arg.into());
+        self
+    }
+
+    /// Give up connecting to the remote machine after `secs` seconds
+    pub fn connect_timeout(mut self, secs: u32) -> Self {
+        self.connect_timeout = Some(secs);
+        self
+    }
+
+    /// Connect to the remote machine and start pinentry there
+    pub fn spawn(&self) -> io::Result<ProcessTransport> {
+        ProcessTransport::from_command(self.command())
+    }
+
+    fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.exe);
+        // the protocol needs a clean pipe, not a terminal
+        cmd.arg("-T");
+        if let Some(secs) = self.connect_timeout {
+            cmd.arg("-o").arg(format!("ConnectTimeout={}", secs));
+        }
+        cmd.args(&self.ssh_args);
+        // ssh passes the remote command to a shell, so it has to be quoted
+        let remote = std::iter::once(&self.program)
+            .chain(&self.remote_args)
+            .map(|arg| shell_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+        cmd.arg("--").arg(&self.destination).arg(remote);
+        cmd
+    }
+}
+
+#[cfg(feature = "process")]
+fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:@,+".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// An Assuan connection over a transport, after the greeting has been received
+///
+/// Requests are sent one at a time with [`send`](Connection::send) or [`request_raw`](Connection::request_raw),
+/// without the checks and retries of sessions. Dropping the connection says `BYE` and shuts the transport down like
+/// [`bye`](Connection::bye), without reporting errors.
+pub struct Connection {
+    stream: BufStream<Box<dyn Transport>>,
+    closed: bool,
+    // the status lines received during the last exchange
+    statuses: Vec<Status>,
+}
+
+impl Connection {
+    /// Connect to pinentry (or any other Assuan server) over `transport`, waiting for its greeting
+    pub fn open<T: Transport + 'static>(transport: T) -> Result<Connection> {
+        let mut connection = Connection {
+            stream: BufStream::new(Box::new(transport) as Box<dyn Transport>),
+            closed: false,
+            statuses: Vec::new(),
+        };
+        if let Err(e) = connection.read_greeting() {
+            debug!("pinentry did not greet: {}", e);
+            connection.close();
+            return Err(e);
+        }
+        debug!("connected to pinentry");
+        Ok(connection)
+    }
+
+    /// Spawn the pinentry program `exe` (a path or a name looked up in `PATH`) and connect to it
+    #[cfg(feature = "process")]
+    pub fn spawn<S: AsRef<OsStr>>(exe: S) -> Result<Connection> {
+        Connection::open(ProcessTransport::spawn(exe)?)
+    }
+
+    /// Send `cmd` and read its response: `PIN` with the data sent back (e.g. for `GETPIN` or `GETINFO`), `OK`, or
+    /// `NOTOK` with the error
+    ///
+    /// Status lines sent before the response are available from [`statuses`](Connection::statuses), and inquiries
+    /// are cancelled.
+    pub fn send(&mut self, cmd: &AssuanCommand) -> Result<AssuanResponse> {
+        self.statuses.clear();
+        assuan::request(&cmd.to_line(), &mut self.stream, &mut self.statuses)
+    }
+
+    /// Send `request` (a command line without the newline, e.g. `SETKEYINFO n/0123`) and read its response like
+    /// [`send`](Connection::send)
+    ///
+    /// Fails with [`Error::InvalidConfiguration`] if `request` is not a client command.
+    pub fn request_raw(&mut self, request: &str) -> Result<AssuanResponse> {
+        self.request_raw_reply(request, None)?.into_response(false)
+    }
+
+    /// Same as [`request_raw`](Connection::request_raw), returning the whole reply - the request and the reply are
+    /// checked with `filter`
+    pub(crate) fn request_raw_reply(&mut self, request: &str, filter: Option<&CommandFilter>) -> Result<Reply> {
+        let line = match Line::parse(request.as_bytes()) {
+            Ok(line @ Line::Command(_, _)) if !request.contains(['\r', '\n']) => line,
+            _ => return Err(invalid(&format!("not an Assuan command: {}", request))),
+        };
+        if let (Some(filter), Line::Command(name, params)) = (filter, &line) {
+            filter.check_request(name, params.as_deref())?;
+        }
+        self.statuses.clear();
+        assuan::request_reply(&line, &mut self.stream, filter, &mut self.statuses)
+    }
+
+    fn read_greeting(&mut self) -> Result<()> {
+        // Check whether first line is OK
+        match assuan::read_line(&mut self.stream)? {
+            Some(Line::Ok(greeting)) => {
+                trace!("< OK {}", greeting.as_deref().unwrap_or_default());
+                Ok(())
+            }
+            Some(line) => Err(Error::ProtocolError(format!("unexpected greeting: {:?}", line))),
+            None => Err(Error::ProtocolError("pinentry exited without a greeting".to_string())),
+        }
+    }
+
+    pub(crate) fn process_commands(
+        &mut self,
+        cmds: &[AssuanCommand],
+        filter: Option<&CommandFilter>,
+    ) -> Result<AssuanResponse> {
+        self.process_commands_with(cmds, &mut |_| None, filter)
+    }
+
+    pub(crate) fn process_commands_with(
+        &mut self,
+        cmds: &[AssuanCommand],
+        on_inquire: &mut InquiryHandler<'_>,
+        filter: Option<&CommandFilter>,
+    ) -> Result<AssuanResponse> {
+        self.statuses.clear();
+        assuan::process_stream_with(cmds.iter(), &mut self.stream, on_inquire, filter, &mut self.statuses)
+    }
+
+    /// The status lines received during the last exchange of commands
+    pub fn statuses(&self) -> &[Status] {
+        &self.statuses
+    }
+
+    /// Forget the status lines of the last exchange, when a prompt is answered without pinentry
+    pub(crate) fn clear_statuses(&mut self) {
+        self.statuses.clear();
+    }
+
+    /// Say `BYE`, shut the transport down (waiting for pinentry to exit) and check that pinentry acknowledged it
+    pub fn bye(&mut self) -> Result<()> {
+        debug!("saying goodbye to pinentry");
+        self.shut_down()
+    }
+
+    /// Say `BYE` (if still possible) and shut the transport down, ignoring errors
+    pub(crate) fn close(&mut self) {
+        if self.closed {
+            return;
+        }
+        debug!("closing the connection to pinentry");
+        let _ = self.shut_down();
+    }
+
+    fn shut_down(&mut self) -> Result<()> {
+        self.closed = true;
+        let mut buf = Vec::new();
+        AssuanCommand::Bye.to_line().encode(&mut buf)?;
+        let sent = self.stream.write_all(&buf).and_then(|_| self.stream.flush());
+        // waits (for a limited time) for pinentry to exit
+        let closed = self.stream.inner.close();
+        sent?;
+        closed?;
+        // pinentry answers before exiting, so reading the answer does not block
+        match assuan::read_line(&mut self.stream)? {
+            Some(Line::Ok(_)) => Ok(()),
+            Some(line) => Err(Error::from_response(assuan::describe(&line))),
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "pinentry exited without answering").into()),
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// The size of the read buffer of connections
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// A buffered reader that passes writes through to the underlying stream
+///
+/// The buffer is locked in memory (with the `secstr` feature), and bytes are wiped as soon as they have been consumed,
+/// as they may be part of a PIN.
+struct BufStream<T> {
+    inner: T,
+    buf: SecretPin,
+    // the unread bytes are buf[pos..filled]
+    pos: usize,
+    filled: usize,
+}
+
+impl<T> BufStream<T> {
+    fn new(inner: T) -> Self {
+        BufStream {
+            inner,
+            buf: SecretPin::new(vec![0; BUFFER_SIZE]),
+            pos: 0,
+            filled: 0,
+        }
+    }
+}
+
+impl<T: Read> Read for BufStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<T: Read> BufRead for BufStream<T> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.filled {
+            self.pos = 0;
+            self.filled = 0;
+            self.filled = self.inner.read(self.buf.unsecure_mut())?;
+        }
+        Ok(&self.buf.unsecure()[self.pos..self.filled])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let end = (self.pos + amt).min(self.filled);
+        self.buf.unsecure_mut()[self.pos..end].zeroize();
+        self.pos = end;
+    }
+}
+
+impl<T: Write> Write for BufStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    use std::fs;
+    use std::io::Cursor;
+    use std::str;
+    use std::sync::{Arc, Mutex};
+
+    use super::super::pinentry;
+
+    /// A transport replaying canned responses and recording what is written to it
+    struct ScriptedTransport {
+        responses: Cursor<Vec<u8>>,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Read for ScriptedTransport {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.responses.read(buf)
+        }
+    }
+
+    impl Write for ScriptedTransport {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for ScriptedTransport {}
+
+    #[test]
+    fn test_session_over_transport() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let transport = ScriptedTransport {
+            responses: Cursor::new(b"OK Pleased to meet you\nOK\nOK\nD secret\nOK\n".to_vec()),
+            written: written.clone(),
+        };
+
+        let mut session = pinentry()
+            .window_title("Socket")
+            .connect_transport(transport)
+            .expect("session is started");
+        let pin = session.pin("PIN:").expect("PIN is returned");
+
+        assert_eq!("secret", str::from_utf8(pin.unsecure()).unwrap());
+        assert_eq!(
+            "SETTITLE Socket\nSETPROMPT PIN:\nGETPIN\n",
+            str::from_utf8(&written.lock().unwrap()).unwrap()
+        );
+    }
+
+    #[cfg(feature = "process")]
+    #[test]
+    fn test_ssh_command() {
+        let ssh = Ssh::new("admin@workstation".to_string())
+            .program("pinentry-curses")
+            .remote_arg("--ttyname")
+            .remote_arg("/dev/pts/3")
+            .remote_arg("it's")
+            .ssh_arg("-p2222")
+            .connect_timeout(10);
+        let cmd = ssh.command();
+
+        assert_eq!("ssh", cmd.get_program());
+        let args: Vec<_> = cmd.get_args().map(|a| a.to_str().unwrap()).collect();
+        assert_eq!(
+            vec![
+                "-T",
+                "-o",
+                "ConnectTimeout=10",
+                "-p2222",
+                "--",
+                "admin@workstation",
+                r"pinentry-curses --ttyname /dev/pts/3 'it'\''s'",
+            ],
+            args
+        );
+    }
+
+    #[test]
+    fn test_connection() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let transport = ScriptedTransport {
+            responses: Cursor::new(
+                b"OK Pleased to meet you\nOK\n# comment\nD 1.2.\nD 3\nOK\nS PIN_REPEATED\nD sec%0Aret\nOK\n\
+                  ERR 536871187 Unknown IPC command <User defined source 1>\nOK closing connection\n"
+                    .to_vec(),
+            ),
+            written: written.clone(),
+        };
+
+        let mut connection = Connection::open(transport).expect("connection is opened");
+        assert!(matches!(
+            connection.request_raw("SETKEYINFO n/0123"),
+            Ok(AssuanResponse::OK)
+        ));
+        match connection.send(&AssuanCommand::GetInfo("version".to_string())) {
+            Ok(AssuanResponse::PIN(version)) => assert_eq!(b"1.2.3", version.unsecure()),
+            x => panic!("unexpected result {:?}", x),
+        }
+        match connection.send(&AssuanCommand::GetPin) {
+            Ok(AssuanResponse::PIN(pin)) => assert_eq!(b"sec\nret", pin.unsecure()),
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert_eq!("PIN_REPEATED", connection.statuses()[0].keyword);
+        match connection.request_raw("FROB") {
+            Ok(AssuanResponse::NOTOK(error)) => assert!(error.starts_with("ERR 536871187")),
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert!(connection.statuses().is_empty());
+        connection.bye().expect("pinentry says goodbye");
+
+        assert_eq!(
+            "SETKEYINFO n/0123\nGETINFO version\nGETPIN\nFROB\nBYE\n",
+            str::from_utf8(&written.lock().unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_connection_request_raw_rejects_non_commands() {
+        let transport = ScriptedTransport {
+            responses: Cursor::new(b"OK Pleased to meet you\n".to_vec()),
+            written: Arc::new(Mutex::new(Vec::new())),
+        };
+        let mut connection = Connection::open(transport).expect("connection is opened");
+        for request in &["OK", "D data", "", "GETPIN\nBYE"] {
+            assert!(matches!(
+                connection.request_raw(request),
+                Err(Error::InvalidConfiguration(_))
+            ));
+        }
+    }
+
+    #[cfg(all(feature = "process", unix))]
+    #[test]
+    fn test_process_killed_on_panic() {
+        use std::thread;
+
+        // `sleep` does not exit at the end of its input, like pinentry showing a dialog
+        let transport = ProcessTransport::from_command({
+            let mut cmd = Command::new("sleep");
+            cmd.arg("30");
+            cmd
+        })
+        .unwrap();
+        let child = transport.child().clone();
+        let started = Instant::now();
+        let res = thread::spawn(move || {
+            let _transport = transport;
+            panic!("caller failed mid-exchange");
+        })
+        .join();
+        assert!(res.is_err());
+        // killed without waiting for the exit timeout, and reaped
+        assert!(started.elapsed() < EXIT_TIMEOUT);
+        assert!(child.lock().unwrap().try_wait().unwrap().is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_connect_socket() {
+        use std::io::BufReader;
+        use std::os::unix::net::UnixListener;
+        use std::thread;
+
+        let dir = std::env::temp_dir().join(format!("pinentry-rs-socket-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("S.pinentry");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut received = Vec::new();
+            writer.write_all(b"OK Pleased to meet you\n").unwrap();
+            for line in BufReader::new(stream).lines() {
+                let line = line.unwrap();
+                let response: &[u8] = match line.as_str() {
+                    "GETPIN" => b"D secret\nOK\n",
+                    "BYE" => b"OK closing connection\n",
+                    _ => b"OK\n",
+                };
+                writer.write_all(response).unwrap();
+                received.push(line);
+            }
+            received
+        });
+
+        let mut session = pinentry()
+            .window_title("Socket")
+            .connect_socket(&path)
+            .expect("session is started");
+        let pin = session.pin("PIN:").expect("PIN is returned");
+        assert_eq!(b"secret", pin.unsecure());
+        session.close().expect("session is closed");
+
+        assert_eq!(
+            vec!["SETTITLE Socket", "SETPROMPT PIN:", "GETPIN", "BYE"],
+            server.join().unwrap()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_connect_transport_rejects_respawn() {
+        let transport = ScriptedTransport {
+            responses: Cursor::new(b"OK Pleased to meet you\n".to_vec()),
+            written: Arc::new(Mutex::new(Vec::new())),
+        };
+        let res = pinentry().respawn(true).connect_transport(transport);
+        assert!(matches!(res, Err(Error::InvalidConfiguration(_))));
+    }
+
+    #[test]
+    fn test_buffer_wiped() {
+        let mut stream = BufStream::new(Cursor::new(b"D s%65cret\nOK\n".to_vec()));
+        match assuan::read_line(&mut stream).unwrap() {
+            Some(Line::Data(pin)) => assert_eq!(b"secret", pin.unsecure()),
+            x => panic!("unexpected line {:?}", x),
+        }
+        // the PIN line has been wiped from the buffer, only the unread rest is left
+        let buf = stream.buf.unsecure();
+        assert!(buf[..stream.pos].iter().all(|&b| b == 0));
+        assert_eq!(b"OK\n", &buf[stream.pos..stream.filled]);
+        assert!(matches!(assuan::read_line(&mut stream).unwrap(), Some(Line::Ok(None))));
+        assert!(stream.buf.unsecure().iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_connection_bad_greeting() {
+        let transport = ScriptedTransport {
+            responses: Cursor::new(b"ERR 1 go away\n".to_vec()),
+            written: Arc::new(Mutex::new(Vec::new())),
+        };
+        assert!(pinentry().connect_transport(transport).is_err());
+    }
+}
@@ -0,0 +1,88 @@
+//! Types for the verifier-driven unlock loop
+//!
+//! Most applications prompt for a passphrase, try to unlock something with it, and prompt again with an error
+//! text if that fails. `unlock()` (on [`PinentryBuilder`](super::PinentryBuilder),
+//! [`PinentrySession`](super::PinentrySession) and [`SessionPrompt`](super::SessionPrompt)) manages that loop:
+//!
+//! ```no_run
+//! # extern crate pinentry_rs;
+//! # #[cfg(feature = "process")]
+//! # fn run() -> Result<(), pinentry_rs::unlock::UnlockError<std::io::Error>> {
+//! use pinentry_rs::pinentry;
+//! use pinentry_rs::unlock::VerifyError;
+//!
+//! # fn open_vault(_: &[u8]) -> Result<Option<()>, std::io::Error> { Ok(Some(())) }
+//! let vault = pinentry().max_attempts(5).unlock("Vault passphrase:", |passphrase| {
+//!     match open_vault(passphrase) {
+//!         Ok(Some(vault)) => Ok(vault),
+//!         Ok(None) => Err(VerifyError::Retry("Wrong passphrase".to_string())),
+//!         Err(e) => Err(VerifyError::Fatal(e)),
+//!     }
+//! })?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::error;
+use std::fmt::{Display, Formatter};
+
+use super::messages::Message;
+use super::Error;
+
+/// Number of attempts if not configured otherwise
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+
+/// Why a verifier did not accept a PIN
+#[derive(Debug)]
+pub enum VerifyError<E> {
+    /// The PIN is wrong - prompt again, showing the message as error text
+    Retry(String),
+    /// Unlocking failed for another reason - give up
+    Fatal(E),
+}
+
+/// Errors of the unlock loop
+#[derive(Debug)]
+pub enum UnlockError<E> {
+    /// Prompting failed (including the user cancelling the prompt)
+    Pinentry(Error),
+    /// The verifier gave up with an error
+    Failed(E),
+    /// All attempts were used up with wrong PINs
+    LockedOut {
+        /// Number of PINs tried
+        attempts: u32,
+    },
+}
+
+impl<E: Display> Display for UnlockError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnlockError::Pinentry(ref cause) => write!(f, "{}", cause),
+            UnlockError::Failed(ref cause) => write!(
+                f,
+                "{}",
+                Message::UnlockFailed {
+                    cause: &cause.to_string()
+                }
+            ),
+            UnlockError::LockedOut { attempts } => write!(f, "{}", Message::LockedOut { attempts: *attempts }),
+        }
+    }
+}
+
+impl<E: error::Error + 'static> error::Error for UnlockError<E> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            UnlockError::Pinentry(ref cause) => Some(cause),
+            UnlockError::Failed(ref cause) => Some(cause),
+            UnlockError::LockedOut { .. } => None,
+        }
+    }
+}
+
+impl<E> From<Error> for UnlockError<E> {
+    fn from(e: Error) -> Self {
+        UnlockError::Pinentry(e)
+    }
+}